@@ -194,6 +194,18 @@ defmodule Contents.Behaviour.Content do
   """
   @callback world_size() :: {width :: float(), height :: float()}
 
+  @doc """
+  ローカルプレイヤーの移動規則を Formula の式言語ソースで返す。クライアント予測用のオプション。
+
+  `Network.ZenohBridge` が `Core.Formula.compile_source/1` でコンパイルし、
+  `game/room/{room_id}/predict/movement` へ配信する。クライアントは同じ VM で実行し、
+  権威スナップショットの間の `PlayerSprite` の位置を予測する。
+  入力は `x`, `y`（現在位置）、`dx`, `dy`（移動入力）、`dt`（秒）。出力は次の `x`, `y`（2 出力または `vec2`）。
+  サーバー側の移動処理と同じ規則にすること（ずれはスナップショット受信時に補正される）。
+  未実装の Content では配信せず、クライアントは補間位置のまま描く。
+  """
+  @callback movement_formula() :: String.t()
+
   @optional_callbacks [
     entity_registry: 0,
     enemy_exp_reward: 1,
@@ -203,6 +215,7 @@ defmodule Contents.Behaviour.Content do
     after_zenoh_audio_cues_sent: 1,
     mesh_definitions: 0,
     world_size: 0,
+    movement_formula: 0,
     on_quit_requested: 0,
     level_up_scene: 0,
     boss_alert_scene: 0,
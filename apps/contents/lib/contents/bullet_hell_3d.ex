@@ -31,6 +31,8 @@ defmodule Content.BulletHell3D do
   def build_frame(playing_state, context),
     do: Content.BulletHell3D.Playing.build_frame(playing_state, context)
 
+  def movement_formula, do: Content.BulletHell3D.Playing.movement_formula()
+
   def zenoh_audio_cues(playing_state) do
     Map.get(playing_state, :pending_zenoh_audio_relpaths, [])
   end
@@ -408,6 +408,19 @@ defmodule Content.BulletHell3D.Playing do
     {clamp(nx, -@field_half, @field_half), 0.0, clamp(nz, -@field_half, @field_half)}
   end
 
+  @doc """
+  `move_player/4` と同じ移動規則の Formula 式言語ソース（クライアント予測用）。
+  入力の `x`, `y` はプレイヤーの X, Z 座標、`dx`, `dy` は移動入力 `{dx, dz}`。
+  """
+  def movement_formula do
+    """
+    let len = sqrt(dx * dx + dy * dy)
+    let step = select(len > 0.001, #{@player_speed} * dt / max(len, 0.001), 0.0)
+    output clamp(x + dx * step, -#{@field_half}, #{@field_half})
+    output clamp(y + dy * step, -#{@field_half}, #{@field_half})
+    """
+  end
+
   # ── 弾移動 ────────────────────────────────────────────────────────
 
   defp move_bullets(bullet_objects, dt) do
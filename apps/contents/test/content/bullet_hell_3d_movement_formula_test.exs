@@ -0,0 +1,52 @@
+defmodule Content.BulletHell3DMovementFormulaTest do
+  @moduledoc false
+  use ExUnit.Case, async: false
+
+  alias Content.BulletHell3D.Playing
+
+  # {開始位置 {x, z}, 移動入力 {dx, dz}}。斜め入力の正規化・フィールド端のクランプ・停止
+  @cases [
+    {{0.0, 0.0}, {3.0, 4.0}},
+    {{9.9, -2.0}, {1.0, 0.0}},
+    {{1.0, 1.0}, {0.0, 0.0}}
+  ]
+
+  setup do
+    if :ets.whereis(:client_info) == :undefined do
+      :ets.new(:client_info, [:named_table, :public, :set, read_concurrency: true])
+    end
+
+    :ets.delete_all_objects(:client_info)
+
+    assert {:ok, bytecode} = Network.ZenohBridge.compile_movement_formula(Content.BulletHell3D)
+    {:ok, bytecode: bytecode}
+  end
+
+  test "移動 Formula はサーバーの移動処理と同じ位置を返す", %{bytecode: bytecode} do
+    for {{x, z}, {dx, dz} = input} <- @cases do
+      {:ok, state} = Playing.init(%{})
+      state = put_in(state.player_object.transform.position, {x, 0.0, z})
+
+      assert {:continue, state} =
+               Playing.update(%{dt: 0.1, tick_ms: 100}, %{state | move_input: input})
+
+      {server_x, _y, server_z} = state.player_object.transform.position
+
+      inputs = %{"x" => x, "y" => z, "dx" => dx, "dy" => dz, "dt" => 0.1}
+      assert {:ok, {[predicted_x, predicted_z], []}} = Core.Formula.run(bytecode, inputs)
+      assert_in_delta predicted_x, server_x, 1.0e-5
+      assert_in_delta predicted_z, server_z, 1.0e-5
+    end
+  end
+
+  test "ZenohBridge は client_info のあるルームへ移動 Formula を送る", %{bytecode: bytecode} do
+    {:ok, session_id} = Zenohex.Session.open(Zenohex.Config.default())
+    key = "game/room/main/predict/movement"
+    {:ok, _sub} = Zenohex.Session.declare_subscriber(session_id, key, self())
+    :ets.insert(:client_info, {{:main, :info}, %{os: "linux", arch: "x86_64", family: "unix"}})
+
+    state = %{session_id: session_id, movement_formula: {:ok, bytecode}}
+    assert {:noreply, ^state} = Network.ZenohBridge.handle_info(:publish_movement_formula, state)
+    assert_receive %Zenohex.Sample{key_expr: ^key, payload: ^bytecode, kind: :put}, 2_000
+  end
+end
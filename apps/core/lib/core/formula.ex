@@ -17,15 +17,127 @@ defmodule Core.Formula do
   - 8: LT, 9: GT, 10: EQ
   - 11: STORE_OUTPUT
   - 12: READ_STORE, 13: WRITE_STORE（Phase 2）
+  - 14: JUMP, 15: JUMP_IF_FALSE, 16: JUMP_IF_TRUE（ジャンプ先はバイトオフセット。前方のみ）
+  - 17: ABS, 18: FLOOR, 19: CEIL, 20: SQRT, 21: SIN, 22: COS（単項）
+  - 23: MIN, 24: MAX, 25: ATAN2, 26: POW, 27: MOD（二項）
+  - 28: CLAMP, 29: LERP（三項）
+  - 30: NOT, 31: AND, 32: OR, 33: XOR（論理）
+  - 34: LE, 35: GE, 36: NE（比較）
+  - 37: SELECT（分岐なし選択）
+  - 38: LOAD_VEC, 39: MAKE_VEC, 40: EXTRACT, 41: DOT, 42: LENGTH, 43: NORMALIZE（ベクトル）
+  - 44: LOAD_CONST（コンテナ形式のみ。定数プールのインデックス）
+  - 45: CALL, 46: RET（関数ライブラリの呼び出し）
+  - 47: RANDOM, 48: RANDOM_RANGE（決定的な疑似乱数）
+  - 49: EMIT（名前付きイベントの発行）
+  - 50: LIST_LEN, 51: LIST_INDEX, 52: LIST_SUM, 53: LIST_MIN, 54: LIST_MAX, 55: FOR_EACH, 56: LIST_MAP（リスト）
+  - 57: LOAD_I64, 58: LOAD_F64, 59: CAST_I32, 60: CAST_F32, 61: CAST_I64, 62: CAST_F64（64 bit 値と型変換）
+
+  ## イベント
+  `{:emit, "enemy_died", src}`（ペイロードなしは `{:emit, "wave_cleared"}`）は名前付きイベントを発行する。
+  `run_events/4` は出力・Store に加えて発行順のイベント `[{name, payload | nil}, ...]` を返すため、
+  値の差分から推測せずにそのまま `Core.EventBus.broadcast/1` へ渡せる。`run/4` などはイベントを捨てる。
+
+  ## 変更セット
+  `run/4` などは Store 全体を複製して返すため、大きな Store では書き込みが 1 件でもその分の NIF コストがかかる。
+  `run_changes/4` は Store を読み取り専用で参照し（デコード・複製しない）、書き込んだキーだけを
+  `{key, before, after, :written | :unchanged}` のリストで返す。`apply_changes/2` で元の Store に反映できる。
+
+  ## トレース
+  `run_traced/4` は命令ごとに読み出したレジスタの値と書き込んだ値を記録しながら実行する。
+  本番で想定外の値が出た数式を同じ入力・Store で再実行し、`format_trace/1` でログに出す使い方を想定する。
+  `profile: true` で OpCode ごとの実行回数と所要時間も集計する。通常の実行にはトレースのコストは掛からない。
+
+  ## 関数ライブラリ
+  共通の部分計算（ダメージ軽減・クールダウン等）は名前付き関数として `compile_library/1` で一度だけ登録し、
+  複数のプログラムから `{:call, dst, "name", [args]}` で呼び出せる。関数は引数を r0.. で受け取り `{:ret, src}` で返す。
+  レジスタは呼び出しごとに独立し、入力・Store・出力は呼び出し元と共有する。
+  プログラムには `link/2`（または `run/4` の `:library` オプション）でライブラリを結び付ける。
+
+  ## Store スキーマ
+  `with_store_schema/2`（または `run/4` の `:store_schema` オプション）でプログラムに
+  `%{"hp" => {:i32, 100}, "pos" => {:vec2, {0.0, 0.0}}}` のような Store キーの型と既定値を結び付けられる。
+  宣言したキーが Store に無ければ `{:error, :store_not_found, key, location}` の代わりに既定値を読む
+  （既定値は書き込まれるまで戻り値の Store に現れない）。宣言と異なる型の値を読み書きすると
+  `{:error, :store_type_mismatch, {key, expected, actual}, location}`。型は厳密に比較し、`:f32` のキーに整数は書けない
+  （`:i64` / `:f64` のキーは、Store から読んだ i32 / f32 の値を値を変えずに広げて読む）。
+  宣言していないキーは従来どおり検査しない。
+
+  ## 64 bit 値
+  入力・Store の integer は i32 に収まれば i32、収まらなければ i64 として渡る（i64 も超えると
+  `{:error, :integer_out_of_range, "123..."}`）。float は既定で f32 に丸めるため、倍精度で渡す値は `{:f64, x}` と書く。
+  演算は i32 同士なら i32、整数だけで i64 を含めば i64（どちらも飽和）、i64 / f64 を含む浮動小数点の演算は f64、
+  それ以外は従来どおり f32。`{:load_i64, dst, n}`・`{:load_f64, dst, x}` で 64 bit の定数を、
+  `{:cast_i32 | :cast_f32 | :cast_i64 | :cast_f64, dst, src}` で明示的な型変換を書ける
+  （整数へは 0 方向に切り捨て、収まらない値・NaN・無限大は `{:error, :domain_error, "cast_i32", location}` 等）。
+  戻り値では i64 は integer、f64 は float になる。
+
+  ## 乱数
+  `{:random, dst}`（[0, 1) の float）と `{:random_range, dst, lo, hi}`（整数同士なら両端を含む整数、
+  それ以外は [lo, hi) の float）は Store の予約キー `"$rng"` に置いた状態から決定的に値を作り、状態を進めて書き戻す。
+  実行は `(bytecode, inputs, store_values)` だけで決まるため、リプレイやサーバーでの検証で同じ結果を再現できる。
+  状態は `seed_random/2` で初期化する（無ければ `{:error, :store_not_found, "$rng", location}`。
+  Store スキーマで `"$rng"` の既定値を宣言していればそれを使う）。
+  返った Store を次の実行に渡せば列の続きになる。
+
+  ## コンテナ形式
+  `build(instructions, container: true)` はマジック `<<0xFF, "FVM">>`・バージョン・文字列表・
+  定数プール・CRC-32 を持つバージョン付き形式を出力する。名前は文字列表の u16 インデックスで参照するため
+  255 バイト制限がない。ヘッダのない従来形式も引き続き実行できる。`pack/1` で従来形式を変換できる。
+
+  ## ベクトル値
+  入力・Store には `{x, y}` / `{x, y, z}` のタプル、または `%{x: _, y: _}` / `%{x: _, y: _, z: _}` の
+  マップを渡せる。出力・Store の戻り値では float のタプルになる。
+
+  ## リスト値
+  入力・Store には数値・真偽値・ベクトルを要素に持つリスト（`[1, 2.5, {0.0, 1.0}]`。入れ子は不可）を渡せる。
+  `{:list_len, dst, src}`・`{:list_index, dst, list, index}`（0 始まり。範囲外は `{:error, :index_out_of_range, index, location}`）・
+  `{:list_sum | :list_min | :list_max, dst, src}` で集計し、`{:for_each, dst, "name", list, init}` は
+  ライブラリ関数 name(acc, elem) で畳み込み、`{:list_map, dst, "name", list}` は name(elem) の結果のリストを作る。
+  反復回数はリスト長で決まり、長さは `:max_list_len` で制限する。
+  `run_batch/4` の列形式ではリストが列として解釈されるため、全エンティティ共通のリストは行形式で渡す。
+
+  ## エラーの位置
+  実行・デコードのエラーは `{:error, reason, detail, location}` で、location は失敗した命令の
+  `%{pc: 命令インデックス, offset: バイトオフセット, function: 関数名 | nil, origin: 生成元 | nil}`。
+  offset はコード部分の先頭から数え（コンテナ形式のヘッダ・表を含まない）、関数の中で失敗した場合は
+  function とその関数内の pc / offset になる。Ret せずに終端に達した関数は pc が命令数、offset がコード長。
+  最適化したプログラムでも offset は最適化前のバイトコード上の位置を指す。
+  コンテナのヘッダ・表の誤りは location が nil。入力の整数範囲・Store スキーマの誤りなど、
+  命令に関係しないエラーは従来どおり `{:error, reason, detail}`。
+
+  生成したプログラムでは、命令リストに `{:origin, node_id}`（または `{:origin, {line, column}}`）を挟み、
+  `source_map/2` で得た `[{offset, origin}]` を `with_source_map/2`（または `run/4` の `:source_map` オプション）で
+  結び付けると、location の origin に失敗した命令を生成したノード ID・ソース位置が入る。
+  `Core.FormulaGraph.run/3` はグラフのノード ID を自動で結び付ける。
+
+  ## 実行上限（opts）
+  `run/4`・`run_compiled/4`・`run_batch/4` は最後の引数にキーワードリストを取る。
+  - `:max_instructions` — 実行できる命令数（既定 100_000）
+  - `:max_store_entries` — 実行後の Store の最大キー数（既定 4_096）
+  - `:max_outputs` — 出力の最大数（既定 1_024）
+  - `:max_call_depth` — 関数呼び出しの入れ子の最大数（既定 16）
+  - `:max_events` — EMIT で発行できるイベントの最大数（既定 256）
+  - `:max_list_len` — 入力・Store から読むリストと LIST_MAP の結果の最大長（既定 4_096）
+  - `:dirty` — `true` なら DirtyCpu スケジューラで実行する（長いプログラム・大きなバッチ向け）
+
+  上限を超えると `{:error, :budget_exceeded, {kind, limit}, location}` を返す
+  （kind は `:instructions` | `:store_entries` | `:outputs` | `:call_depth` | `:events` | `:list_len`）。
   """
 
   alias Core.NifBridge
 
+  @random_state_key "$rng"
+  # EMIT の src オペランドで「ペイロードなし」を表す値
+  @no_payload 0xFF
+
   @doc """
   バイトコードを実行し、出力値のリストと更新後の Store を返す。
 
   store_values を省略した場合は空の map を渡し、Store を使わない実行になる。
   戻り値の updated_store はキー・値のリスト形式。Map.new/1 で map に変換可能。
+  opts の `:library` に `compile_library/1` のハンドルを渡すと、CALL の呼び出し先として使う。
+  `:store_schema` に Store スキーマを渡すと、宣言したキーの既定値と型検査を使う（`with_store_schema/2`）。
+  `:source_map` に `source_map/2` の結果を渡すと、エラーの location に生成元が入る（`with_source_map/2`）。
 
   ## 例
       bytecode = Core.Formula.build([...])
@@ -35,29 +147,523 @@ defmodule Core.Formula do
       Core.Formula.run(bytecode, %{}, %{"score" => 0})
       # => {:ok, {outputs, [{"score", new_value}, ...]}}
   """
-  @spec run(binary(), map(), map()) ::
-          {:ok, {[number() | boolean()], [{String.t(), number() | boolean()}]}}
-          | {:error, atom(), String.t() | integer() | nil}
-  def run(bytecode, inputs, store_values \\ %{})
-      when is_binary(bytecode) and is_map(inputs) and is_map(store_values) do
-    case NifBridge.run_formula_bytecode(bytecode, inputs, store_values) do
+  @type element ::
+          number() | boolean() | {:f64, number()} | {float(), float()} | {float(), float(), float()}
+  @type value :: element() | [element()]
+
+  @type location :: %{
+          pc: non_neg_integer(),
+          offset: non_neg_integer(),
+          function: String.t() | nil,
+          origin: origin() | nil
+        }
+  @type origin :: atom() | {pos_integer(), pos_integer()}
+  @type error :: {:error, atom(), term()} | {:error, atom(), term(), location() | nil}
+  @type source_map :: [{non_neg_integer(), origin()}]
+
+  @type run_opt ::
+          {:max_instructions, non_neg_integer()}
+          | {:max_store_entries, non_neg_integer()}
+          | {:max_outputs, non_neg_integer()}
+          | {:max_call_depth, non_neg_integer()}
+          | {:max_events, non_neg_integer()}
+          | {:max_list_len, non_neg_integer()}
+          | {:dirty, boolean()}
+          | {:library, reference()}
+          | {:store_schema, store_schema()}
+          | {:source_map, source_map()}
+
+  @limit_keys [
+    :max_instructions,
+    :max_store_entries,
+    :max_outputs,
+    :max_call_depth,
+    :max_events,
+    :max_list_len
+  ]
+
+  @spec run(binary(), map(), map(), [run_opt()]) ::
+          {:ok, {[value()], [{String.t(), value()}]}}
+          | error()
+  def run(bytecode, inputs, store_values \\ %{}, opts \\ [])
+
+  def run(bytecode, inputs, store_values, opts)
+      when is_binary(bytecode) and is_map(inputs) and is_map(store_values) and is_list(opts) do
+    {library, opts} = Keyword.pop(opts, :library)
+    {store_schema, opts} = Keyword.pop(opts, :store_schema)
+    {source_map, opts} = Keyword.pop(opts, :source_map)
+
+    if is_nil(library) and is_nil(store_schema) and is_nil(source_map) do
+      run_bytecode(bytecode, inputs, store_values, opts)
+    else
+      with {:ok, program} <- prepare(bytecode, library, store_schema, source_map) do
+        run_compiled(program, inputs, store_values, opts)
+      end
+    end
+  end
+
+  defp prepare(bytecode, library, store_schema, source_map) do
+    with {:ok, program} <- if(library, do: link(bytecode, library), else: compile(bytecode)),
+         {:ok, program} <- bind(program, store_schema, &with_store_schema/2) do
+      bind(program, source_map, &with_source_map/2)
+    end
+  end
+
+  defp bind(program, nil, _fun), do: {:ok, program}
+  defp bind(program, value, fun), do: fun.(program, value)
+
+  defp run_bytecode(bytecode, inputs, store_values, opts) do
+    {limits, dirty?} = split_opts(opts)
+
+    result =
+      if dirty?,
+        do: NifBridge.run_formula_bytecode_dirty(bytecode, inputs, store_values, limits),
+        else: NifBridge.run_formula_bytecode(bytecode, inputs, store_values, limits)
+
+    case result do
       {:ok, {outputs, store_list}} -> {:ok, {outputs, store_list}}
+      {:error, reason, detail, location} -> {:error, reason, detail, location}
       {:error, reason, detail} -> {:error, reason, detail}
     end
   end
 
+  @doc """
+  バイトコードを一度だけデコード・検証し、プログラムハンドルを返す。
+
+  毎 tick 同じ数式を実行する場合は `run/3` の代わりに `compile/1` と `run_compiled/3` を使うと、
+  デコードと名前の確保を実行ごとに繰り返さずに済む。ハンドルは不変で、プロセス間で共有してよい。
+
+  ## 例
+      {:ok, program} = Core.Formula.compile(bytecode)
+      Core.Formula.run_compiled(program, %{"dt" => 0.05})
+  """
+  @spec compile(binary()) :: {:ok, reference()} | error()
+  def compile(bytecode) when is_binary(bytecode) do
+    NifBridge.compile_formula(bytecode)
+  end
+
+  @doc """
+  Store に乱数のシードを設定する。seed は任意の整数（i32 に収まらない値は下位 32 bit を使う）。
+
+      store = Formula.seed_random(%{"hp" => 100}, 12_345)
+      Formula.run(Formula.build([{:random, 0}, {:store_output, 0}]), %{}, store)
+      # => {:ok, {[roll], [{"$rng", next_state}, {"hp", 100}]}}
+  """
+  @spec seed_random(map(), integer()) :: map()
+  def seed_random(store_values, seed) when is_map(store_values) and is_integer(seed) do
+    <<state::little-signed-32>> = <<seed::little-32>>
+    Map.put(store_values, @random_state_key, state)
+  end
+
+  @doc """
+  関数名 => バイトコードのマップから関数ライブラリを作り、ハンドルを返す。
+
+  関数は引数を r0, r1, ... で受け取り、`{:ret, src}` で値を返す（返さずに終端に達すると
+  `{:error, :missing_return, name, location}`）。ライブラリ内の関数どうしも呼び合える。
+  ハンドルは不変で、複数のプログラム・プロセスから共有してよい。
+  デコードに失敗した関数があれば `{:error, :invalid_function, {name, {reason, detail, location}}}`。
+
+  ## 例
+      reduce = Core.Formula.build([
+        {:load_i32, 2, 100}, {:sub, 3, 2, 1}, {:mul, 4, 0, 3}, {:div, 5, 4, 2}, {:ret, 5}
+      ])
+      {:ok, library} = Core.Formula.compile_library(%{"reduce_damage" => reduce})
+  """
+  @spec compile_library(%{optional(String.t()) => binary()}) ::
+          {:ok, reference()} | error()
+  def compile_library(functions) when is_map(functions) do
+    NifBridge.compile_formula_library(functions)
+  end
+
+  @doc """
+  バイトコードをデコード・検証し、`compile_library/1` のライブラリを結び付けたプログラムハンドルを返す。
+  `run_compiled/4`・`run_batch/4` で実行する。関数名は実行時に解決し、無ければ `{:error, :undefined_function, name, location}`。
+  """
+  @spec link(binary(), reference()) :: {:ok, reference()} | error()
+  def link(bytecode, library) when is_binary(bytecode) and is_reference(library) do
+    NifBridge.link_formula(bytecode, library)
+  end
+
+  @type store_schema :: %{optional(String.t()) => {value_type(), value()}}
+
+  @doc """
+  プログラムに Store スキーマ（キーごとの型と既定値）を結び付けたハンドルを返す。
+  `program` はバイトコードまたは `compile/1`・`link/2` のハンドルで、ライブラリは引き継ぐ。
+  `run_compiled/4`・`run_batch/4` などハンドルを受け取る実行関数で使う。
+
+  既定値が型に合わないキーがあれば `{:error, :invalid_store_schema, key}`。
+
+  ## 例
+      {:ok, program} = Core.Formula.with_store_schema(bytecode, %{"hp" => {:i32, 100}})
+      Core.Formula.run_compiled(program, %{}, %{})
+  """
+  @spec with_store_schema(binary() | reference(), store_schema()) ::
+          {:ok, reference()} | error()
+  def with_store_schema(program, store_schema)
+      when (is_binary(program) or is_reference(program)) and is_map(store_schema) do
+    NifBridge.bind_store_schema(program, store_schema)
+  end
+
+  @doc """
+  プログラムにソースマップ（`source_map/2` の `[{offset, origin}]`）を結び付けたハンドルを返す。
+  `program` はバイトコードまたはハンドルで、ライブラリ・Store スキーマは引き継ぐ。
+
+  実行・デコードのエラーの location の origin に、offset 以前で最も近いエントリの origin が入る。
+
+  ## 例
+      instructions = [{:origin, :damage}, {:load_input, 0, "damage"}, {:store_output, 0}]
+      bytecode = Core.Formula.build(instructions)
+      {:ok, program} = Core.Formula.with_source_map(bytecode, Core.Formula.source_map(instructions))
+      Core.Formula.run_compiled(program, %{})
+      # => {:error, :input_not_found, "damage", %{pc: 0, offset: 0, function: nil, origin: :damage}}
+  """
+  @spec with_source_map(binary() | reference(), source_map()) :: {:ok, reference()} | error()
+  def with_source_map(program, entries)
+      when (is_binary(program) or is_reference(program)) and is_list(entries) do
+    NifBridge.bind_source_map(program, entries)
+  end
+
+  @doc """
+  命令リストの `{:origin, origin}` の位置を、`build/2` が出力するコード上のバイトオフセットにした
+  `[{offset, origin}]` を返す。opts は `build/2` と同じものを渡すこと（コンテナ形式では名前の長さが変わる）。
+  """
+  @spec source_map([tuple()], keyword()) :: source_map()
+  def source_map(instructions, opts \\ []) do
+    instructions =
+      if Keyword.get(opts, :container, false),
+        do: instructions |> container_refs() |> elem(0),
+        else: instructions
+
+    {entries, _offset} =
+      Enum.reduce(instructions, {[], 0}, fn
+        {:origin, origin}, {entries, offset} -> {[{offset, origin} | entries], offset}
+        {:label, _}, acc -> acc
+        inst, {entries, offset} -> {entries, offset + encoded_size(inst)}
+      end)
+
+    Enum.reverse(entries)
+  end
+
+  @doc """
+  `compile/1` で得たプログラムを実行する。引数と戻り値は `run/4` と同じ。
+  """
+  @spec run_compiled(reference(), map(), map(), [run_opt()]) ::
+          {:ok, {[value()], [{String.t(), value()}]}}
+          | error()
+  def run_compiled(program, inputs, store_values \\ %{}, opts \\ [])
+      when is_reference(program) and is_map(inputs) and is_map(store_values) and is_list(opts) do
+    {limits, dirty?} = split_opts(opts)
+
+    if dirty?,
+      do: NifBridge.run_compiled_dirty(program, inputs, store_values, limits),
+      else: NifBridge.run_compiled(program, inputs, store_values, limits)
+  end
+
+  @doc """
+  プログラムを実行し、出力・更新後の Store に加えて EMIT で発行したイベントを発行順に返す。
+
+  `program` はバイトコードまたは `compile/1`・`link/2` のハンドル。`opts` は `run/4` と同じ（`:library`・`:store_schema` を除く）。
+  イベントは `{name, payload}` で、ペイロードなしの EMIT は payload が nil。
+
+  ## 例
+      bytecode =
+        Core.Formula.build([
+          {:read_store, 0, "hp"},
+          {:load_input, 1, "damage"},
+          {:sub, 2, 0, 1},
+          {:write_store, 2, "hp"},
+          {:emit, "damaged", 1}
+        ])
+
+      {:ok, {[], store, events}} = Core.Formula.run_events(bytecode, %{"damage" => 3}, %{"hp" => 10})
+      # events => [{"damaged", 3}]
+      Core.EventBus.broadcast(events)
+  """
+  @type event :: {String.t(), value() | nil}
+
+  @spec run_events(binary() | reference(), map(), map(), [run_opt()]) ::
+          {:ok, {[value()], [{String.t(), value()}], [event()]}}
+          | error()
+  def run_events(program, inputs, store_values \\ %{}, opts \\ [])
+      when (is_binary(program) or is_reference(program)) and is_map(inputs) and
+             is_map(store_values) and is_list(opts) do
+    {limits, dirty?} = split_opts(opts)
+
+    if dirty?,
+      do: NifBridge.run_formula_events_dirty(program, inputs, store_values, limits),
+      else: NifBridge.run_formula_events(program, inputs, store_values, limits)
+  end
+
+  @doc """
+  プログラムを実行し、Store 全体の代わりに書き込んだキーの変更セットを返す。
+
+  `program` と `opts` は `run_events/4` と同じ。`store_values` は読み取り専用で、プログラムが参照したキーだけを読む。
+  変更は最初の書き込み順の `{key, before, after, flag}` で、before は実行前の値（新規キーは nil）、
+  after は最後に書き込んだ値、flag は値が変わったら `:written`・同じ値を書いただけなら `:unchanged`。
+  参照した Store の値が不正なら `{:error, :invalid_store_value, key, location}`。
+
+  ## 例
+      {:ok, {[], changes, []}} = Core.Formula.run_changes(bytecode, %{"damage" => 3}, room_store)
+      # changes => [{"hp", 10, 7, :written}]
+      room_store = Core.Formula.apply_changes(room_store, changes)
+  """
+  @type store_change :: {String.t(), value() | nil, value(), :written | :unchanged}
+
+  @spec run_changes(binary() | reference(), map(), map(), [run_opt()]) ::
+          {:ok, {[value()], [store_change()], [event()]}}
+          | error()
+  def run_changes(program, inputs, store_values \\ %{}, opts \\ [])
+      when (is_binary(program) or is_reference(program)) and is_map(inputs) and
+             is_map(store_values) and is_list(opts) do
+    {limits, dirty?} = split_opts(opts)
+
+    if dirty?,
+      do: NifBridge.run_formula_changes_dirty(program, inputs, store_values, limits),
+      else: NifBridge.run_formula_changes(program, inputs, store_values, limits)
+  end
+
+  @doc """
+  プログラムをトレース付きで実行する。
+
+  `program` は `run_events/4` と同じ。`opts` は `run/4` の実行上限と `:dirty` に加えて:
+  - `:steps` — 命令ごとのステップを記録する（既定 true）
+  - `:profile` — OpCode ごとの `{op, count, time_ns}` を集計する（既定 false）
+
+  戻り値の `result` は `run/4` の戻り値と同じで、実行時エラーでもそこまでのステップを返す。
+  ステップは実行順の `%{depth, function, pc, op, operands, result}`:
+  - `depth` / `function` — 呼び出しの深さ（トップレベルは 0）と関数名（トップレベルは nil）
+  - `pc` — 関数（またはプログラム）内の命令インデックス
+  - `operands` — 読み出したレジスタと実行前の値 `[{reg, value | nil}]`
+  - `result` — 書き込んだレジスタと実行後の値 `{reg, value}`。書き込まない命令・エラーで止まった命令は nil。
+    CALL・FOR_EACH・LIST_MAP は呼び出し先から戻った値
+
+  ## 例
+      {:ok, %{result: {:ok, {[7], _}}, steps: steps}} =
+        Core.Formula.run_traced(bytecode, %{"damage" => 3}, %{"hp" => 10})
+
+      Enum.each(Core.Formula.format_trace(steps), &Logger.debug/1)
+  """
+  @type trace_step :: %{
+          depth: non_neg_integer(),
+          function: String.t() | nil,
+          pc: non_neg_integer(),
+          op: atom(),
+          operands: [{non_neg_integer(), value() | nil}],
+          result: {non_neg_integer(), value()} | nil
+        }
+  @type trace :: %{
+          result: {:ok, {[value()], [{String.t(), value()}]}} | error(),
+          steps: [trace_step()],
+          profile: [{atom(), non_neg_integer(), non_neg_integer()}] | nil
+        }
+
+  @spec run_traced(binary() | reference(), map(), map(), keyword()) ::
+          {:ok, trace()} | error()
+  def run_traced(program, inputs, store_values \\ %{}, opts \\ [])
+      when (is_binary(program) or is_reference(program)) and is_map(inputs) and
+             is_map(store_values) and is_list(opts) do
+    {options, opts} = Keyword.split(opts, [:steps, :profile])
+    {limits, dirty?} = split_opts(opts)
+    options = Map.new(options)
+
+    if dirty?,
+      do: NifBridge.run_formula_traced_dirty(program, inputs, store_values, options, limits),
+      else: NifBridge.run_formula_traced(program, inputs, store_values, options, limits)
+  end
+
+  @doc """
+  `run_traced/4` のステップを 1 行ずつの文字列にする（ログ・デバッグ表示用）。
+
+      "  double@1 mul r0=21 r1=2 -> r2=42"
+
+  呼び出しの深さ 1 段につき 2 文字字下げし、トップレベルの関数名は `main` と表示する。
+  """
+  @spec format_trace([trace_step()]) :: [String.t()]
+  def format_trace(steps) when is_list(steps) do
+    Enum.map(steps, fn step ->
+      operands = Enum.map(step.operands, fn {reg, value} -> " r#{reg}=#{format_value(value)}" end)
+
+      result =
+        case step.result do
+          {reg, value} -> " -> r#{reg}=#{format_value(value)}"
+          nil -> ""
+        end
+
+      IO.iodata_to_binary([
+        String.duplicate("  ", step.depth),
+        step.function || "main",
+        "@#{step.pc} #{step.op}",
+        operands,
+        result
+      ])
+    end)
+  end
+
+  defp format_value(nil), do: "?"
+  defp format_value(value) when is_tuple(value) or is_list(value), do: inspect(value)
+  defp format_value(value), do: to_string(value)
+
+  @doc """
+  `run_changes/4` の変更セットを Store に反映する。`:unchanged` のキーは書き込まない。
+  """
+  @spec apply_changes(map(), [store_change()]) :: map()
+  def apply_changes(store_values, changes) when is_map(store_values) and is_list(changes) do
+    Enum.reduce(changes, store_values, fn
+      {key, _before, after_value, :written}, acc -> Map.put(acc, key, after_value)
+      {_key, _before, _after, :unchanged}, acc -> acc
+    end)
+  end
+
+  @doc """
+  同じプログラムを複数エンティティの入力で 1 回の NIF 呼び出しで実行する。
+
+  `program` はバイトコードまたは `compile/1` のハンドル。`entities` は次のどちらか:
+  - 入力マップのリスト: `[%{"hp" => 10, "dt" => 0.05}, %{"hp" => 20, "dt" => 0.05}]`
+  - 列形式のマップ: `%{"hp" => [10, 20], "dt" => 0.05}`（リストでない値は全エンティティ共通。リスト長は揃えること）
+
+  結果はエンティティ順のリストで、各要素は `{:ok, outputs}` または `{:error, reason, detail, location}`。
+  あるエンティティのエラーは他のエンティティに影響しない。
+  `store_values` は全エンティティ共通の初期値で、WRITE_STORE の結果は返らない（エンティティ間でも共有されない）。
+  `opts` は `run/4` と同じで、上限はエンティティごとに適用する。
+
+  ## 例
+      Core.Formula.run_batch(program, %{"hp" => [10, 20], "damage" => 3})
+      # => {:ok, [{:ok, [7]}, {:ok, [17]}]}
+  """
+  @spec run_batch(binary() | reference(), [map()] | map(), map(), [run_opt()]) ::
+          {:ok, [{:ok, [value()]} | error()]}
+          | error()
+  def run_batch(program, entities, store_values \\ %{}, opts \\ [])
+      when (is_binary(program) or is_reference(program)) and
+             (is_list(entities) or is_map(entities)) and is_map(store_values) and is_list(opts) do
+    {limits, dirty?} = split_opts(opts)
+
+    if dirty?,
+      do: NifBridge.run_formula_batch_dirty(program, entities, store_values, limits),
+      else: NifBridge.run_formula_batch(program, entities, store_values, limits)
+  end
+
+  @doc """
+  バイトコードを人が読めるテキストアセンブリにする。失敗した数式をログに出す用途を想定。
+
+  ## 例
+      Core.Formula.disassemble(bytecode)
+      # => {:ok, "    load_input r0 \"dt\"\n    load_i32 r1 2\n    mul r2 r0 r1\n    store_output r2\n"}
+  """
+  @spec disassemble(binary()) :: {:ok, String.t()} | error()
+  def disassemble(bytecode) when is_binary(bytecode) do
+    NifBridge.disassemble_formula(bytecode)
+  end
+
+  @doc """
+  テキストアセンブリをバイトコードにする。`disassemble/1` の出力はバイト単位で元のバイトコードに戻る。
+
+  構文エラーは `{:error, :syntax_error, {line, message}}`（line は 1 始まり）。
+  """
+  @spec assemble(String.t()) :: {:ok, binary()} | error()
+  def assemble(source) when is_binary(source) do
+    NifBridge.assemble_formula(source)
+  end
+
+  @doc """
+  バイトコード（従来形式またはコンテナ形式）をチェックサム付きのコンテナ形式に変換する。
+
+  `Core.FormulaStore` などにキャッシュ済みの従来形式プログラムの移行に使う。
+  """
+  @spec pack(binary()) :: {:ok, binary()} | error()
+  def pack(bytecode) when is_binary(bytecode) do
+    NifBridge.pack_formula(bytecode)
+  end
+
+  @doc """
+  バイトコードを実行せずに検証する。コンテンツ読み込み時に不正な数式を弾く用途。
+
+  schema で入力・Store キーの型を宣言する（`:i32` | `:f32` | `:i64` | `:f64` | `:bool` | `:vec2` | `:vec3` | `:list`）。
+  省略したセクションは宣言なしとして扱い、その名前の型は検査しない。
+
+  問題があれば `{:error, :verification_failed, diagnostics}` を返す。各診断は
+  `%{pc: 命令インデックス, offset: バイトオフセット, kind: kind, detail: detail}`。
+
+  | kind | detail |
+  |:---|:---|
+  | `:uninitialized_register` | レジスタ番号 |
+  | `:maybe_uninitialized_register` | レジスタ番号（一部の分岐でだけ未初期化） |
+  | `:type_mismatch` | `{op, [operand_types]}`（operand_types は取りうる型のリスト） |
+  | `:undeclared_input` | 入力名 |
+  | `:undeclared_store_key` | Store キー |
+  | `:store_type_mismatch` | `{key, expected, found_types}` |
+
+  VM は Bool を数値文脈で 0 / 1 に強制変換するが、検証では型エラーとして報告する。
+
+  ## 例
+      Core.Formula.verify(bytecode, %{inputs: %{"dt" => :f32}, store: %{"score" => :i32}})
+      # => :ok
+  """
+  @type value_type :: :i32 | :f32 | :i64 | :f64 | :bool | :vec2 | :vec3 | :list
+  @type schema :: %{
+          optional(:inputs) => %{optional(String.t()) => value_type()},
+          optional(:store) => %{optional(String.t()) => value_type()}
+        }
+
+  @spec verify(binary(), schema()) :: :ok | error()
+  def verify(bytecode, schema \\ %{}) when is_binary(bytecode) and is_map(schema) do
+    NifBridge.verify_formula(bytecode, schema)
+  end
+
+  @doc """
+  バイトコードを最適化する（定数部分式の畳み込み・結果が使われない命令の除去・レジスタの詰め直し）。
+
+  出力・Store への書き込み・実行時エラーは元のバイトコードと同じになり、実行命令数だけが減る。
+  ゼロ除算など失敗しうる命令は、結果が使われなくても残す。入力と同じ形式（従来形式 / コンテナ形式）で返す。
+  """
+  @spec optimize(binary()) :: {:ok, binary()} | error()
+  def optimize(bytecode) when is_binary(bytecode) do
+    NifBridge.optimize_formula(bytecode)
+  end
+
+  @doc """
+  式言語のソースをコンテナ形式のバイトコードにコンパイルする（最適化済み）。
+
+  1 行 1 文（`;` でも区切れる）。文は `output 式`、`store.キー = 式`、`let 名前 = 式` の 3 種類。
+  let で定義していない識別子は入力、`store.キー` は Store の値を読む。
+  演算子は `+ - * / %`、比較 `== != < > <= >=`、論理 `and or not`（`&& || !`）、成分 `.x .y .z`。
+  関数は abs floor ceil sqrt sin cos length normalize min max atan2 pow xor dot clamp lerp select vec2 vec3。
+
+  構文エラーは `{:error, :parse_error, {line, column, message}}`（line / column は 1 始まり）。
+
+  ## 例
+      {:ok, bytecode} = Core.Formula.compile_source("output hp - damage * (1 - armor)")
+      Core.Formula.run(bytecode, %{"hp" => 100.0, "damage" => 40.0, "armor" => 0.25})
+      # => {:ok, {[70.0], []}}
+  """
+  @spec compile_source(String.t()) :: {:ok, binary()} | error()
+  def compile_source(source) when is_binary(source) do
+    NifBridge.compile_formula_source(source)
+  end
+
+  @doc false
+  # 実行 opts を NIF の limits マップと DirtyCpu で実行するかに分ける（Core.FormulaGraph も使う）
+  def split_opts(opts) do
+    {dirty?, rest} = Keyword.pop(opts, :dirty, false)
+    {Map.new(Keyword.take(rest, @limit_keys)), dirty?}
+  end
+
   @doc """
   命令リストからバイナリバイトコードを生成する。
 
+  ## オプション
+  - `:container` — `true` ならコンテナ形式（文字列表・CRC-32 付き）で出力する。既定は従来形式
+
   ## 制約
-  - `LOAD_INPUT` の `name` は 255 バイト以下であること。超過時は `IO.iodata_to_binary/1` でランタイムエラーとなる。
-  - 入力の integer は i32 範囲（-2^31 ～ 2^31-1）内であること。
+  - 従来形式では `LOAD_INPUT` の `name` は 255 バイト以下であること。超過時は `IO.iodata_to_binary/1` でランタイムエラーとなる。
+  - `load_i32` の value は i32 範囲（-2^31 ～ 2^31-1）、`load_i64` の value は i64 範囲内であること。
 
   ## 命令形式
   - `{:load_input, dst, name}` - 入力 name をレジスタ dst へ
   - `{:load_i32, dst, value}` - 定数 i32 をレジスタ dst へ
   - `{:load_f32, dst, value}` - 定数 f32 をレジスタ dst へ
   - `{:load_bool, dst, value}` - 定数 bool をレジスタ dst へ
+  - `{:load_i64, dst, value}` / `{:load_f64, dst, value}` - 定数 i64 / f64 をレジスタ dst へ
   - `{:add, dst, src_a, src_b}` - ADD
   - `{:sub, dst, src_a, src_b}` - SUB
   - `{:mul, dst, src_a, src_b}` - MUL
@@ -68,14 +674,149 @@ defmodule Core.Formula do
   - `{:store_output, src}` - レジスタ src を出力へ
   - `{:read_store, dst, key}` - Store の key をレジスタ dst へ（Phase 2）
   - `{:write_store, src, key}` - レジスタ src を Store の key に書き込み（Phase 2）
+  - `{:label, name}` - ジャンプ先ラベル（atom）。バイトは出力しない
+  - `{:origin, origin}` - 以降の命令の生成元（ノード ID の atom か `{line, column}`）。バイトは出力せず、`source_map/2` で使う
+  - `{:jump, target}` - 無条件ジャンプ
+  - `{:jump_if_false, cond, target}` - レジスタ cond が偽ならジャンプ
+  - `{:jump_if_true, cond, target}` - レジスタ cond が真ならジャンプ
+  - `{:abs | :floor | :ceil | :sqrt | :sin | :cos, dst, src}` - 単項数学関数
+  - `{:min | :max | :atan2 | :pow | :mod, dst, src_a, src_b}` - 二項数学関数（atan2 は a = y, b = x）
+  - `{:clamp, dst, src, lo, hi}` - src を [lo, hi] に制限
+  - `{:lerp, dst, src_a, src_b, src_t}` - a + (b - a) * t
+  - `{:not, dst, src}` - 論理否定
+  - `{:and | :or | :xor, dst, src_a, src_b}` - 論理演算（0 以外の数値は真として扱う）
+  - `{:le | :ge | :ne, dst, src_a, src_b}` - 比較
+  - `{:select, dst, cond, src_a, src_b}` - cond が真なら a、偽なら b
+  - `{:load_vec, dst, {x, y}}` / `{:load_vec, dst, {x, y, z}}` - 定数ベクトル
+  - `{:make_vec, dst, [src_x, src_y]}` / `[src_x, src_y, src_z]` - スカラーレジスタからベクトルを組み立てる
+  - `{:extract, dst, src, index}` - ベクトルの index 番目の成分（0 始まり）
+  - `{:dot, dst, src_a, src_b}` - 内積
+  - `{:length, dst, src}` - ベクトル長
+  - `{:normalize, dst, src}` - 単位ベクトル（長さ 0 はそのまま）
+  - `{:call, dst, name, [src, ...]}` - ライブラリ関数 name を呼び、戻り値をレジスタ dst へ（引数は呼び出し先の r0.. に入る）
+  - `{:ret, src}` - 関数から src を返す（トップレベルではプログラムを終了する）
+  - `{:random, dst}` - [0, 1) の乱数（Store の `"$rng"` を進める）
+  - `{:random_range, dst, lo, hi}` - lo..hi の乱数（整数同士は両端を含む整数、それ以外は [lo, hi) の float）
+  - `{:emit, name, src}` / `{:emit, name}` - イベント name を発行する（src はペイロード。`run_events/4` で受け取る）
+  - `{:list_len, dst, src}` - リストの長さ
+  - `{:list_index, dst, list, index}` - リストの index 番目の要素（0 始まり）
+  - `{:list_sum | :list_min | :list_max, dst, src}` - 要素の合計（空は 0）・最小・最大（空はドメインエラー）
+  - `{:for_each, dst, name, list, init}` - acc = name(acc, elem) を先頭の要素から順に適用した結果（空なら init）
+  - `{:list_map, dst, name, list}` - 各要素に name(elem) を適用したリスト
+  - `{:cast_i32 | :cast_f32 | :cast_i64 | :cast_f64, dst, src}` - src を指定した型に変換する
+
+  add / sub / mul / div はベクトル同士（同次元）で成分ごと、ベクトルとスカラーではスカラーを全成分に適用する。
+
+  ジャンプの target はラベル名（atom）またはバイトオフセット（integer）。
+  VM は後方ジャンプをデコード時に拒否するため、ラベルはジャンプ命令より後ろに置くこと。
   """
-  @spec build([tuple()]) :: binary()
-  def build(instructions) do
+  @spec build([tuple()], keyword()) :: binary()
+  def build(instructions, opts \\ []) do
+    if Keyword.get(opts, :container, false) do
+      build_container(instructions)
+    else
+      encode_code(instructions)
+    end
+  end
+
+  defp encode_code(instructions) do
+    labels = label_offsets(instructions)
+
     instructions
+    |> Enum.reject(&(match?({:label, _}, &1) or match?({:origin, _}, &1)))
+    |> Enum.map(&resolve_label(&1, labels))
     |> Enum.flat_map(&encode_instruction/1)
     |> IO.iodata_to_binary()
   end
 
+  # 名前は出現順に文字列表へ集め、命令は {:name_ref, opcode, reg, index}（CALL は {:call_ref, ...}）に置き換える。
+  # 定数は従来どおりインラインで書く（定数プールは空）
+  defp build_container(instructions) do
+    {refs, names} = container_refs(instructions)
+
+    strings = Enum.map(names, &<<byte_size(&1)::little-16, &1::binary>>)
+
+    body =
+      IO.iodata_to_binary([
+        <<0xFF, "FVM", 1::little-16, 1>>,
+        <<length(names)::little-16>>,
+        strings,
+        <<0::little-16>>,
+        encode_code(refs)
+      ])
+
+    body <> <<:erlang.crc32(body)::little-32>>
+  end
+
+  defp container_refs(instructions) do
+    Enum.map_reduce(instructions, [], fn inst, names ->
+      case name_operand(inst) do
+        {name, to_ref} ->
+          name = to_string(name)
+          index = Enum.find_index(names, &(&1 == name)) || length(names)
+          names = if index == length(names), do: names ++ [name], else: names
+          {to_ref.(index), names}
+
+        nil ->
+          {inst, names}
+      end
+    end)
+  end
+
+  defp name_operand({:load_input, dst, name}), do: {name, &{:name_ref, 0, dst, &1}}
+  defp name_operand({:read_store, dst, key}), do: {key, &{:name_ref, 12, dst, &1}}
+  defp name_operand({:write_store, src, key}), do: {key, &{:name_ref, 13, src, &1}}
+  defp name_operand({:call, dst, name, args}), do: {name, &{:call_ref, dst, &1, args}}
+  defp name_operand({:emit, name}), do: {name, &{:name_ref, 49, @no_payload, &1}}
+  defp name_operand({:emit, name, src}), do: {name, &{:name_ref, 49, src, &1}}
+
+  defp name_operand({:for_each, dst, name, list, init}),
+    do: {name, &{:name_ref, 55, [dst, list, init], &1}}
+
+  defp name_operand({:list_map, dst, name, list}), do: {name, &{:name_ref, 56, [dst, list], &1}}
+  defp name_operand(_inst), do: nil
+
+  # ジャンプ命令のサイズはジャンプ先に依存しないため、仮の 0 でエンコードして長さを数える
+  defp label_offsets(instructions) do
+    {labels, _offset} =
+      Enum.reduce(instructions, {%{}, 0}, fn
+        {:label, name}, {labels, offset} ->
+          {Map.put(labels, name, offset), offset}
+
+        {:origin, _}, acc ->
+          acc
+
+        inst, {labels, offset} ->
+          {labels, offset + encoded_size(inst)}
+      end)
+
+    labels
+  end
+
+  defp encoded_size(inst),
+    do: inst |> resolve_label(:placeholder) |> encode_instruction() |> length()
+
+  defp resolve_label({:jump, target}, labels) when is_atom(target),
+    do: {:jump, label_target(target, labels)}
+
+  defp resolve_label({op, cond, target}, labels)
+       when op in [:jump_if_false, :jump_if_true] and is_atom(target),
+       do: {op, cond, label_target(target, labels)}
+
+  defp resolve_label(inst, _labels), do: inst
+
+  defp label_target(_name, :placeholder), do: 0
+  defp label_target(name, labels), do: Map.fetch!(labels, name)
+
+  defp encode_instruction({:name_ref, op, regs, index}) when is_list(regs),
+    do: [op | regs] ++ :binary.bin_to_list(<<index::little-16>>)
+
+  defp encode_instruction({:name_ref, op, reg, index}),
+    do: [op, reg] ++ :binary.bin_to_list(<<index::little-16>>)
+
+  defp encode_instruction({:call_ref, dst, index, args}),
+    do: [45, dst] ++ :binary.bin_to_list(<<index::little-16>>) ++ [length(args) | args]
+
   defp encode_instruction({:load_input, dst, name}) when is_binary(name) do
     name_bin = name
     len = byte_size(name_bin)
@@ -98,6 +839,19 @@ defmodule Core.Formula do
     [3, dst, if(value, do: 1, else: 0)]
   end
 
+  defp encode_instruction({:load_i64, dst, value}) when is_integer(value) do
+    [57, dst] ++ :binary.bin_to_list(<<value::little-signed-integer-64>>)
+  end
+
+  defp encode_instruction({:load_f64, dst, value}) when is_number(value) do
+    [58, dst] ++ :binary.bin_to_list(<<value::little-float-64>>)
+  end
+
+  defp encode_instruction({:cast_i32, dst, src}), do: [59, dst, src]
+  defp encode_instruction({:cast_f32, dst, src}), do: [60, dst, src]
+  defp encode_instruction({:cast_i64, dst, src}), do: [61, dst, src]
+  defp encode_instruction({:cast_f64, dst, src}), do: [62, dst, src]
+
   defp encode_instruction({:add, dst, src_a, src_b}), do: [4, dst, src_a, src_b]
   defp encode_instruction({:sub, dst, src_a, src_b}), do: [5, dst, src_a, src_b]
   defp encode_instruction({:mul, dst, src_a, src_b}), do: [6, dst, src_a, src_b]
@@ -106,6 +860,42 @@ defmodule Core.Formula do
   defp encode_instruction({:gt, dst, src_a, src_b}), do: [9, dst, src_a, src_b]
   defp encode_instruction({:eq, dst, src_a, src_b}), do: [10, dst, src_a, src_b]
   defp encode_instruction({:store_output, src}), do: [11, src]
+  defp encode_instruction({:abs, dst, src}), do: [17, dst, src]
+  defp encode_instruction({:floor, dst, src}), do: [18, dst, src]
+  defp encode_instruction({:ceil, dst, src}), do: [19, dst, src]
+  defp encode_instruction({:sqrt, dst, src}), do: [20, dst, src]
+  defp encode_instruction({:sin, dst, src}), do: [21, dst, src]
+  defp encode_instruction({:cos, dst, src}), do: [22, dst, src]
+  defp encode_instruction({:min, dst, src_a, src_b}), do: [23, dst, src_a, src_b]
+  defp encode_instruction({:max, dst, src_a, src_b}), do: [24, dst, src_a, src_b]
+  defp encode_instruction({:atan2, dst, src_a, src_b}), do: [25, dst, src_a, src_b]
+  defp encode_instruction({:pow, dst, src_a, src_b}), do: [26, dst, src_a, src_b]
+  defp encode_instruction({:mod, dst, src_a, src_b}), do: [27, dst, src_a, src_b]
+  defp encode_instruction({:clamp, dst, src, lo, hi}), do: [28, dst, src, lo, hi]
+  defp encode_instruction({:lerp, dst, src_a, src_b, src_t}), do: [29, dst, src_a, src_b, src_t]
+  defp encode_instruction({:not, dst, src}), do: [30, dst, src]
+  defp encode_instruction({:and, dst, src_a, src_b}), do: [31, dst, src_a, src_b]
+  defp encode_instruction({:or, dst, src_a, src_b}), do: [32, dst, src_a, src_b]
+  defp encode_instruction({:xor, dst, src_a, src_b}), do: [33, dst, src_a, src_b]
+  defp encode_instruction({:le, dst, src_a, src_b}), do: [34, dst, src_a, src_b]
+  defp encode_instruction({:ge, dst, src_a, src_b}), do: [35, dst, src_a, src_b]
+  defp encode_instruction({:ne, dst, src_a, src_b}), do: [36, dst, src_a, src_b]
+  defp encode_instruction({:select, dst, cond, src_a, src_b}), do: [37, dst, cond, src_a, src_b]
+
+  defp encode_instruction({:load_vec, dst, vec}) when tuple_size(vec) in [2, 3] do
+    components = Tuple.to_list(vec)
+
+    [38, dst, length(components)] ++
+      Enum.flat_map(components, &:binary.bin_to_list(<<&1::little-float-32>>))
+  end
+
+  defp encode_instruction({:make_vec, dst, srcs}) when length(srcs) in [2, 3],
+    do: [39, dst, length(srcs)] ++ srcs
+
+  defp encode_instruction({:extract, dst, src, index}), do: [40, dst, src, index]
+  defp encode_instruction({:dot, dst, src_a, src_b}), do: [41, dst, src_a, src_b]
+  defp encode_instruction({:length, dst, src}), do: [42, dst, src]
+  defp encode_instruction({:normalize, dst, src}), do: [43, dst, src]
 
   defp encode_instruction({:read_store, dst, key}) when is_binary(key) do
     key_bin = key
@@ -126,4 +916,47 @@ defmodule Core.Formula do
   defp encode_instruction({:write_store, src, key}) when is_atom(key) do
     encode_instruction({:write_store, src, to_string(key)})
   end
+
+  defp encode_instruction({:call, dst, name, args}) when is_list(args) do
+    name_bin = to_string(name)
+    [45, dst, byte_size(name_bin)] ++ :binary.bin_to_list(name_bin) ++ [length(args) | args]
+  end
+
+  defp encode_instruction({:ret, src}), do: [46, src]
+  defp encode_instruction({:random, dst}), do: [47, dst]
+  defp encode_instruction({:random_range, dst, lo, hi}), do: [48, dst, lo, hi]
+  defp encode_instruction({:emit, name}), do: encode_instruction({:emit, name, @no_payload})
+
+  defp encode_instruction({:emit, name, src}) do
+    name_bin = to_string(name)
+    [49, src, byte_size(name_bin)] ++ :binary.bin_to_list(name_bin)
+  end
+
+  defp encode_instruction({:list_len, dst, src}), do: [50, dst, src]
+  defp encode_instruction({:list_index, dst, list, index}), do: [51, dst, list, index]
+  defp encode_instruction({:list_sum, dst, src}), do: [52, dst, src]
+  defp encode_instruction({:list_min, dst, src}), do: [53, dst, src]
+  defp encode_instruction({:list_max, dst, src}), do: [54, dst, src]
+
+  defp encode_instruction({:for_each, dst, name, list, init}) do
+    name_bin = to_string(name)
+    [55, dst, list, init, byte_size(name_bin)] ++ :binary.bin_to_list(name_bin)
+  end
+
+  defp encode_instruction({:list_map, dst, name, list}) do
+    name_bin = to_string(name)
+    [56, dst, list, byte_size(name_bin)] ++ :binary.bin_to_list(name_bin)
+  end
+
+  defp encode_instruction({:jump, target}) when is_integer(target) do
+    [14] ++ :binary.bin_to_list(<<target::little-unsigned-integer-32>>)
+  end
+
+  defp encode_instruction({:jump_if_false, cond, target}) when is_integer(target) do
+    [15, cond] ++ :binary.bin_to_list(<<target::little-unsigned-integer-32>>)
+  end
+
+  defp encode_instruction({:jump_if_true, cond, target}) when is_integer(target) do
+    [16, cond] ++ :binary.bin_to_list(<<target::little-unsigned-integer-32>>)
+  end
 end
@@ -8,6 +8,15 @@ defmodule Core.FormulaGraph do
   - `:input` - 外部入力。params: %{name: "player_x"}
   - `:output` - 出力。params: %{}。接続元の値を出力へ。
   - `:add`, `:sub`, `:mul`, `:div`, `:lt`, `:gt`, `:eq` - 二項演算。入力ポート :a, :b
+  - `:min`, `:max`, `:atan2`, `:pow`, `:mod` - 二項数学関数。入力ポート :a, :b
+  - `:abs`, `:floor`, `:ceil`, `:sqrt`, `:sin`, `:cos` - 単項数学関数。入力ポート :a
+  - `:clamp` - 入力ポート :a, :lo, :hi
+  - `:lerp` - 入力ポート :a, :b, :t
+  - `:and`, `:or`, `:xor`, `:le`, `:ge`, `:ne` - 論理演算・比較。入力ポート :a, :b
+  - `:not` - 論理否定。入力ポート :a
+  - `:select` - 入力ポート :cond, :a, :b。cond が真なら a、偽なら b
+  - `:dot` - 内積。入力ポート :a, :b
+  - `:length`, `:normalize` - ベクトル長・単位ベクトル。入力ポート :a
   - `:int` - 定数整数。params: %{value: 10}
   - `:float` - 定数浮動小数。params: %{value: 1.0}
   - `:bool` - 定数真偽。params: %{value: true}
@@ -36,6 +45,18 @@ defmodule Core.FormulaGraph do
 
       FormulaGraph.run(graph, %{"player_x" => 1.0, "player_y" => 2.0})
       # => {:ok, {[3.0], []}}
+
+  ## 差分評価
+  毎 tick 同じグラフを評価する場合は `load/1` でグラフのまま Rust 側に読み込み、`evaluate/4` で評価する。
+  ノードごとに前回の値を覚えておき、前回から値の変わった入力・Store 読み出しの下流のノードだけを計算し直す
+  （演算の意味はバイトコードの実行と同じ）。計算し直したノードは `recomputed/1` で確認できる。
+
+      {:ok, handle} = FormulaGraph.load(graph)
+      FormulaGraph.evaluate(handle, %{"player_x" => 1.0, "player_y" => 2.0})
+      # => {:ok, {[3.0], []}}
+      FormulaGraph.evaluate(handle, %{"player_x" => 1.0, "player_y" => 5.0})
+      FormulaGraph.recomputed(handle)
+      # => [:n2, :n3]
   """
 
   defstruct nodes: [], edges: [], outputs: []
@@ -54,8 +75,14 @@ defmodule Core.FormulaGraph do
         }
 
   alias Core.Formula
-
-  @producer_ops [:input, :int, :float, :bool, :add, :sub, :mul, :div, :lt, :gt, :eq, :read_store]
+  alias Core.NifBridge
+
+  @binary_ops [:add, :sub, :mul, :div, :lt, :gt, :eq, :min, :max, :atan2, :pow, :mod] ++
+                [:and, :or, :xor, :le, :ge, :ne, :dot]
+  @unary_ops [:abs, :floor, :ceil, :sqrt, :sin, :cos, :not, :length, :normalize]
+  @ternary_ops [:clamp, :lerp, :select]
+  @producer_ops [:input, :int, :float, :bool, :read_store] ++
+                  @binary_ops ++ @unary_ops ++ @ternary_ops
   @sink_ops [:output, :write_store]
   @valid_ops @producer_ops ++ @sink_ops
 
@@ -66,14 +93,35 @@ defmodule Core.FormulaGraph do
   - `{:ok, bytecode}` - 成功
   - `{:error, reason, detail}` - 失敗（循環参照、未知ノード、ポート不足など）
   """
-  @spec compile(t()) :: {:ok, binary()} | {:error, atom(), term()}
-  def compile(%__MODULE__{nodes: nodes, edges: edges, outputs: outputs}) do
+  @spec compile(t()) :: {:ok, binary()} | Formula.error()
+  def compile(%__MODULE__{} = graph) do
+    with {:ok, instructions} <- compile_instructions(graph) do
+      {:ok, Formula.build(instructions, container: true)}
+    end
+  end
+
+  @doc """
+  `compile/1` と同じバイトコードに加えて、各ノードの命令の先頭オフセットとノード ID の
+  ソースマップ（`Core.Formula.source_map/2`）を返す。
+
+  `Core.Formula.run/4` の `:source_map` に渡すと、実行時エラーの location の origin が
+  失敗した命令を生成したノード ID になる。
+  """
+  @spec compile_with_source_map(t()) ::
+          {:ok, binary(), Formula.source_map()} | Formula.error()
+  def compile_with_source_map(%__MODULE__{} = graph) do
+    with {:ok, instructions} <- compile_instructions(graph) do
+      {:ok, Formula.build(instructions, container: true),
+       Formula.source_map(instructions, container: true)}
+    end
+  end
+
+  defp compile_instructions(%__MODULE__{nodes: nodes, edges: edges, outputs: outputs}) do
     with {:ok, node_map} <- validate_nodes(nodes),
          {:ok, incoming, outgoing} <- build_incoming(edges, node_map),
          {:ok, sorted} <- topological_sort(nodes, incoming, outgoing),
-         {:ok, reg_map} <- assign_registers(sorted, node_map),
-         {:ok, instructions} <- emit_instructions(sorted, node_map, reg_map, incoming, outputs) do
-      {:ok, Formula.build(instructions)}
+         {:ok, reg_map} <- assign_registers(sorted, node_map) do
+      emit_instructions(sorted, node_map, reg_map, incoming, outputs)
     end
   end
 
@@ -81,16 +129,76 @@ defmodule Core.FormulaGraph do
   グラフをコンパイルして実行する。
 
   inputs と store_values は Formula.run/3 と同様。
+  実行時エラーの location の origin には、失敗した命令を生成したノード ID が入る。
   """
   @spec run(t(), map(), map()) ::
           {:ok, {[term()], [{String.t(), term()}]}}
-          | {:error, atom(), term()}
+          | Formula.error()
   def run(graph, inputs, store_values \\ %{}) do
-    with {:ok, bytecode} <- compile(graph) do
-      Formula.run(bytecode, inputs, store_values)
+    with {:ok, bytecode, source_map} <- compile_with_source_map(graph) do
+      Formula.run(bytecode, inputs, store_values, source_map: source_map)
     end
   end
 
+  @doc """
+  グラフを Rust 側に読み込み、`evaluate/4` で差分評価するハンドルを返す。
+
+  ノードのポートは種類ごとに決まっており、未知のポートへのエッジ・同じポートへの複数のエッジ・
+  出力ノード以外を指す `outputs` は `compile/1` と異なりエラーにする。
+
+  ## 戻り値
+  - `{:ok, handle}` - 成功
+  - `{:error, :invalid_graph, detail}` - 構造の誤り（`{:unknown_node, id}`、`{:missing_input, id, port}`、
+    `{:unknown_port, id, port}`、`{:duplicate_input, id, port}`、`{:not_an_output, id}` など）
+  - `{:error, :cycle_detected, nil}` - 循環参照
+  """
+  @spec load(t()) :: {:ok, reference()} | Formula.error()
+  def load(%__MODULE__{nodes: nodes, edges: edges, outputs: outputs}) do
+    with {:ok, _node_map} <- validate_nodes(nodes) do
+      specs =
+        Enum.map(nodes, fn n -> {n.id, n.op, node_param(n.op, Map.get(n, :params, %{}))} end)
+
+      NifBridge.load_formula_graph(specs, edges, outputs)
+    end
+  end
+
+  @doc """
+  `load/1` で読み込んだグラフを評価する。引数と戻り値は `run/3` と同じで、`opts` は `Core.Formula.run/4` の
+  実行上限と `:dirty`（`:max_instructions` は計算し直した演算ノードの数で数える）。
+
+  評価時のエラーは `{:error, reason, detail, %{node: node_id}}`。エラーの後の評価はすべてのノードを計算し直す。
+  ハンドルは前回の値を書き換えるため、同じハンドルの評価は直列化される（プロセスごとに `load/1` するのがよい）。
+  """
+  @spec evaluate(reference(), map(), map(), [Formula.run_opt()]) ::
+          {:ok, {[Formula.value()], [{String.t(), Formula.value()}]}}
+          | {:error, atom(), term(), %{node: node_id()}}
+          | Formula.error()
+  def evaluate(handle, inputs, store_values \\ %{}, opts \\ [])
+      when is_reference(handle) and is_map(inputs) and is_map(store_values) and is_list(opts) do
+    {limits, dirty?} = Formula.split_opts(opts)
+
+    if dirty?,
+      do: NifBridge.evaluate_formula_graph_dirty(handle, inputs, store_values, limits),
+      else: NifBridge.evaluate_formula_graph(handle, inputs, store_values, limits)
+  end
+
+  @doc """
+  直前の `evaluate/4` で値を計算し直したノード ID を評価順に返す。
+  値の変わった入力・Store 読み出し・定数と、計算し直した演算ノードで、出力・Store 書き込みは含まない。
+  """
+  @spec recomputed(reference()) :: [node_id()]
+  def recomputed(handle) when is_reference(handle) do
+    NifBridge.formula_graph_recomputed(handle)
+  end
+
+  defp node_param(:input, params), do: params |> Map.fetch!(:name) |> to_string()
+  defp node_param(op, params) when op in [:int, :float, :bool], do: Map.fetch!(params, :value)
+
+  defp node_param(op, params) when op in [:read_store, :write_store],
+    do: (params[:key] || params["key"]) |> to_string()
+
+  defp node_param(_op, _params), do: nil
+
   # --- Validation ---
 
   defp validate_nodes(nodes) when not is_list(nodes),
@@ -221,7 +329,7 @@ defmodule Core.FormulaGraph do
   defp emit_nodes(nids, node_map, reg_map, incoming) do
     Enum.reduce_while(nids, {:ok, []}, fn nid, {:ok, acc} ->
       case emit_node(node_map[nid], reg_map, incoming) do
-        {:ok, instrs} -> {:cont, {:ok, acc ++ instrs}}
+        {:ok, instrs} -> {:cont, {:ok, acc ++ [{:origin, nid} | instrs]}}
         {:error, _, _} = err -> {:halt, err}
       end
     end)
@@ -256,13 +364,36 @@ defmodule Core.FormulaGraph do
     {:ok, [{:read_store, reg_map[node.id], key}]}
   end
 
-  defp dispatch_emit(op, node, reg_map, incoming)
-       when op in [:add, :sub, :mul, :div, :lt, :gt, :eq] do
+  defp dispatch_emit(op, node, reg_map, incoming) when op in @binary_ops do
     with {:ok, {ra, rb}} <- get_input_regs(node.id, incoming, reg_map, [:a, :b]) do
       {:ok, [{op, reg_map[node.id], ra, rb}]}
     end
   end
 
+  defp dispatch_emit(op, node, reg_map, incoming) when op in @unary_ops do
+    with {:ok, {ra}} <- get_input_regs(node.id, incoming, reg_map, [:a]) do
+      {:ok, [{op, reg_map[node.id], ra}]}
+    end
+  end
+
+  defp dispatch_emit(:clamp, node, reg_map, incoming) do
+    with {:ok, {ra, rlo, rhi}} <- get_input_regs(node.id, incoming, reg_map, [:a, :lo, :hi]) do
+      {:ok, [{:clamp, reg_map[node.id], ra, rlo, rhi}]}
+    end
+  end
+
+  defp dispatch_emit(:lerp, node, reg_map, incoming) do
+    with {:ok, {ra, rb, rt}} <- get_input_regs(node.id, incoming, reg_map, [:a, :b, :t]) do
+      {:ok, [{:lerp, reg_map[node.id], ra, rb, rt}]}
+    end
+  end
+
+  defp dispatch_emit(:select, node, reg_map, incoming) do
+    with {:ok, {rc, ra, rb}} <- get_input_regs(node.id, incoming, reg_map, [:cond, :a, :b]) do
+      {:ok, [{:select, reg_map[node.id], rc, ra, rb}]}
+    end
+  end
+
   defp dispatch_emit(:output, node, reg_map, incoming) do
     case get_data_input(node.id, incoming, reg_map) do
       {:ok, src} -> {:ok, [{:store_output, src}]}
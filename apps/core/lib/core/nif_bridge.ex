@@ -1,6 +1,27 @@
 defmodule Core.NifBridge do
   @moduledoc """
-  Rustler NIF — **`run_formula_bytecode/3` のみ**（`Core.Formula` 経由で利用）。
+  Rustler NIF — **Formula VM のみ**（`Core.Formula` 経由で利用）。
+
+  - `run_formula_bytecode/4` — バイトコードを毎回デコードして実行（`run_formula_bytecode/3` は実行上限を既定値にした互換版）
+  - `compile_formula/1` / `run_compiled/4` — 一度だけデコード・検証したプログラムハンドルで実行
+  - `run_formula_batch/4` — 同じプログラムを複数エンティティの入力で 1 回の呼び出しで実行
+  - `run_formula_events/4` — 出力・Store に加えて EMIT で発行したイベントを返す
+  - `run_formula_changes/4` — Store を読み取り専用で参照し、書き込んだキーの変更セットだけを返す
+  - `run_formula_traced/5` — 命令ごとのレジスタの値と OpCode ごとの集計を記録しながら実行する（調査用）
+  - `disassemble_formula/1` / `assemble_formula/1` — バイトコードとテキストアセンブリの相互変換
+  - `pack_formula/1` — バイトコードをバージョン付きコンテナ形式に変換
+  - `verify_formula/2` — 実行前の静的検証（未初期化レジスタ・型の不整合・未宣言の名前）
+  - `optimize_formula/1` — 定数畳み込み・不要命令の除去・レジスタの詰め直し
+  - `compile_formula_source/1` — 式言語のソースをバイトコードにコンパイル
+  - `compile_formula_library/1` — 関数ライブラリ（CALL の呼び出し先）を作る
+  - `link_formula/2` — バイトコードに関数ライブラリを結び付けたプログラムハンドルを返す
+  - `bind_store_schema/2` — プログラムに Store キーの型と既定値を結び付けたハンドルを返す
+  - `bind_source_map/2` — プログラムにバイトオフセット => 生成元（ノード ID・ソース位置）の表を結び付けたハンドルを返す
+  - `load_formula_graph/3` / `evaluate_formula_graph/4` — 計算グラフを Rust 側に読み込み、変わった入力の下流だけを再計算する
+  - `formula_graph_recomputed/1` — 直前の評価で計算し直したノード ID を返す
+
+実行系 NIF は最後の引数に実行上限 `limits`（`%{max_instructions: n, max_store_entries: n, max_outputs: n, max_call_depth: n, max_events: n, max_list_len: n}`、
+省略したキーは既定値）を取る。`*_dirty` 版は同じ引数で DirtyCpu スケジューラ上で実行する。
 
   ゲーム ECS・物理用 NIF はフェーズ 4 で Rust 側から削除済み。
 
@@ -17,7 +38,146 @@ defmodule Core.NifBridge do
   bytecode: バイナリ形式のバイトコード
   inputs: %{"name" => value}
   store_values: Store 初期値 %{"key" => value}
+  limits: 実行上限 %{max_instructions: n, ...}
   """
+  def run_formula_bytecode(_bytecode, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc "`run_formula_bytecode/4` を実行上限の既定値で実行する（上限を導入する前からの互換用）"
   def run_formula_bytecode(_bytecode, _inputs, _store_values),
     do: :erlang.nif_error(:nif_not_loaded)
+
+  def run_formula_bytecode_dirty(_bytecode, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  bytecode をデコード・検証し、プログラムハンドル（リソース）を返す。
+  """
+  def compile_formula(_bytecode), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  program: `compile_formula/1` が返したハンドル
+  inputs / store_values / limits: `run_formula_bytecode/4` と同じ
+  """
+  def run_compiled(_program, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def run_compiled_dirty(_program, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  program: バイトコードまたは `compile_formula/1` のハンドル
+  entities: 入力マップのリスト、または入力名ごとのリストを持つマップ
+  store_values: 全エンティティ共通の Store 初期値
+  limits: エンティティごとの実行上限
+  """
+  def run_formula_batch(_program, _entities, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def run_formula_batch_dirty(_program, _entities, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  program: バイトコードまたは `compile_formula/1` のハンドル
+  inputs / store_values / limits: `run_formula_bytecode/4` と同じ
+  戻り値: `{:ok, {outputs, updated_store, [{name, payload | nil}, ...]}}`
+  """
+  def run_formula_events(_program, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def run_formula_events_dirty(_program, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  program / inputs / limits: `run_formula_events/4` と同じ
+  store_values: Store。読み取り専用で、参照したキーだけをデコードする
+  戻り値: `{:ok, {outputs, [{key, before | nil, after, :written | :unchanged}, ...], events}}`
+  """
+  def run_formula_changes(_program, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def run_formula_changes_dirty(_program, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  program / inputs / store_values / limits: `run_formula_events/4` と同じ
+  options: `%{steps: boolean, profile: boolean}`（省略時は steps: true, profile: false）
+  戻り値: `{:ok, %{result: run_formula_bytecode/4 の戻り値, steps: [...], profile: [...] | nil}}`
+  """
+  def run_formula_traced(_program, _inputs, _store_values, _options, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def run_formula_traced_dirty(_program, _inputs, _store_values, _options, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  bytecode をテキストアセンブリにする。
+  """
+  def disassemble_formula(_bytecode), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  テキストアセンブリ（`disassemble_formula/1` の形式）をバイトコードにする。
+  """
+  def assemble_formula(_source), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  bytecode（従来形式またはコンテナ形式）をチェックサム付きのコンテナ形式にする。
+  """
+  def pack_formula(_bytecode), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  bytecode を実行せずに検証する。schema は `%{inputs: %{name => type}, store: %{key => type}}`。
+  """
+  def verify_formula(_bytecode, _schema), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  bytecode を最適化する。出力・Store への書き込み・実行時エラーは変わらない。
+  """
+  def optimize_formula(_bytecode), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  式言語のソースをコンテナ形式のバイトコードにコンパイルする。構文エラーは `{:error, :parse_error, {line, column, message}}`。
+  """
+  def compile_formula_source(_source), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  関数名 => バイトコードのマップから関数ライブラリのハンドルを作る。
+  """
+  def compile_formula_library(_functions), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  bytecode をコンパイルし、関数ライブラリを結び付けたプログラムハンドルを返す。
+  """
+  def link_formula(_bytecode, _library), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  プログラム（バイトコードまたはハンドル）に Store スキーマを結び付けたプログラムハンドルを返す。
+  """
+  def bind_store_schema(_program, _schema), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  プログラム（バイトコードまたはハンドル）にソースマップ `[{offset, origin}]` を結び付けたプログラムハンドルを返す。
+  """
+  def bind_source_map(_program, _entries), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  nodes: `[{id, op, param}]`、edges: `[{from, to, port}]`、outputs: 出力ノード ID のリスト。
+  差分評価するグラフのハンドル（リソース）を返す。
+  """
+  def load_formula_graph(_nodes, _edges, _outputs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  graph: `load_formula_graph/3` が返したハンドル
+  inputs / store_values / limits: `run_formula_bytecode/4` と同じ
+  """
+  def evaluate_formula_graph(_graph, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def evaluate_formula_graph_dirty(_graph, _inputs, _store_values, _limits),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  直前の `evaluate_formula_graph/4` で値を計算し直したノード ID のリストを返す。
+  """
+  def formula_graph_recomputed(_graph), do: :erlang.nif_error(:nif_not_loaded)
 end
@@ -4,6 +4,113 @@ defmodule Core.NifBridge.Behaviour do
   本番は `Core.NifBridge` が直接 NIF を呼ぶ。
   """
 
+  @type limits :: %{
+          optional(:max_instructions) => non_neg_integer(),
+          optional(:max_store_entries) => non_neg_integer(),
+          optional(:max_outputs) => non_neg_integer(),
+          optional(:max_call_depth) => non_neg_integer(),
+          optional(:max_events) => non_neg_integer(),
+          optional(:max_list_len) => non_neg_integer()
+        }
+
+  # 実行・デコードのエラーは末尾に失敗した命令の位置（命令に属さなければ nil）を持つ
+  @type error :: {:error, atom(), term()} | {:error, atom(), term(), map() | nil}
+
+  @callback run_formula_bytecode(
+              bytecode :: binary(),
+              inputs :: map(),
+              store_values :: map(),
+              limits :: limits()
+            ) :: {:ok, {list(), map()}} | error()
+
   @callback run_formula_bytecode(bytecode :: binary(), inputs :: map(), store_values :: map()) ::
-              {:ok, {list(), map()}} | {:error, atom(), term()}
+              {:ok, {list(), map()}} | error()
+
+  @callback compile_formula(bytecode :: binary()) ::
+              {:ok, reference()} | error()
+
+  @callback run_compiled(
+              program :: reference(),
+              inputs :: map(),
+              store_values :: map(),
+              limits :: limits()
+            ) :: {:ok, {list(), map()}} | error()
+
+  @callback run_formula_batch(
+              program :: binary() | reference(),
+              entities :: [map()] | map(),
+              store_values :: map(),
+              limits :: limits()
+            ) :: {:ok, [{:ok, list()} | error()]} | error()
+
+  @callback run_formula_events(
+              program :: binary() | reference(),
+              inputs :: map(),
+              store_values :: map(),
+              limits :: limits()
+            ) :: {:ok, {list(), list(), [{String.t(), term()}]}} | error()
+
+  @callback run_formula_changes(
+              program :: binary() | reference(),
+              inputs :: map(),
+              store_values :: map(),
+              limits :: limits()
+            ) ::
+              {:ok, {list(), [{String.t(), term(), term(), :written | :unchanged}], list()}}
+              | error()
+
+  @callback run_formula_traced(
+              program :: binary() | reference(),
+              inputs :: map(),
+              store_values :: map(),
+              options :: %{optional(:steps) => boolean(), optional(:profile) => boolean()},
+              limits :: limits()
+            ) :: {:ok, map()} | error()
+
+  @callback disassemble_formula(bytecode :: binary()) ::
+              {:ok, String.t()} | error()
+
+  @callback assemble_formula(source :: String.t()) ::
+              {:ok, binary()} | error()
+
+  @callback pack_formula(bytecode :: binary()) ::
+              {:ok, binary()} | error()
+
+  @callback verify_formula(bytecode :: binary(), schema :: map()) ::
+              :ok | error()
+
+  @callback optimize_formula(bytecode :: binary()) ::
+              {:ok, binary()} | error()
+
+  @callback compile_formula_source(source :: String.t()) ::
+              {:ok, binary()} | error()
+
+  @callback compile_formula_library(functions :: %{optional(String.t()) => binary()}) ::
+              {:ok, reference()} | error()
+
+  @callback link_formula(bytecode :: binary(), library :: reference()) ::
+              {:ok, reference()} | error()
+
+  @callback bind_store_schema(program :: binary() | reference(), schema :: map()) ::
+              {:ok, reference()} | error()
+
+  @callback bind_source_map(
+              program :: binary() | reference(),
+              entries :: [{non_neg_integer(), term()}]
+            ) :: {:ok, reference()} | error()
+
+  @callback load_formula_graph(
+              nodes :: [{atom(), atom(), term()}],
+              edges :: [{atom(), atom(), atom()}],
+              outputs :: [atom()]
+            ) :: {:ok, reference()} | error()
+
+  @callback evaluate_formula_graph(
+              graph :: reference(),
+              inputs :: map(),
+              store_values :: map(),
+              limits :: limits()
+            ) :: {:ok, {list(), map()}} | error()
+
+  @callback formula_graph_recomputed(graph :: reference()) :: [atom()]
 end
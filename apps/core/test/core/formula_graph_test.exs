@@ -194,4 +194,128 @@ defmodule Core.FormulaGraphTest do
                FormulaGraph.compile(graph)
     end
   end
+
+  describe "エラーの位置" do
+    test "実行時エラーの origin は失敗した命令を生成したノード ID" do
+      graph = %FormulaGraph{
+        nodes: [
+          %{id: :hp, op: :input, params: %{name: "hp"}},
+          %{id: :zero, op: :int, params: %{value: 0}},
+          %{id: :ratio, op: :div, params: %{}},
+          %{id: :out, op: :output, params: %{}}
+        ],
+        edges: [
+          {:hp, :ratio, :a},
+          {:zero, :ratio, :b},
+          {:ratio, :out, :value}
+        ],
+        outputs: [:out]
+      }
+
+      assert {:error, :division_by_zero, nil, %{origin: :ratio}} =
+               FormulaGraph.run(graph, %{"hp" => 10})
+
+      assert {:error, :input_not_found, "hp", %{origin: :hp}} = FormulaGraph.run(graph, %{})
+
+      assert {:ok, bytecode, source_map} = FormulaGraph.compile_with_source_map(graph)
+      assert {:ok, ^bytecode} = FormulaGraph.compile(graph)
+      assert source_map |> Enum.map(&elem(&1, 1)) |> Enum.sort() == [:hp, :out, :ratio, :zero]
+    end
+  end
+
+  describe "load/1・evaluate/4（差分評価）" do
+    test "run/3 と同じ結果を返し、変わった入力の下流だけを再計算する" do
+      graph = scaled_sum_graph()
+      assert {:ok, handle} = FormulaGraph.load(graph)
+
+      inputs = %{"x" => 1, "y" => 2}
+      assert FormulaGraph.evaluate(handle, inputs) == FormulaGraph.run(graph, inputs)
+      assert FormulaGraph.recomputed(handle) == [:x, :y, :k, :sum, :scaled]
+
+      assert {:ok, {[6], []}} = FormulaGraph.evaluate(handle, inputs)
+      assert FormulaGraph.recomputed(handle) == []
+
+      assert {:ok, {[14], []}} = FormulaGraph.evaluate(handle, %{"x" => 1, "y" => 6})
+      assert FormulaGraph.recomputed(handle) == [:y, :sum, :scaled]
+
+      # 和が変わらなければ scaled は再計算しない
+      assert {:ok, {[14], []}} = FormulaGraph.evaluate(handle, %{"x" => 6, "y" => 1})
+      assert FormulaGraph.recomputed(handle) == [:x, :y, :sum]
+    end
+
+    test "Store の読み書きは :after の順序に従う" do
+      graph = %FormulaGraph{
+        nodes: [
+          %{id: :read, op: :read_store, params: %{key: "score"}},
+          %{id: :v, op: :input, params: %{name: "v"}},
+          %{id: :write, op: :write_store, params: %{key: "score"}},
+          %{id: :out, op: :output, params: %{}}
+        ],
+        edges: [
+          {:v, :write, :value},
+          {:write, :read, :after},
+          {:read, :out, :value}
+        ],
+        outputs: [:out]
+      }
+
+      assert {:ok, handle} = FormulaGraph.load(graph)
+
+      assert {:ok, {[5], [{"score", 5}]}} =
+               FormulaGraph.evaluate(handle, %{"v" => 5}, %{"score" => 1})
+    end
+
+    test "評価時のエラーは失敗したノードを返し、次の評価で全体を再計算する" do
+      assert {:ok, handle} = FormulaGraph.load(scaled_sum_graph())
+
+      assert {:error, :input_not_found, "y", %{node: :y}} =
+               FormulaGraph.evaluate(handle, %{"x" => 1})
+
+      assert {:ok, {[6], []}} = FormulaGraph.evaluate(handle, %{"x" => 1, "y" => 2})
+      assert FormulaGraph.recomputed(handle) == [:x, :y, :k, :sum, :scaled]
+
+      assert {:error, :budget_exceeded, {:instructions, 1}, %{node: :scaled}} =
+               FormulaGraph.evaluate(handle, %{"x" => 2, "y" => 2}, %{}, max_instructions: 1)
+    end
+
+    test "構造の誤りは読み込み時に返す" do
+      graph = scaled_sum_graph()
+
+      assert {:error, :invalid_graph, {:unknown_port, :sum, :c}} =
+               FormulaGraph.load(%{graph | edges: [{:x, :sum, :c} | graph.edges]})
+
+      assert {:error, :invalid_graph, {:duplicate_input, :sum, :a}} =
+               FormulaGraph.load(%{graph | edges: [{:y, :sum, :a} | graph.edges]})
+
+      assert {:error, :invalid_graph, {:missing_input, :scaled, :b}} =
+               FormulaGraph.load(%{graph | edges: List.delete(graph.edges, {:k, :scaled, :b})})
+
+      assert {:error, :invalid_graph, {:not_an_output, :sum}} =
+               FormulaGraph.load(%{graph | outputs: [:sum]})
+
+      assert {:error, :cycle_detected, nil} =
+               FormulaGraph.load(%{graph | edges: [{:scaled, :sum, :after} | graph.edges]})
+    end
+  end
+
+  defp scaled_sum_graph do
+    %FormulaGraph{
+      nodes: [
+        %{id: :x, op: :input, params: %{name: "x"}},
+        %{id: :y, op: :input, params: %{name: "y"}},
+        %{id: :k, op: :int, params: %{value: 2}},
+        %{id: :sum, op: :add, params: %{}},
+        %{id: :scaled, op: :mul, params: %{}},
+        %{id: :out, op: :output, params: %{}}
+      ],
+      edges: [
+        {:x, :sum, :a},
+        {:y, :sum, :b},
+        {:sum, :scaled, :a},
+        {:k, :scaled, :b},
+        {:scaled, :out, :value}
+      ],
+      outputs: [:out]
+    }
+  end
 end
@@ -50,7 +50,7 @@ defmodule Core.FormulaTest do
           {:store_output, 0}
         ])
 
-      assert {:error, :input_not_found, "missing"} = Formula.run(bytecode, %{})
+      assert {:error, :input_not_found, "missing", _} = Formula.run(bytecode, %{})
     end
 
     test "0 除算でエラー" do
@@ -62,13 +62,13 @@ defmodule Core.FormulaTest do
           {:store_output, 2}
         ])
 
-      assert {:error, :division_by_zero, nil} = Formula.run(bytecode, %{})
+      assert {:error, :division_by_zero, nil, _} = Formula.run(bytecode, %{})
     end
 
     test "不正なオペコードでエラー" do
       # 不正なオペコード 99 を含むバイトコード
       bytecode = <<99>>
-      assert {:error, :invalid_opcode, 99} = Formula.run(bytecode, %{})
+      assert {:error, :invalid_opcode, 99, _} = Formula.run(bytecode, %{})
     end
 
     test "未初期化レジスタ参照でエラー" do
@@ -78,7 +78,7 @@ defmodule Core.FormulaTest do
           {:store_output, 1}
         ])
 
-      assert {:error, :type_mismatch, msg} = Formula.run(bytecode, %{})
+      assert {:error, :type_mismatch, msg, _} = Formula.run(bytecode, %{})
       assert msg =~ "uninitialized"
     end
 
@@ -86,7 +86,7 @@ defmodule Core.FormulaTest do
       # レジスタ 64 は範囲外（0-63 が有効）
       # LOAD_I32 r64, 0
       bytecode = <<1, 64, 0, 0, 0, 0>>
-      assert {:error, :register_out_of_range, 64} = Formula.run(bytecode, %{})
+      assert {:error, :register_out_of_range, 64, _} = Formula.run(bytecode, %{})
     end
 
     test "Store の read/write" do
@@ -112,8 +112,652 @@ defmodule Core.FormulaTest do
           {:store_output, 0}
         ])
 
-      assert {:error, :store_not_found, "missing"} =
+      assert {:error, :store_not_found, "missing", _} =
                Formula.run(bytecode, %{}, %{})
     end
+
+    test "条件ジャンプで分岐" do
+      bytecode =
+        Formula.build([
+          {:load_input, 0, "hit"},
+          {:jump_if_false, 0, :miss},
+          {:load_i32, 1, 10},
+          {:jump, :done},
+          {:label, :miss},
+          {:load_i32, 1, 0},
+          {:label, :done},
+          {:store_output, 1}
+        ])
+
+      assert {:ok, {[10], _}} = Formula.run(bytecode, %{"hit" => true})
+      assert {:ok, {[0], _}} = Formula.run(bytecode, %{"hit" => false})
+    end
+
+    test "後方ジャンプはエラー" do
+      bytecode =
+        Formula.build([
+          {:label, :top},
+          {:load_i32, 0, 1},
+          {:jump, :top}
+        ])
+
+      assert {:error, :backward_jump, 0, _} = Formula.run(bytecode, %{})
+    end
+  end
+
+  describe "compile/1 と run_compiled/3" do
+    test "コンパイル済みプログラムを繰り返し実行" do
+      bytecode =
+        Formula.build([
+          {:load_input, 0, "dt"},
+          {:load_i32, 1, 2},
+          {:mul, 2, 0, 1},
+          {:store_output, 2}
+        ])
+
+      assert {:ok, program} = Formula.compile(bytecode)
+      assert {:ok, {[2], _}} = Formula.run_compiled(program, %{"dt" => 1})
+      assert {:ok, {[6], _}} = Formula.run_compiled(program, %{"dt" => 3})
+    end
+
+    test "デコードエラーはコンパイル時に返る" do
+      assert {:error, :invalid_opcode, 99, _} = Formula.compile(<<99>>)
+    end
+  end
+
+  describe "run_batch/3" do
+    setup do
+      bytecode =
+        Formula.build([
+          {:load_input, 0, "hp"},
+          {:load_input, 1, "damage"},
+          {:div, 2, 0, 1},
+          {:store_output, 2}
+        ])
+
+      {:ok, bytecode: bytecode}
+    end
+
+    test "入力マップのリストでエンティティごとのエラーを個別に返す", %{bytecode: bytecode} do
+      entities = [%{"hp" => 10, "damage" => 2}, %{"hp" => 10, "damage" => 0}, %{"hp" => 1}]
+
+      assert {:ok,
+              [
+                {:ok, [5]},
+                {:error, :division_by_zero, nil, _},
+                {:error, :input_not_found, "damage", _}
+              ]} = Formula.run_batch(bytecode, entities)
+    end
+
+    test "列形式ではリストでない値を共通値として使う", %{bytecode: bytecode} do
+      assert {:ok, program} = Formula.compile(bytecode)
+
+      assert {:ok, [{:ok, [5]}, {:ok, [10]}]} =
+               Formula.run_batch(program, %{"hp" => [10, 20], "damage" => 2})
+    end
+  end
+
+  describe "実行上限" do
+    setup do
+      bytecode =
+        Formula.build([
+          {:load_i32, 0, 1},
+          {:store_output, 0},
+          {:store_output, 0},
+          {:write_store, 0, "a"}
+        ])
+
+      {:ok, bytecode: bytecode}
+    end
+
+    test "上限を超えると budget_exceeded を返す", %{bytecode: bytecode} do
+      assert {:error, :budget_exceeded, {:instructions, 2}, _} =
+               Formula.run(bytecode, %{}, %{}, max_instructions: 2)
+
+      assert {:error, :budget_exceeded, {:outputs, 1}, _} =
+               Formula.run(bytecode, %{}, %{}, max_outputs: 1)
+
+      assert {:error, :budget_exceeded, {:store_entries, 0}, _} =
+               Formula.run(bytecode, %{}, %{}, max_store_entries: 0)
+    end
+
+    test "dirty: true でも同じ結果を返す", %{bytecode: bytecode} do
+      assert {:ok, {[1, 1], [{"a", 1}]}} = Formula.run(bytecode, %{}, %{}, dirty: true)
+      assert {:ok, program} = Formula.compile(bytecode)
+
+      assert {:error, :budget_exceeded, {:instructions, 1}, _} =
+               Formula.run_compiled(program, %{}, %{}, dirty: true, max_instructions: 1)
+    end
+
+    test "互換用の run_formula_bytecode/3 は既定の上限で実行する", %{bytecode: bytecode} do
+      assert {:ok, {[1, 1], [{"a", 1}]}} = Core.NifBridge.run_formula_bytecode(bytecode, %{}, %{})
+    end
+  end
+
+  describe "disassemble/1 と assemble/1" do
+    test "build/1 の出力を往復できる" do
+      bytecode =
+        Formula.build([
+          {:load_input, 0, "dt"},
+          {:load_bool, 1, true},
+          {:jump_if_false, 1, :skip},
+          {:load_f32, 2, 0.5},
+          {:mul, 0, 0, 2},
+          {:label, :skip},
+          {:store_output, 0}
+        ])
+
+      assert {:ok, text} = Formula.disassemble(bytecode)
+      assert text =~ "load_input r0 \"dt\""
+      assert text =~ "jump_if_false r1 L0"
+      assert {:ok, ^bytecode} = Formula.assemble(text)
+    end
+
+    test "構文エラーは行番号を返す" do
+      assert {:error, :syntax_error, {2, _message}} =
+               Formula.assemble("load_i32 r0 1\nfrobnicate r0")
+    end
+  end
+
+  describe "コンテナ形式" do
+    setup do
+      instructions = [
+        {:load_input, 0, "dt"},
+        {:read_store, 1, "score"},
+        {:add, 1, 1, 0},
+        {:write_store, 1, "score"},
+        {:load_input, 2, "dt"},
+        {:store_output, 2}
+      ]
+
+      %{instructions: instructions}
+    end
+
+    test "build(container: true) は従来形式と同じ結果になる", %{instructions: instructions} do
+      container = Formula.build(instructions, container: true)
+      assert <<0xFF, "FVM", 1::little-16, 1, _::binary>> = container
+
+      assert Formula.run(container, %{"dt" => 0.5}, %{"score" => 1.0}) ==
+               Formula.run(Formula.build(instructions), %{"dt" => 0.5}, %{"score" => 1.0})
+    end
+
+    test "pack/1 は従来形式をコンテナ形式に変換する", %{instructions: instructions} do
+      assert {:ok, <<0xFF, "FVM", _::binary>> = packed} =
+               Formula.pack(Formula.build(instructions))
+
+      assert {:ok, {[0.5], [{"score", 1.5}]}} =
+               Formula.run(packed, %{"dt" => 0.5}, %{"score" => 1.0})
+    end
+
+    test "チェックサム不一致・未知のバージョンはエラー", %{instructions: instructions} do
+      container = Formula.build(instructions, container: true)
+      <<body::binary-size(byte_size(container) - 1), last>> = container
+
+      assert {:error, :checksum_mismatch, nil, nil} =
+               Formula.run(<<body::binary, Bitwise.bxor(last, 1)>>, %{"dt" => 0.5})
+
+      <<magic::binary-size(4), _version::little-16, rest::binary>> = container
+      assert {:error, :unsupported_version, 2, nil} =
+               Formula.run(<<magic::binary, 2::little-16, rest::binary>>, %{})
+    end
+  end
+
+  describe "verify/2" do
+    test "型の合ったプログラムは :ok" do
+      bytecode =
+        Formula.build([
+          {:load_input, 0, "dt"},
+          {:read_store, 1, "score"},
+          {:add, 1, 1, 0},
+          {:write_store, 1, "score"}
+        ])
+
+      assert :ok = Formula.verify(bytecode, %{inputs: %{"dt" => :f32}, store: %{"score" => :f32}})
+    end
+
+    test "未初期化レジスタと型の不整合を命令位置付きで報告する" do
+      bytecode =
+        Formula.build([
+          {:load_bool, 0, true},
+          {:load_f32, 1, 1.0},
+          {:lt, 2, 0, 1},
+          {:store_output, 3}
+        ])
+
+      assert {:error, :verification_failed, [mismatch, uninit]} = Formula.verify(bytecode)
+      assert %{pc: 2, offset: 9, kind: :type_mismatch, detail: {:lt, [[:bool], [:f32]]}} = mismatch
+      assert %{pc: 3, offset: 13, kind: :uninitialized_register, detail: 3} = uninit
+    end
+
+    test "スキーマに無い入力を報告する" do
+      bytecode = Formula.build([{:load_input, 0, "dt"}, {:store_output, 0}])
+
+      assert {:error, :verification_failed, [%{kind: :undeclared_input, detail: "dt"}]} =
+               Formula.verify(bytecode, %{inputs: %{}})
+    end
+  end
+
+  describe "optimize/1" do
+    test "定数を畳み込み、結果は変わらない" do
+      bytecode =
+        Formula.build([
+          {:load_input, 0, "hp"},
+          {:load_i32, 1, 2},
+          {:load_i32, 2, 3},
+          {:add, 3, 1, 2},
+          {:load_i32, 9, 100},
+          {:sub, 4, 0, 3},
+          {:write_store, 4, "hp"},
+          {:store_output, 4}
+        ])
+
+      assert {:ok, optimized} = Formula.optimize(bytecode)
+      assert byte_size(optimized) < byte_size(bytecode)
+      assert {:ok, text} = Formula.disassemble(optimized)
+      refute text =~ "add"
+
+      assert Formula.run(optimized, %{"hp" => 10}) == Formula.run(bytecode, %{"hp" => 10})
+    end
+
+    test "ゼロ除算は畳み込まずに残す" do
+      bytecode = Formula.build([{:load_i32, 0, 1}, {:load_i32, 1, 0}, {:div, 2, 0, 1}])
+
+      assert {:ok, optimized} = Formula.optimize(bytecode)
+      assert {:error, :division_by_zero, _, _} = Formula.run(optimized, %{})
+    end
+  end
+
+  describe "compile_source/1" do
+    test "式をコンパイルして実行できる" do
+      assert {:ok, bytecode} = Formula.compile_source("output hp - damage * (1 - armor)")
+
+      assert {:ok, {[70.0], []}} =
+               Formula.run(bytecode, %{"hp" => 100.0, "damage" => 40.0, "armor" => 0.25})
+    end
+
+    test "let・Store の読み書き・複数の出力" do
+      source = """
+      let reduced = damage * (1 - armor)
+      store.hp = max(store.hp - reduced, 0)
+      output store.hp
+      output store.hp <= 0
+      """
+
+      assert {:ok, bytecode} = Formula.compile_source(source)
+
+      assert {:ok, {[0, true], [{"hp", 0}]}} =
+               Formula.run(bytecode, %{"damage" => 30, "armor" => 0}, %{"hp" => 20})
+    end
+
+    test "構文エラーは行と列を返す" do
+      assert {:error, :parse_error, {2, 12, "expected expression, found `)`"}} =
+               Formula.compile_source("output 1\noutput (2 +)")
+    end
+  end
+
+  describe "関数ライブラリ" do
+    setup do
+      reduce =
+        Formula.build([
+          {:load_i32, 2, 100},
+          {:sub, 3, 2, 1},
+          {:mul, 4, 0, 3},
+          {:div, 5, 4, 2},
+          {:ret, 5}
+        ])
+
+      {:ok, library} = Formula.compile_library(%{"reduce_damage" => reduce})
+      %{library: library}
+    end
+
+    test "複数のプログラムから同じ関数を呼び出せる", %{library: library} do
+      hit =
+        Formula.build(
+          [
+            {:load_input, 0, "damage"},
+            {:load_i32, 1, 25},
+            {:call, 2, "reduce_damage", [0, 1]},
+            {:store_output, 2}
+          ],
+          container: true
+        )
+
+      heavy_hit =
+        Formula.build([
+          {:load_input, 0, "damage"},
+          {:load_i32, 1, 50},
+          {:call, 2, "reduce_damage", [0, 1]},
+          {:store_output, 2}
+        ])
+
+      assert {:ok, {[30], []}} = Formula.run(hit, %{"damage" => 40}, %{}, library: library)
+      assert {:ok, program} = Formula.link(heavy_hit, library)
+      assert {:ok, {[20], []}} = Formula.run_compiled(program, %{"damage" => 40})
+    end
+
+    test "ライブラリ無しの CALL と深さの上限", %{library: library} do
+      bytecode = Formula.build([{:call, 0, "reduce_damage", []}, {:store_output, 0}])
+
+      assert {:error, :undefined_function, "reduce_damage", _} = Formula.run(bytecode, %{})
+
+      assert {:error, :budget_exceeded, {:call_depth, 0}, _} =
+               Formula.run(bytecode, %{}, %{}, library: library, max_call_depth: 0)
+    end
+
+    test "デコードできない関数は名前付きで報告する" do
+      assert {:error, :invalid_function, {"bad", {:invalid_opcode, 99, %{pc: 0, offset: 0}}}} =
+               Formula.compile_library(%{"bad" => <<99>>})
+    end
+  end
+
+  describe "乱数" do
+    @roll [
+      {:load_i32, 1, 1},
+      {:load_i32, 2, 6},
+      {:random_range, 0, 1, 2},
+      {:random, 3},
+      {:store_output, 0},
+      {:store_output, 3}
+    ]
+
+    test "同じシードからは同じ値になり、返った Store で列が進む" do
+      bytecode = Formula.build(@roll)
+      store = Formula.seed_random(%{}, 2024)
+
+      assert {:ok, {[die, unit], [{"$rng", next}]} = first} = Formula.run(bytecode, %{}, store)
+      assert die in 1..6
+      assert unit >= 0.0 and unit < 1.0
+      assert ^first = Formula.run(bytecode, %{}, store)
+
+      assert {:ok, {_, [{"$rng", after_next}]}} =
+               Formula.run(bytecode, %{}, %{"$rng" => next})
+
+      refute after_next == next
+    end
+
+    test "シードが無ければ Store のキー不足として報告する" do
+      assert {:error, :store_not_found, "$rng", _} = Formula.run(Formula.build(@roll), %{})
+    end
+
+    test "i32 に収まらないシードは下位 32 bit を使う" do
+      assert %{"$rng" => -1} = Formula.seed_random(%{}, 0xFFFF_FFFF)
+    end
+  end
+
+  describe "イベント" do
+    @hit [
+      {:read_store, 0, "hp"},
+      {:load_input, 1, "damage"},
+      {:sub, 2, 0, 1},
+      {:write_store, 2, "hp"},
+      {:emit, "damaged", 1},
+      {:load_i32, 3, 0},
+      {:gt, 4, 2, 3},
+      {:jump_if_true, 4, :alive},
+      {:emit, "died"},
+      {:label, :alive},
+      {:store_output, 2}
+    ]
+
+    test "発行順のイベントを出力・Store と一緒に返す" do
+      for bytecode <- [Formula.build(@hit), Formula.build(@hit, container: true)] do
+        assert {:ok, {[-2], [{"hp", -2}], [{"damaged", 5}, {"died", nil}]}} =
+                 Formula.run_events(bytecode, %{"damage" => 5}, %{"hp" => 3})
+      end
+
+      assert {:ok, program} = Formula.compile(Formula.build(@hit))
+
+      assert {:ok, {[7], _, [{"damaged", 3}]}} =
+               Formula.run_events(program, %{"damage" => 3}, %{"hp" => 10})
+    end
+
+    test "run/4 はイベントを捨て、上限は :max_events で制御する" do
+      bytecode = Formula.build(@hit)
+      assert {:ok, {[-2], [{"hp", -2}]}} = Formula.run(bytecode, %{"damage" => 5}, %{"hp" => 3})
+
+      assert {:error, :budget_exceeded, {:events, 1}, _} =
+               Formula.run_events(bytecode, %{"damage" => 5}, %{"hp" => 3}, max_events: 1)
+    end
+  end
+
+  describe "変更セット" do
+    @damage [
+      {:read_store, 0, "hp"},
+      {:load_input, 1, "damage"},
+      {:sub, 2, 0, 1},
+      {:write_store, 2, "hp"},
+      {:read_store, 3, "shield"},
+      {:write_store, 3, "shield"},
+      {:write_store, 1, "last_damage"}
+    ]
+
+    test "書き込んだキーだけを前後の値付きで返す" do
+      store = %{"hp" => 10, "shield" => 2, "name_len" => 5}
+      bytecode = Formula.build(@damage)
+
+      assert {:ok, {[], changes, []}} = Formula.run_changes(bytecode, %{"damage" => 3}, store)
+
+      assert changes == [
+               {"hp", 10, 7, :written},
+               {"shield", 2, 2, :unchanged},
+               {"last_damage", nil, 3, :written}
+             ]
+
+      assert {:ok, {[], full_store}} = Formula.run(bytecode, %{"damage" => 3}, store)
+      assert Formula.apply_changes(store, changes) == Map.new(full_store)
+    end
+
+    test "参照しない Store の値はデコードしない" do
+      store = %{"hp" => 10, "shield" => 2, "blob" => "not a value"}
+      bytecode = Formula.build(@damage)
+
+      assert {:ok, {[], [{"hp", 10, 9, :written} | _], []}} =
+               Formula.run_changes(bytecode, %{"damage" => 1}, store)
+
+      assert {:error, :invalid_store_value, "hp", _} =
+               Formula.run_changes(bytecode, %{"damage" => 1}, %{"hp" => "bad"})
+    end
+  end
+
+  describe "リスト" do
+    setup do
+      {:ok, library} =
+        Formula.compile_library(%{
+          "add" => Formula.build([{:add, 2, 0, 1}, {:ret, 2}]),
+          "double" => Formula.build([{:load_i32, 1, 2}, {:mul, 2, 0, 1}, {:ret, 2}])
+        })
+
+      %{library: library}
+    end
+
+    @aggregate [
+      {:load_input, 0, "xs"},
+      {:list_len, 1, 0},
+      {:list_sum, 2, 0},
+      {:list_max, 3, 0},
+      {:load_i32, 4, 0},
+      {:list_index, 5, 0, 4},
+      {:store_output, 1},
+      {:store_output, 2},
+      {:store_output, 3},
+      {:store_output, 5}
+    ]
+
+    test "集計と添字アクセス" do
+      bytecode = Formula.build(@aggregate)
+      assert {:ok, {[3, 9, 7, 3], []}} = Formula.run(bytecode, %{"xs" => [3, -1, 7]})
+
+      assert {:error, :domain_error, "list_max", _} = Formula.run(bytecode, %{"xs" => []})
+
+      assert {:error, :budget_exceeded, {:list_len, 2}, _} =
+               Formula.run(bytecode, %{"xs" => [1, 2, 3]}, %{}, max_list_len: 2)
+    end
+
+    test "for_each と list_map はライブラリ関数を要素ごとに呼ぶ", %{library: library} do
+      instructions = [
+        {:read_store, 0, "xs"},
+        {:load_i32, 1, 100},
+        {:for_each, 2, "add", 0, 1},
+        {:list_map, 3, "double", 0},
+        {:store_output, 2},
+        {:store_output, 3},
+        {:write_store, 3, "xs"}
+      ]
+
+      for bytecode <- [Formula.build(instructions), Formula.build(instructions, container: true)] do
+        assert {:ok, {[106, [2, 4, 6]], [{"xs", [2, 4, 6]}]}} =
+                 Formula.run(bytecode, %{}, %{"xs" => [1, 2, 3]}, library: library)
+      end
+    end
+  end
+
+  describe "トレース" do
+    @traced [
+      {:read_store, 0, "hp"},
+      {:load_input, 1, "damage"},
+      {:sub, 2, 0, 1},
+      {:write_store, 2, "hp"},
+      {:store_output, 2}
+    ]
+
+    test "命令ごとのオペランドと結果を記録し、結果は run/4 と同じ" do
+      bytecode = Formula.build(@traced)
+
+      assert {:ok, %{result: result, steps: steps, profile: nil}} =
+               Formula.run_traced(bytecode, %{"damage" => 3}, %{"hp" => 10})
+
+      assert result == Formula.run(bytecode, %{"damage" => 3}, %{"hp" => 10})
+
+      assert Formula.format_trace(steps) == [
+               "main@0 read_store -> r0=10",
+               "main@1 load_input -> r1=3",
+               "main@2 sub r0=10 r1=3 -> r2=7",
+               "main@3 write_store r2=7",
+               "main@4 store_output r2=7"
+             ]
+    end
+
+    test "実行時エラーでもそこまでのステップを返し、profile で集計する" do
+      bytecode = Formula.build([{:load_i32, 0, 1}, {:load_i32, 1, 0}, {:div, 2, 0, 1}])
+
+      assert {:ok, %{result: {:error, :division_by_zero, nil, _}, steps: steps, profile: profile}} =
+               Formula.run_traced(bytecode, %{}, %{}, profile: true)
+
+      assert %{op: :div, operands: [{0, 1}, {1, 0}], result: nil} = List.last(steps)
+      assert [{:load_i32, 2, _}, {:div, 1, _}] = profile
+    end
+  end
+
+  describe "Store スキーマ" do
+    @store_schema %{"hp" => {:i32, 100}, "speed" => {:f32, 1.5}}
+
+    test "宣言したキーが Store に無ければ既定値を読み、読むだけでは書き込まない" do
+      bytecode = Formula.build([{:read_store, 0, "hp"}, {:store_output, 0}])
+
+      assert {:ok, {[100], []}} = Formula.run(bytecode, %{}, %{}, store_schema: @store_schema)
+      assert {:ok, {[7], _}} = Formula.run(bytecode, %{}, %{"hp" => 7}, store_schema: @store_schema)
+      assert {:error, :store_not_found, "hp", _} = Formula.run(bytecode, %{}, %{})
+    end
+
+    test "宣言と異なる型の読み書きは store_type_mismatch" do
+      write = Formula.build([{:load_i32, 0, 2}, {:write_store, 0, "speed"}])
+
+      assert {:error, :store_type_mismatch, {"speed", :f32, :i32}, _} =
+               Formula.run(write, %{}, %{}, store_schema: @store_schema)
+
+      read = Formula.build([{:read_store, 0, "hp"}])
+
+      assert {:error, :store_type_mismatch, {"hp", :i32, :bool}, _} =
+               Formula.run(read, %{}, %{"hp" => true}, store_schema: @store_schema)
+    end
+
+    test "ハンドルに結び付けて再利用でき、既定値が型に合わなければ invalid_store_schema" do
+      {:ok, program} = Formula.compile(Formula.build([{:read_store, 0, "hp"}, {:store_output, 0}]))
+      assert {:ok, bound} = Formula.with_store_schema(program, @store_schema)
+      assert {:ok, {[100], []}} = Formula.run_compiled(bound, %{}, %{})
+
+      assert {:error, :invalid_store_schema, "hp"} =
+               Formula.with_store_schema(program, %{"hp" => {:i32, 1.5}})
+    end
+  end
+
+  describe "64 bit 値" do
+    test "i32 に収まらない integer は i64 のまま計算して integer で返す" do
+      bytecode =
+        Formula.build([{:load_input, 0, "ticks"}, {:load_i32, 1, 1}, {:add, 2, 0, 1}, {:store_output, 2}])
+
+      assert {:ok, {[5_000_000_001], []}} = Formula.run(bytecode, %{"ticks" => 5_000_000_000})
+      assert {:ok, {[8], []}} = Formula.run(bytecode, %{"ticks" => 7})
+
+      assert {:error, :integer_out_of_range, "18446744073709551616"} =
+               Formula.run(bytecode, %{"ticks" => 18_446_744_073_709_551_616})
+    end
+
+    test "{:f64, x} と load_f64 は倍精度のまま計算する" do
+      bytecode =
+        Formula.build([{:load_input, 0, "x"}, {:load_f64, 1, 0.2}, {:add, 2, 0, 1}, {:store_output, 2}])
+
+      assert {:ok, {[sum], []}} = Formula.run(bytecode, %{"x" => {:f64, 0.1}})
+      assert sum == 0.1 + 0.2
+    end
+
+    test "cast は 0 方向に切り捨て、収まらない値は domain_error" do
+      bytecode =
+        Formula.build([
+          {:load_input, 0, "x"},
+          {:cast_i32, 1, 0},
+          {:cast_f64, 2, 1},
+          {:store_output, 1},
+          {:store_output, 2}
+        ])
+
+      assert {:ok, {[-2, -2.0], []}} = Formula.run(bytecode, %{"x" => -2.9})
+
+      assert {:error, :domain_error, "cast_i32", _} =
+               Formula.run(bytecode, %{"x" => 5_000_000_000})
+    end
+  end
+
+  describe "エラーの位置" do
+    test "実行時エラーは失敗した命令のインデックスとバイトオフセットを返す" do
+      bytecode = Formula.build([{:load_i32, 0, 1}, {:load_i32, 1, 0}, {:div, 2, 0, 1}])
+
+      assert {:error, :division_by_zero, nil, %{pc: 2, offset: 12, function: nil, origin: nil}} =
+               Formula.run(bytecode, %{})
+    end
+
+    test "関数の中のエラーは関数名と関数内の位置を返す" do
+      {:ok, library} =
+        Formula.compile_library(%{"boom" => Formula.build([{:load_input, 0, "nope"}, {:ret, 0}])})
+
+      bytecode = Formula.build([{:call, 0, "boom", []}, {:store_output, 0}])
+
+      assert {:error, :input_not_found, "nope", %{pc: 0, offset: 0, function: "boom"}} =
+               Formula.run(bytecode, %{}, %{}, library: library)
+    end
+
+    test "ソースマップで生成元を引く" do
+      instructions = [
+        {:origin, :x},
+        {:load_input, 0, "x"},
+        {:origin, {3, 5}},
+        {:load_i32, 1, 0},
+        {:div, 2, 0, 1},
+        {:store_output, 2}
+      ]
+
+      # コンテナ形式では名前が 2 バイトのインデックスになる
+      source_map = Formula.source_map(instructions, container: true)
+      assert source_map == [{0, :x}, {4, {3, 5}}]
+      bytecode = Formula.build(instructions, container: true)
+
+      assert {:error, :division_by_zero, nil, %{pc: 2, offset: 10, origin: {3, 5}}} =
+               Formula.run(bytecode, %{"x" => 1}, %{}, source_map: source_map)
+
+      {:ok, program} = Formula.with_source_map(bytecode, source_map)
+
+      assert {:error, :input_not_found, "x", %{pc: 0, origin: :x}} =
+               Formula.run_compiled(program, %{})
+    end
   end
 end
@@ -5,6 +5,8 @@ defmodule Network.ZenohBridge do
   - フレーム publish: `game/room/{room_id}/frame`
   - movement/action subscribe: `game/room/*/input/movement`, `game/room/*/input/action`
   - client_info subscribe: `contents/room/*/client/info` → `:client_info` ETS に保存
+  - 移動 Formula publish: `game/room/{room_id}/predict/movement`（コンテンツの `movement_formula/0` を
+    起動時に一度だけコンパイルしたバイトコード。client_info 受信時と、client_info のあるルームへ定期的に送る）
   - 受信した入力は `Contents.Events.Game` へ `{:move_input, dx, dy}` / `{:ui_action, name}` で配送
 
   `AUTH_REQUIRED=true` のとき、movement / action / client_info のペイロードは
@@ -27,6 +29,9 @@ defmodule Network.ZenohBridge do
   @action_selector "game/room/*/input/action"
   @client_info_selector "contents/room/*/client/info"
 
+  # 移動 Formula の再送間隔。クライアントの購読開始が client_info 受信時の publish に間に合わない場合に備える
+  @movement_formula_interval_ms 2_000
+
   def start_link(opts \\ []) do
     GenServer.start_link(__MODULE__, opts, name: __MODULE__)
   end
@@ -70,12 +75,15 @@ defmodule Network.ZenohBridge do
 
         Logger.info("[input:ZenohBridge] init: subscribed to movement=#{@movement_selector}")
 
+        schedule_movement_formula()
+
         {:ok,
          %{
            session_id: session_id,
            mov_sub: mov_sub,
            act_sub: act_sub,
-           info_sub: info_sub
+           info_sub: info_sub,
+           movement_formula: compile_movement_formula(Core.Config.current())
          }}
 
       {:error, reason} ->
@@ -139,6 +147,13 @@ defmodule Network.ZenohBridge do
   end
 
   @impl true
+  def handle_info(:publish_movement_formula, state) do
+    Enum.each(client_info_rooms(), &publish_movement_formula(state, &1))
+
+    schedule_movement_formula()
+    {:noreply, state}
+  end
+
   def handle_info(%Zenohex.Sample{key_expr: key_expr, payload: payload, kind: kind}, state) do
     parsed = parse_input_key(key_expr)
 
@@ -155,7 +170,9 @@ defmodule Network.ZenohBridge do
           handle_action(room_id, payload)
 
         {:client_info, room_id} ->
-          handle_client_info(room_id, payload)
+          with :stored <- handle_client_info(room_id, payload) do
+            publish_movement_formula(state, room_id)
+          end
 
         :unknown ->
           Logger.debug("[input:ZenohBridge] Unknown key_expr=#{key_expr}")
@@ -275,6 +292,65 @@ defmodule Network.ZenohBridge do
   defp room_id_for_registry("main"), do: :main
   defp room_id_for_registry(id) when is_binary(id), do: id
 
+  # ── 移動 Formula（クライアント予測）────────────────────────────────────
+
+  defp schedule_movement_formula do
+    Process.send_after(self(), :publish_movement_formula, @movement_formula_interval_ms)
+  end
+
+  defp client_info_rooms do
+    if :ets.whereis(:client_info) == :undefined do
+      []
+    else
+      :client_info
+      |> :ets.match({{:"$1", :info}, :_})
+      |> Enum.map(fn [room_key] -> normalize_room_id(room_key) end)
+    end
+  end
+
+  defp publish_movement_formula(
+         %{session_id: session_id, movement_formula: {:ok, bytecode}},
+         room_id
+       ) do
+    key = "#{@frame_key}/#{room_id}/predict/movement"
+
+    case Zenohex.Session.put(session_id, key, bytecode, []) do
+      :ok ->
+        :ok
+
+      {:error, reason} ->
+        Logger.warning(
+          "[ZenohBridge] publish movement formula failed room=#{room_id}: #{inspect(reason)}"
+        )
+    end
+  end
+
+  # session が無い状態（テスト等）・移動 Formula の無いコンテンツでは送らない
+  defp publish_movement_formula(_state, _room_id), do: :ok
+
+  @doc false
+  # コンテンツの movement_formula/0 をコンパイルする。init で一度だけ呼び、結果を state に持つ。
+  # 未実装・コンパイル失敗は :none（失敗は起動時に 1 回だけログに出す）
+  def compile_movement_formula(content) do
+    Code.ensure_loaded(content)
+
+    if function_exported?(content, :movement_formula, 0) do
+      case Core.Formula.compile_source(content.movement_formula()) do
+        {:ok, bytecode} ->
+          {:ok, bytecode}
+
+        error ->
+          Logger.warning(
+            "[ZenohBridge] #{inspect(content)}.movement_formula/0 failed to compile: #{inspect(error)}"
+          )
+
+          :none
+      end
+    else
+      :none
+    end
+  end
+
   # ── client_info ETS ──────────────────────────────────────────────────────
 
   defp ensure_client_info_table do
@@ -316,6 +392,7 @@ defmodule Network.ZenohBridge do
         case normalize_client_info(info) do
           normalized when is_map(normalized) ->
             :ets.insert(:client_info, {{room_key, :info}, normalized})
+            :stored
 
           _ ->
             Logger.debug("[ZenohBridge] Invalid client info structure room=#{room_id}")
@@ -115,4 +115,57 @@ defmodule Network.ZenohBridgeTest do
       assert info.os == "darwin"
     end
   end
+
+  describe "handle_info/2 movement formula" do
+    test "session が無い状態では移動 Formula を送らずに処理を続ける" do
+      :ets.insert(:client_info, {{:main, :info}, %{os: "linux", arch: "x86_64", family: "unix"}})
+
+      assert {:noreply, %{test_state: true}} =
+               Network.ZenohBridge.handle_info(:publish_movement_formula, %{test_state: true})
+    end
+
+    test "コンテンツの移動 Formula をコンパイルし、client_info のあるルームへ送る" do
+      assert {:ok, bytecode} = Network.ZenohBridge.compile_movement_formula(__MODULE__.Mover)
+      assert {:ok, {[x, y], []}} = Core.Formula.run(bytecode, mover_inputs(3.0, 4.0))
+      assert_in_delta x, 0.36, 1.0e-5
+      assert_in_delta y, 0.48, 1.0e-5
+
+      {:ok, session_id} = Zenohex.Session.open(Zenohex.Config.default())
+      key = "game/room/main/predict/movement"
+      {:ok, _sub} = Zenohex.Session.declare_subscriber(session_id, key, self())
+      :ets.insert(:client_info, {{:main, :info}, %{os: "linux", arch: "x86_64", family: "unix"}})
+
+      state = %{session_id: session_id, movement_formula: {:ok, bytecode}}
+
+      assert {:noreply, ^state} =
+               Network.ZenohBridge.handle_info(:publish_movement_formula, state)
+
+      assert_receive %Zenohex.Sample{key_expr: ^key, payload: ^bytecode, kind: :put}, 2_000
+    end
+
+    test "movement_formula/0 の無いコンテンツ・コンパイルできないソースは送らない" do
+      assert :none = Network.ZenohBridge.compile_movement_formula(__MODULE__)
+      assert :none = Network.ZenohBridge.compile_movement_formula(__MODULE__.Broken)
+    end
+  end
+
+  defp mover_inputs(dx, dy), do: %{"x" => 0.0, "y" => 0.0, "dx" => dx, "dy" => dy, "dt" => 0.1}
+
+  defmodule Mover do
+    @moduledoc false
+    # 入力方向へ速さ 6 で進む（Content.BulletHell3D のプレイヤー移動と同じ規則）
+    def movement_formula do
+      """
+      let len = sqrt(dx * dx + dy * dy)
+      let step = select(len > 0.001, 6.0 * dt / max(len, 0.001), 0.0)
+      output x + dx * step
+      output y + dy * step
+      """
+    end
+  end
+
+  defmodule Broken do
+    @moduledoc false
+    def movement_formula, do: "output (x +"
+  end
 end
@@ -19,3 +19,8 @@ pub fn action_key(room_id: &str) -> String {
 pub fn client_info_key(room_id: &str) -> String {
     format!("contents/room/{room_id}/client/info")
 }
+
+/// 移動 Formula（クライアント予測用バイトコード）配信用キー
+pub fn predict_movement_key(room_id: &str) -> String {
+    format!("game/room/{room_id}/predict/movement")
+}
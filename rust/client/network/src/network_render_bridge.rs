@@ -4,12 +4,16 @@
 //! Zenoh 通信は platform/desktop.rs の ClientSession を経由する。
 //! 受信スナップショットは `shared::SnapshotInterpolator` で直近 2 枚を保持し、
 //! ~100ms の描画遅延バッファ上で座標を線形補間して 60fps 描画へ渡す。
+//! サーバーが移動 Formula を配信していれば、ローカルプレイヤーは入力から予測した位置で描く。
 
-use crate::{action_key, client_info_key, frame_key, movement_key, ClientInfo, ClientSession};
+use crate::{
+    action_key, client_info_key, frame_key, movement_key, predict_movement_key, ClientInfo,
+    ClientSession,
+};
 use audio::AudioCommandSender;
 use render::window::{KeyCode, KeyState, RenderBridge};
 use render::RenderFrame;
-use shared::SnapshotInterpolator;
+use shared::{MovementFormulaReceiver, SnapshotInterpolator, Vec2};
 use std::collections::HashSet;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
@@ -40,6 +44,8 @@ pub struct NetworkRenderBridge {
     #[allow(dead_code)]
     room_id: String,
     recv_handle: Option<thread::JoinHandle<()>>,
+    /// 移動 Formula の受信スレッド。受信したら `snapshots` に予測器を設定する。
+    predict_handle: Option<thread::JoinHandle<()>>,
     shutdown: Arc<AtomicBool>,
     /// 最後にフレームを受信した時刻（`creation_time` からの経過ミリ秒。未受信は `u64::MAX`）。
     /// `CONNECTED_TIMEOUT` 以内なら接続中とみなす（単調カウンタだと切断後も
@@ -105,6 +111,41 @@ impl NetworkRenderBridge {
             })
         };
 
+        let predict_handle = {
+            let snapshots_clone = Arc::clone(&snapshots);
+            let receiver = Mutex::new(MovementFormulaReceiver::default());
+            session.spawn_subscriber(
+                &predict_movement_key(room_id),
+                Arc::clone(&shutdown),
+                move |bytes| {
+                    let Ok(mut receiver) = receiver.lock() else {
+                        log::warn!("[predict] receiver lock failed (poisoned)");
+                        return;
+                    };
+                    let predictor = match receiver.accept(&bytes) {
+                        Ok(Some(p)) => p,
+                        // 同じバイトコードの再配信
+                        Ok(None) => return,
+                        Err(e) => {
+                            log::warn!("[predict] invalid movement formula: {e:?}");
+                            return;
+                        }
+                    };
+                    match snapshots_clone.lock() {
+                        Ok(mut guard) => guard.set_predictor(predictor),
+                        Err(e) => {
+                            log::warn!("[predict] snapshots lock failed (poisoned): {e}");
+                            return;
+                        }
+                    }
+                    log::info!(
+                        "[predict] movement formula installed ({} bytes)",
+                        bytes.len()
+                    );
+                },
+            )
+        };
+
         let bridge = Self {
             snapshots,
             audio_tx,
@@ -114,6 +155,7 @@ impl NetworkRenderBridge {
             action_key_expr: action_key(room_id),
             room_id: room_id.to_string(),
             recv_handle: Some(recv_handle),
+            predict_handle: Some(predict_handle),
             shutdown,
             last_frame_elapsed_ms,
             creation_time,
@@ -175,7 +217,10 @@ fn decode_render_frame_from_zenoh(
 impl Drop for NetworkRenderBridge {
     fn drop(&mut self) {
         self.shutdown.store(true, Ordering::SeqCst);
-        if let Some(h) = self.recv_handle.take() {
+        for h in [self.recv_handle.take(), self.predict_handle.take()]
+            .into_iter()
+            .flatten()
+        {
             let _ = h.join();
         }
     }
@@ -195,6 +240,10 @@ impl RenderBridge for NetworkRenderBridge {
         let now = Instant::now();
         match self.snapshots.lock() {
             Ok(mut guard) => {
+                if let Err(e) = guard.predict(Vec2::new(dx, dy), now) {
+                    // 次のスナップショットで権威位置に戻る
+                    log::debug!("[predict] movement formula failed: {e:?}");
+                }
                 if let Some(ref tx) = self.audio_tx {
                     for url in guard.take_pending_audio() {
                         tx.play_se_from_relative_path(url);
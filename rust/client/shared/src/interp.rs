@@ -3,10 +3,14 @@
 //! サーバーの低頻度更新（10〜20Hz）を描画タイミング（~60Hz）に合わせて補間。
 //! `SnapshotInterpolator` が複数スナップショットをキュー保持し、描画遅延バッファ
 //! （観測間隔の約 2 倍）上の表示時刻を挟む 2 枚で座標を線形補間する。
+//! 移動 Formula を受信済みなら、ローカルプレイヤーだけは遅延なしの予測位置で描く（predict.rs）。
 
 use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
+use formula::VmError;
+
+use crate::predict::MovementPredictor;
 use crate::render_frame::{CameraParams, DrawCommand, RenderFrame, UiCanvas};
 use crate::types::Vec2;
 
@@ -415,6 +419,8 @@ pub struct SnapshotInterpolator {
     estimated_interval: Duration,
     pending_audio: Vec<String>,
     delay: Duration,
+    /// ローカルプレイヤー（PlayerSprite）の予測。None なら補間位置のまま描く
+    predictor: Option<MovementPredictor>,
 }
 
 impl Default for SnapshotInterpolator {
@@ -436,6 +442,7 @@ impl SnapshotInterpolator {
             estimated_interval,
             pending_audio: Vec::new(),
             delay,
+            predictor: None,
         }
     }
 
@@ -444,6 +451,23 @@ impl SnapshotInterpolator {
         self.delay
     }
 
+    /// 移動 Formula による予測を有効にする（差し替え可）。受信済みの最新スナップショットを起点にする。
+    pub fn set_predictor(&mut self, mut predictor: MovementPredictor) {
+        let latest = self.snapshots.back().and_then(|(_, f)| player_position(f));
+        if let (Some(position), Some(received_at)) = (latest, self.last_received_at) {
+            predictor.reconcile(position, received_at);
+        }
+        self.predictor = Some(predictor);
+    }
+
+    /// ローカルの移動入力で予測位置を進める。予測が無効なら何もしない。
+    pub fn predict(&mut self, input: Vec2, now: Instant) -> Result<(), VmError> {
+        match self.predictor.as_mut() {
+            Some(predictor) => predictor.step(input, now),
+            None => Ok(()),
+        }
+    }
+
     fn ema_duration(current: Duration, sample: Duration) -> Duration {
         let current_ms = current.as_secs_f64() * 1000.0;
         let sample_ms = sample.as_secs_f64() * 1000.0;
@@ -459,6 +483,8 @@ impl SnapshotInterpolator {
     /// 受信が大きく開いて再生タイムラインが実時間から遅れた場合はキューをリセットし、
     /// 以降も補間が効く状態に戻す（瞬断・一時停止対策）。
     ///
+    /// 予測が有効なら、フレームの PlayerSprite を権威位置として予測を補正する（誤差が小さければ滑らかに寄せる）。
+    ///
     /// `received_at` は受信順で単調非減少であること。最新より古い受信時刻の push は破棄する。
     /// ペイロード内容のアウトオブオーダー検知にはサーバー tick が必要。
     pub fn push(&mut self, mut frame: RenderFrame, received_at: Instant) {
@@ -498,6 +524,12 @@ impl SnapshotInterpolator {
             Some((last_play, _)) => *last_play + self.estimated_interval,
         };
 
+        if let (Some(predictor), Some(position)) =
+            (self.predictor.as_mut(), player_position(&frame))
+        {
+            predictor.reconcile(position, received_at);
+        }
+
         let cues = std::mem::take(&mut frame.audio_cues);
         if !cues.is_empty() {
             self.pending_audio.extend(cues);
@@ -517,7 +549,22 @@ impl SnapshotInterpolator {
     /// `now` 時点の表示用フレームを返す。スナップショットが無い場合は `None`。
     ///
     /// `render_time = now - delay` を挟む 2 枚を再生タイムライン上から選び補間する。
+    /// 予測が有効なら PlayerSprite の座標を予測位置に置き換える。
     pub fn sample(&self, now: Instant) -> Option<RenderFrame> {
+        let mut frame = self.interpolate(now)?;
+        if let Some(predicted) = self.predictor.as_ref().and_then(|p| p.position()) {
+            for cmd in &mut frame.commands {
+                if let DrawCommand::PlayerSprite { x, y, .. } = cmd {
+                    *x = predicted.x;
+                    *y = predicted.y;
+                    break;
+                }
+            }
+        }
+        Some(frame)
+    }
+
+    fn interpolate(&self, now: Instant) -> Option<RenderFrame> {
         let render_time = now.checked_sub(self.delay).unwrap_or(now);
 
         match self.snapshots.len() {
@@ -555,6 +602,14 @@ impl SnapshotInterpolator {
     }
 }
 
+/// ローカルプレイヤー（最初の PlayerSprite）の位置
+fn player_position(frame: &RenderFrame) -> Option<Vec2> {
+    frame.commands.iter().find_map(|cmd| match cmd {
+        DrawCommand::PlayerSprite { x, y, .. } => Some(Vec2::new(*x, *y)),
+        _ => None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -938,4 +993,48 @@ mod tests {
             other => panic!("unexpected: {other:?}"),
         }
     }
+
+    #[test]
+    fn snapshot_predictor_draws_local_player_ahead_and_corrects_on_push() {
+        // 入力方向へ速さ 10 で進む移動 Formula
+        let bytecode =
+            formula::compile_source("output x + dx * 10.0 * dt\noutput y + dy * 10.0 * dt")
+                .expect("compile");
+        let predictor = MovementPredictor::new(&bytecode)
+            .expect("decode")
+            .with_latency(Duration::from_millis(100));
+        let mut interp = SnapshotInterpolator::with_delay(Duration::from_millis(100));
+        let t0 = Instant::now();
+        interp.push(player_at(0.0, 0.0), t0);
+        interp.set_predictor(predictor);
+
+        interp.predict(Vec2::ZERO, t0).unwrap();
+        for i in 1..=4 {
+            interp
+                .predict(Vec2::new(1.0, 0.0), t0 + Duration::from_millis(50 * i))
+                .unwrap();
+        }
+        let frame = interp
+            .sample(t0 + Duration::from_millis(200))
+            .expect("frame");
+        match &frame.commands[0] {
+            DrawCommand::PlayerSprite { x, .. } => assert!((*x - 2.0).abs() < 1e-4, "x={x}"),
+            other => panic!("unexpected command: {other:?}"),
+        }
+        // カメラは補間のまま（遅延バッファ上の位置）
+        assert!(matches!(frame.camera, CameraParams::Camera2D { offset_x, .. } if offset_x == 0.0));
+
+        // 権威位置 0.5 + 未反映の 2 ステップ 1.0 = 1.5 へ補正。描画位置は跳ばずに 2.0 から寄っていく
+        interp.push(player_at(0.5, 0.0), t0 + Duration::from_millis(200));
+        interp
+            .predict(Vec2::ZERO, t0 + Duration::from_millis(300))
+            .unwrap();
+        let frame = interp
+            .sample(t0 + Duration::from_millis(300))
+            .expect("frame");
+        match &frame.commands[0] {
+            DrawCommand::PlayerSprite { x, .. } => assert!(*x > 1.5 && *x < 1.7, "x={x}"),
+            other => panic!("unexpected command: {other:?}"),
+        }
+    }
 }
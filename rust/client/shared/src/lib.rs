@@ -3,6 +3,7 @@
 //! Elixir の状態を Rust 側に「映し出す」ための層。
 //! - Zero-Copy: bytemuck によるバイナリ直接参照
 //! - Smoothing: 20Hz 更新を 60Hz 描画用に補間
+//! - Predict: サーバー配信の移動 Formula でローカルプレイヤーを予測
 
 pub mod display;
 pub mod engine_color;
@@ -16,6 +17,8 @@ pub use interp::{
     interpolate_render_frame, lerp, lerp_draw_command, lerp_vec2, SnapshotInterpolator,
     INTERP_DELAY,
 };
+#[allow(deprecated)]
 pub use predict::predict_input;
+pub use predict::{MovementFormulaReceiver, MovementPredictor, PREDICT_LATENCY};
 pub use store::Store;
 pub use types::*;
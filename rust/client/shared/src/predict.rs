@@ -1,13 +1,346 @@
 //! 入力予測ロジック（レイテンシ対策）
 //!
-//! クライアント側で入力のクライアント予測を行う。
-//! 詳細な実装は network 連携後に追加予定。
+//! サーバーが配信する移動 Formula（Zenoh キー `game/room/{id}/predict/movement`）を
+//! サーバーと同じ VM（`formula` クレート）で実行し、権威スナップショットの間の
+//! ローカルプレイヤー位置を予測する。予測と次のスナップショットのずれは
+//! `SnapshotInterpolator::push` が `MovementPredictor::reconcile` で補正する。
+//!
+//! 移動 Formula の契約:
+//! - 入力: `x`, `y`（現在位置）, `dx`, `dy`（移動入力。キー入力なら -1.0〜1.0）, `dt`（秒）。すべて F32
+//! - 出力: 次の位置。`output x'` と `output y'` の 2 つ、または `output vec2(x', y')` の 1 つ
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use formula::{execute, DecodeError, Limits, Program, Value, VmError, VmErrorKind};
 
 use crate::types::Vec2;
 
+/// 入力を送ってから、それを反映したスナップショットを受信するまでの既定の目安（往復 + 1 tick）。
+/// これより新しい入力はサーバー未反映とみなし、権威位置の上に再適用する。
+pub const PREDICT_LATENCY: Duration = Duration::from_millis(100);
+
+/// 1 ステップの dt の上限。描画が止まった後の 1 ステップで大きく飛ばないようにする。
+const MAX_STEP: Duration = Duration::from_millis(100);
+
+/// 保持する入力履歴の上限（60fps × 1 秒）。
+const MAX_HISTORY: usize = 60;
+
+/// 補正オフセットが 1/e に減衰するまでの時間。
+const CORRECTION_TIME: f32 = 0.1;
+
+/// 予測と権威位置のずれがこれを超えたら、滑らかに寄せずに権威位置へ即座に合わせる。
+pub const SNAP_DISTANCE: f32 = 3.0;
+
+/// 移動 Formula によるローカルプレイヤーの位置予測。
+///
+/// 位置は「最後の権威位置 + サーバー未反映の入力の再適用」。描画用の位置には
+/// 再計算で生じた差を補正オフセットとして足し、オフセットはステップごとに 0 へ減衰させる。
+pub struct MovementPredictor {
+    program: Program,
+    limits: Limits,
+    latency: Duration,
+    /// 予測位置。None は権威位置を未受信
+    position: Option<Vec2>,
+    /// 描画位置 - 予測位置
+    correction: Vec2,
+    /// 適用した入力 `(適用時刻, 入力, dt 秒)`。古い順
+    history: VecDeque<(Instant, Vec2, f32)>,
+    last_step: Option<Instant>,
+}
+
+impl MovementPredictor {
+    /// 移動 Formula のバイトコード（従来形式・コンテナ形式）から作る
+    pub fn new(bytecode: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            program: Program::compile(bytecode)?,
+            limits: Limits::default(),
+            latency: PREDICT_LATENCY,
+            position: None,
+            correction: Vec2::ZERO,
+            history: VecDeque::new(),
+            last_step: None,
+        })
+    }
+
+    /// 入力を再適用する範囲（既定は `PREDICT_LATENCY`）を変える
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// 描画用の予測位置。権威位置を未受信なら None
+    pub fn position(&self) -> Option<Vec2> {
+        self.position
+            .map(|p| Vec2::new(p.x + self.correction.x, p.y + self.correction.y))
+    }
+
+    /// 前回のステップから `now` までの移動を `input` で予測する。
+    ///
+    /// Formula が失敗した場合は位置を進めずにエラーを返す（次の `reconcile` で権威位置に戻る）。
+    pub fn step(&mut self, input: Vec2, now: Instant) -> Result<(), VmError> {
+        let elapsed = self
+            .last_step
+            .map(|last| now.saturating_duration_since(last).min(MAX_STEP));
+        self.last_step = Some(now);
+        let (Some(position), Some(elapsed)) = (self.position, elapsed) else {
+            return Ok(());
+        };
+        let dt = elapsed.as_secs_f32();
+        if dt == 0.0 {
+            return Ok(());
+        }
+
+        self.position = Some(self.advance(position, input, dt)?);
+        self.history.push_back((now, input, dt));
+        while self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+        let decay = (-dt / CORRECTION_TIME).exp();
+        self.correction = Vec2::new(self.correction.x * decay, self.correction.y * decay);
+        Ok(())
+    }
+
+    /// 権威位置を取り込み、`received_at - latency` より後の入力を再適用して予測し直す。
+    ///
+    /// 再計算前後の描画位置の差は補正オフセットとして残し、描画が跳ばないようにする。
+    /// 差が `SNAP_DISTANCE` を超える場合（テレポート・リスポーン等）は補正せず権威位置に合わせる。
+    pub fn reconcile(&mut self, authoritative: Vec2, received_at: Instant) {
+        let applied_until = received_at.checked_sub(self.latency).unwrap_or(received_at);
+        while self
+            .history
+            .front()
+            .is_some_and(|(at, _, _)| *at <= applied_until)
+        {
+            self.history.pop_front();
+        }
+
+        let replayed = self
+            .history
+            .iter()
+            .try_fold(authoritative, |p, &(_, input, dt)| {
+                self.advance(p, input, dt)
+            });
+        let position = replayed.unwrap_or_else(|_| {
+            self.history.clear();
+            authoritative
+        });
+
+        self.correction = match self.position() {
+            Some(shown) => {
+                let diff = Vec2::new(shown.x - position.x, shown.y - position.y);
+                if diff.x.hypot(diff.y) > SNAP_DISTANCE {
+                    Vec2::ZERO
+                } else {
+                    diff
+                }
+            }
+            None => Vec2::ZERO,
+        };
+        self.position = Some(position);
+    }
+
+    fn advance(&self, position: Vec2, input: Vec2, dt: f32) -> Result<Vec2, VmError> {
+        let inputs = HashMap::from([
+            ("x".to_string(), Value::F32(position.x)),
+            ("y".to_string(), Value::F32(position.y)),
+            ("dx".to_string(), Value::F32(input.x)),
+            ("dy".to_string(), Value::F32(input.y)),
+            ("dt".to_string(), Value::F32(dt)),
+        ]);
+        let (outputs, _) = execute(&self.program, &inputs, &HashMap::new(), &self.limits)?;
+        match outputs.as_slice() {
+            [Value::Vec2([x, y])] => Ok(Vec2::new(*x, *y)),
+            [x, y, ..] => match (x.as_f32(), y.as_f32()) {
+                (Some(x), Some(y)) => Ok(Vec2::new(x, y)),
+                _ => Err(output_mismatch()),
+            },
+            _ => Err(output_mismatch()),
+        }
+    }
+}
+
+/// 移動 Formula（`game/room/{id}/predict/movement`）の受信処理。
+///
+/// サーバーは同じバイトコードを定期的に再送するため、前回と同じバイト列では予測器を作り直さない。
+#[derive(Default)]
+pub struct MovementFormulaReceiver {
+    installed: Option<Vec<u8>>,
+}
+
+impl MovementFormulaReceiver {
+    /// 受信したバイトコードから予測器を作る。前回と同じバイト列なら `Ok(None)`。
+    /// デコードに失敗した場合は受信済みとして扱わず、次の受信で作り直す
+    pub fn accept(&mut self, bytes: &[u8]) -> Result<Option<MovementPredictor>, DecodeError> {
+        if self.installed.as_deref() == Some(bytes) {
+            return Ok(None);
+        }
+        let predictor = MovementPredictor::new(bytes)?;
+        self.installed = Some(bytes.to_vec());
+        Ok(Some(predictor))
+    }
+}
+
 /// 入力予測（スケルトン）
 /// 現時点では入力をそのまま返す
+#[deprecated(note = "移動 Formula による予測は `MovementPredictor` を使う")]
 #[inline]
 pub fn predict_input(current: Vec2, _delta_ms: f32) -> Vec2 {
     current
 }
+
+fn output_mismatch() -> VmError {
+    VmError {
+        kind: VmErrorKind::TypeMismatch("movement formula must output x, y or vec2".to_string()),
+        location: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 入力方向へ速さ 10 で進み、±5 にクランプする
+    const SOURCE: &str = "let len = sqrt(dx * dx + dy * dy)\n\
+                          let step = select(len > 0.001, 10.0 * dt / max(len, 0.001), 0.0)\n\
+                          output clamp(x + dx * step, -5.0, 5.0)\n\
+                          output clamp(y + dy * step, -5.0, 5.0)";
+
+    fn predictor() -> MovementPredictor {
+        let bytecode = formula::compile_source(SOURCE).expect("compile");
+        MovementPredictor::new(&bytecode).expect("decode")
+    }
+
+    fn assert_near(actual: Option<Vec2>, x: f32, y: f32) {
+        let p = actual.expect("position");
+        assert!(
+            (p.x - x).abs() < 1e-4 && (p.y - y).abs() < 1e-4,
+            "expected ({x}, {y}), got ({}, {})",
+            p.x,
+            p.y
+        );
+    }
+
+    #[test]
+    fn waits_for_authoritative_position() {
+        let mut predictor = predictor();
+        let t0 = Instant::now();
+        predictor.step(Vec2::new(1.0, 0.0), t0).unwrap();
+        predictor
+            .step(Vec2::new(1.0, 0.0), t0 + Duration::from_millis(50))
+            .unwrap();
+        assert!(predictor.position().is_none());
+
+        predictor.reconcile(Vec2::new(1.0, 2.0), t0 + Duration::from_millis(60));
+        assert_near(predictor.position(), 1.0, 2.0);
+    }
+
+    #[test]
+    fn steps_with_formula_and_clamps() {
+        let mut predictor = predictor();
+        let t0 = Instant::now();
+        predictor.reconcile(Vec2::ZERO, t0);
+        predictor.step(Vec2::ZERO, t0).unwrap();
+
+        predictor
+            .step(Vec2::new(1.0, 0.0), t0 + Duration::from_millis(100))
+            .unwrap();
+        assert_near(predictor.position(), 1.0, 0.0);
+
+        // 斜め入力は正規化される
+        predictor
+            .step(Vec2::new(1.0, 1.0), t0 + Duration::from_millis(200))
+            .unwrap();
+        let d = std::f32::consts::FRAC_1_SQRT_2;
+        assert_near(predictor.position(), 1.0 + d, d);
+
+        // dt は MAX_STEP に抑えられ、位置は Formula のクランプに従う
+        for i in 0..10 {
+            predictor
+                .step(Vec2::new(1.0, 0.0), t0 + Duration::from_secs(1 + i))
+                .unwrap();
+        }
+        assert_near(predictor.position(), 5.0, d);
+    }
+
+    #[test]
+    fn reconcile_replays_unacknowledged_inputs_and_smooths_error() {
+        let mut predictor = predictor().with_latency(Duration::from_millis(100));
+        let t0 = Instant::now();
+        predictor.reconcile(Vec2::ZERO, t0);
+        predictor.step(Vec2::ZERO, t0).unwrap();
+        for i in 1..=4 {
+            predictor
+                .step(Vec2::new(1.0, 0.0), t0 + Duration::from_millis(50 * i))
+                .unwrap();
+        }
+        assert_near(predictor.position(), 2.0, 0.0);
+
+        // t0+200 時点の受信で、サーバーは t0+100 までの入力を反映して x = 0.8 と判定した。
+        // 未反映の 2 ステップ（+1.0）を再適用した 1.8 が新しい予測位置。描画は 2.0 のまま
+        predictor.reconcile(Vec2::new(0.8, 0.0), t0 + Duration::from_millis(200));
+        assert_near(predictor.position(), 2.0, 0.0);
+
+        // 補正オフセット 0.2 は減衰していく
+        predictor
+            .step(Vec2::ZERO, t0 + Duration::from_millis(300))
+            .unwrap();
+        let shown = predictor.position().unwrap().x;
+        assert!(shown > 1.8 && shown < 1.9, "shown={shown}");
+    }
+
+    #[test]
+    fn reconcile_snaps_on_large_error() {
+        let mut predictor = predictor();
+        let t0 = Instant::now();
+        predictor.reconcile(Vec2::ZERO, t0);
+        predictor.reconcile(Vec2::new(-4.0, 4.0), t0 + Duration::from_millis(50));
+        assert_near(predictor.position(), -4.0, 4.0);
+    }
+
+    #[test]
+    fn formula_errors_do_not_move_the_player() {
+        let bytecode = formula::compile_source("output x + missing\noutput y").unwrap();
+        let mut predictor = MovementPredictor::new(&bytecode).unwrap();
+        let t0 = Instant::now();
+        predictor.reconcile(Vec2::new(1.0, 1.0), t0);
+        predictor.step(Vec2::ZERO, t0).unwrap();
+
+        let err = predictor
+            .step(Vec2::new(1.0, 0.0), t0 + Duration::from_millis(16))
+            .unwrap_err();
+        assert!(matches!(err.kind, VmErrorKind::InputNotFound(ref name) if name == "missing"));
+        assert_near(predictor.position(), 1.0, 1.0);
+
+        let bytecode = formula::compile_source("output x").unwrap();
+        let mut predictor = MovementPredictor::new(&bytecode).unwrap();
+        predictor.reconcile(Vec2::ZERO, t0);
+        predictor.step(Vec2::ZERO, t0).unwrap();
+        let err = predictor
+            .step(Vec2::ZERO, t0 + Duration::from_millis(16))
+            .unwrap_err();
+        assert!(matches!(err.kind, VmErrorKind::TypeMismatch(_)));
+    }
+
+    #[test]
+    fn receiver_installs_new_bytecode_once() {
+        let bytecode = formula::compile_source(SOURCE).unwrap();
+        let mut receiver = MovementFormulaReceiver::default();
+
+        assert!(receiver.accept(&[0xff]).is_err());
+        let mut predictor = receiver.accept(&bytecode).unwrap().expect("new formula");
+        let t0 = Instant::now();
+        predictor.reconcile(Vec2::ZERO, t0);
+        predictor.step(Vec2::ZERO, t0).unwrap();
+        predictor
+            .step(Vec2::new(0.0, 1.0), t0 + Duration::from_millis(100))
+            .unwrap();
+        assert_near(predictor.position(), 0.0, 1.0);
+
+        // 再配信は無視し、別の Formula なら作り直す
+        assert!(receiver.accept(&bytecode).unwrap().is_none());
+        let other = formula::compile_source("output x\noutput y").unwrap();
+        assert!(receiver.accept(&other).unwrap().is_some());
+        assert!(receiver.accept(&bytecode).unwrap().is_some());
+    }
+}
@@ -0,0 +1,227 @@
+//! Path: rust/formula-cli/src/json.rs
+//! Summary: Formula の値・実行エラーと JSON の相互変換（NIF の Elixir 項との対応に合わせる）
+//!
+//! | JSON | 値 |
+//! |:---|:---|
+//! | i32 に収まる整数 / それ以外の整数 | I32 / I64 |
+//! | 小数 | F32 |
+//! | `{"f64": x}` / `{"i64": n}` | F64 / I64 |
+//! | true / false | Bool |
+//! | `{"x", "y"(, "z")}` | Vec2 / Vec3 |
+//! | 配列 | List（要素は配列以外） |
+//!
+//! 出力ではベクトルを `{"x", "y"(, "z")}`、有限でない浮動小数点を null にする。
+
+use formula::{DecodeErrorKind, Location, Origin, Value, VmError, VmErrorKind};
+use serde_json::{json, Map, Number, Value as Json};
+use std::collections::HashMap;
+
+/// 入力・Store の JSON を値として読めない
+#[derive(Debug)]
+pub enum JsonError {
+    /// トップレベルがオブジェクトでない
+    NotAnObject,
+    /// i64 に収まらない整数。detail は元の表記
+    IntegerOutOfRange(String),
+    /// 値として読めない。detail はキー
+    InvalidValue(String),
+}
+
+impl JsonError {
+    /// `{"error": {"reason", "detail", "location": null}}`
+    pub fn to_json(&self) -> Json {
+        let (reason, detail) = match self {
+            JsonError::NotAnObject => ("not_an_object", Json::Null),
+            JsonError::IntegerOutOfRange(v) => ("integer_out_of_range", json!(v)),
+            JsonError::InvalidValue(key) => ("invalid_value", json!(key)),
+        };
+        error_json(reason, detail, Json::Null)
+    }
+}
+
+/// `{"name": value, ...}` を入力・Store の値にする
+pub fn to_value_map(json: &Json) -> Result<HashMap<String, Value>, JsonError> {
+    let object = json.as_object().ok_or(JsonError::NotAnObject)?;
+    object
+        .iter()
+        .map(|(key, value)| Ok((key.clone(), to_value(key, value)?)))
+        .collect()
+}
+
+/// スカラー・ベクトル、またはそれらを要素に持つ配列（入れ子の配列は InvalidValue）
+fn to_value(key: &str, json: &Json) -> Result<Value, JsonError> {
+    if let Json::Array(items) = json {
+        let items = items
+            .iter()
+            .map(|item| to_element(key, item))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Value::List(items.into()));
+    }
+    to_element(key, json)
+}
+
+/// 配列以外の値
+fn to_element(key: &str, json: &Json) -> Result<Value, JsonError> {
+    let invalid = || JsonError::InvalidValue(key.to_string());
+    match json {
+        Json::Number(n) => to_number(n),
+        Json::Bool(b) => Ok(Value::Bool(*b)),
+        Json::Object(object) => to_tagged(object)
+            .or_else(|| to_vector(object))
+            .ok_or_else(invalid),
+        Json::Null | Json::String(_) | Json::Array(_) => Err(invalid()),
+    }
+}
+
+fn to_number(n: &Number) -> Result<Value, JsonError> {
+    if let Some(i) = n.as_i64() {
+        return Ok(match i32::try_from(i) {
+            Ok(i) => Value::I32(i),
+            Err(_) => Value::I64(i),
+        });
+    }
+    if n.is_u64() {
+        return Err(JsonError::IntegerOutOfRange(n.to_string()));
+    }
+    Ok(Value::F32(n.as_f64().unwrap_or(f64::NAN) as f32))
+}
+
+/// 型を明示した数値 `{"f64": x}` / `{"i64": n}`。小数は既定では f32 に丸めるため、倍精度は `{"f64": x}` で渡す
+fn to_tagged(object: &Map<String, Json>) -> Option<Value> {
+    let [(tag, value)] = object.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    match tag.as_str() {
+        "f64" => value.as_f64().map(Value::F64),
+        "i64" => value.as_i64().map(Value::I64),
+        _ => None,
+    }
+}
+
+/// x, y(, z) キーだけを持つオブジェクトをベクトルとして読む
+fn to_vector(object: &Map<String, Json>) -> Option<Value> {
+    let mut components = [0.0f32; 3];
+    let mut n = 0;
+    for (c, key) in components.iter_mut().zip(["x", "y", "z"]) {
+        let Some(component) = object.get(key) else {
+            break;
+        };
+        *c = component.as_f64()? as f32;
+        n += 1;
+    }
+    if n < 2 || object.len() != n {
+        return None;
+    }
+    match n {
+        2 => Some(Value::Vec2([components[0], components[1]])),
+        _ => Some(Value::Vec3(components)),
+    }
+}
+
+pub fn from_value(value: &Value) -> Json {
+    let float = |x: f64| Number::from_f64(x).map_or(Json::Null, Json::Number);
+    let vector = |components: &[f32]| {
+        let object = ["x", "y", "z"]
+            .iter()
+            .zip(components)
+            .map(|(key, c)| (key.to_string(), float(*c as f64)))
+            .collect();
+        Json::Object(object)
+    };
+    match value {
+        Value::F32(x) => float(*x as f64),
+        Value::I32(x) => json!(x),
+        Value::F64(x) => float(*x),
+        Value::I64(x) => json!(x),
+        Value::Bool(x) => json!(x),
+        Value::Vec2(v) => vector(v),
+        Value::Vec3(v) => vector(v),
+        Value::List(items) => Json::Array(items.iter().map(from_value).collect()),
+    }
+}
+
+/// 出力と更新後の Store。Store のキーは名前順
+pub fn result_json(outputs: &[Value], store: &HashMap<String, Value>) -> Json {
+    let store: Map<String, Json> = store
+        .iter()
+        .map(|(key, value)| (key.clone(), from_value(value)))
+        .collect();
+    json!({
+        "outputs": outputs.iter().map(from_value).collect::<Vec<_>>(),
+        "store": store,
+    })
+}
+
+/// `{"error": {"reason", "detail", "location"}}`。reason と detail は NIF の `{:error, reason, detail, location}` と同じ
+pub fn vm_error_json(e: &VmError) -> Json {
+    let (reason, detail) = error_reason(&e.kind);
+    error_json(reason, detail, location_json(e.location.as_ref()))
+}
+
+fn error_json(reason: &str, detail: Json, location: Json) -> Json {
+    json!({
+        "error": {
+            "reason": reason,
+            "detail": detail,
+            "location": location,
+        }
+    })
+}
+
+/// `{"pc", "offset", "function", "origin"}`。origin はノード ID の文字列か `{"line", "column"}`
+fn location_json(location: Option<&Location>) -> Json {
+    let Some(location) = location else {
+        return Json::Null;
+    };
+    let origin = match &location.origin {
+        Some(Origin::Node(id)) => json!(id),
+        Some(Origin::Span { line, column }) => json!({ "line": line, "column": column }),
+        None => Json::Null,
+    };
+    json!({
+        "pc": location.pc,
+        "offset": location.offset,
+        "function": location.function,
+        "origin": origin,
+    })
+}
+
+fn error_reason(kind: &VmErrorKind) -> (&'static str, Json) {
+    match kind {
+        VmErrorKind::Decode(de) => match de {
+            DecodeErrorKind::UnexpectedEof => ("unexpected_eof", Json::Null),
+            DecodeErrorKind::InvalidOpCode(b) => ("invalid_opcode", json!(b)),
+            DecodeErrorKind::RegisterOutOfRange(r) => ("register_out_of_range", json!(r)),
+            DecodeErrorKind::InvalidUtf8 => ("invalid_utf8", Json::Null),
+            DecodeErrorKind::JumpOutOfBounds(t) => ("jump_out_of_bounds", json!(t)),
+            DecodeErrorKind::JumpNotOnBoundary(t) => ("jump_not_on_boundary", json!(t)),
+            DecodeErrorKind::BackwardJump(t) => ("backward_jump", json!(t)),
+            DecodeErrorKind::InvalidVectorSize(n) => ("invalid_vector_size", json!(n)),
+            DecodeErrorKind::UnsupportedVersion(v) => ("unsupported_version", json!(v)),
+            DecodeErrorKind::UnsupportedFlags(f) => ("unsupported_flags", json!(f)),
+            DecodeErrorKind::ChecksumMismatch => ("checksum_mismatch", Json::Null),
+            DecodeErrorKind::StringOutOfRange(i) => ("string_out_of_range", json!(i)),
+            DecodeErrorKind::ConstantOutOfRange(i) => ("constant_out_of_range", json!(i)),
+            DecodeErrorKind::InvalidConstant(tag) => ("invalid_constant", json!(tag)),
+            DecodeErrorKind::TooManyArguments(n) => ("too_many_arguments", json!(n)),
+        },
+        VmErrorKind::InputNotFound(name) => ("input_not_found", json!(name)),
+        VmErrorKind::StoreNotFound(name) => ("store_not_found", json!(name)),
+        VmErrorKind::TypeMismatch(msg) => ("type_mismatch", json!(msg)),
+        VmErrorKind::RegisterOutOfRange(r) => ("register_out_of_range", json!(r)),
+        VmErrorKind::DivisionByZero => ("division_by_zero", Json::Null),
+        VmErrorKind::DomainError(op) => ("domain_error", json!(op)),
+        VmErrorKind::StepLimitExceeded(limit) => ("step_limit_exceeded", json!(limit)),
+        VmErrorKind::BudgetExceeded(budget, limit) => {
+            ("budget_exceeded", json!([budget.as_str(), limit]))
+        }
+        VmErrorKind::UndefinedFunction(name) => ("undefined_function", json!(name)),
+        VmErrorKind::MissingReturn(name) => ("missing_return", json!(name)),
+        VmErrorKind::InvalidStoreValue(key) => ("invalid_store_value", json!(key)),
+        VmErrorKind::IndexOutOfRange(i) => ("index_out_of_range", json!(i)),
+        VmErrorKind::StoreTypeMismatch(key, expected, actual) => (
+            "store_type_mismatch",
+            json!([key, expected.as_str(), actual.as_str()]),
+        ),
+    }
+}
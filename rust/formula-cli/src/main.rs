@@ -0,0 +1,142 @@
+//! Path: rust/formula-cli/src/main.rs
+//! Summary: Formula のバイトコードを JSON の入力・Store で実行し、結果を JSON で出力する
+//!
+//! 使用方法:
+//!   formula-cli [--inputs FILE] [--store FILE] [--limits FILE] BYTECODE
+//!
+//!   BYTECODE   - バイトコードのファイル（従来形式・コンテナ形式）。`-` なら標準入力
+//!   --inputs   - 入力の JSON オブジェクト（未指定時は {}）
+//!   --store    - Store の初期値の JSON オブジェクト（未指定時は {}）
+//!   --limits   - 実行上限の JSON オブジェクト（`{"max_instructions": 1000}` 等。無いキーは既定値）
+//!
+//! 成功すると `{"outputs": [...], "store": {...}}` を標準出力に書き、終了コード 0。
+//! 実行エラーと入力・Store の JSON の誤りは `{"error": {"reason", "detail", "location"}}` を標準出力に書き、終了コード 1。
+//! 引数・ファイル・JSON 構文の誤りは標準エラーにメッセージを書き、終了コード 2。値の対応は json.rs を参照。
+
+mod json;
+
+use formula::{run, Limits};
+use serde_json::Value as Json;
+use std::io::Read;
+use std::process::ExitCode;
+
+struct Args {
+    bytecode: String,
+    inputs: Option<String>,
+    store: Option<String>,
+    limits: Option<String>,
+}
+
+fn main() -> ExitCode {
+    match execute() {
+        Ok((result, ok)) => {
+            println!("{result}");
+            if ok {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(1)
+            }
+        }
+        Err(message) => {
+            eprintln!("formula-cli: {message}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// (出力する JSON, 成功したか)。Err は引数・ファイルの誤り
+fn execute() -> Result<(Json, bool), String> {
+    let args = parse_args(std::env::args().skip(1))?;
+    let bytecode = read_bytes(&args.bytecode)?;
+    let inputs = read_json(args.inputs.as_deref())?;
+    let store = read_json(args.store.as_deref())?;
+    let limits = parse_limits(&read_json(args.limits.as_deref())?)?;
+
+    let values = json::to_value_map(&inputs).and_then(|inputs| {
+        let store = json::to_value_map(&store)?;
+        Ok((inputs, store))
+    });
+    let (inputs, store) = match values {
+        Ok(values) => values,
+        Err(e) => return Ok((e.to_json(), false)),
+    };
+    Ok(match run(&bytecode, &inputs, &store, &limits) {
+        Ok((outputs, store)) => (json::result_json(&outputs, &store), true),
+        Err(e) => (json::vm_error_json(&e), false),
+    })
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let (mut bytecode, mut inputs, mut store, mut limits) = (None, None, None, None);
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "--inputs" => &mut inputs,
+            "--store" => &mut store,
+            "--limits" => &mut limits,
+            "-h" | "--help" => return Err(usage()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}\n{}", usage())),
+            _ => {
+                if bytecode.replace(arg).is_some() {
+                    return Err(usage());
+                }
+                continue;
+            }
+        };
+        *slot = Some(
+            args.next()
+                .ok_or_else(|| format!("{arg} requires a file"))?,
+        );
+    }
+    Ok(Args {
+        bytecode: bytecode.ok_or_else(usage)?,
+        inputs,
+        store,
+        limits,
+    })
+}
+
+fn usage() -> String {
+    "usage: formula-cli [--inputs FILE] [--store FILE] [--limits FILE] BYTECODE".to_string()
+}
+
+fn read_bytes(path: &str) -> Result<Vec<u8>, String> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("stdin: {e}"))?;
+        return Ok(bytes);
+    }
+    std::fs::read(path).map_err(|e| format!("{path}: {e}"))
+}
+
+/// 未指定なら空のオブジェクト
+fn read_json(path: Option<&str>) -> Result<Json, String> {
+    let Some(path) = path else {
+        return Ok(Json::Object(Default::default()));
+    };
+    let bytes = read_bytes(path)?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("{path}: {e}"))
+}
+
+/// NIF の limits と同じキー。無いキーは既定値
+fn parse_limits(json: &Json) -> Result<Limits, String> {
+    let object = json.as_object().ok_or("limits: expected an object")?;
+    let mut limits = Limits::default();
+    for (key, value) in object {
+        let slot = match key.as_str() {
+            "max_instructions" => &mut limits.max_instructions,
+            "max_store_entries" => &mut limits.max_store_entries,
+            "max_outputs" => &mut limits.max_outputs,
+            "max_call_depth" => &mut limits.max_call_depth,
+            "max_events" => &mut limits.max_events,
+            "max_list_len" => &mut limits.max_list_len,
+            _ => return Err(format!("limits: unknown key {key}")),
+        };
+        *slot = value
+            .as_u64()
+            .and_then(|v| usize::try_from(v).ok())
+            .ok_or_else(|| format!("limits: {key} must be a non-negative integer"))?;
+    }
+    Ok(limits)
+}
@@ -0,0 +1,93 @@
+//! formula-cli をプロセスとして実行し、標準出力の JSON と終了コードを確かめる。
+//! バイトコードはテキストアセンブリから `formula::assemble` で作る。
+
+use serde_json::{json, Value as Json};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// テストごとの一時ディレクトリにファイルを書く
+fn write(test: &str, name: &str, bytes: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("formula-cli-{}-{test}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("temp dir");
+    let path = dir.join(name);
+    std::fs::write(&path, bytes).expect("write");
+    path
+}
+
+/// (終了コード, 標準出力の JSON)
+fn formula_cli(test: &str, source: &str, files: &[(&str, Json)]) -> (i32, Json) {
+    let bytecode = formula::assemble(source).expect("assemble");
+    let mut command = Command::new(env!("CARGO_BIN_EXE_formula-cli"));
+    for (option, value) in files {
+        let path = write(test, option, value.to_string().as_bytes());
+        command.arg(format!("--{option}")).arg(path);
+    }
+    command.arg(write(test, "program.fvm", &bytecode));
+    let output = command.output().expect("run formula-cli");
+    let stdout = serde_json::from_slice(&output.stdout).unwrap_or(Json::Null);
+    (output.status.code().unwrap_or(-1), stdout)
+}
+
+#[test]
+fn prints_outputs_and_updated_store() {
+    let source = "load_input r0 \"hp\"\nload_input r1 \"damage\"\nsub r2 r0 r1\nstore_output r2\n\
+                  write_store r2 \"hp\"\nload_input r3 \"dir\"\nstore_output r3";
+    let inputs = json!({ "hp": 100, "damage": 2.5, "dir": { "x": 1.0, "y": 0.5 } });
+    let store = json!({ "hp": 100, "big": 10_000_000_000i64, "tags": [1, 2] });
+
+    let (code, stdout) = formula_cli("success", source, &[("inputs", inputs), ("store", store)]);
+
+    assert_eq!(code, 0);
+    assert_eq!(
+        stdout,
+        json!({
+            "outputs": [97.5, { "x": 1.0, "y": 0.5 }],
+            "store": { "big": 10_000_000_000i64, "hp": 97.5, "tags": [1, 2] },
+        })
+    );
+}
+
+#[test]
+fn runtime_errors_are_reported_with_location() {
+    let source = "load_input r0 \"x\"\nload_i32 r1 0\ndiv r2 r0 r1\nstore_output r2";
+
+    let (code, stdout) = formula_cli("division", source, &[("inputs", json!({ "x": 1 }))]);
+    assert_eq!(code, 1);
+    assert_eq!(stdout["error"]["reason"], "division_by_zero");
+    assert_eq!(stdout["error"]["location"]["pc"], 2);
+
+    let (code, stdout) = formula_cli("missing", source, &[]);
+    assert_eq!(code, 1);
+    assert_eq!(stdout["error"]["reason"], "input_not_found");
+    assert_eq!(stdout["error"]["detail"], "x");
+
+    let (code, stdout) = formula_cli(
+        "budget",
+        source,
+        &[
+            ("inputs", json!({ "x": 1 })),
+            ("limits", json!({ "max_instructions": 1 })),
+        ],
+    );
+    assert_eq!(code, 1);
+    assert_eq!(stdout["error"]["detail"], json!(["instructions", 1]));
+}
+
+#[test]
+fn invalid_values_and_arguments_are_rejected() {
+    let source = "load_input r0 \"x\"\nstore_output r0";
+
+    let (code, stdout) = formula_cli("invalid", source, &[("inputs", json!({ "x": "text" }))]);
+    assert_eq!(code, 1);
+    assert_eq!(stdout["error"]["reason"], "invalid_value");
+    assert_eq!(stdout["error"]["detail"], "x");
+
+    let (code, stdout) = formula_cli("limits", source, &[("limits", json!({ "max_fuel": 1 }))]);
+    assert_eq!(code, 2);
+    assert_eq!(stdout, Json::Null);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_formula-cli"))
+        .output()
+        .expect("run formula-cli");
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -0,0 +1,823 @@
+//! Path: rust/formula/src/asm.rs
+//! Summary: Formula バイトコードのテキストアセンブリ（アセンブラ・逆アセンブラ）
+//!
+//! 書式（1 行 1 命令。`;` 以降は行末までコメント）:
+//!
+//! ```text
+//!     load_input r0 "dt"
+//!     load_f32 r1 2.5
+//!     jump_if_false r3 L0
+//!     mul r2 r0 r1
+//! L0:
+//!     store_output r2
+//! ```
+//!
+//! - ニーモニックは `OpCode::mnemonic`（`Core.Formula.build/1` の命令 atom と同じ綴り）
+//! - レジスタは `r0`..`r63`。入力名・Store キーはダブルクォート文字列（`\"` `\\` `\n` `\t` `\r` `\u{..}`）
+//! - ジャンプ先はラベル名。`名前:` だけの行でラベルを定義する（プログラム末尾のラベルは終端）
+//! - f32 / f64 は Rust の表記。`inf` / `-inf`、NaN はビット列付きの `nan:0x7fc00000`（f64 は 16 桁）
+//! - load_vec / make_vec の次元（2 / 3）はオペランド数で決まる
+//! - call は `call r0 "関数名" r1 r2` のように、戻り値のレジスタ・関数名・引数レジスタの順に書く
+//! - emit は `emit "イベント名" r0`（ペイロードなしは `emit "イベント名"`）
+//!
+//! 逆アセンブル → アセンブルは正規形のバイトコード（load_bool が 0 / 1）についてバイト単位で一致する。
+//! アセンブラは従来形式を出力する。コンテナ形式の LoadConst は逆アセンブル時にインラインの定数ロードとして表示する。
+
+use super::decode::{decode_bytecode, DecodeError, Instruction, Name, REGISTER_COUNT};
+use super::encode::{encode_instructions, EncodeError};
+use super::opcode::OpCode;
+use super::value::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Debug)]
+pub enum AsmError {
+    /// 構文エラー。line は 1 始まり
+    Syntax {
+        line: usize,
+        message: String,
+    },
+    Encode(EncodeError),
+    /// 生成したバイトコードがデコード検証を通らない（後方ジャンプ等）
+    Decode(DecodeError),
+}
+
+fn syntax(line: usize, message: impl Into<String>) -> AsmError {
+    AsmError::Syntax {
+        line,
+        message: message.into(),
+    }
+}
+
+/// バイトコードをデコードし、テキストアセンブリにする
+pub fn disassemble(bytecode: &[u8]) -> Result<String, DecodeError> {
+    Ok(format_instructions(&decode_bytecode(bytecode)?))
+}
+
+/// 命令列をテキストアセンブリにする。ジャンプ先には出現順に L0, L1, ... のラベルを振る
+pub fn format_instructions(instructions: &[Instruction]) -> String {
+    let mut targets: Vec<usize> = instructions.iter().filter_map(jump_target).collect();
+    targets.sort_unstable();
+    targets.dedup();
+    let label = |index: usize| {
+        targets
+            .binary_search(&index)
+            .ok()
+            .map(|k| format!("L{}", k))
+    };
+
+    let mut out = String::new();
+    for (i, inst) in instructions.iter().enumerate() {
+        if let Some(name) = label(i) {
+            let _ = writeln!(out, "{}:", name);
+        }
+        out.push_str("    ");
+        out.push_str(inst.opcode().mnemonic());
+        for operand in operands(inst, |target| label(target).unwrap_or_default()) {
+            out.push(' ');
+            out.push_str(&operand);
+        }
+        out.push('\n');
+    }
+    if let Some(name) = label(instructions.len()) {
+        let _ = writeln!(out, "{}:", name);
+    }
+    out
+}
+
+fn jump_target(inst: &Instruction) -> Option<usize> {
+    match inst {
+        Instruction::Jump { target }
+        | Instruction::JumpIfFalse { target, .. }
+        | Instruction::JumpIfTrue { target, .. } => Some(*target),
+        _ => None,
+    }
+}
+
+fn reg(r: u8) -> String {
+    format!("r{}", r)
+}
+
+fn operands(inst: &Instruction, label: impl Fn(usize) -> String) -> Vec<String> {
+    match inst {
+        Instruction::LoadInput { dst, name } | Instruction::ReadStore { dst, name } => {
+            vec![reg(*dst), quote(name)]
+        }
+        Instruction::WriteStore { src, name } => vec![reg(*src), quote(name)],
+        Instruction::Emit { src, name } => {
+            std::iter::once(quote(name)).chain(src.map(reg)).collect()
+        }
+        Instruction::LoadI32 { dst, value } => vec![reg(*dst), value.to_string()],
+        Instruction::LoadF32 { dst, value } => vec![reg(*dst), format_f32(*value)],
+        Instruction::LoadBool { dst, value } => vec![reg(*dst), value.to_string()],
+        Instruction::LoadI64 { dst, value } => vec![reg(*dst), value.to_string()],
+        Instruction::LoadF64 { dst, value } => vec![reg(*dst), format_f64(*value)],
+        Instruction::StoreOutput { src } | Instruction::Ret { src } => vec![reg(*src)],
+        Instruction::Random { dst } => vec![reg(*dst)],
+        Instruction::RandomRange { dst, lo, hi } => vec![reg(*dst), reg(*lo), reg(*hi)],
+        Instruction::Call { dst, name, args } => [reg(*dst), quote(name)]
+            .into_iter()
+            .chain(args.iter().map(|r| reg(*r)))
+            .collect(),
+        Instruction::ForEach {
+            dst,
+            list,
+            init,
+            name,
+        } => vec![reg(*dst), quote(name), reg(*list), reg(*init)],
+        Instruction::ListMap { dst, list, name } => vec![reg(*dst), quote(name), reg(*list)],
+        Instruction::Jump { target } => vec![label(*target)],
+        Instruction::JumpIfFalse { cond, target } | Instruction::JumpIfTrue { cond, target } => {
+            vec![reg(*cond), label(*target)]
+        }
+        Instruction::Abs { dst, src }
+        | Instruction::Floor { dst, src }
+        | Instruction::Ceil { dst, src }
+        | Instruction::Sqrt { dst, src }
+        | Instruction::Sin { dst, src }
+        | Instruction::Cos { dst, src }
+        | Instruction::CastI32 { dst, src }
+        | Instruction::CastF32 { dst, src }
+        | Instruction::CastI64 { dst, src }
+        | Instruction::CastF64 { dst, src }
+        | Instruction::Not { dst, src }
+        | Instruction::Length { dst, src }
+        | Instruction::Normalize { dst, src }
+        | Instruction::ListLen { dst, src }
+        | Instruction::ListSum { dst, src }
+        | Instruction::ListMin { dst, src }
+        | Instruction::ListMax { dst, src } => vec![reg(*dst), reg(*src)],
+        Instruction::ListIndex { dst, list, index } => vec![reg(*dst), reg(*list), reg(*index)],
+        Instruction::Clamp { dst, src, lo, hi } => vec![reg(*dst), reg(*src), reg(*lo), reg(*hi)],
+        Instruction::Lerp { dst, a, b, t } => vec![reg(*dst), reg(*a), reg(*b), reg(*t)],
+        Instruction::Select { dst, cond, a, b } => vec![reg(*dst), reg(*cond), reg(*a), reg(*b)],
+        Instruction::LoadVec { dst, value } => std::iter::once(reg(*dst))
+            .chain(
+                value
+                    .components()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|c| format_f32(*c)),
+            )
+            .collect(),
+        Instruction::MakeVec { dst, n, srcs } => std::iter::once(reg(*dst))
+            .chain(srcs[..*n as usize].iter().map(|r| reg(*r)))
+            .collect(),
+        Instruction::Extract { dst, src, index } => vec![reg(*dst), reg(*src), index.to_string()],
+        Instruction::Add { dst, src_a, src_b }
+        | Instruction::Sub { dst, src_a, src_b }
+        | Instruction::Mul { dst, src_a, src_b }
+        | Instruction::Div { dst, src_a, src_b }
+        | Instruction::Lt { dst, src_a, src_b }
+        | Instruction::Gt { dst, src_a, src_b }
+        | Instruction::Eq { dst, src_a, src_b }
+        | Instruction::Min { dst, src_a, src_b }
+        | Instruction::Max { dst, src_a, src_b }
+        | Instruction::Atan2 { dst, src_a, src_b }
+        | Instruction::Pow { dst, src_a, src_b }
+        | Instruction::Mod { dst, src_a, src_b }
+        | Instruction::And { dst, src_a, src_b }
+        | Instruction::Or { dst, src_a, src_b }
+        | Instruction::Xor { dst, src_a, src_b }
+        | Instruction::Le { dst, src_a, src_b }
+        | Instruction::Ge { dst, src_a, src_b }
+        | Instruction::Ne { dst, src_a, src_b }
+        | Instruction::Dot { dst, src_a, src_b } => vec![reg(*dst), reg(*src_a), reg(*src_b)],
+    }
+}
+
+/// f32 をビット単位で復元できる表記にする（有限値は Rust の最短往復表記）
+fn format_f32(x: f32) -> String {
+    if x.is_nan() {
+        format!("nan:0x{:08x}", x.to_bits())
+    } else if x.is_infinite() {
+        if x > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        format!("{:?}", x)
+    }
+}
+
+fn parse_f32(s: &str) -> Option<f32> {
+    if let Some(hex) = s.strip_prefix("nan:0x") {
+        return u32::from_str_radix(hex, 16)
+            .ok()
+            .map(f32::from_bits)
+            .filter(|x| x.is_nan());
+    }
+    s.parse().ok()
+}
+
+fn format_f64(x: f64) -> String {
+    if x.is_nan() {
+        format!("nan:0x{:016x}", x.to_bits())
+    } else if x.is_infinite() {
+        if x > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        format!("{:?}", x)
+    }
+}
+
+fn parse_f64(s: &str) -> Option<f64> {
+    if let Some(hex) = s.strip_prefix("nan:0x") {
+        return u64::from_str_radix(hex, 16)
+            .ok()
+            .map(f64::from_bits)
+            .filter(|x| x.is_nan());
+    }
+    s.parse().ok()
+}
+
+fn quote(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 2);
+    out.push('"');
+    for c in name.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{{{:x}}}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '"' {
+            chars.next();
+            tokens.push(Token::Str(read_string(&mut chars)?));
+            continue;
+        }
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == ';' || c == '"' {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+fn read_string(chars: &mut impl Iterator<Item = char>) -> Result<String, String> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            None => return Err("unterminated string".into()),
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('u') => {
+                    if chars.next() != Some('{') {
+                        return Err("expected '{' after \\u".into());
+                    }
+                    let hex: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid unicode escape \\u{{{}}}", hex))?;
+                    s.push(c);
+                }
+                other => return Err(format!("invalid escape {:?}", other)),
+            },
+            Some(c) => s.push(c),
+        }
+    }
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// 1 行分のオペランドを先頭から読む
+struct Operands<'a> {
+    line: usize,
+    tokens: std::slice::Iter<'a, Token>,
+}
+
+impl<'a> Operands<'a> {
+    fn word(&mut self, what: &str) -> Result<&'a str, AsmError> {
+        match self.tokens.next() {
+            Some(Token::Word(w)) => Ok(w),
+            Some(Token::Str(_)) => Err(syntax(self.line, format!("expected {}, got string", what))),
+            None => Err(syntax(self.line, format!("missing {}", what))),
+        }
+    }
+
+    fn reg(&mut self) -> Result<u8, AsmError> {
+        let w = self.word("register")?;
+        w.strip_prefix('r')
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|r| (*r as usize) < REGISTER_COUNT)
+            .ok_or_else(|| syntax(self.line, format!("invalid register {}", w)))
+    }
+
+    fn name(&mut self) -> Result<Name, AsmError> {
+        match self.tokens.next() {
+            Some(Token::Str(s)) if s.len() <= u8::MAX as usize => Ok(Name::from(s.as_str())),
+            Some(Token::Str(_)) => Err(syntax(self.line, "name longer than 255 bytes")),
+            _ => Err(syntax(self.line, "expected quoted name")),
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, AsmError> {
+        let w = self.word(what)?;
+        w.parse()
+            .map_err(|_| syntax(self.line, format!("invalid {} {}", what, w)))
+    }
+
+    fn f32(&mut self) -> Result<f32, AsmError> {
+        let w = self.word("float")?;
+        parse_f32(w).ok_or_else(|| syntax(self.line, format!("invalid float {}", w)))
+    }
+
+    fn f64(&mut self) -> Result<f64, AsmError> {
+        let w = self.word("float")?;
+        parse_f64(w).ok_or_else(|| syntax(self.line, format!("invalid float {}", w)))
+    }
+
+    fn remaining(&self) -> usize {
+        self.tokens.len()
+    }
+}
+
+/// テキストアセンブリをバイトコードにする。生成結果は `decode_bytecode` で検証してから返す
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut instructions = Vec::new();
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    // (命令インデックス, 行番号, ラベル名)
+    let mut fixups: Vec<(usize, usize, String)> = Vec::new();
+    let lines: Vec<(usize, Vec<Token>)> = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| {
+            tokenize(text)
+                .map(|t| (i + 1, t))
+                .map_err(|m| syntax(i + 1, m))
+        })
+        .collect::<Result<_, _>>()?;
+
+    for (line, tokens) in &lines {
+        let line = *line;
+        let Some(first) = tokens.first() else {
+            continue;
+        };
+        let Token::Word(head) = first else {
+            return Err(syntax(line, "expected mnemonic or label"));
+        };
+        if let Some(name) = head.strip_suffix(':') {
+            if tokens.len() != 1 || !is_label(name) {
+                return Err(syntax(line, format!("invalid label {}", head)));
+            }
+            if labels.insert(name, instructions.len()).is_some() {
+                return Err(syntax(line, format!("duplicate label {}", name)));
+            }
+            continue;
+        }
+        let op = OpCode::from_mnemonic(head)
+            .ok_or_else(|| syntax(line, format!("unknown mnemonic {}", head)))?;
+        let mut ops = Operands {
+            line,
+            tokens: tokens[1..].iter(),
+        };
+        let mut jump_label = |ops: &mut Operands<'_>| -> Result<usize, AsmError> {
+            let name = ops.word("label")?;
+            fixups.push((instructions.len(), line, name.to_string()));
+            Ok(0)
+        };
+        let inst = parse_instruction(op, &mut ops, &mut jump_label)?;
+        if ops.remaining() > 0 {
+            return Err(syntax(line, "too many operands"));
+        }
+        instructions.push(inst);
+    }
+
+    for (index, line, name) in fixups {
+        let resolved = *labels
+            .get(name.as_str())
+            .ok_or_else(|| syntax(line, format!("undefined label {}", name)))?;
+        match &mut instructions[index] {
+            Instruction::Jump { target }
+            | Instruction::JumpIfFalse { target, .. }
+            | Instruction::JumpIfTrue { target, .. } => *target = resolved,
+            _ => unreachable!("fixup must point at a jump instruction"),
+        }
+    }
+
+    let bytecode = encode_instructions(&instructions).map_err(AsmError::Encode)?;
+    decode_bytecode(&bytecode).map_err(AsmError::Decode)?;
+    Ok(bytecode)
+}
+
+fn parse_instruction(
+    op: OpCode,
+    ops: &mut Operands<'_>,
+    jump_label: &mut impl FnMut(&mut Operands<'_>) -> Result<usize, AsmError>,
+) -> Result<Instruction, AsmError> {
+    let inst = match op {
+        OpCode::LoadInput => Instruction::LoadInput {
+            dst: ops.reg()?,
+            name: ops.name()?,
+        },
+        OpCode::LoadI32 => Instruction::LoadI32 {
+            dst: ops.reg()?,
+            value: ops.parse("i32")?,
+        },
+        OpCode::LoadF32 => Instruction::LoadF32 {
+            dst: ops.reg()?,
+            value: ops.f32()?,
+        },
+        OpCode::LoadBool => Instruction::LoadBool {
+            dst: ops.reg()?,
+            value: ops.parse("bool")?,
+        },
+        OpCode::LoadI64 => Instruction::LoadI64 {
+            dst: ops.reg()?,
+            value: ops.parse("i64")?,
+        },
+        OpCode::LoadF64 => Instruction::LoadF64 {
+            dst: ops.reg()?,
+            value: ops.f64()?,
+        },
+        OpCode::StoreOutput => Instruction::StoreOutput { src: ops.reg()? },
+        OpCode::ReadStore => Instruction::ReadStore {
+            dst: ops.reg()?,
+            name: ops.name()?,
+        },
+        OpCode::WriteStore => Instruction::WriteStore {
+            src: ops.reg()?,
+            name: ops.name()?,
+        },
+        OpCode::Emit => {
+            let name = ops.name()?;
+            let src = if ops.remaining() > 0 {
+                Some(ops.reg()?)
+            } else {
+                None
+            };
+            Instruction::Emit { src, name }
+        }
+        OpCode::Jump => Instruction::Jump {
+            target: jump_label(ops)?,
+        },
+        OpCode::JumpIfFalse => Instruction::JumpIfFalse {
+            cond: ops.reg()?,
+            target: jump_label(ops)?,
+        },
+        OpCode::JumpIfTrue => Instruction::JumpIfTrue {
+            cond: ops.reg()?,
+            target: jump_label(ops)?,
+        },
+        OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Div
+        | OpCode::Lt
+        | OpCode::Gt
+        | OpCode::Eq
+        | OpCode::Min
+        | OpCode::Max
+        | OpCode::Atan2
+        | OpCode::Pow
+        | OpCode::Mod
+        | OpCode::And
+        | OpCode::Or
+        | OpCode::Xor
+        | OpCode::Le
+        | OpCode::Ge
+        | OpCode::Ne
+        | OpCode::Dot
+        | OpCode::ListIndex => {
+            let (dst, src_a, src_b) = (ops.reg()?, ops.reg()?, ops.reg()?);
+            match op {
+                OpCode::Add => Instruction::Add { dst, src_a, src_b },
+                OpCode::Sub => Instruction::Sub { dst, src_a, src_b },
+                OpCode::Mul => Instruction::Mul { dst, src_a, src_b },
+                OpCode::Div => Instruction::Div { dst, src_a, src_b },
+                OpCode::Lt => Instruction::Lt { dst, src_a, src_b },
+                OpCode::Gt => Instruction::Gt { dst, src_a, src_b },
+                OpCode::Eq => Instruction::Eq { dst, src_a, src_b },
+                OpCode::Min => Instruction::Min { dst, src_a, src_b },
+                OpCode::Max => Instruction::Max { dst, src_a, src_b },
+                OpCode::Atan2 => Instruction::Atan2 { dst, src_a, src_b },
+                OpCode::Pow => Instruction::Pow { dst, src_a, src_b },
+                OpCode::Mod => Instruction::Mod { dst, src_a, src_b },
+                OpCode::And => Instruction::And { dst, src_a, src_b },
+                OpCode::Or => Instruction::Or { dst, src_a, src_b },
+                OpCode::Xor => Instruction::Xor { dst, src_a, src_b },
+                OpCode::Le => Instruction::Le { dst, src_a, src_b },
+                OpCode::Ge => Instruction::Ge { dst, src_a, src_b },
+                OpCode::Ne => Instruction::Ne { dst, src_a, src_b },
+                OpCode::Dot => Instruction::Dot { dst, src_a, src_b },
+                OpCode::ListIndex => Instruction::ListIndex {
+                    dst,
+                    list: src_a,
+                    index: src_b,
+                },
+                _ => unreachable!(),
+            }
+        }
+        OpCode::Abs
+        | OpCode::Floor
+        | OpCode::Ceil
+        | OpCode::Sqrt
+        | OpCode::Sin
+        | OpCode::Cos
+        | OpCode::CastI32
+        | OpCode::CastF32
+        | OpCode::CastI64
+        | OpCode::CastF64
+        | OpCode::Not
+        | OpCode::Length
+        | OpCode::Normalize
+        | OpCode::ListLen
+        | OpCode::ListSum
+        | OpCode::ListMin
+        | OpCode::ListMax => {
+            let (dst, src) = (ops.reg()?, ops.reg()?);
+            match op {
+                OpCode::Abs => Instruction::Abs { dst, src },
+                OpCode::Floor => Instruction::Floor { dst, src },
+                OpCode::Ceil => Instruction::Ceil { dst, src },
+                OpCode::Sqrt => Instruction::Sqrt { dst, src },
+                OpCode::Sin => Instruction::Sin { dst, src },
+                OpCode::Cos => Instruction::Cos { dst, src },
+                OpCode::CastI32 => Instruction::CastI32 { dst, src },
+                OpCode::CastF32 => Instruction::CastF32 { dst, src },
+                OpCode::CastI64 => Instruction::CastI64 { dst, src },
+                OpCode::CastF64 => Instruction::CastF64 { dst, src },
+                OpCode::Not => Instruction::Not { dst, src },
+                OpCode::Length => Instruction::Length { dst, src },
+                OpCode::Normalize => Instruction::Normalize { dst, src },
+                OpCode::ListLen => Instruction::ListLen { dst, src },
+                OpCode::ListSum => Instruction::ListSum { dst, src },
+                OpCode::ListMin => Instruction::ListMin { dst, src },
+                OpCode::ListMax => Instruction::ListMax { dst, src },
+                _ => unreachable!(),
+            }
+        }
+        OpCode::ForEach => Instruction::ForEach {
+            dst: ops.reg()?,
+            name: ops.name()?,
+            list: ops.reg()?,
+            init: ops.reg()?,
+        },
+        OpCode::ListMap => Instruction::ListMap {
+            dst: ops.reg()?,
+            name: ops.name()?,
+            list: ops.reg()?,
+        },
+        OpCode::Clamp => Instruction::Clamp {
+            dst: ops.reg()?,
+            src: ops.reg()?,
+            lo: ops.reg()?,
+            hi: ops.reg()?,
+        },
+        OpCode::Lerp => Instruction::Lerp {
+            dst: ops.reg()?,
+            a: ops.reg()?,
+            b: ops.reg()?,
+            t: ops.reg()?,
+        },
+        OpCode::Select => Instruction::Select {
+            dst: ops.reg()?,
+            cond: ops.reg()?,
+            a: ops.reg()?,
+            b: ops.reg()?,
+        },
+        OpCode::LoadVec => {
+            let dst = ops.reg()?;
+            let value = match ops.remaining() {
+                2 => Value::Vec2([ops.f32()?, ops.f32()?]),
+                3 => Value::Vec3([ops.f32()?, ops.f32()?, ops.f32()?]),
+                _ => return Err(syntax(ops.line, "load_vec takes 2 or 3 components")),
+            };
+            Instruction::LoadVec { dst, value }
+        }
+        OpCode::MakeVec => {
+            let dst = ops.reg()?;
+            let n = ops.remaining();
+            if !matches!(n, 2 | 3) {
+                return Err(syntax(ops.line, "make_vec takes 2 or 3 source registers"));
+            }
+            let mut srcs = [0u8; 3];
+            for src in srcs.iter_mut().take(n) {
+                *src = ops.reg()?;
+            }
+            Instruction::MakeVec {
+                dst,
+                n: n as u8,
+                srcs,
+            }
+        }
+        OpCode::Extract => Instruction::Extract {
+            dst: ops.reg()?,
+            src: ops.reg()?,
+            index: ops.parse("index")?,
+        },
+        OpCode::Call => {
+            let (dst, name) = (ops.reg()?, ops.name()?);
+            let args = (0..ops.remaining())
+                .map(|_| ops.reg())
+                .collect::<Result<_, _>>()?;
+            Instruction::Call { dst, name, args }
+        }
+        OpCode::Ret => Instruction::Ret { src: ops.reg()? },
+        OpCode::Random => Instruction::Random { dst: ops.reg()? },
+        OpCode::RandomRange => Instruction::RandomRange {
+            dst: ops.reg()?,
+            lo: ops.reg()?,
+            hi: ops.reg()?,
+        },
+        OpCode::LoadConst => {
+            return Err(syntax(
+                ops.line,
+                "load_const is container-only; write the constant inline (load_i32 / load_f32 / load_vec)",
+            ))
+        }
+    };
+    Ok(inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::DecodeErrorKind;
+
+    /// すべての OpCode（コンテナ専用の LoadConst を除く）を 1 回以上含む正規形のバイトコード
+    fn every_opcode_program() -> Vec<u8> {
+        let source = r#"
+            load_input r0 "dt"
+            load_i32 r1 -7
+            load_f32 r2 -0.0
+            load_f32 r3 nan:0x7fc00001
+            load_bool r4 true
+            jump_if_false r4 L0
+            add r5 r0 r1
+            sub r5 r0 r1
+            mul r5 r0 r1
+            div r5 r0 r1
+            lt r5 r0 r1
+            gt r5 r0 r1
+            eq r5 r0 r1
+        L0:
+            store_output r5
+            read_store r6 "say \"hi\"\n"
+            write_store r6 "ключ"
+            jump L1
+            abs r7 r2
+            floor r7 r2
+            ceil r7 r2
+            sqrt r7 r2
+            sin r7 r2
+            cos r7 r2
+        L1:
+            min r8 r0 r1
+            max r8 r0 r1
+            atan2 r8 r0 r1
+            pow r8 r0 r1
+            mod r8 r0 r1
+            clamp r9 r0 r1 r2
+            lerp r9 r0 r1 r2
+            not r10 r4
+            and r10 r4 r4
+            or r10 r4 r4
+            xor r10 r4 r4
+            le r10 r0 r1
+            ge r10 r0 r1
+            ne r10 r0 r1
+            select r11 r4 r0 r1
+            load_vec r12 1.5 inf
+            load_vec r13 1e-30 -2.0 3.0
+            make_vec r14 r0 r1
+            make_vec r15 r0 r1 r2
+            extract r16 r15 2
+            dot r17 r12 r14
+            length r17 r13
+            normalize r63 r13
+            call r18 "damage_reduction" r0 r1
+            call r19 "zero"
+            random r20
+            random_range r21 r0 r1
+            emit "enemy_died" r21
+            emit "wave_cleared"
+            list_len r22 r0
+            list_index r23 r0 r1
+            list_sum r24 r0
+            list_min r25 r0
+            list_max r26 r0
+            for_each r27 "accumulate" r0 r1
+            list_map r28 "double" r0
+            load_i64 r29 -9223372036854775808
+            load_f64 r30 0.1
+            load_f64 r31 nan:0x7ff8000000000001
+            cast_i32 r32 r29
+            cast_f32 r32 r30
+            cast_i64 r32 r2
+            cast_f64 r32 r1
+            jump_if_true r4 L2
+            ret r18
+        L2:
+        "#;
+        assemble(source).expect("assemble")
+    }
+
+    #[test]
+    fn round_trip_is_byte_exact_for_every_opcode() {
+        let bytecode = every_opcode_program();
+        let instructions = decode_bytecode(&bytecode).expect("decode");
+        let mut seen: Vec<u8> = instructions.iter().map(|i| i.opcode() as u8).collect();
+        seen.sort_unstable();
+        seen.dedup();
+        let all: Vec<u8> = (0..=u8::MAX)
+            .filter(|b| OpCode::from_u8(*b).is_some_and(|op| op != OpCode::LoadConst))
+            .collect();
+        assert_eq!(seen, all, "test program must cover every opcode");
+
+        let text = disassemble(&bytecode).expect("disassemble");
+        assert_eq!(assemble(&text).expect("reassemble"), bytecode);
+        // 2 回目の逆アセンブルも同じテキストになる
+        assert_eq!(disassemble(&bytecode).expect("disassemble"), text);
+    }
+
+    #[test]
+    fn mnemonics_round_trip() {
+        for op in (0..=u8::MAX).filter_map(OpCode::from_u8) {
+            assert_eq!(OpCode::from_mnemonic(op.mnemonic()), Some(op));
+        }
+    }
+
+    #[test]
+    fn disassembly_is_readable() {
+        let bytecode = assemble(
+            "load_input r0 \"dt\" ; 経過時間\nload_i32 r1 2\nmul r2 r0 r1\nstore_output r2\n",
+        )
+        .expect("assemble");
+        assert_eq!(
+            disassemble(&bytecode).expect("disassemble"),
+            "    load_input r0 \"dt\"\n    load_i32 r1 2\n    mul r2 r0 r1\n    store_output r2\n"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_report_line() {
+        let cases = [
+            ("load_i32 r0 1\nfrobnicate r0", 2),
+            ("load_i32 r64 1", 1),
+            ("load_i32 r0", 1),
+            ("store_output r0 r1", 1),
+            ("jump nowhere", 1),
+            ("L0:\nL0:", 2),
+            ("load_input r0 \"dt", 1),
+            ("make_vec r0 r1", 1),
+        ];
+        for (source, expected_line) in cases {
+            match assemble(source) {
+                Err(AsmError::Syntax { line, .. }) => assert_eq!(line, expected_line, "{}", source),
+                other => panic!("{}: unexpected {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn backward_jump_is_rejected_by_verification() {
+        let result = assemble("L0:\nload_bool r0 true\njump L0");
+        assert!(matches!(
+            result,
+            Err(AsmError::Decode(DecodeError {
+                kind: DecodeErrorKind::BackwardJump(0),
+                ..
+            }))
+        ));
+    }
+}
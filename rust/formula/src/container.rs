@@ -0,0 +1,304 @@
+//! Path: rust/formula/src/container.rs
+//! Summary: バージョン付きバイトコードコンテナ（ヘッダ・文字列表・定数プール・チェックサム）
+//!
+//! レイアウト（数値はすべてリトルエンディアン）:
+//!
+//! | フィールド | サイズ | 内容 |
+//! |:---|:---|:---|
+//! | magic | 4 | `0xFF 'F' 'V' 'M'`（0xFF は OpCode として予約し、従来形式と区別する） |
+//! | version | u16 | 現在は 1 |
+//! | flags | u8 | bit0: 末尾にチェックサムあり。その他のビットは 0 |
+//! | 文字列表 | u16 件数 + (u16 長さ + UTF-8) × 件数 | LoadInput / ReadStore / WriteStore / Call / Emit が u16 インデックスで参照 |
+//! | 定数プール | u16 件数 + (u8 タグ + 値) × 件数 | LoadConst が u16 インデックスで参照 |
+//! | コード | 残り | 命令列。ジャンプ先はコード先頭からのバイトオフセット |
+//! | チェックサム | u32 | flags bit0 のとき。直前までの全バイトの CRC-32（IEEE） |
+//!
+//! 定数タグ: 0 = i32, 1 = f32, 2 = bool (u8), 3 = vec2 (f32 × 2), 4 = vec3 (f32 × 3), 5 = i64, 6 = f64
+
+use super::decode::{DecodeErrorKind, Name};
+use super::value::Value;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+pub const MAGIC: [u8; 4] = [0xFF, b'F', b'V', b'M'];
+pub const VERSION: u16 = 1;
+pub const FLAG_CHECKSUM: u8 = 0x01;
+
+const TAG_I32: u8 = 0;
+const TAG_F32: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_VEC2: u8 = 3;
+const TAG_VEC3: u8 = 4;
+const TAG_I64: u8 = 5;
+const TAG_F64: u8 = 6;
+
+/// ヘッダを解析したコンテナ。code はコード部分のスライス
+pub struct Container<'a> {
+    pub strings: Vec<Name>,
+    pub constants: Vec<Value>,
+    pub code: &'a [u8],
+}
+
+/// CRC-32（IEEE 802.3。zlib / `:erlang.crc32/1` と同じ）
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// 先頭読み出し用のカーソル
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeErrorKind> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or(DecodeErrorKind::UnexpectedEof)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeErrorKind> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeErrorKind> {
+        let bytes: [u8; 2] = self
+            .take(2)?
+            .try_into()
+            .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeErrorKind> {
+        let bytes: [u8; 4] = self
+            .take(4)?
+            .try_into()
+            .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn f32(&mut self) -> Result<f32, DecodeErrorKind> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeErrorKind> {
+        let bytes: [u8; 8] = self
+            .take(8)?
+            .try_into()
+            .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+/// magic で始まるか（コンテナ形式か）
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// コンテナならヘッダ・表を解析して返す。magic で始まらない従来形式は None
+pub fn parse(bytes: &[u8]) -> Result<Option<Container<'_>>, DecodeErrorKind> {
+    if !is_container(bytes) {
+        return Ok(None);
+    }
+    let mut reader = Reader {
+        buf: bytes,
+        pos: MAGIC.len(),
+    };
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(DecodeErrorKind::UnsupportedVersion(version));
+    }
+    let flags = reader.u8()?;
+    if flags & !FLAG_CHECKSUM != 0 {
+        return Err(DecodeErrorKind::UnsupportedFlags(flags));
+    }
+    if flags & FLAG_CHECKSUM != 0 {
+        let body_len = bytes
+            .len()
+            .checked_sub(4)
+            .filter(|n| *n >= reader.pos)
+            .ok_or(DecodeErrorKind::UnexpectedEof)?;
+        let (body, tail) = bytes.split_at(body_len);
+        let expected = u32::from_le_bytes(
+            tail.try_into()
+                .map_err(|_| DecodeErrorKind::UnexpectedEof)?,
+        );
+        if crc32(body) != expected {
+            return Err(DecodeErrorKind::ChecksumMismatch);
+        }
+        reader.buf = body;
+    }
+
+    let string_count = reader.u16()?;
+    let mut strings = Vec::with_capacity(string_count as usize);
+    for _ in 0..string_count {
+        let len = reader.u16()? as usize;
+        let s = std::str::from_utf8(reader.take(len)?).map_err(|_| DecodeErrorKind::InvalidUtf8)?;
+        strings.push(Arc::from(s));
+    }
+
+    let constant_count = reader.u16()?;
+    let mut constants = Vec::with_capacity(constant_count as usize);
+    for _ in 0..constant_count {
+        let value = match reader.u8()? {
+            TAG_I32 => Value::I32(reader.u32()? as i32),
+            TAG_F32 => Value::F32(reader.f32()?),
+            TAG_BOOL => Value::Bool(reader.u8()? != 0),
+            TAG_VEC2 => Value::Vec2([reader.f32()?, reader.f32()?]),
+            TAG_VEC3 => Value::Vec3([reader.f32()?, reader.f32()?, reader.f32()?]),
+            TAG_I64 => Value::I64(reader.u64()? as i64),
+            TAG_F64 => Value::F64(f64::from_bits(reader.u64()?)),
+            tag => return Err(DecodeErrorKind::InvalidConstant(tag)),
+        };
+        constants.push(value);
+    }
+
+    Ok(Some(Container {
+        strings,
+        constants,
+        code: &reader.buf[reader.pos..],
+    }))
+}
+
+/// コンテナを書き出す。件数・長さが u16 に収まることは呼び出し側で確認済みであること
+pub fn write(strings: &[&str], constants: &[Value], code: &[u8], checksum: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + code.len());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.push(if checksum { FLAG_CHECKSUM } else { 0 });
+
+    out.extend_from_slice(&(strings.len() as u16).to_le_bytes());
+    for s in strings {
+        out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    out.extend_from_slice(&(constants.len() as u16).to_le_bytes());
+    for value in constants {
+        match value {
+            Value::I32(v) => {
+                out.push(TAG_I32);
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::F32(v) => {
+                out.push(TAG_F32);
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Bool(v) => out.extend([TAG_BOOL, *v as u8]),
+            Value::I64(v) => {
+                out.push(TAG_I64);
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::F64(v) => {
+                out.push(TAG_F64);
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Vec2(_) | Value::Vec3(_) => {
+                let components = value.components().unwrap_or_default();
+                out.push(if components.len() == 2 {
+                    TAG_VEC2
+                } else {
+                    TAG_VEC3
+                });
+                for c in components {
+                    out.extend_from_slice(&c.to_le_bytes());
+                }
+            }
+            Value::List(_) => unreachable!("lists are never pooled as constants"),
+        }
+    }
+
+    out.extend_from_slice(code);
+    if checksum {
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_reference() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn legacy_stream_is_not_a_container() {
+        assert!(parse(&[1, 0, 1, 0, 0, 0]).expect("parse").is_none());
+    }
+
+    #[test]
+    fn tables_round_trip() {
+        let constants = [
+            Value::I32(-5),
+            Value::F32(1.5),
+            Value::Bool(true),
+            Value::Vec3([1.0, 2.0, 3.0]),
+            Value::I64(i64::MIN),
+            Value::F64(0.1),
+        ];
+        let bytes = write(&["dt", "score"], &constants, &[11, 0], true);
+        let container = parse(&bytes).expect("parse").expect("container");
+        assert_eq!(
+            container.strings.iter().map(|s| &**s).collect::<Vec<_>>(),
+            ["dt", "score"]
+        );
+        assert!(matches!(
+            container.constants.as_slice(),
+            [
+                Value::I32(-5),
+                Value::F32(x),
+                Value::Bool(true),
+                Value::Vec3([1.0, 2.0, 3.0]),
+                Value::I64(i64::MIN),
+                Value::F64(y)
+            ] if *x == 1.5 && *y == 0.1
+        ));
+        assert_eq!(container.code, &[11, 0]);
+    }
+
+    #[test]
+    fn header_errors() {
+        let mut bytes = write(&[], &[], &[11, 0], true);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            parse(&bytes),
+            Err(DecodeErrorKind::ChecksumMismatch)
+        ));
+
+        let mut bytes = write(&[], &[], &[], false);
+        bytes[4] = 2;
+        assert!(matches!(
+            parse(&bytes),
+            Err(DecodeErrorKind::UnsupportedVersion(2))
+        ));
+
+        let mut bytes = write(&[], &[], &[], false);
+        bytes[6] = 0x80;
+        assert!(matches!(
+            parse(&bytes),
+            Err(DecodeErrorKind::UnsupportedFlags(0x80))
+        ));
+
+        assert!(matches!(parse(&MAGIC), Err(DecodeErrorKind::UnexpectedEof)));
+    }
+}
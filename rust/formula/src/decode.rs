@@ -0,0 +1,1125 @@
+//! Path: rust/formula/src/decode.rs
+//! Summary: バイナリ形式のバイトコードをパースする
+//!
+//! コンテナ形式（container.rs）と、ヘッダのない従来形式の両方を受け付ける。
+
+use super::container;
+use super::opcode::OpCode;
+use super::source_map::Origin;
+use super::value::Value;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// 入力名・Store キー。デコード時にインターンし、同じ名前は 1 つの確保を共有する。
+pub type Name = Arc<str>;
+
+/// デコード後の 1 命令
+///
+/// ジャンプ系の target はデコード時に解決済みの命令インデックス（命令数と等しい場合は終端）。
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    LoadInput {
+        dst: u8,
+        name: Name,
+    },
+    LoadI32 {
+        dst: u8,
+        value: i32,
+    },
+    LoadF32 {
+        dst: u8,
+        value: f32,
+    },
+    LoadBool {
+        dst: u8,
+        value: bool,
+    },
+    LoadI64 {
+        dst: u8,
+        value: i64,
+    },
+    LoadF64 {
+        dst: u8,
+        value: f64,
+    },
+    Add {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Sub {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Mul {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Div {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Lt {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Gt {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Eq {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    StoreOutput {
+        src: u8,
+    },
+    ReadStore {
+        dst: u8,
+        name: Name,
+    },
+    WriteStore {
+        src: u8,
+        name: Name,
+    },
+    Jump {
+        target: usize,
+    },
+    JumpIfFalse {
+        cond: u8,
+        target: usize,
+    },
+    JumpIfTrue {
+        cond: u8,
+        target: usize,
+    },
+    Abs {
+        dst: u8,
+        src: u8,
+    },
+    Floor {
+        dst: u8,
+        src: u8,
+    },
+    Ceil {
+        dst: u8,
+        src: u8,
+    },
+    Sqrt {
+        dst: u8,
+        src: u8,
+    },
+    Sin {
+        dst: u8,
+        src: u8,
+    },
+    Cos {
+        dst: u8,
+        src: u8,
+    },
+    CastI32 {
+        dst: u8,
+        src: u8,
+    },
+    CastF32 {
+        dst: u8,
+        src: u8,
+    },
+    CastI64 {
+        dst: u8,
+        src: u8,
+    },
+    CastF64 {
+        dst: u8,
+        src: u8,
+    },
+    Min {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Max {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Atan2 {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Pow {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Mod {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Clamp {
+        dst: u8,
+        src: u8,
+        lo: u8,
+        hi: u8,
+    },
+    Lerp {
+        dst: u8,
+        a: u8,
+        b: u8,
+        t: u8,
+    },
+    Not {
+        dst: u8,
+        src: u8,
+    },
+    And {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Or {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Xor {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Le {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Ge {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Ne {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Select {
+        dst: u8,
+        cond: u8,
+        a: u8,
+        b: u8,
+    },
+    LoadVec {
+        dst: u8,
+        value: Value,
+    },
+    MakeVec {
+        dst: u8,
+        n: u8,
+        srcs: [u8; 3],
+    },
+    Extract {
+        dst: u8,
+        src: u8,
+        index: u8,
+    },
+    Dot {
+        dst: u8,
+        src_a: u8,
+        src_b: u8,
+    },
+    Length {
+        dst: u8,
+        src: u8,
+    },
+    Normalize {
+        dst: u8,
+        src: u8,
+    },
+    Call {
+        dst: u8,
+        name: Name,
+        args: Vec<u8>,
+    },
+    Ret {
+        src: u8,
+    },
+    Random {
+        dst: u8,
+    },
+    RandomRange {
+        dst: u8,
+        lo: u8,
+        hi: u8,
+    },
+    /// src が None ならペイロードなし
+    Emit {
+        src: Option<u8>,
+        name: Name,
+    },
+    ListLen {
+        dst: u8,
+        src: u8,
+    },
+    ListIndex {
+        dst: u8,
+        list: u8,
+        index: u8,
+    },
+    ListSum {
+        dst: u8,
+        src: u8,
+    },
+    ListMin {
+        dst: u8,
+        src: u8,
+    },
+    ListMax {
+        dst: u8,
+        src: u8,
+    },
+    /// name は 2 引数（累積値, 要素）のライブラリ関数
+    ForEach {
+        dst: u8,
+        list: u8,
+        init: u8,
+        name: Name,
+    },
+    /// name は 1 引数（要素）のライブラリ関数
+    ListMap {
+        dst: u8,
+        list: u8,
+        name: Name,
+    },
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Instruction::LoadInput { .. } => OpCode::LoadInput,
+            Instruction::LoadI32 { .. } => OpCode::LoadI32,
+            Instruction::LoadF32 { .. } => OpCode::LoadF32,
+            Instruction::LoadBool { .. } => OpCode::LoadBool,
+            Instruction::LoadI64 { .. } => OpCode::LoadI64,
+            Instruction::LoadF64 { .. } => OpCode::LoadF64,
+            Instruction::Add { .. } => OpCode::Add,
+            Instruction::Sub { .. } => OpCode::Sub,
+            Instruction::Mul { .. } => OpCode::Mul,
+            Instruction::Div { .. } => OpCode::Div,
+            Instruction::Lt { .. } => OpCode::Lt,
+            Instruction::Gt { .. } => OpCode::Gt,
+            Instruction::Eq { .. } => OpCode::Eq,
+            Instruction::StoreOutput { .. } => OpCode::StoreOutput,
+            Instruction::ReadStore { .. } => OpCode::ReadStore,
+            Instruction::WriteStore { .. } => OpCode::WriteStore,
+            Instruction::Jump { .. } => OpCode::Jump,
+            Instruction::JumpIfFalse { .. } => OpCode::JumpIfFalse,
+            Instruction::JumpIfTrue { .. } => OpCode::JumpIfTrue,
+            Instruction::Abs { .. } => OpCode::Abs,
+            Instruction::Floor { .. } => OpCode::Floor,
+            Instruction::Ceil { .. } => OpCode::Ceil,
+            Instruction::Sqrt { .. } => OpCode::Sqrt,
+            Instruction::Sin { .. } => OpCode::Sin,
+            Instruction::Cos { .. } => OpCode::Cos,
+            Instruction::CastI32 { .. } => OpCode::CastI32,
+            Instruction::CastF32 { .. } => OpCode::CastF32,
+            Instruction::CastI64 { .. } => OpCode::CastI64,
+            Instruction::CastF64 { .. } => OpCode::CastF64,
+            Instruction::Min { .. } => OpCode::Min,
+            Instruction::Max { .. } => OpCode::Max,
+            Instruction::Atan2 { .. } => OpCode::Atan2,
+            Instruction::Pow { .. } => OpCode::Pow,
+            Instruction::Mod { .. } => OpCode::Mod,
+            Instruction::Clamp { .. } => OpCode::Clamp,
+            Instruction::Lerp { .. } => OpCode::Lerp,
+            Instruction::Not { .. } => OpCode::Not,
+            Instruction::And { .. } => OpCode::And,
+            Instruction::Or { .. } => OpCode::Or,
+            Instruction::Xor { .. } => OpCode::Xor,
+            Instruction::Le { .. } => OpCode::Le,
+            Instruction::Ge { .. } => OpCode::Ge,
+            Instruction::Ne { .. } => OpCode::Ne,
+            Instruction::Select { .. } => OpCode::Select,
+            Instruction::LoadVec { .. } => OpCode::LoadVec,
+            Instruction::MakeVec { .. } => OpCode::MakeVec,
+            Instruction::Extract { .. } => OpCode::Extract,
+            Instruction::Dot { .. } => OpCode::Dot,
+            Instruction::Length { .. } => OpCode::Length,
+            Instruction::Normalize { .. } => OpCode::Normalize,
+            Instruction::Call { .. } => OpCode::Call,
+            Instruction::Ret { .. } => OpCode::Ret,
+            Instruction::Random { .. } => OpCode::Random,
+            Instruction::RandomRange { .. } => OpCode::RandomRange,
+            Instruction::Emit { .. } => OpCode::Emit,
+            Instruction::ListLen { .. } => OpCode::ListLen,
+            Instruction::ListIndex { .. } => OpCode::ListIndex,
+            Instruction::ListSum { .. } => OpCode::ListSum,
+            Instruction::ListMin { .. } => OpCode::ListMin,
+            Instruction::ListMax { .. } => OpCode::ListMax,
+            Instruction::ForEach { .. } => OpCode::ForEach,
+            Instruction::ListMap { .. } => OpCode::ListMap,
+        }
+    }
+
+    /// Store を書き換えるか（WriteStore と、乱数状態を進める Random / RandomRange）
+    pub(super) fn writes_store(&self) -> bool {
+        matches!(
+            self,
+            Instruction::WriteStore { .. }
+                | Instruction::Random { .. }
+                | Instruction::RandomRange { .. }
+        )
+    }
+
+    /// 書き込み先レジスタと読み出すレジスタ（オペランド順）
+    pub fn operands(&self) -> (Option<u8>, Vec<u8>) {
+        let mut copy = self.clone();
+        let (dst, srcs) = copy.operands_mut();
+        (dst.copied(), srcs.into_iter().map(|r| *r).collect())
+    }
+
+    /// operands の可変参照版。レジスタの付け替え（最適化）に使う
+    pub fn operands_mut(&mut self) -> (Option<&mut u8>, Vec<&mut u8>) {
+        match self {
+            Instruction::LoadInput { dst, .. }
+            | Instruction::LoadI32 { dst, .. }
+            | Instruction::LoadF32 { dst, .. }
+            | Instruction::LoadBool { dst, .. }
+            | Instruction::LoadI64 { dst, .. }
+            | Instruction::LoadF64 { dst, .. }
+            | Instruction::LoadVec { dst, .. }
+            | Instruction::ReadStore { dst, .. }
+            | Instruction::Random { dst } => (Some(dst), vec![]),
+            Instruction::StoreOutput { src }
+            | Instruction::WriteStore { src, .. }
+            | Instruction::Ret { src } => (None, vec![src]),
+            Instruction::Jump { .. } => (None, vec![]),
+            Instruction::Emit { src, .. } => (None, src.iter_mut().collect()),
+            Instruction::JumpIfFalse { cond, .. } | Instruction::JumpIfTrue { cond, .. } => {
+                (None, vec![cond])
+            }
+            Instruction::Add { dst, src_a, src_b }
+            | Instruction::Sub { dst, src_a, src_b }
+            | Instruction::Mul { dst, src_a, src_b }
+            | Instruction::Div { dst, src_a, src_b }
+            | Instruction::Lt { dst, src_a, src_b }
+            | Instruction::Gt { dst, src_a, src_b }
+            | Instruction::Eq { dst, src_a, src_b }
+            | Instruction::Min { dst, src_a, src_b }
+            | Instruction::Max { dst, src_a, src_b }
+            | Instruction::Atan2 { dst, src_a, src_b }
+            | Instruction::Pow { dst, src_a, src_b }
+            | Instruction::Mod { dst, src_a, src_b }
+            | Instruction::And { dst, src_a, src_b }
+            | Instruction::Or { dst, src_a, src_b }
+            | Instruction::Xor { dst, src_a, src_b }
+            | Instruction::Le { dst, src_a, src_b }
+            | Instruction::Ge { dst, src_a, src_b }
+            | Instruction::Ne { dst, src_a, src_b }
+            | Instruction::Dot { dst, src_a, src_b } => (Some(dst), vec![src_a, src_b]),
+            Instruction::ListIndex { dst, list, index } => (Some(dst), vec![list, index]),
+            Instruction::ForEach {
+                dst, list, init, ..
+            } => (Some(dst), vec![list, init]),
+            Instruction::ListMap { dst, list, .. } => (Some(dst), vec![list]),
+            Instruction::Abs { dst, src }
+            | Instruction::Floor { dst, src }
+            | Instruction::Ceil { dst, src }
+            | Instruction::Sqrt { dst, src }
+            | Instruction::Sin { dst, src }
+            | Instruction::Cos { dst, src }
+            | Instruction::CastI32 { dst, src }
+            | Instruction::CastF32 { dst, src }
+            | Instruction::CastI64 { dst, src }
+            | Instruction::CastF64 { dst, src }
+            | Instruction::Not { dst, src }
+            | Instruction::Length { dst, src }
+            | Instruction::Normalize { dst, src }
+            | Instruction::ListLen { dst, src }
+            | Instruction::ListSum { dst, src }
+            | Instruction::ListMin { dst, src }
+            | Instruction::ListMax { dst, src }
+            | Instruction::Extract { dst, src, .. } => (Some(dst), vec![src]),
+            Instruction::Clamp { dst, src, lo, hi } => (Some(dst), vec![src, lo, hi]),
+            Instruction::RandomRange { dst, lo, hi } => (Some(dst), vec![lo, hi]),
+            Instruction::Lerp { dst, a, b, t } => (Some(dst), vec![a, b, t]),
+            Instruction::Select { dst, cond, a, b } => (Some(dst), vec![cond, a, b]),
+            Instruction::MakeVec { dst, n, srcs } => {
+                (Some(dst), srcs[..*n as usize].iter_mut().collect())
+            }
+            Instruction::Call { dst, args, .. } => (Some(dst), args.iter_mut().collect()),
+        }
+    }
+}
+
+pub const REGISTER_COUNT: usize = 64;
+
+/// Emit の src オペランドで「ペイロードなし」を表す値（レジスタ番号としては使われない）
+pub const NO_PAYLOAD: u8 = 0xFF;
+
+/// エラーが起きた命令の位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// ライブラリ関数の中で起きた場合はその名前（トップレベルは None）
+    pub function: Option<String>,
+    /// 命令インデックス
+    pub pc: usize,
+    /// その命令のバイトオフセット（コード部分の先頭から。`decode_with_offsets` と同じ）
+    pub offset: usize,
+    /// プログラムに結び付けた `SourceMap` で offset を引いた生成元（グラフのノード・ソースの位置）
+    pub origin: Option<Origin>,
+}
+
+/// デコードの失敗。位置はデコード中の命令（ジャンプ先の誤りはジャンプ命令）。
+/// コンテナのヘッダ・表の誤りは命令に属さないため location は None
+#[derive(Debug)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    pub location: Option<Location>,
+}
+
+impl DecodeError {
+    fn at(kind: DecodeErrorKind, pc: usize, offset: usize) -> Self {
+        Self {
+            kind,
+            location: Some(Location {
+                function: None,
+                pc,
+                offset,
+                origin: None,
+            }),
+        }
+    }
+}
+
+impl From<DecodeErrorKind> for DecodeError {
+    fn from(kind: DecodeErrorKind) -> Self {
+        Self {
+            kind,
+            location: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeErrorKind {
+    UnexpectedEof,
+    InvalidOpCode(u8),
+    RegisterOutOfRange(u8),
+    InvalidUtf8,
+    /// ジャンプ先がバイトコード長を超える
+    JumpOutOfBounds(u32),
+    /// ジャンプ先が命令境界に一致しない
+    JumpNotOnBoundary(u32),
+    /// ジャンプ先が自命令以前（前方ジャンプのみ許可）
+    BackwardJump(u32),
+    /// ベクトルの次元が 2 / 3 以外
+    InvalidVectorSize(u8),
+    /// コンテナのバージョンが未対応
+    UnsupportedVersion(u16),
+    /// コンテナの flags に未知のビットがある
+    UnsupportedFlags(u8),
+    /// コンテナのチェックサムが一致しない
+    ChecksumMismatch,
+    /// 文字列表のインデックスが範囲外
+    StringOutOfRange(u16),
+    /// 定数プールのインデックスが範囲外
+    ConstantOutOfRange(u16),
+    /// 定数プールのタグが未知
+    InvalidConstant(u8),
+    /// Call の引数がレジスタ数を超える
+    TooManyArguments(u8),
+}
+
+fn ensure_len(buf: &[u8], need: usize) -> Result<(), DecodeErrorKind> {
+    if buf.len() < need {
+        Err(DecodeErrorKind::UnexpectedEof)
+    } else {
+        Ok(())
+    }
+}
+
+fn check_register(r: u8) -> Result<(), DecodeErrorKind> {
+    if r >= REGISTER_COUNT as u8 {
+        Err(DecodeErrorKind::RegisterOutOfRange(r))
+    } else {
+        Ok(())
+    }
+}
+
+/// 名前のインターン表。デコード 1 回分の間だけ保持する
+#[derive(Default)]
+struct NameInterner {
+    table: HashMap<Box<[u8]>, Name>,
+}
+
+impl NameInterner {
+    fn intern(&mut self, bytes: &[u8]) -> Result<Name, DecodeErrorKind> {
+        if let Some(name) = self.table.get(bytes) {
+            return Ok(name.clone());
+        }
+        let s = std::str::from_utf8(bytes).map_err(|_| DecodeErrorKind::InvalidUtf8)?;
+        let name: Name = Arc::from(s);
+        self.table.insert(bytes.into(), name.clone());
+        Ok(name)
+    }
+}
+
+fn check_vector_size(n: u8) -> Result<usize, DecodeErrorKind> {
+    match n {
+        2 | 3 => Ok(n as usize),
+        _ => Err(DecodeErrorKind::InvalidVectorSize(n)),
+    }
+}
+
+fn read_u16(buf: &[u8]) -> Result<u16, DecodeErrorKind> {
+    let bytes: [u8; 2] = buf
+        .get(..2)
+        .ok_or(DecodeErrorKind::UnexpectedEof)?
+        .try_into()
+        .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+/// 名前オペランドの読み方。従来形式はインライン（u8 長さ + バイト列）、コンテナは文字列表の u16 インデックス
+enum Names<'a> {
+    Inline(NameInterner),
+    Table(&'a [Name]),
+}
+
+impl Names<'_> {
+    /// 名前オペランドを読み、(名前, 消費したバイト数) を返す
+    fn read(&mut self, buf: &[u8]) -> Result<(Name, usize), DecodeErrorKind> {
+        match self {
+            Names::Inline(interner) => {
+                ensure_len(buf, 1)?;
+                let len = buf[0] as usize;
+                ensure_len(&buf[1..], len)?;
+                Ok((interner.intern(&buf[1..1 + len])?, 1 + len))
+            }
+            Names::Table(strings) => {
+                let index = read_u16(buf)?;
+                let name = strings
+                    .get(index as usize)
+                    .ok_or(DecodeErrorKind::StringOutOfRange(index))?;
+                Ok((name.clone(), 2))
+            }
+        }
+    }
+}
+
+fn read_u32(buf: &[u8]) -> Result<u32, DecodeErrorKind> {
+    let bytes: [u8; 4] = buf
+        .get(..4)
+        .ok_or(DecodeErrorKind::UnexpectedEof)?
+        .try_into()
+        .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// 未解決のジャンプ。(命令インデックス, 命令の開始オフセット, ジャンプ先バイトオフセット)
+type PendingJump = (usize, usize, u32);
+
+/// バイト列を命令列にデコードする
+///
+/// magic で始まればコンテナ形式として解析し（未知のバージョンは `UnsupportedVersion`）、
+/// それ以外はヘッダのない従来形式として扱う。
+///
+/// ジャンプ先は前方（自命令より後ろ）かつ命令境界上であることを検証し、命令インデックスに解決する。
+/// 後方ジャンプを許さないため、デコードに成功したプログラムは必ず停止する。
+pub fn decode_bytecode(bytecode: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    decode_with_offsets(bytecode).map(|(instructions, _)| instructions)
+}
+
+/// `decode_bytecode` と同じ。各命令のバイトオフセット（コード部分の先頭から。コンテナではヘッダ・表を含まない）も返す。
+/// オフセットは命令数 + 1 個で、末尾はコード部分の長さ（最後の命令の終端）
+pub fn decode_with_offsets(bytecode: &[u8]) -> Result<(Vec<Instruction>, Vec<usize>), DecodeError> {
+    match container::parse(bytecode)? {
+        Some(c) => decode_code(c.code, Names::Table(&c.strings), Some(&c.constants)),
+        None => decode_code(bytecode, Names::Inline(NameInterner::default()), None),
+    }
+}
+
+/// 命令列本体をデコードする。constants は定数プール（従来形式では None で、LoadConst は不正な OpCode）
+///
+/// 失敗した命令のインデックスとバイトオフセットをエラーに付ける（ジャンプ先の誤りはジャンプ命令の位置）。
+/// 末尾で命令が途切れた場合は、その命令の先頭を指す。
+fn decode_code(
+    bytecode: &[u8],
+    mut names: Names<'_>,
+    constants: Option<&[Value]>,
+) -> Result<(Vec<Instruction>, Vec<usize>), DecodeError> {
+    let mut instructions = Vec::new();
+    let mut offsets = Vec::new();
+    let mut pending_jumps: Vec<PendingJump> = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytecode.len() {
+        let index = instructions.len();
+        let (inst, next) = decode_instruction(
+            bytecode,
+            pos,
+            index,
+            &mut names,
+            constants,
+            &mut pending_jumps,
+        )
+        .map_err(|kind| DecodeError::at(kind, index, pos))?;
+        offsets.push(pos);
+        instructions.push(inst);
+        pos = next;
+    }
+
+    for (index, inst_offset, target) in pending_jumps {
+        let resolved = resolve_jump_target(&offsets, bytecode.len(), inst_offset, target)
+            .map_err(|kind| DecodeError::at(kind, index, inst_offset))?;
+        match &mut instructions[index] {
+            Instruction::Jump { target }
+            | Instruction::JumpIfFalse { target, .. }
+            | Instruction::JumpIfTrue { target, .. } => *target = resolved,
+            _ => unreachable!("pending jump must point at a jump instruction"),
+        }
+    }
+
+    offsets.push(bytecode.len());
+    Ok((instructions, offsets))
+}
+
+/// start から 1 命令をデコードし、(命令, 次の命令のオフセット) を返す。ジャンプは pending_jumps に積み、後で解決する
+fn decode_instruction(
+    bytecode: &[u8],
+    start: usize,
+    index: usize,
+    names: &mut Names<'_>,
+    constants: Option<&[Value]>,
+    pending_jumps: &mut Vec<PendingJump>,
+) -> Result<(Instruction, usize), DecodeErrorKind> {
+    let mut pos = start;
+    ensure_len(&bytecode[pos..], 1)?;
+    let op = OpCode::from_u8(bytecode[pos]).ok_or(DecodeErrorKind::InvalidOpCode(bytecode[pos]))?;
+    pos += 1;
+
+    let inst = match op {
+        OpCode::LoadInput => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let dst = bytecode[pos];
+            let (name, used) = names.read(&bytecode[pos + 1..])?;
+            pos += 1 + used;
+            check_register(dst)?;
+            Instruction::LoadInput { dst, name }
+        }
+        OpCode::LoadI32 => {
+            ensure_len(&bytecode[pos..], 5)?;
+            let dst = bytecode[pos];
+            let bytes: [u8; 4] = bytecode[pos + 1..pos + 5]
+                .try_into()
+                .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+            let value = i32::from_le_bytes(bytes);
+            pos += 5;
+            check_register(dst)?;
+            Instruction::LoadI32 { dst, value }
+        }
+        OpCode::LoadF32 => {
+            ensure_len(&bytecode[pos..], 5)?;
+            let dst = bytecode[pos];
+            let bytes: [u8; 4] = bytecode[pos + 1..pos + 5]
+                .try_into()
+                .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+            let value = f32::from_le_bytes(bytes);
+            pos += 5;
+            check_register(dst)?;
+            Instruction::LoadF32 { dst, value }
+        }
+        OpCode::LoadI64 => {
+            ensure_len(&bytecode[pos..], 9)?;
+            let dst = bytecode[pos];
+            let bytes: [u8; 8] = bytecode[pos + 1..pos + 9]
+                .try_into()
+                .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+            let value = i64::from_le_bytes(bytes);
+            pos += 9;
+            check_register(dst)?;
+            Instruction::LoadI64 { dst, value }
+        }
+        OpCode::LoadF64 => {
+            ensure_len(&bytecode[pos..], 9)?;
+            let dst = bytecode[pos];
+            let bytes: [u8; 8] = bytecode[pos + 1..pos + 9]
+                .try_into()
+                .map_err(|_| DecodeErrorKind::UnexpectedEof)?;
+            let value = f64::from_le_bytes(bytes);
+            pos += 9;
+            check_register(dst)?;
+            Instruction::LoadF64 { dst, value }
+        }
+        OpCode::LoadBool => {
+            ensure_len(&bytecode[pos..], 2)?;
+            let dst = bytecode[pos];
+            let value = bytecode[pos + 1] != 0;
+            pos += 2;
+            check_register(dst)?;
+            Instruction::LoadBool { dst, value }
+        }
+        OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Div
+        | OpCode::Lt
+        | OpCode::Gt
+        | OpCode::Eq
+        | OpCode::Min
+        | OpCode::Max
+        | OpCode::Atan2
+        | OpCode::Pow
+        | OpCode::Mod
+        | OpCode::And
+        | OpCode::Or
+        | OpCode::Xor
+        | OpCode::Le
+        | OpCode::Ge
+        | OpCode::Ne
+        | OpCode::Dot
+        | OpCode::ListIndex => {
+            ensure_len(&bytecode[pos..], 3)?;
+            let dst = bytecode[pos];
+            let src_a = bytecode[pos + 1];
+            let src_b = bytecode[pos + 2];
+            pos += 3;
+            check_register(dst)?;
+            check_register(src_a)?;
+            check_register(src_b)?;
+            match op {
+                OpCode::Add => Instruction::Add { dst, src_a, src_b },
+                OpCode::Sub => Instruction::Sub { dst, src_a, src_b },
+                OpCode::Mul => Instruction::Mul { dst, src_a, src_b },
+                OpCode::Div => Instruction::Div { dst, src_a, src_b },
+                OpCode::Lt => Instruction::Lt { dst, src_a, src_b },
+                OpCode::Gt => Instruction::Gt { dst, src_a, src_b },
+                OpCode::Eq => Instruction::Eq { dst, src_a, src_b },
+                OpCode::Min => Instruction::Min { dst, src_a, src_b },
+                OpCode::Max => Instruction::Max { dst, src_a, src_b },
+                OpCode::Atan2 => Instruction::Atan2 { dst, src_a, src_b },
+                OpCode::Pow => Instruction::Pow { dst, src_a, src_b },
+                OpCode::Mod => Instruction::Mod { dst, src_a, src_b },
+                OpCode::And => Instruction::And { dst, src_a, src_b },
+                OpCode::Or => Instruction::Or { dst, src_a, src_b },
+                OpCode::Xor => Instruction::Xor { dst, src_a, src_b },
+                OpCode::Le => Instruction::Le { dst, src_a, src_b },
+                OpCode::Ge => Instruction::Ge { dst, src_a, src_b },
+                OpCode::Ne => Instruction::Ne { dst, src_a, src_b },
+                OpCode::Dot => Instruction::Dot { dst, src_a, src_b },
+                OpCode::ListIndex => Instruction::ListIndex {
+                    dst,
+                    list: src_a,
+                    index: src_b,
+                },
+                _ => unreachable!(),
+            }
+        }
+        OpCode::Abs
+        | OpCode::Floor
+        | OpCode::Ceil
+        | OpCode::Sqrt
+        | OpCode::Sin
+        | OpCode::Cos
+        | OpCode::CastI32
+        | OpCode::CastF32
+        | OpCode::CastI64
+        | OpCode::CastF64
+        | OpCode::Not
+        | OpCode::Length
+        | OpCode::Normalize
+        | OpCode::ListLen
+        | OpCode::ListSum
+        | OpCode::ListMin
+        | OpCode::ListMax => {
+            ensure_len(&bytecode[pos..], 2)?;
+            let dst = bytecode[pos];
+            let src = bytecode[pos + 1];
+            pos += 2;
+            check_register(dst)?;
+            check_register(src)?;
+            match op {
+                OpCode::Abs => Instruction::Abs { dst, src },
+                OpCode::Floor => Instruction::Floor { dst, src },
+                OpCode::Ceil => Instruction::Ceil { dst, src },
+                OpCode::Sqrt => Instruction::Sqrt { dst, src },
+                OpCode::Sin => Instruction::Sin { dst, src },
+                OpCode::Cos => Instruction::Cos { dst, src },
+                OpCode::CastI32 => Instruction::CastI32 { dst, src },
+                OpCode::CastF32 => Instruction::CastF32 { dst, src },
+                OpCode::CastI64 => Instruction::CastI64 { dst, src },
+                OpCode::CastF64 => Instruction::CastF64 { dst, src },
+                OpCode::Not => Instruction::Not { dst, src },
+                OpCode::Length => Instruction::Length { dst, src },
+                OpCode::Normalize => Instruction::Normalize { dst, src },
+                OpCode::ListLen => Instruction::ListLen { dst, src },
+                OpCode::ListSum => Instruction::ListSum { dst, src },
+                OpCode::ListMin => Instruction::ListMin { dst, src },
+                OpCode::ListMax => Instruction::ListMax { dst, src },
+                _ => unreachable!(),
+            }
+        }
+        OpCode::Clamp | OpCode::Lerp | OpCode::Select => {
+            ensure_len(&bytecode[pos..], 4)?;
+            let dst = bytecode[pos];
+            let r1 = bytecode[pos + 1];
+            let r2 = bytecode[pos + 2];
+            let r3 = bytecode[pos + 3];
+            pos += 4;
+            check_register(dst)?;
+            check_register(r1)?;
+            check_register(r2)?;
+            check_register(r3)?;
+            match op {
+                OpCode::Clamp => Instruction::Clamp {
+                    dst,
+                    src: r1,
+                    lo: r2,
+                    hi: r3,
+                },
+                OpCode::Lerp => Instruction::Lerp {
+                    dst,
+                    a: r1,
+                    b: r2,
+                    t: r3,
+                },
+                OpCode::Select => Instruction::Select {
+                    dst,
+                    cond: r1,
+                    a: r2,
+                    b: r3,
+                },
+                _ => unreachable!(),
+            }
+        }
+        OpCode::StoreOutput => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let src = bytecode[pos];
+            pos += 1;
+            check_register(src)?;
+            Instruction::StoreOutput { src }
+        }
+        OpCode::ReadStore => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let dst = bytecode[pos];
+            let (name, used) = names.read(&bytecode[pos + 1..])?;
+            pos += 1 + used;
+            check_register(dst)?;
+            Instruction::ReadStore { dst, name }
+        }
+        OpCode::WriteStore => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let src = bytecode[pos];
+            let (name, used) = names.read(&bytecode[pos + 1..])?;
+            pos += 1 + used;
+            check_register(src)?;
+            Instruction::WriteStore { src, name }
+        }
+        OpCode::Emit => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let src = bytecode[pos];
+            let (name, used) = names.read(&bytecode[pos + 1..])?;
+            pos += 1 + used;
+            let src = if src == NO_PAYLOAD {
+                None
+            } else {
+                check_register(src)?;
+                Some(src)
+            };
+            Instruction::Emit { src, name }
+        }
+        OpCode::ForEach => {
+            ensure_len(&bytecode[pos..], 3)?;
+            let dst = bytecode[pos];
+            let list = bytecode[pos + 1];
+            let init = bytecode[pos + 2];
+            let (name, used) = names.read(&bytecode[pos + 3..])?;
+            pos += 3 + used;
+            check_register(dst)?;
+            check_register(list)?;
+            check_register(init)?;
+            Instruction::ForEach {
+                dst,
+                list,
+                init,
+                name,
+            }
+        }
+        OpCode::ListMap => {
+            ensure_len(&bytecode[pos..], 2)?;
+            let dst = bytecode[pos];
+            let list = bytecode[pos + 1];
+            let (name, used) = names.read(&bytecode[pos + 2..])?;
+            pos += 2 + used;
+            check_register(dst)?;
+            check_register(list)?;
+            Instruction::ListMap { dst, list, name }
+        }
+        OpCode::LoadVec => {
+            ensure_len(&bytecode[pos..], 2)?;
+            let dst = bytecode[pos];
+            let n = check_vector_size(bytecode[pos + 1])?;
+            pos += 2;
+            ensure_len(&bytecode[pos..], n * 4)?;
+            let mut components = [0.0f32; 3];
+            for c in components.iter_mut().take(n) {
+                *c = f32::from_le_bytes(read_u32(&bytecode[pos..])?.to_le_bytes());
+                pos += 4;
+            }
+            check_register(dst)?;
+            let value = match n {
+                2 => Value::Vec2([components[0], components[1]]),
+                _ => Value::Vec3(components),
+            };
+            Instruction::LoadVec { dst, value }
+        }
+        OpCode::MakeVec => {
+            ensure_len(&bytecode[pos..], 2)?;
+            let dst = bytecode[pos];
+            let n = check_vector_size(bytecode[pos + 1])?;
+            pos += 2;
+            ensure_len(&bytecode[pos..], n)?;
+            let mut srcs = [0u8; 3];
+            srcs[..n].copy_from_slice(&bytecode[pos..pos + n]);
+            pos += n;
+            check_register(dst)?;
+            for src in &srcs[..n] {
+                check_register(*src)?;
+            }
+            Instruction::MakeVec {
+                dst,
+                n: n as u8,
+                srcs,
+            }
+        }
+        OpCode::Extract => {
+            ensure_len(&bytecode[pos..], 3)?;
+            let dst = bytecode[pos];
+            let src = bytecode[pos + 1];
+            let index = bytecode[pos + 2];
+            pos += 3;
+            check_register(dst)?;
+            check_register(src)?;
+            Instruction::Extract { dst, src, index }
+        }
+        OpCode::LoadConst => {
+            let constants = constants.ok_or(DecodeErrorKind::InvalidOpCode(op as u8))?;
+            ensure_len(&bytecode[pos..], 3)?;
+            let dst = bytecode[pos];
+            let index = read_u16(&bytecode[pos + 1..])?;
+            pos += 3;
+            check_register(dst)?;
+            // 定数プールは格納形式の違いにすぎないため、インラインの定数ロードと同じ命令にする
+            match constants.get(index as usize) {
+                Some(Value::I32(value)) => Instruction::LoadI32 { dst, value: *value },
+                Some(Value::F32(value)) => Instruction::LoadF32 { dst, value: *value },
+                Some(Value::Bool(value)) => Instruction::LoadBool { dst, value: *value },
+                Some(Value::I64(value)) => Instruction::LoadI64 { dst, value: *value },
+                Some(Value::F64(value)) => Instruction::LoadF64 { dst, value: *value },
+                Some(value) => Instruction::LoadVec {
+                    dst,
+                    value: value.clone(),
+                },
+                None => return Err(DecodeErrorKind::ConstantOutOfRange(index)),
+            }
+        }
+        OpCode::Call => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let dst = bytecode[pos];
+            let (name, used) = names.read(&bytecode[pos + 1..])?;
+            pos += 1 + used;
+            ensure_len(&bytecode[pos..], 1)?;
+            let argc = bytecode[pos];
+            if argc as usize > REGISTER_COUNT {
+                return Err(DecodeErrorKind::TooManyArguments(argc));
+            }
+            pos += 1;
+            ensure_len(&bytecode[pos..], argc as usize)?;
+            let args = bytecode[pos..pos + argc as usize].to_vec();
+            pos += argc as usize;
+            check_register(dst)?;
+            for arg in &args {
+                check_register(*arg)?;
+            }
+            Instruction::Call { dst, name, args }
+        }
+        OpCode::Ret => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let src = bytecode[pos];
+            pos += 1;
+            check_register(src)?;
+            Instruction::Ret { src }
+        }
+        OpCode::Random => {
+            ensure_len(&bytecode[pos..], 1)?;
+            let dst = bytecode[pos];
+            pos += 1;
+            check_register(dst)?;
+            Instruction::Random { dst }
+        }
+        OpCode::RandomRange => {
+            ensure_len(&bytecode[pos..], 3)?;
+            let dst = bytecode[pos];
+            let lo = bytecode[pos + 1];
+            let hi = bytecode[pos + 2];
+            pos += 3;
+            check_register(dst)?;
+            check_register(lo)?;
+            check_register(hi)?;
+            Instruction::RandomRange { dst, lo, hi }
+        }
+        OpCode::Jump => {
+            let target = read_u32(&bytecode[pos..])?;
+            pos += 4;
+            pending_jumps.push((index, start, target));
+            Instruction::Jump { target: 0 }
+        }
+        OpCode::JumpIfFalse | OpCode::JumpIfTrue => {
+            ensure_len(&bytecode[pos..], 5)?;
+            let cond = bytecode[pos];
+            let target = read_u32(&bytecode[pos + 1..])?;
+            pos += 5;
+            check_register(cond)?;
+            pending_jumps.push((index, start, target));
+            if op == OpCode::JumpIfFalse {
+                Instruction::JumpIfFalse { cond, target: 0 }
+            } else {
+                Instruction::JumpIfTrue { cond, target: 0 }
+            }
+        }
+    };
+
+    Ok((inst, pos))
+}
+
+/// ジャンプ先バイトオフセットを命令インデックスへ解決する。
+/// バイトコード長と等しいオフセットは「終端へのジャンプ」として instructions.len() に解決する。
+fn resolve_jump_target(
+    offsets: &[usize],
+    bytecode_len: usize,
+    inst_offset: usize,
+    target: u32,
+) -> Result<usize, DecodeErrorKind> {
+    let target_usize = target as usize;
+    if target_usize > bytecode_len {
+        return Err(DecodeErrorKind::JumpOutOfBounds(target));
+    }
+    if target_usize <= inst_offset {
+        return Err(DecodeErrorKind::BackwardJump(target));
+    }
+    if target_usize == bytecode_len {
+        return Ok(offsets.len());
+    }
+    offsets
+        .binary_search(&target_usize)
+        .map_err(|_| DecodeErrorKind::JumpNotOnBoundary(target))
+}
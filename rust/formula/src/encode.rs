@@ -0,0 +1,447 @@
+//! Path: rust/formula/src/encode.rs
+//! Summary: 命令列をバイナリ形式のバイトコードへエンコードする（decode.rs の逆）
+//!
+//! `encode_instructions` は従来形式（名前・定数をインライン）、`encode_container` はコンテナ形式。
+
+use super::container;
+use super::decode::{Instruction, NO_PAYLOAD};
+use super::opcode::OpCode;
+use super::value::{BitsKey, Value};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub enum EncodeError {
+    /// 入力名・Store キーが長すぎる（従来形式は 255 バイト、コンテナは 65535 バイトまで）
+    NameTooLong(String),
+    /// ジャンプ先の命令インデックスが命令数を超える
+    JumpOutOfBounds(usize),
+    /// LoadVec の値がベクトルでない、または MakeVec の n が 2 / 3 以外
+    InvalidVector,
+    /// バイトオフセットが u32 に、文字列表・定数プールの件数が u16 に、または Call の引数の数が u8 に収まらない
+    TooLarge,
+}
+
+/// コンテナ形式の文字列表と定数プール（出現順、重複なし）
+#[derive(Default)]
+struct Tables<'a> {
+    strings: Vec<&'a str>,
+    string_index: HashMap<&'a str, u16>,
+    constants: Vec<Value>,
+    constant_index: HashMap<BitsKey, u16>,
+}
+
+impl<'a> Tables<'a> {
+    fn build(instructions: &'a [Instruction]) -> Result<Self, EncodeError> {
+        let mut tables = Self::default();
+        for inst in instructions {
+            match inst {
+                Instruction::LoadInput { name, .. }
+                | Instruction::ReadStore { name, .. }
+                | Instruction::WriteStore { name, .. }
+                | Instruction::Call { name, .. }
+                | Instruction::Emit { name, .. }
+                | Instruction::ForEach { name, .. }
+                | Instruction::ListMap { name, .. } => tables.add_string(name)?,
+                Instruction::LoadI32 { value, .. } => tables.add_constant(Value::I32(*value))?,
+                Instruction::LoadF32 { value, .. } => tables.add_constant(Value::F32(*value))?,
+                Instruction::LoadI64 { value, .. } => tables.add_constant(Value::I64(*value))?,
+                Instruction::LoadF64 { value, .. } => tables.add_constant(Value::F64(*value))?,
+                Instruction::LoadVec { value, .. } => {
+                    // 定数にできるのはベクトルだけ（リストは定数プールに置かない）
+                    value.components().ok_or(EncodeError::InvalidVector)?;
+                    tables.add_constant(value.clone())?
+                }
+                _ => {}
+            }
+        }
+        Ok(tables)
+    }
+
+    fn add_string(&mut self, s: &'a str) -> Result<(), EncodeError> {
+        if self.string_index.contains_key(s) {
+            return Ok(());
+        }
+        if s.len() > u16::MAX as usize {
+            return Err(EncodeError::NameTooLong(s.into()));
+        }
+        let index = u16::try_from(self.strings.len()).map_err(|_| EncodeError::TooLarge)?;
+        self.strings.push(s);
+        self.string_index.insert(s, index);
+        Ok(())
+    }
+
+    fn add_constant(&mut self, value: Value) -> Result<(), EncodeError> {
+        let key = value.bits_key();
+        if self.constant_index.contains_key(&key) {
+            return Ok(());
+        }
+        let index = u16::try_from(self.constants.len()).map_err(|_| EncodeError::TooLarge)?;
+        self.constants.push(value);
+        self.constant_index.insert(key, index);
+        Ok(())
+    }
+}
+
+/// 定数ロードか（コンテナ形式では LoadConst にする）
+fn is_constant_load(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::LoadI32 { .. }
+            | Instruction::LoadF32 { .. }
+            | Instruction::LoadI64 { .. }
+            | Instruction::LoadF64 { .. }
+            | Instruction::LoadVec { .. }
+    )
+}
+
+/// 1 命令のエンコード後のバイト数（OpCode バイトを含む）
+fn encoded_len(inst: &Instruction, tables: Option<&Tables>) -> usize {
+    if tables.is_some() && is_constant_load(inst) {
+        return 4;
+    }
+    match inst {
+        Instruction::LoadInput { name, .. }
+        | Instruction::ReadStore { name, .. }
+        | Instruction::WriteStore { name, .. }
+        | Instruction::Emit { name, .. } => 2 + tables.map_or(1 + name.len(), |_| 2),
+        Instruction::LoadI32 { .. } | Instruction::LoadF32 { .. } => 6,
+        Instruction::LoadI64 { .. } | Instruction::LoadF64 { .. } => 10,
+        Instruction::LoadBool { .. } => 3,
+        Instruction::StoreOutput { .. } | Instruction::Ret { .. } | Instruction::Random { .. } => 2,
+        Instruction::Call { name, args, .. } => {
+            3 + tables.map_or(1 + name.len(), |_| 2) + args.len()
+        }
+        Instruction::ForEach { name, .. } => 4 + tables.map_or(1 + name.len(), |_| 2),
+        Instruction::ListMap { name, .. } => 3 + tables.map_or(1 + name.len(), |_| 2),
+        Instruction::Jump { .. } => 5,
+        Instruction::JumpIfFalse { .. } | Instruction::JumpIfTrue { .. } => 6,
+        Instruction::Abs { .. }
+        | Instruction::Floor { .. }
+        | Instruction::Ceil { .. }
+        | Instruction::Sqrt { .. }
+        | Instruction::Sin { .. }
+        | Instruction::Cos { .. }
+        | Instruction::CastI32 { .. }
+        | Instruction::CastF32 { .. }
+        | Instruction::CastI64 { .. }
+        | Instruction::CastF64 { .. }
+        | Instruction::Not { .. }
+        | Instruction::Length { .. }
+        | Instruction::Normalize { .. }
+        | Instruction::ListLen { .. }
+        | Instruction::ListSum { .. }
+        | Instruction::ListMin { .. }
+        | Instruction::ListMax { .. } => 3,
+        Instruction::Clamp { .. } | Instruction::Lerp { .. } | Instruction::Select { .. } => 5,
+        Instruction::RandomRange { .. } => 4,
+        Instruction::LoadVec { value, .. } => 3 + 4 * value.components().map_or(0, <[f32]>::len),
+        Instruction::MakeVec { n, .. } => 3 + *n as usize,
+        Instruction::Extract { .. } => 4,
+        Instruction::Add { .. }
+        | Instruction::Sub { .. }
+        | Instruction::Mul { .. }
+        | Instruction::Div { .. }
+        | Instruction::Lt { .. }
+        | Instruction::Gt { .. }
+        | Instruction::Eq { .. }
+        | Instruction::Min { .. }
+        | Instruction::Max { .. }
+        | Instruction::Atan2 { .. }
+        | Instruction::Pow { .. }
+        | Instruction::Mod { .. }
+        | Instruction::And { .. }
+        | Instruction::Or { .. }
+        | Instruction::Xor { .. }
+        | Instruction::Le { .. }
+        | Instruction::Ge { .. }
+        | Instruction::Ne { .. }
+        | Instruction::Dot { .. }
+        | Instruction::ListIndex { .. } => 4,
+    }
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str, tables: Option<&Tables>) -> Result<(), EncodeError> {
+    if let Some(tables) = tables {
+        out.extend_from_slice(&tables.string_index[name].to_le_bytes());
+        return Ok(());
+    }
+    let len = u8::try_from(name.len()).map_err(|_| EncodeError::NameTooLong(name.into()))?;
+    out.push(len);
+    out.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+/// 命令列を従来形式（ヘッダなし）のバイトコードにする。
+///
+/// ジャンプの target（命令インデックス）はバイトオフセットに戻す。命令数と等しい target は終端（バイトコード長）。
+/// LoadBool は 0 / 1 で書くため、デコード結果を再エンコードすると正規形のバイト列になる。
+pub fn encode_instructions(instructions: &[Instruction]) -> Result<Vec<u8>, EncodeError> {
+    encode_code(instructions, None)
+}
+
+/// 命令列をコンテナ形式のバイトコードにする。名前は文字列表、i32 / f32 / ベクトル定数は定数プールに置く。
+/// checksum が true なら末尾に CRC-32 を付ける。
+pub fn encode_container(
+    instructions: &[Instruction],
+    checksum: bool,
+) -> Result<Vec<u8>, EncodeError> {
+    let tables = Tables::build(instructions)?;
+    let code = encode_code(instructions, Some(&tables))?;
+    Ok(container::write(
+        &tables.strings,
+        &tables.constants,
+        &code,
+        checksum,
+    ))
+}
+
+/// 命令列本体をエンコードする。tables があればコンテナ形式（名前・定数をインデックス参照）
+fn encode_code(
+    instructions: &[Instruction],
+    tables: Option<&Tables>,
+) -> Result<Vec<u8>, EncodeError> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut total = 0usize;
+    for inst in instructions {
+        offsets.push(total);
+        total += encoded_len(inst, tables);
+    }
+    offsets.push(total);
+    let offset_of = |target: usize| -> Result<[u8; 4], EncodeError> {
+        let offset = *offsets
+            .get(target)
+            .ok_or(EncodeError::JumpOutOfBounds(target))?;
+        let offset = u32::try_from(offset).map_err(|_| EncodeError::TooLarge)?;
+        Ok(offset.to_le_bytes())
+    };
+
+    let mut out = Vec::with_capacity(total);
+    for inst in instructions {
+        if let (Some(tables), true) = (tables, is_constant_load(inst)) {
+            let (dst, value) = match inst {
+                Instruction::LoadI32 { dst, value } => (*dst, Value::I32(*value)),
+                Instruction::LoadF32 { dst, value } => (*dst, Value::F32(*value)),
+                Instruction::LoadI64 { dst, value } => (*dst, Value::I64(*value)),
+                Instruction::LoadF64 { dst, value } => (*dst, Value::F64(*value)),
+                Instruction::LoadVec { dst, value } => (*dst, value.clone()),
+                _ => unreachable!("is_constant_load covers only constant loads"),
+            };
+            let index = tables.constant_index[&value.bits_key()];
+            out.extend([OpCode::LoadConst as u8, dst]);
+            out.extend_from_slice(&index.to_le_bytes());
+            continue;
+        }
+        out.push(inst.opcode() as u8);
+        match inst {
+            Instruction::LoadInput { dst, name } | Instruction::ReadStore { dst, name } => {
+                out.push(*dst);
+                push_name(&mut out, name, tables)?;
+            }
+            Instruction::WriteStore { src, name } => {
+                out.push(*src);
+                push_name(&mut out, name, tables)?;
+            }
+            Instruction::Emit { src, name } => {
+                out.push(src.unwrap_or(NO_PAYLOAD));
+                push_name(&mut out, name, tables)?;
+            }
+            Instruction::LoadI32 { dst, value } => {
+                out.push(*dst);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            Instruction::LoadF32 { dst, value } => {
+                out.push(*dst);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            Instruction::LoadI64 { dst, value } => {
+                out.push(*dst);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            Instruction::LoadF64 { dst, value } => {
+                out.push(*dst);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            Instruction::LoadBool { dst, value } => out.extend([*dst, *value as u8]),
+            Instruction::StoreOutput { src } | Instruction::Ret { src } => out.push(*src),
+            Instruction::Random { dst } => out.push(*dst),
+            Instruction::RandomRange { dst, lo, hi } => out.extend([*dst, *lo, *hi]),
+            Instruction::Call { dst, name, args } => {
+                out.push(*dst);
+                push_name(&mut out, name, tables)?;
+                out.push(u8::try_from(args.len()).map_err(|_| EncodeError::TooLarge)?);
+                out.extend_from_slice(args);
+            }
+            Instruction::ForEach {
+                dst,
+                list,
+                init,
+                name,
+            } => {
+                out.extend([*dst, *list, *init]);
+                push_name(&mut out, name, tables)?;
+            }
+            Instruction::ListMap { dst, list, name } => {
+                out.extend([*dst, *list]);
+                push_name(&mut out, name, tables)?;
+            }
+            Instruction::Jump { target } => out.extend(offset_of(*target)?),
+            Instruction::JumpIfFalse { cond, target }
+            | Instruction::JumpIfTrue { cond, target } => {
+                out.push(*cond);
+                out.extend(offset_of(*target)?);
+            }
+            Instruction::Abs { dst, src }
+            | Instruction::Floor { dst, src }
+            | Instruction::Ceil { dst, src }
+            | Instruction::Sqrt { dst, src }
+            | Instruction::Sin { dst, src }
+            | Instruction::Cos { dst, src }
+            | Instruction::CastI32 { dst, src }
+            | Instruction::CastF32 { dst, src }
+            | Instruction::CastI64 { dst, src }
+            | Instruction::CastF64 { dst, src }
+            | Instruction::Not { dst, src }
+            | Instruction::Length { dst, src }
+            | Instruction::Normalize { dst, src }
+            | Instruction::ListLen { dst, src }
+            | Instruction::ListSum { dst, src }
+            | Instruction::ListMin { dst, src }
+            | Instruction::ListMax { dst, src } => out.extend([*dst, *src]),
+            Instruction::Clamp { dst, src, lo, hi } => out.extend([*dst, *src, *lo, *hi]),
+            Instruction::Lerp { dst, a, b, t } => out.extend([*dst, *a, *b, *t]),
+            Instruction::Select { dst, cond, a, b } => out.extend([*dst, *cond, *a, *b]),
+            Instruction::LoadVec { dst, value } => {
+                let components = value.components().ok_or(EncodeError::InvalidVector)?;
+                out.extend([*dst, components.len() as u8]);
+                for c in components {
+                    out.extend_from_slice(&c.to_le_bytes());
+                }
+            }
+            Instruction::MakeVec { dst, n, srcs } => {
+                if !matches!(n, 2 | 3) {
+                    return Err(EncodeError::InvalidVector);
+                }
+                out.extend([*dst, *n]);
+                out.extend_from_slice(&srcs[..*n as usize]);
+            }
+            Instruction::Extract { dst, src, index } => out.extend([*dst, *src, *index]),
+            Instruction::Add { dst, src_a, src_b }
+            | Instruction::Sub { dst, src_a, src_b }
+            | Instruction::Mul { dst, src_a, src_b }
+            | Instruction::Div { dst, src_a, src_b }
+            | Instruction::Lt { dst, src_a, src_b }
+            | Instruction::Gt { dst, src_a, src_b }
+            | Instruction::Eq { dst, src_a, src_b }
+            | Instruction::Min { dst, src_a, src_b }
+            | Instruction::Max { dst, src_a, src_b }
+            | Instruction::Atan2 { dst, src_a, src_b }
+            | Instruction::Pow { dst, src_a, src_b }
+            | Instruction::Mod { dst, src_a, src_b }
+            | Instruction::And { dst, src_a, src_b }
+            | Instruction::Or { dst, src_a, src_b }
+            | Instruction::Xor { dst, src_a, src_b }
+            | Instruction::Le { dst, src_a, src_b }
+            | Instruction::Ge { dst, src_a, src_b }
+            | Instruction::Ne { dst, src_a, src_b }
+            | Instruction::Dot { dst, src_a, src_b } => out.extend([*dst, *src_a, *src_b]),
+            Instruction::ListIndex { dst, list, index } => out.extend([*dst, *list, *index]),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, format_instructions};
+    use crate::decode::{decode_bytecode, DecodeError, DecodeErrorKind};
+
+    const SOURCE: &str = r#"
+        load_input r0 "dt"
+        load_f32 r1 0.5
+        load_f32 r2 0.5
+        load_vec r3 1.0 -0.0
+        load_bool r4 true
+        jump_if_false r4 L0
+        mul r0 r0 r1
+    L0:
+        read_store r5 "score"
+        write_store r0 "score"
+        store_output r0
+    "#;
+
+    #[test]
+    fn container_decodes_to_same_instructions_as_legacy() {
+        let legacy = assemble(SOURCE).expect("assemble");
+        let instructions = decode_bytecode(&legacy).expect("decode legacy");
+        for checksum in [false, true] {
+            let packed = encode_container(&instructions, checksum).expect("encode container");
+            assert!(packed.starts_with(&container::MAGIC));
+            let decoded = decode_bytecode(&packed).expect("decode container");
+            assert_eq!(
+                format_instructions(&decoded),
+                format_instructions(&instructions)
+            );
+            // 再エンコードしても同じバイト列
+            assert_eq!(
+                encode_container(&decoded, checksum).expect("re-encode"),
+                packed
+            );
+        }
+    }
+
+    #[test]
+    fn container_dedups_names_and_constants() {
+        let instructions = decode_bytecode(&assemble(SOURCE).expect("assemble")).expect("decode");
+        let tables = Tables::build(&instructions).expect("tables");
+        assert_eq!(tables.strings, ["dt", "score"]);
+        // 0.5 は 1 つにまとまり、vec の -0.0 は 0.0 と区別される
+        assert_eq!(tables.constants.len(), 2);
+    }
+
+    #[test]
+    fn container_lifts_name_length_limit() {
+        let long = "k".repeat(300);
+        let instructions = vec![Instruction::LoadInput {
+            dst: 0,
+            name: long.as_str().into(),
+        }];
+        assert!(matches!(
+            encode_instructions(&instructions),
+            Err(EncodeError::NameTooLong(_))
+        ));
+        let packed = encode_container(&instructions, false).expect("encode container");
+        assert!(matches!(
+            decode_bytecode(&packed).expect("decode").as_slice(),
+            [Instruction::LoadInput { name, .. }] if name.len() == 300
+        ));
+    }
+
+    #[test]
+    fn index_errors() {
+        // 従来形式に LoadConst は無い
+        assert!(matches!(
+            decode_bytecode(&[44, 0, 0, 0]),
+            Err(DecodeError {
+                kind: DecodeErrorKind::InvalidOpCode(44),
+                ..
+            })
+        ));
+        let code = [44, 0, 3, 0];
+        assert!(matches!(
+            decode_bytecode(&container::write(&[], &[], &code, false)),
+            Err(DecodeError {
+                kind: DecodeErrorKind::ConstantOutOfRange(3),
+                ..
+            })
+        ));
+        let code = [0, 0, 1, 0];
+        assert!(matches!(
+            decode_bytecode(&container::write(&["dt"], &[], &code, false)),
+            Err(DecodeError {
+                kind: DecodeErrorKind::StringOutOfRange(1),
+                ..
+            })
+        ));
+    }
+}
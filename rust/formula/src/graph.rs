@@ -0,0 +1,707 @@
+//! Path: rust/formula/src/graph.rs
+//! Summary: 計算グラフ（ノード・名前付きポート・エッジ）の保持と差分評価
+//!
+//! `Core.FormulaGraph` のグラフを命令列に落とさずにそのまま保持し、ノードごとの前回の値を覚えておく。
+//! 評価では入力・Store 読み出し・定数のノードの値を前回と比べ（ビット列で比較）、値が変わったノードの
+//! 下流だけを再計算する。再計算した結果が前回と同じなら、その先へは変更を伝えない。
+//!
+//! 演算ノードは対応する命令を `vm::eval_pure` で評価するため、型昇格・飽和演算・誤差付き比較・
+//! ゼロ除算などの意味論はバイトコードの実行と一致する。
+//!
+//! ポートはノードの種類ごとに決まる（二項演算は a / b、clamp は a / lo / hi 等、出力・Store 書き込みは value）。
+//! `after` ポートは値を受け取らず、評価順序だけを指定する（Store への書き込みの後に読む等）。
+//! 出力・Store 書き込みは上流が変わらなくても毎回適用する（Store は呼び出しごとに渡されるため）。
+
+use super::decode::Instruction;
+use super::limits::{Budget, Limits};
+use super::list;
+use super::store::StoreAccess;
+use super::value::Value;
+use super::vm::{eval_pure, VmErrorKind};
+use std::collections::{BTreeSet, HashMap};
+
+/// 値を受け取らず、評価順序だけを指定するポート
+const ORDER_PORT: &str = "after";
+
+/// 演算ノードの種類。対応する命令と同じ意味で評価する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Gt,
+    Eq,
+    Min,
+    Max,
+    Atan2,
+    Pow,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Le,
+    Ge,
+    Ne,
+    Dot,
+    Abs,
+    Floor,
+    Ceil,
+    Sqrt,
+    Sin,
+    Cos,
+    Not,
+    Length,
+    Normalize,
+    Clamp,
+    Lerp,
+    Select,
+}
+
+impl Op {
+    const ALL: [Op; 31] = [
+        Op::Add,
+        Op::Sub,
+        Op::Mul,
+        Op::Div,
+        Op::Lt,
+        Op::Gt,
+        Op::Eq,
+        Op::Min,
+        Op::Max,
+        Op::Atan2,
+        Op::Pow,
+        Op::Mod,
+        Op::And,
+        Op::Or,
+        Op::Xor,
+        Op::Le,
+        Op::Ge,
+        Op::Ne,
+        Op::Dot,
+        Op::Abs,
+        Op::Floor,
+        Op::Ceil,
+        Op::Sqrt,
+        Op::Sin,
+        Op::Cos,
+        Op::Not,
+        Op::Length,
+        Op::Normalize,
+        Op::Clamp,
+        Op::Lerp,
+        Op::Select,
+    ];
+
+    /// Elixir のノード種別 atom 名
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Lt => "lt",
+            Op::Gt => "gt",
+            Op::Eq => "eq",
+            Op::Min => "min",
+            Op::Max => "max",
+            Op::Atan2 => "atan2",
+            Op::Pow => "pow",
+            Op::Mod => "mod",
+            Op::And => "and",
+            Op::Or => "or",
+            Op::Xor => "xor",
+            Op::Le => "le",
+            Op::Ge => "ge",
+            Op::Ne => "ne",
+            Op::Dot => "dot",
+            Op::Abs => "abs",
+            Op::Floor => "floor",
+            Op::Ceil => "ceil",
+            Op::Sqrt => "sqrt",
+            Op::Sin => "sin",
+            Op::Cos => "cos",
+            Op::Not => "not",
+            Op::Length => "length",
+            Op::Normalize => "normalize",
+            Op::Clamp => "clamp",
+            Op::Lerp => "lerp",
+            Op::Select => "select",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|op| op.as_str() == name)
+    }
+
+    /// 入力ポート名（命令のオペランド順）
+    fn ports(self) -> &'static [&'static str] {
+        match self {
+            Op::Abs
+            | Op::Floor
+            | Op::Ceil
+            | Op::Sqrt
+            | Op::Sin
+            | Op::Cos
+            | Op::Not
+            | Op::Length
+            | Op::Normalize => &["a"],
+            Op::Clamp => &["a", "lo", "hi"],
+            Op::Lerp => &["a", "b", "t"],
+            Op::Select => &["cond", "a", "b"],
+            _ => &["a", "b"],
+        }
+    }
+
+    /// ポート順にレジスタ 0.. を読み、結果を dst に書く命令
+    fn instruction(self, dst: u8) -> Instruction {
+        let (src_a, src_b) = (0, 1);
+        let src = src_a;
+        match self {
+            Op::Add => Instruction::Add { dst, src_a, src_b },
+            Op::Sub => Instruction::Sub { dst, src_a, src_b },
+            Op::Mul => Instruction::Mul { dst, src_a, src_b },
+            Op::Div => Instruction::Div { dst, src_a, src_b },
+            Op::Lt => Instruction::Lt { dst, src_a, src_b },
+            Op::Gt => Instruction::Gt { dst, src_a, src_b },
+            Op::Eq => Instruction::Eq { dst, src_a, src_b },
+            Op::Min => Instruction::Min { dst, src_a, src_b },
+            Op::Max => Instruction::Max { dst, src_a, src_b },
+            Op::Atan2 => Instruction::Atan2 { dst, src_a, src_b },
+            Op::Pow => Instruction::Pow { dst, src_a, src_b },
+            Op::Mod => Instruction::Mod { dst, src_a, src_b },
+            Op::And => Instruction::And { dst, src_a, src_b },
+            Op::Or => Instruction::Or { dst, src_a, src_b },
+            Op::Xor => Instruction::Xor { dst, src_a, src_b },
+            Op::Le => Instruction::Le { dst, src_a, src_b },
+            Op::Ge => Instruction::Ge { dst, src_a, src_b },
+            Op::Ne => Instruction::Ne { dst, src_a, src_b },
+            Op::Dot => Instruction::Dot { dst, src_a, src_b },
+            Op::Abs => Instruction::Abs { dst, src },
+            Op::Floor => Instruction::Floor { dst, src },
+            Op::Ceil => Instruction::Ceil { dst, src },
+            Op::Sqrt => Instruction::Sqrt { dst, src },
+            Op::Sin => Instruction::Sin { dst, src },
+            Op::Cos => Instruction::Cos { dst, src },
+            Op::Not => Instruction::Not { dst, src },
+            Op::Length => Instruction::Length { dst, src },
+            Op::Normalize => Instruction::Normalize { dst, src },
+            Op::Clamp => Instruction::Clamp {
+                dst,
+                src: 0,
+                lo: 1,
+                hi: 2,
+            },
+            Op::Lerp => Instruction::Lerp {
+                dst,
+                a: 0,
+                b: 1,
+                t: 2,
+            },
+            Op::Select => Instruction::Select {
+                dst,
+                cond: 0,
+                a: 1,
+                b: 2,
+            },
+        }
+    }
+}
+
+/// ノードの種類
+#[derive(Debug, Clone)]
+pub enum NodeKind {
+    /// 外部入力（入力名）
+    Input(String),
+    /// 定数（`:int` / `:float` / `:bool`）
+    Const(Value),
+    /// Store 読み取り（キー）
+    ReadStore(String),
+    /// 演算
+    Op(Op),
+    /// 出力。value ポートの値を出力に加える
+    Output,
+    /// Store 書き込み（キー）。value ポートの値を書く
+    WriteStore(String),
+}
+
+impl NodeKind {
+    fn ports(&self) -> &'static [&'static str] {
+        match self {
+            NodeKind::Input(_) | NodeKind::Const(_) | NodeKind::ReadStore(_) => &[],
+            NodeKind::Op(op) => op.ports(),
+            NodeKind::Output | NodeKind::WriteStore(_) => &["value"],
+        }
+    }
+
+    /// 値を持ち、他のノードのポートに接続できるか
+    fn produces(&self) -> bool {
+        !matches!(self, NodeKind::Output | NodeKind::WriteStore(_))
+    }
+}
+
+/// グラフの構造の誤り（読み込み時に検出する）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    EmptyGraph,
+    DuplicateNode(String),
+    /// エッジ・出力が存在しないノードを指す
+    UnknownNode(String),
+    /// ノードの種類に無いポートへのエッジ。(ノード, ポート)
+    UnknownPort(String, String),
+    /// 同じポートへのエッジが複数ある。(ノード, ポート)
+    DuplicateInput(String, String),
+    /// 接続されていないポートがある。(ノード, ポート)
+    MissingInput(String, String),
+    /// 値を持たないノード（出力・Store 書き込み）から値を受け取ろうとした
+    NotAProducer(String),
+    /// 出力の一覧に出力ノード以外がある
+    NotAnOutput(String),
+    CycleDetected,
+}
+
+/// 評価の失敗と、失敗したノード
+#[derive(Debug)]
+pub struct NodeError {
+    pub node: String,
+    pub kind: VmErrorKind,
+}
+
+#[derive(Debug)]
+struct Node {
+    id: String,
+    kind: NodeKind,
+    /// ポート順の接続元（評価順の添字）
+    inputs: Vec<usize>,
+}
+
+/// 読み込み済みのグラフと、前回の評価で得た各ノードの値
+#[derive(Debug)]
+pub struct Graph {
+    /// 評価順（トポロジカル順）に並べたノード
+    nodes: Vec<Node>,
+    /// 出力ノード（評価順の添字）。評価結果の出力はこの順に並ぶ
+    outputs: Vec<usize>,
+    /// ノードごとの前回の値。未評価・値を持たないノードは None
+    values: Vec<Option<Value>>,
+    /// 直前の評価で値を計算し直したノード（評価順の添字）
+    recomputed: Vec<usize>,
+}
+
+impl Graph {
+    /// ノード (ID, 種類)、エッジ (接続元, 接続先, ポート)、出力ノード ID の一覧からグラフを作る。
+    ///
+    /// 評価順は依存関係を満たす範囲でノードを渡した順に近い順（同時に評価できるノードは先に渡したものから）。
+    pub fn new(
+        nodes: Vec<(String, NodeKind)>,
+        edges: &[(String, String, String)],
+        outputs: &[String],
+    ) -> Result<Self, GraphError> {
+        if nodes.is_empty() {
+            return Err(GraphError::EmptyGraph);
+        }
+        let mut index = HashMap::with_capacity(nodes.len());
+        for (i, (id, _)) in nodes.iter().enumerate() {
+            if index.insert(id.as_str(), i).is_some() {
+                return Err(GraphError::DuplicateNode(id.clone()));
+            }
+        }
+        let lookup = |id: &String| {
+            index
+                .get(id.as_str())
+                .copied()
+                .ok_or_else(|| GraphError::UnknownNode(id.clone()))
+        };
+
+        // ポートごとの接続元と、順序だけの依存を含む後続ノード
+        let mut inputs: Vec<Vec<Option<usize>>> = nodes
+            .iter()
+            .map(|(_, kind)| vec![None; kind.ports().len()])
+            .collect();
+        let mut successors: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+        let mut in_degree = vec![0usize; nodes.len()];
+        for (from, to, port) in edges {
+            let (f, t) = (lookup(from)?, lookup(to)?);
+            if port != ORDER_PORT {
+                let slot = nodes[t]
+                    .1
+                    .ports()
+                    .iter()
+                    .position(|p| p == port)
+                    .ok_or_else(|| GraphError::UnknownPort(to.clone(), port.clone()))?;
+                if !nodes[f].1.produces() {
+                    return Err(GraphError::NotAProducer(from.clone()));
+                }
+                if inputs[t][slot].replace(f).is_some() {
+                    return Err(GraphError::DuplicateInput(to.clone(), port.clone()));
+                }
+            }
+            successors[f].push(t);
+            in_degree[t] += 1;
+        }
+        for ((id, kind), ports) in nodes.iter().zip(&inputs) {
+            if let Some(slot) = ports.iter().position(Option::is_none) {
+                return Err(GraphError::MissingInput(
+                    id.clone(),
+                    kind.ports()[slot].to_string(),
+                ));
+            }
+        }
+        let outputs = outputs
+            .iter()
+            .map(|id| match lookup(id)? {
+                i if matches!(nodes[i].1, NodeKind::Output) => Ok(i),
+                _ => Err(GraphError::NotAnOutput(id.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Kahn 法。準備のできたノードのうち添字の小さいものから取り出す
+        let mut ready: BTreeSet<usize> = (0..nodes.len()).filter(|&i| in_degree[i] == 0).collect();
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some(i) = ready.pop_first() {
+            order.push(i);
+            for &s in &successors[i] {
+                in_degree[s] -= 1;
+                if in_degree[s] == 0 {
+                    ready.insert(s);
+                }
+            }
+        }
+        if order.len() < nodes.len() {
+            return Err(GraphError::CycleDetected);
+        }
+
+        let mut position = vec![0; nodes.len()];
+        for (p, &i) in order.iter().enumerate() {
+            position[i] = p;
+        }
+        let mut nodes: Vec<Option<(String, NodeKind)>> = nodes.into_iter().map(Some).collect();
+        let nodes: Vec<Node> = order
+            .iter()
+            .map(|&i| {
+                let (id, kind) = nodes[i].take().expect("each node is visited once");
+                let inputs = inputs[i]
+                    .iter()
+                    .map(|from| position[from.expect("inputs are checked above")])
+                    .collect();
+                Node { id, kind, inputs }
+            })
+            .collect();
+        Ok(Self {
+            values: vec![None; nodes.len()],
+            outputs: outputs.into_iter().map(|i| position[i]).collect(),
+            recomputed: Vec::new(),
+            nodes,
+        })
+    }
+
+    /// 入力と Store でグラフを評価し、出力ノードの値を出力の一覧の順に返す。store は書き込みで更新する。
+    ///
+    /// 前回から値の変わった入力・Store 読み出しの下流の演算ノードだけを再計算する。
+    /// limits の命令数は再計算した演算ノードの数で数える。
+    /// 失敗したときは覚えていた値をすべて捨て、次の評価はすべてのノードを計算し直す。
+    pub fn evaluate(
+        &mut self,
+        inputs: &HashMap<String, Value>,
+        store: &mut HashMap<String, Value>,
+        limits: &Limits,
+    ) -> Result<Vec<Value>, NodeError> {
+        self.recomputed.clear();
+        match self.evaluate_nodes(inputs, store, limits) {
+            Ok(outputs) => Ok(outputs),
+            Err((i, kind)) => {
+                self.values.iter_mut().for_each(|v| *v = None);
+                Err(NodeError {
+                    node: self.nodes[i].id.clone(),
+                    kind,
+                })
+            }
+        }
+    }
+
+    /// 直前の評価で値を計算し直したノードの ID（評価順）。変わった入力・Store 読み出しと、再計算した演算ノード
+    pub fn recomputed(&self) -> impl Iterator<Item = &str> {
+        self.recomputed.iter().map(|&i| self.nodes[i].id.as_str())
+    }
+
+    fn evaluate_nodes(
+        &mut self,
+        inputs: &HashMap<String, Value>,
+        store: &mut HashMap<String, Value>,
+        limits: &Limits,
+    ) -> Result<Vec<Value>, (usize, VmErrorKind)> {
+        let mut changed = vec![false; self.nodes.len()];
+        let mut fuel = limits.max_instructions;
+        for (i, node) in self.nodes.iter().enumerate() {
+            let fresh = match &node.kind {
+                NodeKind::Input(name) => {
+                    let value = inputs
+                        .get(name)
+                        .ok_or_else(|| (i, VmErrorKind::InputNotFound(name.clone())))?;
+                    list::check_len(value, limits).map_err(|e| (i, e))?;
+                    Some(value.clone())
+                }
+                NodeKind::Const(value) => self.values[i].is_none().then(|| value.clone()),
+                NodeKind::ReadStore(key) => {
+                    let value = store
+                        .read(key)
+                        .map_err(|e| (i, e))?
+                        .ok_or_else(|| (i, VmErrorKind::StoreNotFound(key.clone())))?;
+                    list::check_len(&value, limits).map_err(|e| (i, e))?;
+                    Some(value)
+                }
+                NodeKind::Op(op) => {
+                    if self.values[i].is_some() && !node.inputs.iter().any(|&from| changed[from]) {
+                        continue;
+                    }
+                    if fuel == 0 {
+                        return Err((
+                            i,
+                            VmErrorKind::BudgetExceeded(
+                                Budget::Instructions,
+                                limits.max_instructions,
+                            ),
+                        ));
+                    }
+                    fuel -= 1;
+                    let mut registers: [Option<Value>; 4] = Default::default();
+                    for (register, &from) in registers.iter_mut().zip(&node.inputs) {
+                        *register = self.values[from].clone();
+                    }
+                    let dst = node.inputs.len() as u8;
+                    let (_, value) = eval_pure(&op.instruction(dst), &registers)
+                        .map_err(|e| (i, e))?
+                        .expect("graph operations are pure instructions");
+                    self.recomputed.push(i);
+                    Some(value)
+                }
+                NodeKind::Output => continue,
+                NodeKind::WriteStore(key) => {
+                    let value = self.values[node.inputs[0]]
+                        .clone()
+                        .expect("producers are evaluated first");
+                    store.write(key, value, limits).map_err(|e| (i, e))?;
+                    continue;
+                }
+            };
+            let Some(fresh) = fresh else { continue };
+            let same = self.values[i]
+                .as_ref()
+                .is_some_and(|old| old.bits_key() == fresh.bits_key());
+            if !same {
+                if !matches!(node.kind, NodeKind::Op(_)) {
+                    self.recomputed.push(i);
+                }
+                changed[i] = true;
+                self.values[i] = Some(fresh);
+            }
+        }
+
+        if self.outputs.len() > limits.max_outputs {
+            let i = self.outputs[limits.max_outputs];
+            return Err((
+                i,
+                VmErrorKind::BudgetExceeded(Budget::Outputs, limits.max_outputs),
+            ));
+        }
+        Ok(self
+            .outputs
+            .iter()
+            .map(|&i| {
+                let from = self.nodes[i].inputs[0];
+                self.values[from]
+                    .clone()
+                    .expect("producers are evaluated first")
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, kind: NodeKind) -> (String, NodeKind) {
+        (id.to_string(), kind)
+    }
+
+    fn edge(from: &str, to: &str, port: &str) -> (String, String, String) {
+        (from.to_string(), to.to_string(), port.to_string())
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// out = (x + y) * k、flag = x < 10
+    fn sample() -> Graph {
+        Graph::new(
+            vec![
+                node("x", NodeKind::Input("x".into())),
+                node("y", NodeKind::Input("y".into())),
+                node("k", NodeKind::Const(Value::I32(2))),
+                node("sum", NodeKind::Op(Op::Add)),
+                node("scaled", NodeKind::Op(Op::Mul)),
+                node("ten", NodeKind::Const(Value::I32(10))),
+                node("small", NodeKind::Op(Op::Lt)),
+                node("out", NodeKind::Output),
+                node("flag", NodeKind::Output),
+            ],
+            &[
+                edge("x", "sum", "a"),
+                edge("y", "sum", "b"),
+                edge("sum", "scaled", "a"),
+                edge("k", "scaled", "b"),
+                edge("x", "small", "a"),
+                edge("ten", "small", "b"),
+                edge("scaled", "out", "value"),
+                edge("small", "flag", "value"),
+            ],
+            &ids(&["out", "flag"]),
+        )
+        .unwrap()
+    }
+
+    fn inputs(x: i32, y: i32) -> HashMap<String, Value> {
+        HashMap::from([("x".into(), Value::I32(x)), ("y".into(), Value::I32(y))])
+    }
+
+    fn eval(graph: &mut Graph, inputs: &HashMap<String, Value>) -> Vec<String> {
+        let outputs = graph
+            .evaluate(inputs, &mut HashMap::new(), &Limits::default())
+            .unwrap();
+        outputs.iter().map(Value::to_string).collect()
+    }
+
+    #[test]
+    fn only_nodes_downstream_of_changed_inputs_are_recomputed() {
+        let mut graph = sample();
+        assert_eq!(eval(&mut graph, &inputs(1, 2)), ["6", "true"]);
+        assert_eq!(
+            graph.recomputed().collect::<Vec<_>>(),
+            ["x", "y", "k", "sum", "scaled", "ten", "small"]
+        );
+
+        assert_eq!(eval(&mut graph, &inputs(1, 2)), ["6", "true"]);
+        assert_eq!(graph.recomputed().count(), 0);
+
+        assert_eq!(eval(&mut graph, &inputs(1, 5)), ["12", "true"]);
+        assert_eq!(
+            graph.recomputed().collect::<Vec<_>>(),
+            ["y", "sum", "scaled"]
+        );
+    }
+
+    #[test]
+    fn unchanged_results_stop_propagation() {
+        let mut graph = sample();
+        eval(&mut graph, &inputs(1, 2));
+        // x + y は 3 のまま: sum は再計算するが scaled は再計算しない
+        assert_eq!(eval(&mut graph, &inputs(2, 1)), ["6", "true"]);
+        assert_eq!(
+            graph.recomputed().collect::<Vec<_>>(),
+            ["x", "y", "sum", "small"]
+        );
+    }
+
+    #[test]
+    fn arithmetic_matches_the_vm() {
+        let mut graph = sample();
+        let floats = HashMap::from([("x".into(), Value::F32(0.25)), ("y".into(), Value::I32(1))]);
+        assert_eq!(eval(&mut graph, &floats), ["2.5", "true"]);
+        let saturating = inputs(i32::MAX, 1);
+        assert_eq!(eval(&mut graph, &saturating)[0], i32::MAX.to_string());
+    }
+
+    #[test]
+    fn store_reads_follow_ordered_writes() {
+        let mut graph = Graph::new(
+            vec![
+                node("read", NodeKind::ReadStore("hp".into())),
+                node("x", NodeKind::Input("x".into())),
+                node("write", NodeKind::WriteStore("hp".into())),
+                node("out", NodeKind::Output),
+            ],
+            &[
+                edge("x", "write", "value"),
+                edge("write", "read", ORDER_PORT),
+                edge("read", "out", "value"),
+            ],
+            &ids(&["out"]),
+        )
+        .unwrap();
+        let mut store = HashMap::from([("hp".to_string(), Value::I32(1))]);
+        let x = HashMap::from([("x".into(), Value::I32(7))]);
+        let outputs = graph.evaluate(&x, &mut store, &Limits::default()).unwrap();
+        assert_eq!(outputs[0].to_string(), "7");
+        assert_eq!(store["hp"].to_string(), "7");
+    }
+
+    #[test]
+    fn errors_name_the_node_and_reset_the_cache() {
+        let mut graph = sample();
+        eval(&mut graph, &inputs(1, 2));
+        let err = graph
+            .evaluate(&HashMap::new(), &mut HashMap::new(), &Limits::default())
+            .unwrap_err();
+        assert_eq!(err.node, "x");
+        assert!(matches!(err.kind, VmErrorKind::InputNotFound(ref n) if n == "x"));
+
+        eval(&mut graph, &inputs(1, 2));
+        assert_eq!(graph.recomputed().count(), 7);
+
+        let limits = Limits {
+            max_instructions: 1,
+            ..Limits::default()
+        };
+        let err = graph
+            .evaluate(&inputs(3, 4), &mut HashMap::new(), &limits)
+            .unwrap_err();
+        assert_eq!(err.node, "scaled");
+        assert!(matches!(
+            err.kind,
+            VmErrorKind::BudgetExceeded(Budget::Instructions, 1)
+        ));
+    }
+
+    #[test]
+    fn malformed_graphs_are_rejected() {
+        let add = || node("add", NodeKind::Op(Op::Add));
+        let one = || node("one", NodeKind::Const(Value::I32(1)));
+        assert_eq!(
+            Graph::new(vec![one(), add()], &[edge("one", "add", "a")], &[]).unwrap_err(),
+            GraphError::MissingInput("add".into(), "b".into())
+        );
+        assert_eq!(
+            Graph::new(vec![one(), add()], &[edge("one", "add", "c")], &[]).unwrap_err(),
+            GraphError::UnknownPort("add".into(), "c".into())
+        );
+        assert_eq!(
+            Graph::new(
+                vec![one(), add()],
+                &[edge("one", "add", "a"), edge("one", "add", "a")],
+                &[]
+            )
+            .unwrap_err(),
+            GraphError::DuplicateInput("add".into(), "a".into())
+        );
+        assert_eq!(
+            Graph::new(
+                vec![add()],
+                &[edge("add", "add", "a"), edge("add", "add", "b")],
+                &[]
+            )
+            .unwrap_err(),
+            GraphError::CycleDetected
+        );
+        assert_eq!(
+            Graph::new(vec![one()], &[], &ids(&["one"])).unwrap_err(),
+            GraphError::NotAnOutput("one".into())
+        );
+        assert_eq!(
+            Graph::new(vec![one()], &[edge("one", "two", "a")], &[]).unwrap_err(),
+            GraphError::UnknownNode("two".into())
+        );
+    }
+}
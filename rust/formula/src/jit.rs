@@ -0,0 +1,1175 @@
+//! Path: rust/formula/src/jit.rs
+//! Summary: 繰り返し実行するプログラムを Cranelift でネイティブコードにする JIT（feature = "jit"）
+//!
+//! `Program` ごとに実行回数を数え、`HOT_THRESHOLD` 回を超えたら入力の型の組ごとに 1 回だけコンパイルする。
+//! 対象は I32 / F32 / Bool のスカラーだけを扱い、全経路でレジスタの型が 1 つに定まるプログラム
+//! （型の状態は命令順の 1 回の走査で求める。後方ジャンプが無いため合流点で結合すれば足りる）。
+//! 対象外の命令（Store・呼び出し・ベクトル・リスト・64 bit 値・乱数・Emit 等）を含む・型が定まらない・
+//! 未初期化のレジスタを読みうるプログラムはコンパイルせず、常に解釈実行する。
+//!
+//! ネイティブコードの意味論は vm.rs / math.rs に合わせる（I32 の飽和演算、MIN / -1、誤差付きの eq、
+//! NaN を伝播する min / max、Bool の 0 / 1 への強制変換）。実行時エラー（ゼロ除算・sqrt の定義域外・出力数の上限）を
+//! 検出したネイティブコードは何も返さずに失敗し、同じ入力で解釈実行し直す。エラーとその位置は常に解釈実行が作る。
+//! Store に触れないため、フォールバックしても副作用は重ならない。命令数が `max_instructions` を超える
+//! プログラムはフューエルを数えないネイティブコードでは実行しない。
+
+use super::decode::{Instruction, Name, REGISTER_COUNT};
+use super::limits::Limits;
+use super::value::Value;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Type};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// この回数だけ解釈実行したプログラムをコンパイルする
+pub(super) const HOT_THRESHOLD: u32 = 16;
+
+/// 入力の型の組を 2 bit ずつ u64 のキーに詰めるため、入力名はこの数まで
+const MAX_INPUTS: usize = 32;
+
+/// ネイティブコードで扱う値の型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    I32,
+    F32,
+    Bool,
+}
+
+impl Ty {
+    fn of(value: &Value) -> Option<Ty> {
+        match value {
+            Value::I32(_) => Some(Ty::I32),
+            Value::F32(_) => Some(Ty::F32),
+            Value::Bool(_) => Some(Ty::Bool),
+            _ => None,
+        }
+    }
+
+    /// Bool は 0 / 1 の I8（Cranelift の比較結果と同じ）
+    fn ir(self) -> Type {
+        match self {
+            Ty::I32 => types::I32,
+            Ty::F32 => types::F32,
+            Ty::Bool => types::I8,
+        }
+    }
+
+    /// 四則演算・min / max の結果の型（`value::promote` の I32 / F32 の場合）
+    fn numeric(a: Ty, b: Ty) -> Ty {
+        if a == Ty::I32 && b == Ty::I32 {
+            Ty::I32
+        } else {
+            Ty::F32
+        }
+    }
+}
+
+/// 命令の直前のレジスタの型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Uninit,
+    Ty(Ty),
+    /// 経路によって型が異なる（または未初期化の経路がある）
+    Conflict,
+}
+
+impl Slot {
+    fn join(self, other: Slot) -> Slot {
+        if self == other {
+            self
+        } else {
+            Slot::Conflict
+        }
+    }
+}
+
+type Types = [Slot; REGISTER_COUNT];
+
+/// 入力スロット（入力名の順に 32 bit ずつ）、出力バッファ（1 出力あたり型タグと値の 32 bit × 2）、出力数の上限。
+/// 戻り値は出力数。負なら実行時エラーで、解釈実行し直す
+type Entry = unsafe extern "C" fn(*const u32, *mut u32, u64) -> i64;
+
+/// コンパイル済みのネイティブコード
+struct Compiled {
+    entry: Entry,
+    /// StoreOutput の命令数（前方ジャンプだけなので、実行される出力数の上限）
+    max_outputs: usize,
+    module: Option<JITModule>,
+}
+
+// SAFETY: finalize 後のコードは不変で、entry は入出力のバッファにしか触れない。
+// module は Drop で解放するまで参照しない。
+unsafe impl Send for Compiled {}
+unsafe impl Sync for Compiled {}
+
+impl Drop for Compiled {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: entry はこの Compiled と共に捨てられ、以後呼ばれない
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+impl Compiled {
+    /// 入力スロットで実行する。実行時エラーなら None
+    fn call(&self, slots: &[u32], limits: &Limits) -> Option<Vec<Value>> {
+        let mut buffer = vec![0u32; self.max_outputs * 2];
+        let max_outputs = u64::try_from(limits.max_outputs).unwrap_or(u64::MAX);
+        // SAFETY: 入力スロットは入力名の数、出力バッファは StoreOutput の数 × 2 だけ確保している
+        let count = unsafe { (self.entry)(slots.as_ptr(), buffer.as_mut_ptr(), max_outputs) };
+        let count = usize::try_from(count).ok()?;
+        let outputs = buffer[..count * 2]
+            .chunks_exact(2)
+            .map(|pair| match pair[0] {
+                0 => Value::I32(pair[1] as i32),
+                1 => Value::F32(f32::from_bits(pair[1])),
+                _ => Value::Bool(pair[1] != 0),
+            })
+            .collect();
+        Some(outputs)
+    }
+}
+
+/// プログラムごとの実行回数と、入力の型の組ごとのコンパイル結果（None はコンパイルできない）
+#[derive(Default)]
+pub(super) struct JitCache {
+    calls: AtomicU32,
+    /// LoadInput の入力名（初出順）。None ならコンパイル対象外の命令を含む
+    inputs: OnceLock<Option<Vec<Name>>>,
+    compiled: RwLock<HashMap<u64, Option<Arc<Compiled>>>>,
+}
+
+impl fmt::Debug for JitCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JitCache")
+            .field("calls", &self.calls.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+impl JitCache {
+    #[cfg(test)]
+    pub(super) fn is_compiled(&self) -> bool {
+        self.compiled
+            .read()
+            .is_ok_and(|compiled| compiled.values().any(Option::is_some))
+    }
+
+    /// 十分に実行されたプログラムをネイティブコードで実行し、出力を返す。
+    /// None なら解釈実行する（まだ実行回数が少ない・対象外・入力の型が対象外・実行時エラー）
+    pub(super) fn run(
+        &self,
+        instructions: &[Instruction],
+        inputs: &HashMap<String, Value>,
+        limits: &Limits,
+    ) -> Option<Vec<Value>> {
+        if self.calls.load(Ordering::Relaxed) < HOT_THRESHOLD {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if instructions.len() > limits.max_instructions {
+            return None;
+        }
+        let names = self
+            .inputs
+            .get_or_init(|| input_names(instructions))
+            .as_ref()?;
+        let (key, signature, slots) = bind_inputs(names, inputs)?;
+        let cached = self.compiled.read().ok()?.get(&key).cloned();
+        let compiled = match cached {
+            Some(compiled) => compiled?,
+            None => {
+                let compiled = compile(instructions, names, &signature).map(Arc::new);
+                self.compiled
+                    .write()
+                    .ok()?
+                    .entry(key)
+                    .or_insert(compiled)
+                    .clone()?
+            }
+        };
+        compiled.call(&slots, limits)
+    }
+}
+
+/// 対象外の命令があれば None
+fn input_names(instructions: &[Instruction]) -> Option<Vec<Name>> {
+    let mut names: Vec<Name> = Vec::new();
+    for inst in instructions {
+        match inst {
+            Instruction::LoadInput { name, .. } => {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+            inst if supported(inst) => {}
+            _ => return None,
+        }
+    }
+    (names.len() <= MAX_INPUTS).then_some(names)
+}
+
+/// 入力の型の組のキー、型の組、入力スロット。対象外の型・欠けた入力があれば None（欠けた入力は解釈実行がエラーにする）
+fn bind_inputs(
+    names: &[Name],
+    inputs: &HashMap<String, Value>,
+) -> Option<(u64, Vec<Ty>, Vec<u32>)> {
+    let mut key = 0u64;
+    let mut signature = Vec::with_capacity(names.len());
+    let mut slots = Vec::with_capacity(names.len());
+    for (i, name) in names.iter().enumerate() {
+        let value = inputs.get(&**name)?;
+        let ty = Ty::of(value)?;
+        let slot = match *value {
+            Value::I32(v) => v as u32,
+            Value::F32(v) => v.to_bits(),
+            Value::Bool(v) => v as u32,
+            _ => return None,
+        };
+        key |= (ty as u64 + 1) << (2 * i);
+        signature.push(ty);
+        slots.push(slot);
+    }
+    Some((key, signature, slots))
+}
+
+fn supported(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::LoadInput { .. }
+            | Instruction::LoadI32 { .. }
+            | Instruction::LoadF32 { .. }
+            | Instruction::LoadBool { .. }
+            | Instruction::Add { .. }
+            | Instruction::Sub { .. }
+            | Instruction::Mul { .. }
+            | Instruction::Div { .. }
+            | Instruction::Lt { .. }
+            | Instruction::Gt { .. }
+            | Instruction::Le { .. }
+            | Instruction::Ge { .. }
+            | Instruction::Eq { .. }
+            | Instruction::Ne { .. }
+            | Instruction::And { .. }
+            | Instruction::Or { .. }
+            | Instruction::Xor { .. }
+            | Instruction::Not { .. }
+            | Instruction::Select { .. }
+            | Instruction::Min { .. }
+            | Instruction::Max { .. }
+            | Instruction::Abs { .. }
+            | Instruction::Floor { .. }
+            | Instruction::Ceil { .. }
+            | Instruction::Sqrt { .. }
+            | Instruction::StoreOutput { .. }
+            | Instruction::Jump { .. }
+            | Instruction::JumpIfFalse { .. }
+            | Instruction::JumpIfTrue { .. }
+    )
+}
+
+/// 命令が書き込むレジスタと結果の型。読むレジスタの型が定まらなければ Err(())
+fn result_type(
+    inst: &Instruction,
+    types: &Types,
+    input: impl Fn(&str) -> Ty,
+) -> Result<Option<(u8, Ty)>, ()> {
+    let ty = |r: &u8| match types[*r as usize] {
+        Slot::Ty(t) => Ok(t),
+        _ => Err(()),
+    };
+    let result = match inst {
+        Instruction::LoadInput { dst, name } => (*dst, input(name)),
+        Instruction::LoadI32 { dst, .. } => (*dst, Ty::I32),
+        Instruction::LoadF32 { dst, .. } => (*dst, Ty::F32),
+        Instruction::LoadBool { dst, .. } => (*dst, Ty::Bool),
+        Instruction::Add { dst, src_a, src_b }
+        | Instruction::Sub { dst, src_a, src_b }
+        | Instruction::Mul { dst, src_a, src_b }
+        | Instruction::Div { dst, src_a, src_b }
+        | Instruction::Min { dst, src_a, src_b }
+        | Instruction::Max { dst, src_a, src_b } => (*dst, Ty::numeric(ty(src_a)?, ty(src_b)?)),
+        Instruction::Lt { dst, src_a, src_b }
+        | Instruction::Gt { dst, src_a, src_b }
+        | Instruction::Le { dst, src_a, src_b }
+        | Instruction::Ge { dst, src_a, src_b }
+        | Instruction::Eq { dst, src_a, src_b }
+        | Instruction::Ne { dst, src_a, src_b }
+        | Instruction::And { dst, src_a, src_b }
+        | Instruction::Or { dst, src_a, src_b }
+        | Instruction::Xor { dst, src_a, src_b } => {
+            ty(src_a)?;
+            ty(src_b)?;
+            (*dst, Ty::Bool)
+        }
+        Instruction::Not { dst, src } => {
+            ty(src)?;
+            (*dst, Ty::Bool)
+        }
+        Instruction::Abs { dst, src }
+        | Instruction::Floor { dst, src }
+        | Instruction::Ceil { dst, src } => (*dst, Ty::numeric(ty(src)?, Ty::I32)),
+        Instruction::Sqrt { dst, src } => {
+            ty(src)?;
+            (*dst, Ty::F32)
+        }
+        Instruction::Select { dst, cond, a, b } => {
+            ty(cond)?;
+            // 条件で結果の型が変わるプログラムは型が安定しない
+            let (ta, tb) = (ty(a)?, ty(b)?);
+            if ta != tb {
+                return Err(());
+            }
+            (*dst, ta)
+        }
+        Instruction::StoreOutput { src } => {
+            ty(src)?;
+            return Ok(None);
+        }
+        Instruction::JumpIfFalse { cond, .. } | Instruction::JumpIfTrue { cond, .. } => {
+            ty(cond)?;
+            return Ok(None);
+        }
+        Instruction::Jump { .. } => return Ok(None),
+        _ => return Err(()),
+    };
+    Ok(Some(result))
+}
+
+/// 各命令の直前の型の状態（到達しない命令は None）。型が定まらないレジスタを読めば None
+fn analyze(instructions: &[Instruction], input: impl Fn(&str) -> Ty) -> Option<Vec<Option<Types>>> {
+    let mut states: Vec<Option<Types>> = vec![None; instructions.len() + 1];
+    states[0] = Some([Slot::Uninit; REGISTER_COUNT]);
+    let merge = |states: &mut Vec<Option<Types>>, pc: usize, types: &Types| {
+        states[pc] = Some(match &states[pc] {
+            None => *types,
+            Some(prev) => std::array::from_fn(|r| prev[r].join(types[r])),
+        });
+    };
+    for (pc, inst) in instructions.iter().enumerate() {
+        let Some(mut types) = states[pc] else {
+            continue;
+        };
+        if let Some((dst, ty)) = result_type(inst, &types, &input).ok()? {
+            types[dst as usize] = Slot::Ty(ty);
+        }
+        match inst {
+            Instruction::Jump { target } => merge(&mut states, *target, &types),
+            Instruction::JumpIfFalse { target, .. } | Instruction::JumpIfTrue { target, .. } => {
+                merge(&mut states, *target, &types);
+                merge(&mut states, pc + 1, &types);
+            }
+            _ => merge(&mut states, pc + 1, &types),
+        }
+    }
+    Some(states)
+}
+
+/// 型の組に特化したネイティブコードを作る。対象外・型が定まらない・Cranelift が失敗した場合は None
+fn compile(instructions: &[Instruction], names: &[Name], signature: &[Ty]) -> Option<Compiled> {
+    let input = |name: &str| signature[names.iter().position(|n| &**n == name).expect("bound")];
+    let states = analyze(instructions, input)?;
+
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").ok()?;
+    flags.set("use_colocated_libcalls", "false").ok()?;
+    flags.set("is_pic", "false").ok()?;
+    let isa = cranelift_native::builder()
+        .ok()?
+        .finish(settings::Flags::new(flags))
+        .ok()?;
+    if isa.pointer_type() != types::I64 {
+        return None;
+    }
+    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+    let mut ctx = module.make_context();
+    let params = &mut ctx.func.signature.params;
+    params.extend([types::I64, types::I64, types::I64].map(AbiParam::new));
+    ctx.func.signature.returns.push(AbiParam::new(types::I64));
+    let id = module
+        .declare_function("formula", Linkage::Export, &ctx.func.signature)
+        .ok()?;
+
+    let mut builder_ctx = FunctionBuilderContext::new();
+    let builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+    let max_outputs = Codegen::new(builder, instructions, &states, names, signature).emit();
+
+    module.define_function(id, &mut ctx).ok()?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().ok()?;
+    let code = module.get_finalized_function(id);
+    // SAFETY: Entry と同じシグネチャで宣言・定義した関数
+    let entry = unsafe { std::mem::transmute::<*const u8, Entry>(code) };
+    Some(Compiled {
+        entry,
+        max_outputs,
+        module: Some(module),
+    })
+}
+
+/// 値と型の組
+type Typed = (cranelift_codegen::ir::Value, Ty);
+
+struct Codegen<'a, 'f> {
+    b: FunctionBuilder<'f>,
+    instructions: &'a [Instruction],
+    states: &'a [Option<Types>],
+    names: &'a [Name],
+    signature: &'a [Ty],
+    /// ジャンプ先・条件ジャンプの次の命令の先頭ブロック（命令数は終端）
+    blocks: HashMap<usize, Block>,
+    fail: Block,
+    inputs: cranelift_codegen::ir::Value,
+    outputs: cranelift_codegen::ir::Value,
+    max_outputs: cranelift_codegen::ir::Value,
+    count: Variable,
+}
+
+impl<'a, 'f> Codegen<'a, 'f> {
+    fn new(
+        mut b: FunctionBuilder<'f>,
+        instructions: &'a [Instruction],
+        states: &'a [Option<Types>],
+        names: &'a [Name],
+        signature: &'a [Ty],
+    ) -> Self {
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let params = b.block_params(entry).to_vec();
+
+        for r in 0..REGISTER_COUNT as u8 {
+            for ty in [Ty::I32, Ty::F32, Ty::Bool] {
+                b.declare_var(var(r, ty), ty.ir());
+            }
+        }
+        let count = Variable::from_u32(REGISTER_COUNT as u32 * 3);
+        b.declare_var(count, types::I64);
+        let zero = b.ins().iconst(types::I64, 0);
+        b.def_var(count, zero);
+
+        let mut targets = BTreeSet::from([instructions.len()]);
+        for (pc, inst) in instructions.iter().enumerate() {
+            if states[pc].is_none() {
+                continue;
+            }
+            match inst {
+                Instruction::Jump { target } => {
+                    targets.insert(*target);
+                }
+                Instruction::JumpIfFalse { target, .. }
+                | Instruction::JumpIfTrue { target, .. } => {
+                    targets.extend([*target, pc + 1]);
+                }
+                _ => {}
+            }
+        }
+        let blocks = targets
+            .into_iter()
+            .map(|pc| (pc, b.create_block()))
+            .collect();
+        let fail = b.create_block();
+        Self {
+            b,
+            instructions,
+            states,
+            names,
+            signature,
+            blocks,
+            fail,
+            inputs: params[0],
+            outputs: params[1],
+            max_outputs: params[2],
+            count,
+        }
+    }
+
+    /// 関数本体を生成し、StoreOutput の命令数を返す
+    fn emit(mut self) -> usize {
+        let mut open = true;
+        let mut store_outputs = 0;
+        for (pc, inst) in self.instructions.iter().enumerate() {
+            if let Some(&block) = self.blocks.get(&pc) {
+                if open {
+                    self.b.ins().jump(block, &[]);
+                }
+                self.b.switch_to_block(block);
+                open = true;
+            }
+            let Some(types) = self.states[pc] else {
+                continue;
+            };
+            debug_assert!(open, "reachable instructions start in an open block");
+            store_outputs += matches!(inst, Instruction::StoreOutput { .. }) as usize;
+            open = self.instruction(pc, inst, &types);
+        }
+        let end = self.blocks[&self.instructions.len()];
+        if open {
+            self.b.ins().jump(end, &[]);
+        }
+        self.b.switch_to_block(end);
+        let count = self.b.use_var(self.count);
+        self.b.ins().return_(&[count]);
+
+        self.b.switch_to_block(self.fail);
+        let failed = self.b.ins().iconst(types::I64, -1);
+        self.b.ins().return_(&[failed]);
+
+        self.b.seal_all_blocks();
+        self.b.finalize();
+        store_outputs
+    }
+
+    /// 1 命令を生成する。次の命令へ処理が続くなら true
+    fn instruction(&mut self, pc: usize, inst: &Instruction, types: &Types) -> bool {
+        let get = |b: &mut FunctionBuilder, r: &u8| -> Typed {
+            let Slot::Ty(ty) = types[*r as usize] else {
+                unreachable!("analyze rejects reads of untyped registers")
+            };
+            (b.use_var(var(*r, ty)), ty)
+        };
+        let (dst, value) = match inst {
+            Instruction::LoadInput { dst, name } => {
+                let slot = self.names.iter().position(|n| n == name).expect("bound");
+                let offset = (slot * 4) as i32;
+                let flags = MemFlags::trusted();
+                let value = match self.signature[slot] {
+                    Ty::I32 => (
+                        self.b.ins().load(types::I32, flags, self.inputs, offset),
+                        Ty::I32,
+                    ),
+                    Ty::F32 => (
+                        self.b.ins().load(types::F32, flags, self.inputs, offset),
+                        Ty::F32,
+                    ),
+                    Ty::Bool => {
+                        let raw = self.b.ins().load(types::I32, flags, self.inputs, offset);
+                        (self.b.ins().icmp_imm(IntCC::NotEqual, raw, 0), Ty::Bool)
+                    }
+                };
+                (*dst, value)
+            }
+            Instruction::LoadI32 { dst, value } => (
+                *dst,
+                (self.b.ins().iconst(types::I32, *value as i64), Ty::I32),
+            ),
+            Instruction::LoadF32 { dst, value } => (*dst, (self.b.ins().f32const(*value), Ty::F32)),
+            Instruction::LoadBool { dst, value } => (
+                *dst,
+                (self.b.ins().iconst(types::I8, *value as i64), Ty::Bool),
+            ),
+            Instruction::Add { dst, src_a, src_b }
+            | Instruction::Sub { dst, src_a, src_b }
+            | Instruction::Mul { dst, src_a, src_b } => {
+                let (a, b) = (get(&mut self.b, src_a), get(&mut self.b, src_b));
+                (*dst, self.arithmetic(inst, a, b))
+            }
+            Instruction::Div { dst, src_a, src_b } => {
+                let (a, b) = (get(&mut self.b, src_a), get(&mut self.b, src_b));
+                (*dst, self.div(a, b))
+            }
+            Instruction::Lt { dst, src_a, src_b }
+            | Instruction::Gt { dst, src_a, src_b }
+            | Instruction::Le { dst, src_a, src_b }
+            | Instruction::Ge { dst, src_a, src_b } => {
+                let (a, b) = (get(&mut self.b, src_a), get(&mut self.b, src_b));
+                (*dst, (self.compare(inst, a, b), Ty::Bool))
+            }
+            Instruction::Eq { dst, src_a, src_b } => {
+                let (a, b) = (get(&mut self.b, src_a), get(&mut self.b, src_b));
+                (*dst, (self.eq(a, b), Ty::Bool))
+            }
+            Instruction::Ne { dst, src_a, src_b } => {
+                let (a, b) = (get(&mut self.b, src_a), get(&mut self.b, src_b));
+                let eq = self.eq(a, b);
+                (*dst, (self.b.ins().bxor_imm(eq, 1), Ty::Bool))
+            }
+            Instruction::And { dst, src_a, src_b }
+            | Instruction::Or { dst, src_a, src_b }
+            | Instruction::Xor { dst, src_a, src_b } => {
+                let (a, b) = (get(&mut self.b, src_a), get(&mut self.b, src_b));
+                let (a, b) = (self.truthy(a), self.truthy(b));
+                let value = match inst {
+                    Instruction::And { .. } => self.b.ins().band(a, b),
+                    Instruction::Or { .. } => self.b.ins().bor(a, b),
+                    _ => self.b.ins().bxor(a, b),
+                };
+                (*dst, (value, Ty::Bool))
+            }
+            Instruction::Not { dst, src } => {
+                let a = get(&mut self.b, src);
+                let a = self.truthy(a);
+                (*dst, (self.b.ins().bxor_imm(a, 1), Ty::Bool))
+            }
+            Instruction::Select { dst, cond, a, b } => {
+                let cond = get(&mut self.b, cond);
+                let cond = self.truthy(cond);
+                let (a, b) = (get(&mut self.b, a), get(&mut self.b, b));
+                (*dst, (self.b.ins().select(cond, a.0, b.0), a.1))
+            }
+            Instruction::Min { dst, src_a, src_b } | Instruction::Max { dst, src_a, src_b } => {
+                let (a, b) = (get(&mut self.b, src_a), get(&mut self.b, src_b));
+                let min = matches!(inst, Instruction::Min { .. });
+                (*dst, self.min_max(min, a, b))
+            }
+            Instruction::Abs { dst, src } => {
+                let a = get(&mut self.b, src);
+                (*dst, self.abs(a))
+            }
+            Instruction::Floor { dst, src } | Instruction::Ceil { dst, src } => {
+                let a = get(&mut self.b, src);
+                let value = match a.1 {
+                    Ty::I32 => a,
+                    _ => {
+                        let x = self.float(a);
+                        let value = match inst {
+                            Instruction::Floor { .. } => self.b.ins().floor(x),
+                            _ => self.b.ins().ceil(x),
+                        };
+                        (value, Ty::F32)
+                    }
+                };
+                (*dst, value)
+            }
+            Instruction::Sqrt { dst, src } => {
+                let a = get(&mut self.b, src);
+                let x = self.float(a);
+                let zero = self.b.ins().f32const(0.0);
+                let negative = self.b.ins().fcmp(FloatCC::LessThan, x, zero);
+                self.fail_if(negative);
+                (*dst, (self.b.ins().sqrt(x), Ty::F32))
+            }
+            Instruction::StoreOutput { src } => {
+                let a = get(&mut self.b, src);
+                self.store_output(a);
+                return true;
+            }
+            Instruction::Jump { target } => {
+                let block = self.blocks[target];
+                self.b.ins().jump(block, &[]);
+                return false;
+            }
+            Instruction::JumpIfFalse { cond, target }
+            | Instruction::JumpIfTrue { cond, target } => {
+                let c = get(&mut self.b, cond);
+                let c = self.truthy(c);
+                let (taken, next) = (self.blocks[target], self.blocks[&(pc + 1)]);
+                if taken == next {
+                    self.b.ins().jump(next, &[]);
+                } else if matches!(inst, Instruction::JumpIfTrue { .. }) {
+                    self.b.ins().brif(c, taken, &[], next, &[]);
+                } else {
+                    self.b.ins().brif(c, next, &[], taken, &[]);
+                }
+                return false;
+            }
+            _ => unreachable!("analyze rejects unsupported instructions"),
+        };
+        self.b.def_var(var(dst, value.1), value.0);
+        true
+    }
+
+    /// cond が真なら fail へ分岐し、続きのブロックに移る
+    fn fail_if(&mut self, cond: cranelift_codegen::ir::Value) {
+        let next = self.b.create_block();
+        self.b.ins().brif(cond, self.fail, &[], next, &[]);
+        self.b.switch_to_block(next);
+    }
+
+    /// `Value::as_f32`
+    fn float(&mut self, (v, ty): Typed) -> cranelift_codegen::ir::Value {
+        match ty {
+            Ty::I32 => self.b.ins().fcvt_from_sint(types::F32, v),
+            Ty::F32 => v,
+            Ty::Bool => {
+                let wide = self.b.ins().uextend(types::I32, v);
+                self.b.ins().fcvt_from_sint(types::F32, wide)
+            }
+        }
+    }
+
+    /// `Value::as_bool`（NaN は真）
+    fn truthy(&mut self, (v, ty): Typed) -> cranelift_codegen::ir::Value {
+        match ty {
+            Ty::I32 => self.b.ins().icmp_imm(IntCC::NotEqual, v, 0),
+            Ty::F32 => {
+                let zero = self.b.ins().f32const(0.0);
+                self.b.ins().fcmp(FloatCC::NotEqual, v, zero)
+            }
+            Ty::Bool => v,
+        }
+    }
+
+    /// i64 の値を i32 の範囲に飽和させて縮める
+    fn saturate_i32(&mut self, v: cranelift_codegen::ir::Value) -> cranelift_codegen::ir::Value {
+        let lo = self.b.ins().iconst(types::I64, i32::MIN as i64);
+        let hi = self.b.ins().iconst(types::I64, i32::MAX as i64);
+        let below = self.b.ins().icmp(IntCC::SignedLessThan, v, lo);
+        let v = self.b.ins().select(below, lo, v);
+        let above = self.b.ins().icmp(IntCC::SignedGreaterThan, v, hi);
+        let v = self.b.ins().select(above, hi, v);
+        self.b.ins().ireduce(types::I32, v)
+    }
+
+    /// Add / Sub / Mul（I32 同士は飽和演算、それ以外は f32）
+    fn arithmetic(&mut self, inst: &Instruction, a: Typed, b: Typed) -> Typed {
+        if Ty::numeric(a.1, b.1) == Ty::I32 {
+            let x = self.b.ins().sextend(types::I64, a.0);
+            let y = self.b.ins().sextend(types::I64, b.0);
+            let wide = match inst {
+                Instruction::Add { .. } => self.b.ins().iadd(x, y),
+                Instruction::Sub { .. } => self.b.ins().isub(x, y),
+                _ => self.b.ins().imul(x, y),
+            };
+            (self.saturate_i32(wide), Ty::I32)
+        } else {
+            let (x, y) = (self.float(a), self.float(b));
+            let value = match inst {
+                Instruction::Add { .. } => self.b.ins().fadd(x, y),
+                Instruction::Sub { .. } => self.b.ins().fsub(x, y),
+                _ => self.b.ins().fmul(x, y),
+            };
+            (value, Ty::F32)
+        }
+    }
+
+    /// `binary_div`: 0 除算は失敗、I32 の MIN / -1 は i32::MAX
+    fn div(&mut self, a: Typed, b: Typed) -> Typed {
+        if Ty::numeric(a.1, b.1) == Ty::I32 {
+            let zero = self.b.ins().icmp_imm(IntCC::Equal, b.0, 0);
+            self.fail_if(zero);
+            let min = self.b.ins().icmp_imm(IntCC::Equal, a.0, i32::MIN as i64);
+            let minus_one = self.b.ins().icmp_imm(IntCC::Equal, b.0, -1);
+            let overflow = self.b.ins().band(min, minus_one);
+            let one = self.b.ins().iconst(types::I32, 1);
+            let divisor = self.b.ins().select(overflow, one, b.0);
+            let quotient = self.b.ins().sdiv(a.0, divisor);
+            let max = self.b.ins().iconst(types::I32, i32::MAX as i64);
+            (self.b.ins().select(overflow, max, quotient), Ty::I32)
+        } else {
+            let (x, y) = (self.float(a), self.float(b));
+            let zero = self.b.ins().f32const(0.0);
+            let is_zero = self.b.ins().fcmp(FloatCC::Equal, y, zero);
+            self.fail_if(is_zero);
+            (self.b.ins().fdiv(x, y), Ty::F32)
+        }
+    }
+
+    /// Lt / Gt / Le / Ge（NaN を含めば偽）
+    fn compare(&mut self, inst: &Instruction, a: Typed, b: Typed) -> cranelift_codegen::ir::Value {
+        if Ty::numeric(a.1, b.1) == Ty::I32 {
+            let cc = match inst {
+                Instruction::Lt { .. } => IntCC::SignedLessThan,
+                Instruction::Gt { .. } => IntCC::SignedGreaterThan,
+                Instruction::Le { .. } => IntCC::SignedLessThanOrEqual,
+                _ => IntCC::SignedGreaterThanOrEqual,
+            };
+            self.b.ins().icmp(cc, a.0, b.0)
+        } else {
+            let cc = match inst {
+                Instruction::Lt { .. } => FloatCC::LessThan,
+                Instruction::Gt { .. } => FloatCC::GreaterThan,
+                Instruction::Le { .. } => FloatCC::LessThanOrEqual,
+                _ => FloatCC::GreaterThanOrEqual,
+            };
+            let (x, y) = (self.float(a), self.float(b));
+            self.b.ins().fcmp(cc, x, y)
+        }
+    }
+
+    /// `compare_eq`: Bool 同士・I32 同士は厳密、それ以外は f32 で絶対誤差 f32::EPSILON 未満
+    fn eq(&mut self, a: Typed, b: Typed) -> cranelift_codegen::ir::Value {
+        match (a.1, b.1) {
+            (Ty::Bool, Ty::Bool) | (Ty::I32, Ty::I32) => self.b.ins().icmp(IntCC::Equal, a.0, b.0),
+            _ => {
+                let (x, y) = (self.float(a), self.float(b));
+                let diff = self.b.ins().fsub(x, y);
+                let diff = self.b.ins().fabs(diff);
+                let epsilon = self.b.ins().f32const(f32::EPSILON);
+                self.b.ins().fcmp(FloatCC::LessThan, diff, epsilon)
+            }
+        }
+    }
+
+    /// math::min / max（浮動小数点は NaN を伝播し、等しければ a）
+    fn min_max(&mut self, min: bool, a: Typed, b: Typed) -> Typed {
+        if Ty::numeric(a.1, b.1) == Ty::I32 {
+            let cc = if min {
+                IntCC::SignedGreaterThan
+            } else {
+                IntCC::SignedLessThan
+            };
+            let pick_b = self.b.ins().icmp(cc, a.0, b.0);
+            (self.b.ins().select(pick_b, b.0, a.0), Ty::I32)
+        } else {
+            let (x, y) = (self.float(a), self.float(b));
+            let cc = if min {
+                FloatCC::GreaterThan
+            } else {
+                FloatCC::LessThan
+            };
+            let pick_b = self.b.ins().fcmp(cc, x, y);
+            let value = self.b.ins().select(pick_b, y, x);
+            let unordered = self.b.ins().fcmp(FloatCC::Unordered, x, y);
+            let nan = self.b.ins().f32const(f64::NAN as f32);
+            (self.b.ins().select(unordered, nan, value), Ty::F32)
+        }
+    }
+
+    /// math::abs（I32 は飽和）
+    fn abs(&mut self, a: Typed) -> Typed {
+        match a.1 {
+            Ty::I32 => {
+                let negated = self.b.ins().ineg(a.0);
+                let negative = self.b.ins().icmp_imm(IntCC::SignedLessThan, a.0, 0);
+                let value = self.b.ins().select(negative, negated, a.0);
+                let min = self.b.ins().icmp_imm(IntCC::Equal, a.0, i32::MIN as i64);
+                let max = self.b.ins().iconst(types::I32, i32::MAX as i64);
+                (self.b.ins().select(min, max, value), Ty::I32)
+            }
+            _ => {
+                let x = self.float(a);
+                (self.b.ins().fabs(x), Ty::F32)
+            }
+        }
+    }
+
+    /// 出力数が上限に達していれば失敗。型タグと値を出力バッファに書く
+    fn store_output(&mut self, (v, ty): Typed) {
+        let count = self.b.use_var(self.count);
+        let full = self
+            .b
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, count, self.max_outputs);
+        self.fail_if(full);
+        let offset = self.b.ins().imul_imm(count, 8);
+        let address = self.b.ins().iadd(self.outputs, offset);
+        let (tag, bits) = match ty {
+            Ty::I32 => (0, v),
+            Ty::F32 => (1, v),
+            Ty::Bool => (2, self.b.ins().uextend(types::I32, v)),
+        };
+        let tag = self.b.ins().iconst(types::I32, tag);
+        let flags = MemFlags::trusted();
+        self.b.ins().store(flags, tag, address, 0);
+        self.b.ins().store(flags, bits, address, 4);
+        let next = self.b.ins().iadd_imm(count, 1);
+        self.b.def_var(self.count, next);
+    }
+}
+
+/// レジスタと型の組ごとの変数
+fn var(r: u8, ty: Ty) -> Variable {
+    Variable::from_u32(r as u32 * 3 + ty as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+    use crate::vm::{execute, execute_with_events, VmErrorKind};
+    use std::sync::Arc;
+
+    /// 解釈実行だけの結果（execute_with_events は JIT を通らない）
+    fn interpret(program: &Program, inputs: &HashMap<String, Value>) -> Option<Vec<Value>> {
+        execute_with_events(program, inputs, &HashMap::new(), &Limits::default())
+            .ok()
+            .map(|(outputs, _, _)| outputs)
+    }
+
+    /// JIT だけの結果。Err はコンパイル対象外、Ok(None) は実行時エラー（解釈実行へのフォールバック）
+    fn native(
+        program: &Program,
+        inputs: &HashMap<String, Value>,
+        limits: &Limits,
+    ) -> Result<Option<Vec<Value>>, ()> {
+        let instructions = program.instructions();
+        let names = input_names(instructions).ok_or(())?;
+        let (_, signature, slots) = bind_inputs(&names, inputs).ok_or(())?;
+        let compiled = compile(instructions, &names, &signature).ok_or(())?;
+        Ok(compiled.call(&slots, limits))
+    }
+
+    /// ビット列で比べる。NaN はペイロードを問わない（Elixir には NaN を返さない）
+    fn same(a: &[Value], b: &[Value]) -> bool {
+        let key = |v: &Value| match v {
+            Value::F32(x) if x.is_nan() => vec![u32::MAX],
+            v => v.bits_key(),
+        };
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| key(x) == key(y))
+    }
+
+    fn assert_agrees(program: &Program, inputs: &HashMap<String, Value>) -> bool {
+        let expected = interpret(program, inputs);
+        match native(program, inputs, &Limits::default()) {
+            Err(()) => false,
+            Ok(actual) => {
+                match (&expected, &actual) {
+                    (Some(e), Some(a)) => assert!(
+                        same(e, a),
+                        "{:?}\ninputs {:?}\ninterpreter {:?}\nnative {:?}",
+                        program.instructions(),
+                        inputs,
+                        e,
+                        a
+                    ),
+                    (None, None) => {}
+                    _ => panic!(
+                        "{:?}\ninputs {:?}\ninterpreter {:?}\nnative {:?}",
+                        program.instructions(),
+                        inputs,
+                        expected,
+                        actual
+                    ),
+                }
+                true
+            }
+        }
+    }
+
+    fn assembled(source: &str) -> Program {
+        Program::compile(&crate::asm::assemble(source).expect("assemble")).expect("compile")
+    }
+
+    fn inputs(values: &[(&str, Value)]) -> HashMap<String, Value> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn saturating_integer_arithmetic_matches() {
+        let program = assembled(
+            "load_input r0 \"a\"\nload_input r1 \"b\"\n\
+             add r2 r0 r1\nstore_output r2\nsub r3 r0 r1\nstore_output r3\n\
+             mul r4 r0 r1\nstore_output r4\nabs r5 r0\nstore_output r5\n\
+             min r6 r0 r1\nstore_output r6\nmax r7 r0 r1\nstore_output r7",
+        );
+        let edges = [i32::MIN, i32::MIN + 1, -1, 0, 1, 46341, i32::MAX];
+        for a in edges {
+            for b in edges {
+                let inputs = inputs(&[("a", Value::I32(a)), ("b", Value::I32(b))]);
+                assert!(assert_agrees(&program, &inputs));
+            }
+        }
+    }
+
+    #[test]
+    fn division_semantics_match() {
+        let program =
+            assembled("load_input r0 \"a\"\nload_input r1 \"b\"\ndiv r2 r0 r1\nstore_output r2");
+        let values = [
+            Value::I32(i32::MIN),
+            Value::I32(-1),
+            Value::I32(0),
+            Value::I32(7),
+            Value::F32(0.0),
+            Value::F32(-0.0),
+            Value::F32(2.5),
+            Value::F32(f32::NAN),
+            Value::F32(f32::INFINITY),
+            Value::Bool(false),
+            Value::Bool(true),
+        ];
+        for a in &values {
+            for b in &values {
+                let inputs = inputs(&[("a", a.clone()), ("b", b.clone())]);
+                assert!(assert_agrees(&program, &inputs));
+            }
+        }
+        // ゼロ除算はネイティブコードでは失敗し、解釈実行がエラーを作る
+        let zero = inputs(&[("a", Value::I32(1)), ("b", Value::I32(0))]);
+        assert!(matches!(
+            native(&program, &zero, &Limits::default()),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn unsupported_or_unstable_programs_are_not_compiled() {
+        let unsupported = assembled("read_store r0 \"hp\"\nstore_output r0");
+        assert!(input_names(unsupported.instructions()).is_none());
+
+        // 分岐によって r1 の型が I32 と F32 に分かれる
+        let unstable = assembled(
+            "load_input r0 \"c\"\nload_i32 r1 1\njump_if_false r0 L0\nload_f32 r1 1.5\n\
+             L0:\nstore_output r1",
+        );
+        let c = inputs(&[("c", Value::Bool(true))]);
+        assert!(native(&unstable, &c, &Limits::default()).is_err());
+
+        // 未初期化の可能性があるレジスタを読む
+        let uninit = assembled(
+            "load_input r0 \"c\"\njump_if_false r0 L0\nload_i32 r1 1\nL0:\nstore_output r1",
+        );
+        assert!(native(&uninit, &c, &Limits::default()).is_err());
+    }
+
+    #[test]
+    fn output_budget_falls_back() {
+        let program = assembled("load_i32 r0 1\nstore_output r0\nstore_output r0");
+        let limits = Limits {
+            max_outputs: 1,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            native(&program, &HashMap::new(), &limits),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn hot_programs_run_natively_through_execute() {
+        let program =
+            assembled("load_input r0 \"x\"\nload_input r1 \"y\"\ndiv r2 r0 r1\nstore_output r2");
+        let ok = inputs(&[("x", Value::I32(7)), ("y", Value::I32(2))]);
+        for _ in 0..HOT_THRESHOLD + 4 {
+            let (outputs, _) =
+                execute(&program, &ok, &HashMap::new(), &Limits::default()).expect("runs");
+            assert!(matches!(outputs[..], [Value::I32(3)]));
+        }
+        assert!(program.jit_compiled());
+        // ネイティブコードが失敗しても、エラーと位置は解釈実行と同じ
+        let zero = inputs(&[("x", Value::I32(7)), ("y", Value::I32(0))]);
+        let err = execute(&program, &zero, &HashMap::new(), &Limits::default()).unwrap_err();
+        assert!(matches!(err.kind, VmErrorKind::DivisionByZero));
+        assert_eq!(err.location.map(|l| l.pc), Some(2));
+    }
+
+    /// 小さな xorshift（テストの再現性のため固定の種から）
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    const REGISTERS: u8 = 6;
+
+    fn random_value(rng: &mut Rng) -> Value {
+        const INTS: [i32; 8] = [0, 1, -1, 2, 7, i32::MAX, i32::MIN, -46341];
+        const FLOATS: [f32; 10] = [
+            0.0,
+            -0.0,
+            0.5,
+            -2.5,
+            1e-8,
+            3e9,
+            f32::NAN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::EPSILON,
+        ];
+        match rng.below(3) {
+            0 => Value::I32(INTS[rng.below(INTS.len())]),
+            1 => Value::F32(FLOATS[rng.below(FLOATS.len())]),
+            _ => Value::Bool(rng.below(2) == 1),
+        }
+    }
+
+    fn random_instruction(rng: &mut Rng, pc: usize, len: usize) -> Instruction {
+        let mut reg = || (rng.next() % REGISTERS as u64) as u8;
+        let (dst, src_a, src_b, c) = (reg(), reg(), reg(), reg());
+        match rng.below(24) {
+            0 => Instruction::Add { dst, src_a, src_b },
+            1 => Instruction::Sub { dst, src_a, src_b },
+            2 => Instruction::Mul { dst, src_a, src_b },
+            3 => Instruction::Div { dst, src_a, src_b },
+            4 => Instruction::Lt { dst, src_a, src_b },
+            5 => Instruction::Gt { dst, src_a, src_b },
+            6 => Instruction::Le { dst, src_a, src_b },
+            7 => Instruction::Ge { dst, src_a, src_b },
+            8 => Instruction::Eq { dst, src_a, src_b },
+            9 => Instruction::Ne { dst, src_a, src_b },
+            10 => Instruction::And { dst, src_a, src_b },
+            11 => Instruction::Or { dst, src_a, src_b },
+            12 => Instruction::Xor { dst, src_a, src_b },
+            13 => Instruction::Not { dst, src: src_a },
+            14 => Instruction::Min { dst, src_a, src_b },
+            15 => Instruction::Max { dst, src_a, src_b },
+            16 => Instruction::Abs { dst, src: src_a },
+            17 => Instruction::Floor { dst, src: src_a },
+            18 => Instruction::Ceil { dst, src: src_a },
+            19 => Instruction::Sqrt { dst, src: src_a },
+            20 => Instruction::Select {
+                dst,
+                cond: c,
+                a: src_a,
+                b: src_b,
+            },
+            21 => Instruction::JumpIfFalse {
+                cond: c,
+                target: pc + 1 + rng.below(len - pc),
+            },
+            22 => Instruction::JumpIfTrue {
+                cond: c,
+                target: pc + 1 + rng.below(len - pc),
+            },
+            _ => Instruction::StoreOutput { src: src_a },
+        }
+    }
+
+    /// 入力と定数で全レジスタを初期化した後、ランダムな命令を並べる
+    fn random_program(rng: &mut Rng) -> Program {
+        let mut instructions = Vec::new();
+        for r in 0..REGISTERS {
+            let inst = match (r, random_value(rng)) {
+                (0..=2, _) => Instruction::LoadInput {
+                    dst: r,
+                    name: Arc::from(["x", "y", "z"][r as usize]),
+                },
+                (_, Value::I32(value)) => Instruction::LoadI32 { dst: r, value },
+                (_, Value::F32(value)) => Instruction::LoadF32 { dst: r, value },
+                (_, Value::Bool(value)) => Instruction::LoadBool { dst: r, value },
+                _ => unreachable!(),
+            };
+            instructions.push(inst);
+        }
+        let body = 4 + rng.below(20);
+        let len = instructions.len() + body;
+        for _ in 0..body {
+            let pc = instructions.len();
+            instructions.push(random_instruction(rng, pc, len));
+        }
+        for r in 0..REGISTERS {
+            instructions.push(Instruction::StoreOutput { src: r });
+        }
+        Program::from_instructions(instructions)
+    }
+
+    #[test]
+    fn random_programs_agree_with_the_interpreter() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let (mut compiled, mut runs) = (0, 0);
+        for _ in 0..300 {
+            let program = random_program(&mut rng);
+            for _ in 0..8 {
+                let inputs = inputs(&[
+                    ("x", random_value(&mut rng)),
+                    ("y", random_value(&mut rng)),
+                    ("z", random_value(&mut rng)),
+                ]);
+                runs += 1;
+                compiled += assert_agrees(&program, &inputs) as usize;
+            }
+        }
+        // 型が安定しない（Select の両辺の型が異なる・分岐で型が変わる）プログラムは対象外。比較の数を確保する
+        assert!(compiled * 3 > runs, "compiled {} of {}", compiled, runs);
+    }
+}
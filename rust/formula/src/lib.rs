@@ -0,0 +1,49 @@
+//! Path: rust/formula/src/lib.rs
+//! Summary: コンテンツ数式エンジン（ProtoFlux/Logix 風の計算グラフ実行）
+//!
+//! BEAM に依存しない。Elixir からは `nif` クレート（Rustler の薄いラッパー）、シェルからは `formula-cli`、
+//! Rust のクライアントからは直接使う。最小の使い方は `decode_bytecode`（命令列の検証）と `run`（デコードして実行）、
+//! 繰り返し実行する場合は `Program::compile` と `execute`。
+
+mod asm;
+mod container;
+mod decode;
+mod encode;
+mod graph;
+#[cfg(feature = "jit")]
+mod jit;
+mod library;
+mod limits;
+mod list;
+mod math;
+mod opcode;
+mod optimize;
+mod program;
+mod random;
+mod source;
+mod source_map;
+mod store;
+mod trace;
+mod value;
+mod vector;
+mod verify;
+mod vm;
+
+pub use asm::{assemble, disassemble, AsmError};
+pub use container::is_container;
+pub use decode::{decode_bytecode, DecodeError, DecodeErrorKind, Instruction, Location, Name};
+pub use encode::EncodeError;
+pub use graph::{Graph, GraphError, NodeKind, Op};
+pub use library::Library;
+pub use limits::{Budget, Limits};
+pub use program::Program;
+pub use source::{compile_source, SourceError};
+pub use source_map::{Origin, SourceMap};
+pub use store::{StoreBase, StoreChange, StoreSchema};
+pub use trace::{TraceOptions, TraceStep};
+pub use value::Value;
+pub use verify::{verify_bytecode, DiagnosticKind, Schema, TypeSet, ValueType};
+pub use vm::{
+    execute, execute_batch, execute_changes, execute_traced, execute_with_events, run, Event,
+    VmError, VmErrorKind,
+};
@@ -0,0 +1,231 @@
+//! Path: rust/formula/src/library.rs
+//! Summary: 共有関数ライブラリ（Call で呼び出す名前付き関数の集合）
+//!
+//! 関数は通常のバイトコードで書く。引数は r0..r(argc-1) に入った状態で実行が始まり、
+//! `Ret` で値を返す。レジスタは呼び出しごとに新しい 64 本（呼び出し元のレジスタは見えない）。
+//! 入力・Store・出力は呼び出し元と共有する。関数の終端に Ret なしで達するとエラー。
+//!
+//! ライブラリは一度作れば不変で、`Program::link` で複数のプログラムから参照できる。
+//! ライブラリ内の関数どうしも Call で呼び合える（呼び出しの深さは `Limits::max_call_depth` まで）。
+
+use super::decode::{DecodeError, Instruction};
+use super::program::Program;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Library {
+    functions: HashMap<String, Program>,
+}
+
+impl Library {
+    /// 関数名とバイトコードの組からライブラリを作る。いずれかのデコードに失敗すれば (関数名, エラー)
+    pub fn compile<'a>(
+        functions: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+    ) -> Result<Self, (String, DecodeError)> {
+        let functions = functions
+            .into_iter()
+            .map(|(name, bytecode)| match Program::compile(bytecode) {
+                Ok(program) => Ok((name.to_string(), program)),
+                Err(e) => Err((name.to_string(), e)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { functions })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Program> {
+        self.functions.get(name)
+    }
+
+    /// いずれかの関数が Store を書き換えるか（`Instruction::writes_store`）
+    pub(super) fn writes_store(&self) -> bool {
+        self.functions
+            .values()
+            .any(|f| f.instructions().iter().any(Instruction::writes_store))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::decode::DecodeErrorKind;
+    use crate::limits::{Budget, Limits};
+    use crate::value::Value;
+    use crate::vm::{execute, execute_batch, VmErrorKind};
+    use std::sync::Arc;
+
+    fn library(functions: &[(&str, &str)]) -> Arc<Library> {
+        let bytecodes: Vec<(&str, Vec<u8>)> = functions
+            .iter()
+            .map(|(name, source)| (*name, assemble(source).expect("assemble")))
+            .collect();
+        Arc::new(
+            Library::compile(bytecodes.iter().map(|(n, b)| (*n, b.as_slice()))).expect("library"),
+        )
+    }
+
+    fn program(source: &str, library: &Arc<Library>) -> Program {
+        Program::compile(&assemble(source).expect("assemble"))
+            .expect("compile")
+            .link(library.clone())
+    }
+
+    fn run(
+        program: &Program,
+        limits: &Limits,
+    ) -> Result<(Vec<Value>, HashMap<String, Value>), VmErrorKind> {
+        let inputs = HashMap::from([("damage".to_string(), Value::I32(40))]);
+        let store = HashMap::from([("hp".to_string(), Value::I32(100))]);
+        execute(program, &inputs, &store, limits).map_err(|e| e.kind)
+    }
+
+    const REDUCE: &str = r#"
+        ; r0 = damage, r1 = armor(%)
+        load_i32 r2 100
+        sub r3 r2 r1
+        mul r4 r0 r3
+        div r5 r4 r2
+        ret r5
+    "#;
+
+    #[test]
+    fn calls_share_inputs_and_store_but_not_registers() {
+        let lib = library(&[
+            ("reduce", REDUCE),
+            (
+                "hit",
+                r#"
+                    load_input r1 "damage"
+                    load_i32 r2 25
+                    call r3 "reduce" r1 r2
+                    read_store r4 "hp"
+                    sub r5 r4 r3
+                    write_store r5 "hp"
+                    ret r5
+                "#,
+            ),
+        ]);
+        let main = program(
+            r#"
+                load_i32 r1 7
+                call r0 "hit"
+                store_output r0
+                store_output r1
+            "#,
+            &lib,
+        );
+        let (outputs, store) = run(&main, &Limits::default()).expect("run");
+        assert!(matches!(
+            outputs.as_slice(),
+            [Value::I32(70), Value::I32(7)]
+        ));
+        assert!(matches!(store["hp"], Value::I32(70)));
+        assert!(main.writes_store());
+    }
+
+    #[test]
+    fn top_level_ret_ends_the_program() {
+        let lib = library(&[]);
+        let main = program(
+            "load_i32 r0 1\nstore_output r0\nret r0\nstore_output r0",
+            &lib,
+        );
+        let (outputs, _) = run(&main, &Limits::default()).expect("run");
+        assert_eq!(outputs.len(), 1);
+    }
+
+    #[test]
+    fn call_depth_is_bounded() {
+        // 自分自身を呼び続ける関数は深さの上限で止まる
+        let lib = library(&[("loop", "call r0 \"loop\"\nret r0")]);
+        let main = program("call r0 \"loop\"", &lib);
+        let limits = Limits {
+            max_call_depth: 4,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            run(&main, &limits),
+            Err(VmErrorKind::BudgetExceeded(Budget::CallDepth, 4))
+        ));
+
+        let limits = Limits {
+            max_call_depth: 0,
+            ..Limits::default()
+        };
+        let lib = library(&[("one", "load_i32 r0 1\nret r0")]);
+        assert!(matches!(
+            run(&program("call r0 \"one\"", &lib), &limits),
+            Err(VmErrorKind::BudgetExceeded(Budget::CallDepth, 0))
+        ));
+    }
+
+    #[test]
+    fn fuel_counts_callee_instructions() {
+        let lib = library(&[("reduce", REDUCE)]);
+        let main = program(
+            "load_i32 r0 10\nload_i32 r1 0\ncall r2 \"reduce\" r0 r1\nstore_output r2",
+            &lib,
+        );
+        // トップレベル 4 命令 + 関数 5 命令
+        let limits = Limits {
+            max_instructions: 8,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            run(&main, &limits),
+            Err(VmErrorKind::BudgetExceeded(Budget::Instructions, 8))
+        ));
+        let limits = Limits {
+            max_instructions: 9,
+            ..Limits::default()
+        };
+        let (outputs, _) = run(&main, &limits).expect("run");
+        assert!(matches!(outputs.as_slice(), [Value::I32(10)]));
+    }
+
+    #[test]
+    fn call_errors() {
+        let lib = library(&[("noret", "load_i32 r0 1")]);
+        assert!(matches!(
+            run(&program("call r0 \"missing\"", &lib), &Limits::default()),
+            Err(VmErrorKind::UndefinedFunction(name)) if name == "missing"
+        ));
+        assert!(matches!(
+            run(&program("call r0 \"noret\"", &lib), &Limits::default()),
+            Err(VmErrorKind::MissingReturn(name)) if name == "noret"
+        ));
+        // ライブラリを結び付けていないプログラム
+        let unlinked =
+            Program::compile(&assemble("call r0 \"noret\"").expect("assemble")).expect("compile");
+        assert!(matches!(
+            run(&unlinked, &Limits::default()),
+            Err(VmErrorKind::UndefinedFunction(_))
+        ));
+    }
+
+    #[test]
+    fn batch_isolates_store_writes_made_by_functions() {
+        let lib = library(&[(
+            "bump",
+            "read_store r0 \"hp\"\nload_i32 r1 1\nadd r2 r0 r1\nwrite_store r2 \"hp\"\nret r2",
+        )]);
+        let main = program("call r0 \"bump\"\nstore_output r0", &lib);
+        let store = HashMap::from([("hp".to_string(), Value::I32(1))]);
+        let results = execute_batch(
+            &main,
+            &[HashMap::new(), HashMap::new()],
+            &store,
+            &Limits::default(),
+        );
+        for result in results {
+            assert!(matches!(result.expect("run").as_slice(), [Value::I32(2)]));
+        }
+    }
+
+    #[test]
+    fn library_reports_the_failing_function() {
+        let err = Library::compile([("ok", &[][..]), ("bad", &[99u8][..])]).unwrap_err();
+        assert_eq!(err.0, "bad");
+        assert!(matches!(err.1.kind, DecodeErrorKind::InvalidOpCode(99)));
+    }
+}
@@ -0,0 +1,68 @@
+//! Path: rust/formula/src/limits.rs
+//! Summary: Formula VM の実行上限（命令フューエル・Store 件数・出力数・呼び出しの深さ・イベント数・リスト長）
+
+/// 1 回の実行に課す上限。コンテンツ由来の数式が通常スケジューラを占有しないようにする。
+///
+/// 既定値は通常の数式には十分な大きさ。バッチ実行ではエンティティごとに適用する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// 実行できる命令数（フューエル）。1 命令ごとに 1 消費する
+    pub max_instructions: usize,
+    /// 実行後の Store の最大キー数。WriteStore による新規キーの追加でのみ検査する
+    pub max_store_entries: usize,
+    /// StoreOutput で追加できる出力の最大数
+    pub max_outputs: usize,
+    /// Call の入れ子の最大数。トップレベルからの呼び出しが深さ 1
+    pub max_call_depth: usize,
+    /// Emit で発行できるイベントの最大数
+    pub max_events: usize,
+    /// リストの最大長。入力・Store からレジスタに載せるときと ListMap の結果で検査する
+    pub max_list_len: usize,
+}
+
+impl Limits {
+    pub const DEFAULT_MAX_INSTRUCTIONS: usize = 100_000;
+    pub const DEFAULT_MAX_STORE_ENTRIES: usize = 4_096;
+    pub const DEFAULT_MAX_OUTPUTS: usize = 1_024;
+    pub const DEFAULT_MAX_CALL_DEPTH: usize = 16;
+    pub const DEFAULT_MAX_EVENTS: usize = 256;
+    pub const DEFAULT_MAX_LIST_LEN: usize = 4_096;
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_instructions: Self::DEFAULT_MAX_INSTRUCTIONS,
+            max_store_entries: Self::DEFAULT_MAX_STORE_ENTRIES,
+            max_outputs: Self::DEFAULT_MAX_OUTPUTS,
+            max_call_depth: Self::DEFAULT_MAX_CALL_DEPTH,
+            max_events: Self::DEFAULT_MAX_EVENTS,
+            max_list_len: Self::DEFAULT_MAX_LIST_LEN,
+        }
+    }
+}
+
+/// 超過した上限の種類（`VmErrorKind::BudgetExceeded` の detail）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Instructions,
+    StoreEntries,
+    Outputs,
+    CallDepth,
+    Events,
+    ListLen,
+}
+
+impl Budget {
+    /// NIF で返す atom 名
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Budget::Instructions => "instructions",
+            Budget::StoreEntries => "store_entries",
+            Budget::Outputs => "outputs",
+            Budget::CallDepth => "call_depth",
+            Budget::Events => "events",
+            Budget::ListLen => "list_len",
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Path: rust/formula/src/list.rs
+//! Summary: Formula VM のリスト演算（ListLen, ListIndex, ListSum, ListMin, ListMax）
+//!
+//! リストは入力・Store から渡される読み取り専用の値で、要素はスカラーかベクトル（リストの入れ子は無い）。
+//! 要素は `Arc<[Value]>` で共有するため、レジスタ間のコピーや Store への書き込みで要素は複製されない。
+//!
+//! - 長さは `Limits::max_list_len` まで。LoadInput・ReadStore でレジスタに載せるときと、
+//!   ListMap が結果を作るときに検査し、超過は `VmErrorKind::BudgetExceeded(Budget::ListLen, _)`
+//! - ListSum は binary_add の畳み込み（空リストは I32 の 0）。ListMin / ListMax は math::min / max の畳み込みで、
+//!   空リストは `VmErrorKind::DomainError`
+//! - 添字は I32 で 0 始まり。範囲外（負を含む）は `VmErrorKind::IndexOutOfRange`
+//! - ForEach / ListMap はライブラリ関数を要素ごとに呼ぶため vm.rs の execute_into が扱う
+
+use super::limits::{Budget, Limits};
+use super::math;
+use super::value::Value;
+use super::vm::{binary_add, VmErrorKind};
+use std::sync::Arc;
+
+fn type_mismatch(op: &str) -> VmErrorKind {
+    VmErrorKind::TypeMismatch(op.into())
+}
+
+fn as_list<'a>(op: &str, v: &'a Value) -> Result<&'a Arc<[Value]>, VmErrorKind> {
+    v.as_list().ok_or_else(|| type_mismatch(op))
+}
+
+/// リストなら長さが max_list_len 以下かを検査する（リスト以外は何もしない）
+pub(super) fn check_len(v: &Value, limits: &Limits) -> Result<(), VmErrorKind> {
+    match v {
+        Value::List(items) if items.len() > limits.max_list_len => Err(
+            VmErrorKind::BudgetExceeded(Budget::ListLen, limits.max_list_len),
+        ),
+        _ => Ok(()),
+    }
+}
+
+pub(super) fn len(v: Value) -> Result<Value, VmErrorKind> {
+    let items = as_list("list_len", &v)?;
+    Ok(Value::I32(items.len() as i32))
+}
+
+pub(super) fn index(v: Value, i: Value) -> Result<Value, VmErrorKind> {
+    let items = as_list("list_index", &v)?;
+    let Value::I32(i) = i else {
+        return Err(type_mismatch("list_index"));
+    };
+    usize::try_from(i)
+        .ok()
+        .and_then(|i| items.get(i))
+        .cloned()
+        .ok_or(VmErrorKind::IndexOutOfRange(i))
+}
+
+pub(super) fn sum(v: Value) -> Result<Value, VmErrorKind> {
+    let items = as_list("list_sum", &v)?;
+    items.iter().try_fold(Value::I32(0), |acc, x| {
+        binary_add(acc, x.clone()).ok_or_else(|| type_mismatch("list_sum"))
+    })
+}
+
+pub(super) fn min(v: Value) -> Result<Value, VmErrorKind> {
+    fold_extreme("list_min", v, math::min)
+}
+
+pub(super) fn max(v: Value) -> Result<Value, VmErrorKind> {
+    fold_extreme("list_max", v, math::max)
+}
+
+fn fold_extreme(
+    op: &str,
+    v: Value,
+    f: fn(Value, Value) -> Result<Value, VmErrorKind>,
+) -> Result<Value, VmErrorKind> {
+    let items = as_list(op, &v)?;
+    let (first, rest) = items
+        .split_first()
+        .ok_or_else(|| VmErrorKind::DomainError(op.into()))?;
+    rest.iter().try_fold(first.clone(), |acc, x| {
+        f(acc, x.clone()).map_err(|_| type_mismatch(op))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::library::Library;
+    use crate::program::Program;
+    use crate::vm::{execute, VmError};
+    use std::collections::HashMap;
+
+    fn list(items: &[Value]) -> Value {
+        Value::List(Arc::from(items))
+    }
+
+    fn program(source: &str) -> Program {
+        let functions = [
+            ("add_to", "add r2 r0 r1\nret r2"),
+            ("double", "load_i32 r1 2\nmul r2 r0 r1\nret r2"),
+            ("is_negative", "load_i32 r1 0\nlt r2 r0 r1\nret r2"),
+        ];
+        let bytecodes: Vec<(&str, Vec<u8>)> = functions
+            .iter()
+            .map(|(name, source)| (*name, assemble(source).expect("assemble")))
+            .collect();
+        let library =
+            Library::compile(bytecodes.iter().map(|(n, b)| (*n, b.as_slice()))).expect("library");
+        Program::compile(&assemble(source).expect("assemble"))
+            .expect("compile")
+            .link(Arc::new(library))
+    }
+
+    fn run(source: &str, items: Value, limits: &Limits) -> Result<Vec<Value>, VmErrorKind> {
+        let inputs = HashMap::from([("xs".to_string(), items)]);
+        execute(&program(source), &inputs, &HashMap::new(), limits)
+            .map(|(outputs, _)| outputs)
+            .map_err(|e| e.kind)
+    }
+
+    fn outputs(source: &str, items: Value) -> String {
+        let values = run(source, items, &Limits::default()).expect("run");
+        values
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    const AGGREGATES: &str = r#"
+        load_input r0 "xs"
+        list_len r1 r0
+        store_output r1
+        list_sum r2 r0
+        store_output r2
+        list_min r3 r0
+        store_output r3
+        list_max r4 r0
+        store_output r4
+        load_i32 r5 1
+        list_index r6 r0 r5
+        store_output r6
+    "#;
+
+    #[test]
+    fn aggregates_follow_scalar_promotion() {
+        let ints = list(&[Value::I32(3), Value::I32(-1), Value::I32(7)]);
+        assert_eq!(outputs(AGGREGATES, ints), "3 9 -1 7 -1");
+        let mixed = list(&[Value::I32(2), Value::F32(0.5)]);
+        assert_eq!(outputs(AGGREGATES, mixed), "2 2.5 0.5 2 0.5");
+    }
+
+    #[test]
+    fn empty_and_out_of_range() {
+        let empty = list(&[]);
+        assert_eq!(
+            outputs(
+                "load_input r0 \"xs\"\nlist_sum r1 r0\nstore_output r1",
+                empty.clone()
+            ),
+            "0"
+        );
+        assert!(matches!(
+            run("load_input r0 \"xs\"\nlist_min r1 r0", empty, &Limits::default()),
+            Err(VmErrorKind::DomainError(op)) if op == "list_min"
+        ));
+        for i in [2, -1] {
+            let source = format!("load_input r0 \"xs\"\nload_i32 r1 {i}\nlist_index r2 r0 r1");
+            assert!(matches!(
+                run(&source, list(&[Value::I32(1), Value::I32(2)]), &Limits::default()),
+                Err(VmErrorKind::IndexOutOfRange(got)) if got == i
+            ));
+        }
+        assert!(matches!(
+            run("load_input r0 \"xs\"\nlist_len r1 r0", Value::I32(1), &Limits::default()),
+            Err(VmErrorKind::TypeMismatch(op)) if op == "list_len"
+        ));
+    }
+
+    #[test]
+    fn for_each_folds_and_list_map_maps() {
+        let source = r#"
+            load_input r0 "xs"
+            load_i32 r1 100
+            for_each r2 "add_to" r0 r1
+            store_output r2
+            list_map r3 "double" r0
+            store_output r3
+        "#;
+        let xs = list(&[Value::I32(1), Value::I32(2), Value::I32(3)]);
+        assert_eq!(outputs(source, xs), "106 [2, 4, 6]");
+        // 空リストでは関数を呼ばず init / 空リストを返す
+        assert_eq!(outputs(source, list(&[])), "100 []");
+    }
+
+    #[test]
+    fn iterations_share_one_call_level_and_consume_fuel() {
+        let source = "load_input r0 \"xs\"\nlist_map r1 \"double\" r0\nstore_output r1";
+        let xs = list(&vec![Value::I32(1); 50]);
+        let shallow = Limits {
+            max_call_depth: 1,
+            ..Limits::default()
+        };
+        assert!(run(source, xs.clone(), &shallow).is_ok());
+        let no_calls = Limits {
+            max_call_depth: 0,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            run(source, xs.clone(), &no_calls),
+            Err(VmErrorKind::BudgetExceeded(Budget::CallDepth, 0))
+        ));
+        // 3 + 要素ごとに 3 命令
+        let fuel = Limits {
+            max_instructions: 3 + 3 * 49,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            run(source, xs, &fuel),
+            Err(VmErrorKind::BudgetExceeded(Budget::Instructions, _))
+        ));
+    }
+
+    #[test]
+    fn callee_errors_and_missing_functions_propagate() {
+        let source = "load_input r0 \"xs\"\nlist_map r1 \"is_negative\" r0";
+        let vec2 = Value::Vec2([1.0, 0.0]);
+        assert!(matches!(
+            run(source, list(&[Value::I32(1), vec2]), &Limits::default()),
+            Err(VmErrorKind::TypeMismatch(op)) if op == "lt"
+        ));
+        // 空リストでも関数の有無は検査する
+        assert!(matches!(
+            run("load_input r0 \"xs\"\nlist_map r1 \"nope\" r0", list(&[]), &Limits::default()),
+            Err(VmErrorKind::UndefinedFunction(name)) if name == "nope"
+        ));
+    }
+
+    #[test]
+    fn list_length_budget_applies_to_inputs_and_store() {
+        let limits = Limits {
+            max_list_len: 2,
+            ..Limits::default()
+        };
+        let xs = list(&[Value::I32(1), Value::I32(2), Value::I32(3)]);
+        assert!(matches!(
+            run("load_input r0 \"xs\"", xs.clone(), &limits),
+            Err(VmErrorKind::BudgetExceeded(Budget::ListLen, 2))
+        ));
+        let store = HashMap::from([("xs".to_string(), xs)]);
+        assert!(matches!(
+            execute(
+                &program("read_store r0 \"xs\""),
+                &HashMap::new(),
+                &store,
+                &limits
+            ),
+            Err(VmError {
+                kind: VmErrorKind::BudgetExceeded(Budget::ListLen, 2),
+                ..
+            })
+        ));
+    }
+}
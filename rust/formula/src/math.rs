@@ -0,0 +1,534 @@
+//! Path: rust/formula/src/math.rs
+//! Summary: Formula VM の数学関数ライブラリ（abs, min, max, clamp, sqrt 等）と明示的な型変換（cast）
+//!
+//! 型昇格は vm.rs の binary_add と同じ `value::promote`: 全部 I32 なら I32（saturating）、整数だけで I64 を含めば I64、
+//! それ以外は浮動小数点（64 bit の値を含めば F64、無ければ F32）。
+//! sqrt / sin / cos / atan2 / lerp は常に浮動小数点で、64 bit の値（I64 / F64）を含めば F64、それ以外は F32。
+//! NaN の扱い: NaN オペランドはそのまま NaN として伝播する。
+//! NaN でないオペランドから NaN が生じる場合（負の sqrt 等）は `VmErrorKind::DomainError` とする。
+
+use super::value::{promote, NumKind, Value};
+use super::vm::VmErrorKind;
+use std::cmp::Ordering;
+
+fn type_mismatch(op: &str) -> VmErrorKind {
+    VmErrorKind::TypeMismatch(op.into())
+}
+
+/// promote 済みのオペランドを読む（スカラーでなければ TypeMismatch）
+fn operand<T>(op: &str, v: Option<T>) -> Result<T, VmErrorKind> {
+    v.ok_or_else(|| type_mismatch(op))
+}
+
+fn kind_of(op: &str, values: &[&Value]) -> Result<NumKind, VmErrorKind> {
+    promote(values).ok_or_else(|| type_mismatch(op))
+}
+
+/// 浮動小数点の演算を 64 bit で行うか（I64 / F64 を含む）
+fn is_wide(kind: NumKind) -> bool {
+    matches!(kind, NumKind::I64 | NumKind::F64)
+}
+
+/// 整数の結果を昇格した型に戻す（I32 の場合は呼び出し側で範囲内であること）
+fn int_value(kind: NumKind, v: i64) -> Value {
+    match kind {
+        NumKind::I32 => Value::I32(v as i32),
+        _ => Value::I64(v),
+    }
+}
+
+/// 浮動小数点のオペランドを昇格した精度で読み、f64 に広げる（F32 なら f32 に丸めてから広げる）
+fn float_operand(op: &str, kind: NumKind, v: &Value) -> Result<f64, VmErrorKind> {
+    match kind {
+        NumKind::F32 => operand(op, v.as_f32()).map(f64::from),
+        _ => operand(op, v.as_f64()),
+    }
+}
+
+/// 浮動小数点の結果を昇格した型に戻す（大小の選択だけに使う。F32 の値は f64 を経由しても変わらない）
+fn float_value(kind: NumKind, v: f64) -> Value {
+    match kind {
+        NumKind::F32 => Value::F32(v as f32),
+        _ => Value::F64(v),
+    }
+}
+
+/// 入力に NaN が無いのに結果が NaN になった場合はドメインエラー
+fn check_domain<T: Copy + Into<f64>>(
+    op: &str,
+    inputs: &[T],
+    result: T,
+    value: fn(T) -> Value,
+) -> Result<Value, VmErrorKind> {
+    let is_nan = |x: T| x.into().is_nan();
+    if is_nan(result) && !inputs.iter().any(|x| is_nan(*x)) {
+        return Err(VmErrorKind::DomainError(op.into()));
+    }
+    Ok(value(result))
+}
+
+/// 浮動小数点のみで計算する単項関数（sqrt, sin, cos）
+fn unary_float(
+    op: &str,
+    a: Value,
+    f32_fn: fn(f32) -> f32,
+    f64_fn: fn(f64) -> f64,
+) -> Result<Value, VmErrorKind> {
+    if is_wide(kind_of(op, &[&a])?) {
+        let x = operand(op, a.as_f64())?;
+        check_domain(op, &[x], f64_fn(x), Value::F64)
+    } else {
+        let x = operand(op, a.as_f32())?;
+        check_domain(op, &[x], f32_fn(x), Value::F32)
+    }
+}
+
+pub(super) fn abs(a: Value) -> Result<Value, VmErrorKind> {
+    match a {
+        Value::I32(v) => Ok(Value::I32(v.saturating_abs())),
+        Value::I64(v) => Ok(Value::I64(v.saturating_abs())),
+        Value::F64(v) => Ok(Value::F64(v.abs())),
+        _ => Ok(Value::F32(operand("abs", a.as_f32())?.abs())),
+    }
+}
+
+/// 整数はそのまま。浮動小数点は同じ型のまま切り捨て
+pub(super) fn floor(a: Value) -> Result<Value, VmErrorKind> {
+    match a {
+        Value::I32(_) | Value::I64(_) => Ok(a),
+        Value::F64(v) => Ok(Value::F64(v.floor())),
+        _ => Ok(Value::F32(operand("floor", a.as_f32())?.floor())),
+    }
+}
+
+/// 整数はそのまま。浮動小数点は同じ型のまま切り上げ
+pub(super) fn ceil(a: Value) -> Result<Value, VmErrorKind> {
+    match a {
+        Value::I32(_) | Value::I64(_) => Ok(a),
+        Value::F64(v) => Ok(Value::F64(v.ceil())),
+        _ => Ok(Value::F32(operand("ceil", a.as_f32())?.ceil())),
+    }
+}
+
+/// 常に浮動小数点。負数はドメインエラー（-0.0 は 0 として許容）
+pub(super) fn sqrt(a: Value) -> Result<Value, VmErrorKind> {
+    unary_float("sqrt", a, f32::sqrt, f64::sqrt)
+}
+
+pub(super) fn sin(a: Value) -> Result<Value, VmErrorKind> {
+    unary_float("sin", a, f32::sin, f64::sin)
+}
+
+pub(super) fn cos(a: Value) -> Result<Value, VmErrorKind> {
+    unary_float("cos", a, f32::cos, f64::cos)
+}
+
+/// NaN を伝播する min（f32::min は NaN を無視するため使わない）
+fn min_nan(a: f64, b: f64) -> f64 {
+    match a.partial_cmp(&b) {
+        None => f64::NAN,
+        Some(Ordering::Greater) => b,
+        Some(_) => a,
+    }
+}
+
+/// NaN を伝播する max
+fn max_nan(a: f64, b: f64) -> f64 {
+    match a.partial_cmp(&b) {
+        None => f64::NAN,
+        Some(Ordering::Less) => b,
+        Some(_) => a,
+    }
+}
+
+/// min / max 共通。整数は i64、浮動小数点は f64 で選び、昇格した型で返す
+fn select(
+    op: &str,
+    a: Value,
+    b: Value,
+    int: fn(i64, i64) -> i64,
+    float: fn(f64, f64) -> f64,
+) -> Result<Value, VmErrorKind> {
+    let kind = kind_of(op, &[&a, &b])?;
+    match kind {
+        NumKind::I32 | NumKind::I64 => {
+            let (va, vb) = (operand(op, a.as_i64())?, operand(op, b.as_i64())?);
+            Ok(int_value(kind, int(va, vb)))
+        }
+        NumKind::F32 | NumKind::F64 => {
+            let fa = float_operand(op, kind, &a)?;
+            let fb = float_operand(op, kind, &b)?;
+            Ok(float_value(kind, float(fa, fb)))
+        }
+    }
+}
+
+pub(super) fn min(a: Value, b: Value) -> Result<Value, VmErrorKind> {
+    select("min", a, b, i64::min, min_nan)
+}
+
+pub(super) fn max(a: Value, b: Value) -> Result<Value, VmErrorKind> {
+    select("max", a, b, i64::max, max_nan)
+}
+
+/// clamp(x, lo, hi)。lo > hi はドメインエラー（f32::clamp のような panic はしない）
+pub(super) fn clamp(x: Value, lo: Value, hi: Value) -> Result<Value, VmErrorKind> {
+    let op = "clamp";
+    let kind = kind_of(op, &[&x, &lo, &hi])?;
+    match kind {
+        NumKind::I32 | NumKind::I64 => {
+            let vx = operand(op, x.as_i64())?;
+            let vlo = operand(op, lo.as_i64())?;
+            let vhi = operand(op, hi.as_i64())?;
+            if vlo > vhi {
+                return Err(VmErrorKind::DomainError(op.into()));
+            }
+            Ok(int_value(kind, vx.max(vlo).min(vhi)))
+        }
+        NumKind::F32 | NumKind::F64 => {
+            let fx = float_operand(op, kind, &x)?;
+            let flo = float_operand(op, kind, &lo)?;
+            let fhi = float_operand(op, kind, &hi)?;
+            if flo > fhi {
+                return Err(VmErrorKind::DomainError(op.into()));
+            }
+            Ok(float_value(kind, min_nan(max_nan(fx, flo), fhi)))
+        }
+    }
+}
+
+/// atan2(y, x)。常に浮動小数点
+pub(super) fn atan2(y: Value, x: Value) -> Result<Value, VmErrorKind> {
+    let op = "atan2";
+    if is_wide(kind_of(op, &[&y, &x])?) {
+        let (fy, fx) = (operand(op, y.as_f64())?, operand(op, x.as_f64())?);
+        Ok(Value::F64(fy.atan2(fx)))
+    } else {
+        let (fy, fx) = (operand(op, y.as_f32())?, operand(op, x.as_f32())?);
+        Ok(Value::F32(fy.atan2(fx)))
+    }
+}
+
+/// 整数同士なら saturating_pow（負の指数はドメインエラー）。それ以外は浮動小数点の powf
+pub(super) fn pow(base: Value, exp: Value) -> Result<Value, VmErrorKind> {
+    let op = "pow";
+    match kind_of(op, &[&base, &exp])? {
+        NumKind::I32 => {
+            let (vb, ve) = (operand(op, base.as_i32())?, operand(op, exp.as_i32())?);
+            let ve = u32::try_from(ve).map_err(|_| VmErrorKind::DomainError(op.into()))?;
+            Ok(Value::I32(vb.saturating_pow(ve)))
+        }
+        NumKind::I64 => {
+            let (vb, ve) = (operand(op, base.as_i64())?, operand(op, exp.as_i64())?);
+            if ve < 0 {
+                return Err(VmErrorKind::DomainError(op.into()));
+            }
+            // u32 を超える指数は |base| >= 2 なら飽和、それ以外は結果が変わらない
+            let ve = u32::try_from(ve).unwrap_or(u32::MAX);
+            Ok(Value::I64(vb.saturating_pow(ve)))
+        }
+        NumKind::F32 => {
+            let (fb, fe) = (operand(op, base.as_f32())?, operand(op, exp.as_f32())?);
+            check_domain(op, &[fb, fe], fb.powf(fe), Value::F32)
+        }
+        NumKind::F64 => {
+            let (fb, fe) = (operand(op, base.as_f64())?, operand(op, exp.as_f64())?);
+            check_domain(op, &[fb, fe], fb.powf(fe), Value::F64)
+        }
+    }
+}
+
+/// ユークリッド剰余（除数が正なら結果は常に 0 以上）。除数 0 は `VmErrorKind::DivisionByZero`
+pub(super) fn modulo(a: Value, b: Value) -> Result<Value, VmErrorKind> {
+    let op = "mod";
+    let kind = kind_of(op, &[&a, &b])?;
+    match kind {
+        NumKind::I32 | NumKind::I64 => {
+            let (va, vb) = (operand(op, a.as_i64())?, operand(op, b.as_i64())?);
+            if vb == 0 {
+                return Err(VmErrorKind::DivisionByZero);
+            }
+            // MIN rem_euclid -1 はオーバーフローするが数学的には 0（i32 の値は i64 では溢れない）
+            Ok(int_value(kind, va.checked_rem_euclid(vb).unwrap_or(0)))
+        }
+        NumKind::F32 => {
+            let (fa, fb) = (operand(op, a.as_f32())?, operand(op, b.as_f32())?);
+            if fb == 0.0 {
+                return Err(VmErrorKind::DivisionByZero);
+            }
+            check_domain(op, &[fa, fb], fa.rem_euclid(fb), Value::F32)
+        }
+        NumKind::F64 => {
+            let (fa, fb) = (operand(op, a.as_f64())?, operand(op, b.as_f64())?);
+            if fb == 0.0 {
+                return Err(VmErrorKind::DivisionByZero);
+            }
+            check_domain(op, &[fa, fb], fa.rem_euclid(fb), Value::F64)
+        }
+    }
+}
+
+/// a + (b - a) * t。常に浮動小数点。t はクランプしない（外挿を許す）
+pub(super) fn lerp(a: Value, b: Value, t: Value) -> Result<Value, VmErrorKind> {
+    let op = "lerp";
+    if is_wide(kind_of(op, &[&a, &b, &t])?) {
+        let fa = operand(op, a.as_f64())?;
+        let fb = operand(op, b.as_f64())?;
+        let ft = operand(op, t.as_f64())?;
+        check_domain(op, &[fa, fb, ft], fa + (fb - fa) * ft, Value::F64)
+    } else {
+        let fa = operand(op, a.as_f32())?;
+        let fb = operand(op, b.as_f32())?;
+        let ft = operand(op, t.as_f32())?;
+        check_domain(op, &[fa, fb, ft], fa + (fb - fa) * ft, Value::F32)
+    }
+}
+
+/// 明示的な型変換（CastI32 / CastF32 / CastI64 / CastF64）。Bool は 0 / 1 として変換する。
+///
+/// - 整数へは 0 方向に切り捨てる。変換先に収まらない値・NaN・無限大は `VmErrorKind::DomainError`
+/// - 浮動小数点へは最も近い値に丸める。有限の値が F32 で無限大になる場合は `VmErrorKind::DomainError`
+pub(super) fn cast(to: NumKind, v: Value) -> Result<Value, VmErrorKind> {
+    let op = match to {
+        NumKind::I32 => "cast_i32",
+        NumKind::F32 => "cast_f32",
+        NumKind::I64 => "cast_i64",
+        NumKind::F64 => "cast_f64",
+    };
+    kind_of(op, &[&v])?;
+    let domain_error = || VmErrorKind::DomainError(op.into());
+    match to {
+        NumKind::I32 | NumKind::I64 => {
+            let n = match v {
+                Value::F32(x) => float_to_i64(x as f64),
+                Value::F64(x) => float_to_i64(x),
+                _ => v.as_i64(),
+            };
+            let n = n.ok_or_else(domain_error)?;
+            if to == NumKind::I32 && i32::try_from(n).is_err() {
+                return Err(domain_error());
+            }
+            Ok(int_value(to, n))
+        }
+        NumKind::F32 => {
+            let x = operand(op, v.as_f32())?;
+            if x.is_infinite() && operand(op, v.as_f64())?.is_finite() {
+                return Err(domain_error());
+            }
+            Ok(Value::F32(x))
+        }
+        NumKind::F64 => Ok(Value::F64(operand(op, v.as_f64())?)),
+    }
+}
+
+/// 0 方向に切り捨てた値が i64 に収まれば Some（NaN・無限大は None）
+fn float_to_i64(x: f64) -> Option<i64> {
+    let t = x.trunc();
+    // i64::MIN は 2 の冪で f64 で正確に表せる。上限は -(i64::MIN) 未満
+    (t >= i64::MIN as f64 && t < -(i64::MIN as f64)).then_some(t as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f32_of(v: Value) -> f32 {
+        match v {
+            Value::F32(x) => x,
+            other => panic!("expected F32, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn i32_operands_keep_integer() {
+        assert!(matches!(abs(Value::I32(-3)), Ok(Value::I32(3))));
+        assert!(matches!(
+            abs(Value::I32(i32::MIN)),
+            Ok(Value::I32(i32::MAX))
+        ));
+        assert!(matches!(
+            min(Value::I32(2), Value::I32(-5)),
+            Ok(Value::I32(-5))
+        ));
+        assert!(matches!(
+            max(Value::I32(2), Value::I32(-5)),
+            Ok(Value::I32(2))
+        ));
+        assert!(matches!(
+            pow(Value::I32(2), Value::I32(10)),
+            Ok(Value::I32(1024))
+        ));
+        assert!(matches!(
+            pow(Value::I32(10), Value::I32(20)),
+            Ok(Value::I32(i32::MAX))
+        ));
+        assert!(matches!(floor(Value::I32(7)), Ok(Value::I32(7))));
+    }
+
+    #[test]
+    fn mixed_operands_promote_to_f32() {
+        assert_eq!(f32_of(min(Value::I32(2), Value::F32(1.5)).unwrap()), 1.5);
+        assert_eq!(f32_of(pow(Value::F32(2.0), Value::I32(-1)).unwrap()), 0.5);
+        assert_eq!(f32_of(floor(Value::F32(-1.5)).unwrap()), -2.0);
+        assert_eq!(f32_of(ceil(Value::F32(-1.5)).unwrap()), -1.0);
+    }
+
+    #[test]
+    fn wide_operands_promote_to_i64_or_f64() {
+        assert!(matches!(
+            min(Value::I32(2), Value::I64(-5)),
+            Ok(Value::I64(-5))
+        ));
+        assert!(matches!(
+            abs(Value::I64(i64::MIN)),
+            Ok(Value::I64(i64::MAX))
+        ));
+        assert!(matches!(
+            pow(Value::I64(10), Value::I32(30)),
+            Ok(Value::I64(i64::MAX))
+        ));
+        assert!(matches!(
+            modulo(Value::I64(-1), Value::I32(3)),
+            Ok(Value::I64(2))
+        ));
+        // I64 と浮動小数点の組み合わせは F64（f32 に丸めない）
+        assert!(matches!(
+            max(Value::I64(1 << 40), Value::F32(0.5)),
+            Ok(Value::F64(x)) if x == (1u64 << 40) as f64
+        ));
+        assert!(matches!(sqrt(Value::I64(16)), Ok(Value::F64(x)) if x == 4.0));
+        assert!(matches!(
+            lerp(Value::F64(0.0), Value::F64(1.0), Value::F32(0.5)),
+            Ok(Value::F64(x)) if x == 0.5
+        ));
+    }
+
+    #[test]
+    fn cast_truncates_and_rejects_out_of_range() {
+        assert!(matches!(
+            cast(NumKind::I32, Value::F64(-2.9)),
+            Ok(Value::I32(-2))
+        ));
+        assert!(matches!(
+            cast(NumKind::I64, Value::Bool(true)),
+            Ok(Value::I64(1))
+        ));
+        assert!(matches!(
+            cast(NumKind::F64, Value::I64(i64::MAX)),
+            Ok(Value::F64(x)) if x == i64::MAX as f64
+        ));
+        assert!(matches!(
+            cast(NumKind::F32, Value::F64(0.1)),
+            Ok(Value::F32(x)) if x == 0.1f32
+        ));
+        for (to, v) in [
+            (NumKind::I32, Value::I64(1 << 31)),
+            (NumKind::I64, Value::F64(f64::NAN)),
+            (NumKind::I64, Value::F32(f32::INFINITY)),
+            (NumKind::F32, Value::F64(1e300)),
+        ] {
+            assert!(matches!(cast(to, v), Err(VmErrorKind::DomainError(_))));
+        }
+        assert!(matches!(
+            cast(NumKind::F64, Value::Vec2([0.0; 2])),
+            Err(VmErrorKind::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn modulo_is_euclidean() {
+        assert!(matches!(
+            modulo(Value::I32(-1), Value::I32(3)),
+            Ok(Value::I32(2))
+        ));
+        assert!(matches!(
+            modulo(Value::I32(i32::MIN), Value::I32(-1)),
+            Ok(Value::I32(0))
+        ));
+        assert_eq!(
+            f32_of(modulo(Value::F32(-0.5), Value::F32(2.0)).unwrap()),
+            1.5
+        );
+    }
+
+    #[test]
+    fn modulo_by_zero_errors() {
+        assert!(matches!(
+            modulo(Value::I32(1), Value::I32(0)),
+            Err(VmErrorKind::DivisionByZero)
+        ));
+        assert!(matches!(
+            modulo(Value::F32(1.0), Value::F32(0.0)),
+            Err(VmErrorKind::DivisionByZero)
+        ));
+    }
+
+    #[test]
+    fn domain_errors() {
+        assert!(matches!(
+            sqrt(Value::F32(-1.0)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            sqrt(Value::I32(-4)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            pow(Value::I32(2), Value::I32(-1)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            pow(Value::F32(-8.0), Value::F32(0.5)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            sin(Value::F32(f32::INFINITY)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            clamp(Value::I32(0), Value::I32(5), Value::I32(1)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            clamp(Value::F32(0.0), Value::F32(5.0), Value::F32(1.0)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+    }
+
+    #[test]
+    fn nan_operands_propagate() {
+        assert!(f32_of(sqrt(Value::F32(f32::NAN)).unwrap()).is_nan());
+        assert!(f32_of(min(Value::F32(f32::NAN), Value::F32(1.0)).unwrap()).is_nan());
+        assert!(f32_of(max(Value::F32(1.0), Value::F32(f32::NAN)).unwrap()).is_nan());
+        assert!(
+            f32_of(clamp(Value::F32(f32::NAN), Value::F32(0.0), Value::F32(1.0)).unwrap()).is_nan()
+        );
+        assert!(
+            f32_of(lerp(Value::F32(0.0), Value::F32(1.0), Value::F32(f32::NAN)).unwrap()).is_nan()
+        );
+    }
+
+    #[test]
+    fn clamp_and_lerp() {
+        assert!(matches!(
+            clamp(Value::I32(9), Value::I32(0), Value::I32(5)),
+            Ok(Value::I32(5))
+        ));
+        assert_eq!(
+            f32_of(clamp(Value::F32(-1.0), Value::I32(0), Value::F32(1.0)).unwrap()),
+            0.0
+        );
+        assert_eq!(
+            f32_of(lerp(Value::F32(10.0), Value::F32(20.0), Value::F32(0.25)).unwrap()),
+            12.5
+        );
+    }
+
+    #[test]
+    fn trig() {
+        let a = f32_of(atan2(Value::F32(1.0), Value::F32(0.0)).unwrap());
+        assert!((a - std::f32::consts::FRAC_PI_2).abs() < 1e-6);
+        assert!((f32_of(cos(Value::I32(0)).unwrap()) - 1.0).abs() < f32::EPSILON);
+        assert!(f32_of(sin(Value::F32(0.0)).unwrap()).abs() < f32::EPSILON);
+    }
+}
@@ -0,0 +1,284 @@
+//! Path: rust/formula/src/opcode.rs
+//! Summary: Formula VM の OpCode 定義
+
+/// OpCode バイト値。バイナリ形式のバイトコードで使用。
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    /// 入力値をレジスタへ。オペランド: dst, name_len, name_bytes...
+    LoadInput = 0,
+    /// 定数 i32 をレジスタへ。オペランド: dst, i32_le
+    LoadI32 = 1,
+    /// 定数 f32 をレジスタへ。オペランド: dst, f32_le
+    LoadF32 = 2,
+    /// 定数 bool をレジスタへ。オペランド: dst, u8 (0=false, 1=true)
+    LoadBool = 3,
+    /// r_dst = r_a + r_b
+    Add = 4,
+    /// r_dst = r_a - r_b
+    Sub = 5,
+    /// r_dst = r_a * r_b
+    Mul = 6,
+    /// r_dst = r_a / r_b
+    Div = 7,
+    /// r_dst = (r_a < r_b)
+    Lt = 8,
+    /// r_dst = (r_a > r_b)
+    Gt = 9,
+    /// r_dst = (r_a == r_b)
+    Eq = 10,
+    /// レジスタ値を出力に追加
+    StoreOutput = 11,
+    /// Store からキーで読んでレジスタへ。オペランド: dst, key_len, key_bytes...
+    ReadStore = 12,
+    /// レジスタ値を Store に書き込む。オペランド: src, key_len, key_bytes...
+    WriteStore = 13,
+    /// 無条件ジャンプ。オペランド: target_u32_le（バイトコード先頭からのバイトオフセット）
+    Jump = 14,
+    /// r_cond が偽ならジャンプ。オペランド: cond, target_u32_le
+    JumpIfFalse = 15,
+    /// r_cond が真ならジャンプ。オペランド: cond, target_u32_le
+    JumpIfTrue = 16,
+    /// r_dst = |r_src|。オペランド: dst, src
+    Abs = 17,
+    /// r_dst = floor(r_src)
+    Floor = 18,
+    /// r_dst = ceil(r_src)
+    Ceil = 19,
+    /// r_dst = sqrt(r_src)
+    Sqrt = 20,
+    /// r_dst = sin(r_src)（ラジアン）
+    Sin = 21,
+    /// r_dst = cos(r_src)（ラジアン）
+    Cos = 22,
+    /// r_dst = min(r_a, r_b)
+    Min = 23,
+    /// r_dst = max(r_a, r_b)
+    Max = 24,
+    /// r_dst = atan2(r_a, r_b)（r_a = y, r_b = x）
+    Atan2 = 25,
+    /// r_dst = r_a ^ r_b
+    Pow = 26,
+    /// r_dst = r_a mod r_b（ユークリッド剰余）
+    Mod = 27,
+    /// r_dst = clamp(r_src, r_lo, r_hi)。オペランド: dst, src, lo, hi
+    Clamp = 28,
+    /// r_dst = r_a + (r_b - r_a) * r_t。オペランド: dst, a, b, t
+    Lerp = 29,
+    /// r_dst = !r_src。オペランド: dst, src
+    Not = 30,
+    /// r_dst = r_a && r_b
+    And = 31,
+    /// r_dst = r_a || r_b
+    Or = 32,
+    /// r_dst = r_a ^ r_b（論理排他的論理和）
+    Xor = 33,
+    /// r_dst = (r_a <= r_b)
+    Le = 34,
+    /// r_dst = (r_a >= r_b)
+    Ge = 35,
+    /// r_dst = (r_a != r_b)
+    Ne = 36,
+    /// r_dst = r_cond ? r_a : r_b。オペランド: dst, cond, a, b
+    Select = 37,
+    /// 定数ベクトルをレジスタへ。オペランド: dst, n (2|3), f32_le × n
+    LoadVec = 38,
+    /// スカラーレジスタからベクトルを組み立てる。オペランド: dst, n (2|3), src × n
+    MakeVec = 39,
+    /// ベクトルの成分を取り出す。オペランド: dst, src, index
+    Extract = 40,
+    /// r_dst = dot(r_a, r_b)
+    Dot = 41,
+    /// r_dst = |r_src|（ベクトル長）。オペランド: dst, src
+    Length = 42,
+    /// r_dst = r_src / |r_src|。オペランド: dst, src
+    Normalize = 43,
+    /// 定数プールの値をレジスタへ（コンテナ形式のみ）。オペランド: dst, index_u16_le
+    LoadConst = 44,
+    /// ライブラリ関数を呼び出し、戻り値を r_dst へ。オペランド: dst, name, argc, arg × argc
+    /// （引数は呼び出し先の r0..r(argc-1) に入る。name の形式は LoadInput と同じ）
+    Call = 45,
+    /// 関数から r_src を返す。トップレベルではプログラムを終了する。オペランド: src
+    Ret = 46,
+    /// 一様乱数 [0, 1) の f32 を r_dst へ。Store の乱数状態（`random::STATE_KEY`）を進める。オペランド: dst
+    Random = 47,
+    /// r_lo..r_hi の一様乱数を r_dst へ（i32 同士は両端を含む整数、それ以外は [lo, hi) の f32）。
+    /// Store の乱数状態を進める。オペランド: dst, lo, hi
+    RandomRange = 48,
+    /// 名前付きイベントを発行する（実行結果のイベント列に追加）。オペランド: src, name
+    /// （src = 0xFF ならペイロードなし。name の形式は LoadInput と同じ）
+    Emit = 49,
+    /// リストの要素数（i32）を r_dst へ。オペランド: dst, src
+    ListLen = 50,
+    /// r_dst = r_list[r_index]（0 始まり、i32 の添字）。オペランド: dst, list, index
+    ListIndex = 51,
+    /// リストの要素の総和を r_dst へ（空リストは 0）。オペランド: dst, src
+    ListSum = 52,
+    /// リストの最小要素を r_dst へ（空リストはドメインエラー）。オペランド: dst, src
+    ListMin = 53,
+    /// リストの最大要素を r_dst へ（空リストはドメインエラー）。オペランド: dst, src
+    ListMax = 54,
+    /// 畳み込み: acc = r_init、要素ごとに acc = name(acc, 要素)、r_dst = acc。オペランド: dst, list, init, name
+    /// （name はライブラリ関数。形式は LoadInput と同じ）
+    ForEach = 55,
+    /// 要素ごとに name(要素) を呼び、結果のリストを r_dst へ。オペランド: dst, list, name
+    ListMap = 56,
+    /// 定数 i64 をレジスタへ。オペランド: dst, i64_le
+    LoadI64 = 57,
+    /// 定数 f64 をレジスタへ。オペランド: dst, f64_le
+    LoadF64 = 58,
+    /// r_dst = r_src を i32 に変換（浮動小数点は 0 方向に切り捨て。範囲外はドメインエラー）。オペランド: dst, src
+    CastI32 = 59,
+    /// r_dst = r_src を f32 に変換。オペランド: dst, src
+    CastF32 = 60,
+    /// r_dst = r_src を i64 に変換（浮動小数点は 0 方向に切り捨て。範囲外はドメインエラー）。オペランド: dst, src
+    CastI64 = 61,
+    /// r_dst = r_src を f64 に変換。オペランド: dst, src
+    CastF64 = 62,
+}
+
+impl OpCode {
+    pub fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(OpCode::LoadInput),
+            1 => Some(OpCode::LoadI32),
+            2 => Some(OpCode::LoadF32),
+            3 => Some(OpCode::LoadBool),
+            4 => Some(OpCode::Add),
+            5 => Some(OpCode::Sub),
+            6 => Some(OpCode::Mul),
+            7 => Some(OpCode::Div),
+            8 => Some(OpCode::Lt),
+            9 => Some(OpCode::Gt),
+            10 => Some(OpCode::Eq),
+            11 => Some(OpCode::StoreOutput),
+            12 => Some(OpCode::ReadStore),
+            13 => Some(OpCode::WriteStore),
+            14 => Some(OpCode::Jump),
+            15 => Some(OpCode::JumpIfFalse),
+            16 => Some(OpCode::JumpIfTrue),
+            17 => Some(OpCode::Abs),
+            18 => Some(OpCode::Floor),
+            19 => Some(OpCode::Ceil),
+            20 => Some(OpCode::Sqrt),
+            21 => Some(OpCode::Sin),
+            22 => Some(OpCode::Cos),
+            23 => Some(OpCode::Min),
+            24 => Some(OpCode::Max),
+            25 => Some(OpCode::Atan2),
+            26 => Some(OpCode::Pow),
+            27 => Some(OpCode::Mod),
+            28 => Some(OpCode::Clamp),
+            29 => Some(OpCode::Lerp),
+            30 => Some(OpCode::Not),
+            31 => Some(OpCode::And),
+            32 => Some(OpCode::Or),
+            33 => Some(OpCode::Xor),
+            34 => Some(OpCode::Le),
+            35 => Some(OpCode::Ge),
+            36 => Some(OpCode::Ne),
+            37 => Some(OpCode::Select),
+            38 => Some(OpCode::LoadVec),
+            39 => Some(OpCode::MakeVec),
+            40 => Some(OpCode::Extract),
+            41 => Some(OpCode::Dot),
+            42 => Some(OpCode::Length),
+            43 => Some(OpCode::Normalize),
+            44 => Some(OpCode::LoadConst),
+            45 => Some(OpCode::Call),
+            46 => Some(OpCode::Ret),
+            47 => Some(OpCode::Random),
+            48 => Some(OpCode::RandomRange),
+            49 => Some(OpCode::Emit),
+            50 => Some(OpCode::ListLen),
+            51 => Some(OpCode::ListIndex),
+            52 => Some(OpCode::ListSum),
+            53 => Some(OpCode::ListMin),
+            54 => Some(OpCode::ListMax),
+            55 => Some(OpCode::ForEach),
+            56 => Some(OpCode::ListMap),
+            57 => Some(OpCode::LoadI64),
+            58 => Some(OpCode::LoadF64),
+            59 => Some(OpCode::CastI32),
+            60 => Some(OpCode::CastF32),
+            61 => Some(OpCode::CastI64),
+            62 => Some(OpCode::CastF64),
+            _ => None,
+        }
+    }
+
+    /// テキストアセンブリでのニーモニック（`Core.Formula.build/1` の命令 atom と同じ綴り）
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            OpCode::LoadInput => "load_input",
+            OpCode::LoadI32 => "load_i32",
+            OpCode::LoadF32 => "load_f32",
+            OpCode::LoadBool => "load_bool",
+            OpCode::Add => "add",
+            OpCode::Sub => "sub",
+            OpCode::Mul => "mul",
+            OpCode::Div => "div",
+            OpCode::Lt => "lt",
+            OpCode::Gt => "gt",
+            OpCode::Eq => "eq",
+            OpCode::StoreOutput => "store_output",
+            OpCode::ReadStore => "read_store",
+            OpCode::WriteStore => "write_store",
+            OpCode::Jump => "jump",
+            OpCode::JumpIfFalse => "jump_if_false",
+            OpCode::JumpIfTrue => "jump_if_true",
+            OpCode::Abs => "abs",
+            OpCode::Floor => "floor",
+            OpCode::Ceil => "ceil",
+            OpCode::Sqrt => "sqrt",
+            OpCode::Sin => "sin",
+            OpCode::Cos => "cos",
+            OpCode::Min => "min",
+            OpCode::Max => "max",
+            OpCode::Atan2 => "atan2",
+            OpCode::Pow => "pow",
+            OpCode::Mod => "mod",
+            OpCode::Clamp => "clamp",
+            OpCode::Lerp => "lerp",
+            OpCode::Not => "not",
+            OpCode::And => "and",
+            OpCode::Or => "or",
+            OpCode::Xor => "xor",
+            OpCode::Le => "le",
+            OpCode::Ge => "ge",
+            OpCode::Ne => "ne",
+            OpCode::Select => "select",
+            OpCode::LoadVec => "load_vec",
+            OpCode::MakeVec => "make_vec",
+            OpCode::Extract => "extract",
+            OpCode::Dot => "dot",
+            OpCode::Length => "length",
+            OpCode::Normalize => "normalize",
+            OpCode::LoadConst => "load_const",
+            OpCode::Call => "call",
+            OpCode::Ret => "ret",
+            OpCode::Random => "random",
+            OpCode::RandomRange => "random_range",
+            OpCode::Emit => "emit",
+            OpCode::ListLen => "list_len",
+            OpCode::ListIndex => "list_index",
+            OpCode::ListSum => "list_sum",
+            OpCode::ListMin => "list_min",
+            OpCode::ListMax => "list_max",
+            OpCode::ForEach => "for_each",
+            OpCode::ListMap => "list_map",
+            OpCode::LoadI64 => "load_i64",
+            OpCode::LoadF64 => "load_f64",
+            OpCode::CastI32 => "cast_i32",
+            OpCode::CastF32 => "cast_f32",
+            OpCode::CastI64 => "cast_i64",
+            OpCode::CastF64 => "cast_f64",
+        }
+    }
+
+    pub fn from_mnemonic(s: &str) -> Option<Self> {
+        (0..=u8::MAX)
+            .map_while(Self::from_u8)
+            .find(|op| op.mnemonic() == s)
+    }
+}
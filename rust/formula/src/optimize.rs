@@ -0,0 +1,688 @@
+//! Path: rust/formula/src/optimize.rs
+//! Summary: Formula プログラムの最適化（定数畳み込み・不要命令の除去・レジスタの詰め直し）
+//!
+//! 出力（StoreOutput の値と順序）と Store への書き込みは元のプログラムと同じになる。
+//! 実行時エラーも変えない: 失敗しうる命令（ゼロ除算・定義域外・型不一致・未知の入力等）は
+//! 結果が使われなくても残し、畳み込みで評価に失敗した命令もそのまま残す。
+//! 変わるのは実行命令数だけ（命令フューエルの消費は減る）。
+//!
+//! 1. 前向き解析: 各レジスタの定数値・型を追い、`vm::eval_pure` で定数部分式を評価して定数ロードに置き換える。
+//!    定数条件のジャンプは無条件ジャンプか何もしない命令になり、到達しない命令は除去対象になる。
+//! 2. 後ろ向き生存解析: 結果が読まれない定数ロード・失敗しない演算を除去する。
+//! 3. 残った命令のレジスタを出現順に r0 から振り直し、ジャンプ先を付け替える。
+//!    書き込む前に読むレジスタ（ライブラリ関数の引数 r0..）は番号を保つ。
+//!
+//! 後方ジャンプが無いため、どちらの解析も 1 回の走査で済む。
+
+use super::decode::{Instruction, REGISTER_COUNT};
+use super::opcode::OpCode;
+use super::value::Value;
+use super::verify::{combinations, TypeSet, ValueType};
+use super::vm::eval_pure;
+use std::sync::Arc;
+
+/// 前向き解析でのレジスタの状態
+#[derive(Debug, Clone)]
+enum Slot {
+    /// 少なくとも 1 つの経路で未初期化
+    Uninit,
+    /// すべての経路で同じ定数
+    Known(Value),
+    /// 取りうる型だけが分かっている
+    Typed(TypeSet),
+}
+
+impl Slot {
+    fn types(&self) -> TypeSet {
+        match self {
+            Slot::Uninit => TypeSet::EMPTY,
+            Slot::Known(v) => TypeSet::single(ValueType::of(v)),
+            Slot::Typed(t) => *t,
+        }
+    }
+
+    fn join(&self, other: &Slot) -> Slot {
+        match (self, other) {
+            (Slot::Uninit, _) | (_, Slot::Uninit) => Slot::Uninit,
+            (Slot::Known(a), Slot::Known(b)) if a.bits_key() == b.bits_key() => self.clone(),
+            _ => Slot::Typed(self.types().union(other.types())),
+        }
+    }
+}
+
+type State = [Slot; REGISTER_COUNT];
+
+fn merge(slot: &mut Option<State>, state: &State) {
+    match slot {
+        Some(existing) => {
+            for (a, b) in existing.iter_mut().zip(state) {
+                *a = a.join(b);
+            }
+        }
+        None => *slot = Some(state.clone()),
+    }
+}
+
+/// 型が合っていれば値によらず失敗しない演算（ゼロ除算・定義域チェックのあるものを除く）
+fn is_total(op: OpCode) -> bool {
+    use OpCode::*;
+    matches!(
+        op,
+        Add | Sub
+            | Mul
+            | Lt
+            | Gt
+            | Le
+            | Ge
+            | Eq
+            | Ne
+            | Abs
+            | Floor
+            | Ceil
+            | Min
+            | Max
+            | Atan2
+            | Not
+            | And
+            | Or
+            | Xor
+            | Select
+            | MakeVec
+            | Extract
+            | Dot
+            | Length
+            | Normalize
+            | ListLen
+            | CastF64
+    )
+}
+
+/// 型ごとの代表値。is_total な演算の成否は値に依存しないため、型推論に使える。
+/// 結果の型も Select（条件の値でどちらのオペランドの型になるかが決まる）以外は値に依存しない
+fn sample(t: ValueType) -> Value {
+    match t {
+        ValueType::I32 => Value::I32(1),
+        ValueType::F32 => Value::F32(1.0),
+        ValueType::Bool => Value::Bool(true),
+        ValueType::Vec2 => Value::Vec2([1.0; 2]),
+        ValueType::Vec3 => Value::Vec3([1.0; 3]),
+        ValueType::List => Value::List(Arc::from([])),
+        ValueType::I64 => Value::I64(1),
+        ValueType::F64 => Value::F64(1.0),
+    }
+}
+
+/// 定数ロード命令。リストは定数にできないため None
+fn constant_load(dst: u8, value: Value) -> Option<Instruction> {
+    match value {
+        Value::I32(value) => Some(Instruction::LoadI32 { dst, value }),
+        Value::F32(value) => Some(Instruction::LoadF32 { dst, value }),
+        Value::I64(value) => Some(Instruction::LoadI64 { dst, value }),
+        Value::F64(value) => Some(Instruction::LoadF64 { dst, value }),
+        Value::Bool(value) => Some(Instruction::LoadBool { dst, value }),
+        Value::Vec2(_) | Value::Vec3(_) => Some(Instruction::LoadVec { dst, value }),
+        Value::List(_) => None,
+    }
+}
+
+/// 1 命令分の前向き解析の結果
+#[derive(Default, Clone, Copy)]
+struct Fact {
+    /// 結果が読まれなければ除去してよい（失敗せず副作用も無い）
+    removable: bool,
+    /// 実行しても何も起きない（到達しない、または条件が定数で分岐しない条件ジャンプ）
+    nop: bool,
+}
+
+/// 命令列を最適化する。入力と同じ出力・Store の書き込み・実行時エラーになる命令列を返す
+pub fn optimize(instructions: &[Instruction]) -> Vec<Instruction> {
+    optimize_with_origins(instructions).0
+}
+
+/// `optimize` と同じ。最適化後の各命令が元の命令列のどのインデックスから来たかも返す（エラー位置を元のバイトコードで示すため）
+pub fn optimize_with_origins(instructions: &[Instruction]) -> (Vec<Instruction>, Vec<usize>) {
+    let mut insts = instructions.to_vec();
+    let facts = fold(&mut insts);
+    let (keep, params) = live_instructions(&insts, &facts);
+    let origins = (0..insts.len()).filter(|pc| keep[*pc]).collect();
+    (compact(insts, &keep, params), origins)
+}
+
+fn fold(insts: &mut [Instruction]) -> Vec<Fact> {
+    let n = insts.len();
+    let mut facts = vec![Fact::default(); n];
+    let mut incoming: Vec<Option<State>> = vec![None; n + 1];
+    let mut fallthrough = Some([const { Slot::Uninit }; REGISTER_COUNT]);
+
+    for pc in 0..n {
+        let mut state = fallthrough.take();
+        if let Some(jumped) = incoming[pc].take() {
+            merge(&mut state, &jumped);
+        }
+        let Some(mut state) = state else {
+            facts[pc].nop = true;
+            continue;
+        };
+        facts[pc] = step(&mut insts[pc], &mut state);
+
+        match &insts[pc] {
+            _ if facts[pc].nop => fallthrough = Some(state),
+            Instruction::Jump { target } => merge(&mut incoming[*target], &state),
+            Instruction::Ret { .. } => {}
+            Instruction::JumpIfFalse { target, .. } | Instruction::JumpIfTrue { target, .. } => {
+                merge(&mut incoming[*target], &state);
+                fallthrough = Some(state);
+            }
+            _ => fallthrough = Some(state),
+        }
+    }
+    facts
+}
+
+fn step(inst: &mut Instruction, state: &mut State) -> Fact {
+    match inst {
+        Instruction::LoadInput { dst, .. }
+        | Instruction::ReadStore { dst, .. }
+        | Instruction::Call { dst, .. }
+        | Instruction::RandomRange { dst, .. } => {
+            state[*dst as usize] = Slot::Typed(TypeSet::ANY);
+            Fact::default()
+        }
+        // 要素の型は追跡しない。添字・空リスト・要素の型で失敗しうるため除去しない
+        Instruction::ListIndex { dst, .. }
+        | Instruction::ListSum { dst, .. }
+        | Instruction::ListMin { dst, .. }
+        | Instruction::ListMax { dst, .. }
+        | Instruction::ForEach { dst, .. } => {
+            state[*dst as usize] = Slot::Typed(TypeSet::ANY);
+            Fact::default()
+        }
+        // 呼び出す関数が失敗しうるため除去しない
+        Instruction::ListMap { dst, .. } => {
+            state[*dst as usize] = Slot::Typed(TypeSet::single(ValueType::List));
+            Fact::default()
+        }
+        // 乱数状態を進める副作用があるため、結果が読まれなくても除去しない
+        Instruction::Random { dst } => {
+            state[*dst as usize] = Slot::Typed(TypeSet::single(ValueType::F32));
+            Fact::default()
+        }
+        Instruction::StoreOutput { .. }
+        | Instruction::WriteStore { .. }
+        | Instruction::Emit { .. }
+        | Instruction::Ret { .. } => Fact::default(),
+        Instruction::Jump { .. } => Fact::default(),
+        Instruction::JumpIfFalse { .. } | Instruction::JumpIfTrue { .. } => {
+            fold_branch(inst, state)
+        }
+        _ => step_pure(inst, state),
+    }
+}
+
+/// 条件が定数の条件ジャンプを、分岐するなら無条件ジャンプに、しないなら何もしない命令にする
+fn fold_branch(inst: &mut Instruction, state: &State) -> Fact {
+    let (cond, target, jump_when) = match *inst {
+        Instruction::JumpIfFalse { cond, target } => (cond, target, false),
+        Instruction::JumpIfTrue { cond, target } => (cond, target, true),
+        _ => return Fact::default(),
+    };
+    let Slot::Known(c) = &state[cond as usize] else {
+        return Fact::default();
+    };
+    match c.as_bool() {
+        Some(b) if b == jump_when => {
+            *inst = Instruction::Jump { target };
+            Fact::default()
+        }
+        Some(_) => Fact {
+            removable: false,
+            nop: true,
+        },
+        None => Fact::default(),
+    }
+}
+
+fn step_pure(inst: &mut Instruction, state: &mut State) -> Fact {
+    let (dst, srcs) = inst.operands();
+    let dst = dst.expect("pure instruction writes a register");
+    let slots: Vec<Slot> = srcs.iter().map(|r| state[*r as usize].clone()).collect();
+
+    // すべて定数なら実行時と同じ関数で評価する。失敗する場合は実行時エラーを残すため畳み込まない
+    if slots.iter().all(|s| matches!(s, Slot::Known(_))) {
+        let mut registers: [Option<Value>; REGISTER_COUNT] = [const { None }; REGISTER_COUNT];
+        for (r, slot) in srcs.iter().zip(&slots) {
+            if let Slot::Known(v) = slot {
+                registers[*r as usize] = Some(v.clone());
+            }
+        }
+        if let Ok(Some((_, value))) = eval_pure(inst, &registers) {
+            if let Some(load) = constant_load(dst, value.clone()) {
+                *inst = load;
+                state[dst as usize] = Slot::Known(value);
+                return Fact {
+                    removable: true,
+                    nop: false,
+                };
+            }
+        }
+        state[dst as usize] = Slot::Typed(TypeSet::ANY);
+        return Fact::default();
+    }
+
+    // 型の組み合わせごとに評価し、結果の型と失敗の有無を調べる。定数のオペランドは実際の値を使う
+    let initialized = !slots.iter().any(|s| matches!(s, Slot::Uninit));
+    let operand_types: Vec<TypeSet> = slots
+        .iter()
+        .map(|s| match s {
+            Slot::Uninit => TypeSet::ANY,
+            _ => s.types(),
+        })
+        .collect();
+    let mut result = TypeSet::EMPTY;
+    let mut fails = false;
+    for combination in combinations(&operand_types) {
+        let mut registers: [Option<Value>; REGISTER_COUNT] = [const { None }; REGISTER_COUNT];
+        for ((r, t), slot) in srcs.iter().zip(&combination).zip(&slots) {
+            registers[*r as usize] = Some(match slot {
+                Slot::Known(v) => v.clone(),
+                _ => sample(*t),
+            });
+        }
+        match eval_pure(inst, &registers) {
+            Ok(Some((_, value))) => result = result.with(ValueType::of(&value)),
+            _ => fails = true,
+        }
+    }
+    // 代表値の条件では片方のオペランドしか選ばれないため、両方の型を取りうる
+    if let Instruction::Select { .. } = inst {
+        if result != TypeSet::EMPTY {
+            result = operand_types[1].union(operand_types[2]);
+        }
+    }
+    state[dst as usize] = if result == TypeSet::EMPTY {
+        Slot::Typed(TypeSet::ANY)
+    } else {
+        Slot::Typed(result)
+    };
+    Fact {
+        removable: initialized && !fails && is_total(inst.opcode()),
+        nop: false,
+    }
+}
+
+/// 後ろ向きの生存解析で残す命令と、先頭で生存している（初期化前に読まれる）レジスタを求める。
+/// 生存レジスタは 64 ビットのビット集合
+fn live_instructions(insts: &[Instruction], facts: &[Fact]) -> (Vec<bool>, u64) {
+    let n = insts.len();
+    let mut live_in = vec![0u64; n + 1];
+    let mut keep = vec![true; n];
+
+    for pc in (0..n).rev() {
+        if facts[pc].nop {
+            keep[pc] = false;
+            live_in[pc] = live_in[pc + 1];
+            continue;
+        }
+        let live_out = match &insts[pc] {
+            Instruction::Jump { target } => live_in[*target],
+            Instruction::JumpIfFalse { target, .. } | Instruction::JumpIfTrue { target, .. } => {
+                live_in[pc + 1] | live_in[*target]
+            }
+            Instruction::Ret { .. } => 0,
+            _ => live_in[pc + 1],
+        };
+        let (dst, srcs) = insts[pc].operands();
+        if facts[pc].removable && dst.is_some_and(|d| live_out & (1 << d) == 0) {
+            keep[pc] = false;
+            live_in[pc] = live_out;
+            continue;
+        }
+        let mut live = live_out;
+        if let Some(d) = dst {
+            live &= !(1 << d);
+        }
+        for s in srcs {
+            live |= 1 << s;
+        }
+        live_in[pc] = live;
+    }
+
+    // 間の命令がすべて除去された無条件ジャンプは次の命令へのジャンプなので不要
+    for pc in (0..n).rev() {
+        if let Instruction::Jump { target } = insts[pc] {
+            if keep[pc] && !keep[pc + 1..target].contains(&true) {
+                keep[pc] = false;
+            }
+        }
+    }
+    (keep, live_in[0])
+}
+
+/// 残す命令だけを集め、レジスタを出現順に振り直し、ジャンプ先を新しいインデックスにする。
+/// params（書き込む前に読むレジスタ。ライブラリ関数の引数）は番号を変えない
+fn compact(insts: Vec<Instruction>, keep: &[bool], params: u64) -> Vec<Instruction> {
+    // new_index[pc] = pc より前に残る命令の数（除去された命令へのジャンプは次に残る命令へ）
+    let mut new_index = Vec::with_capacity(insts.len() + 1);
+    let mut count = 0;
+    for k in keep {
+        new_index.push(count);
+        count += *k as usize;
+    }
+    new_index.push(count);
+
+    let mut mapping: [Option<u8>; REGISTER_COUNT] = [None; REGISTER_COUNT];
+    for r in (0..REGISTER_COUNT as u8).filter(|r| params & (1 << r) != 0) {
+        mapping[r as usize] = Some(r);
+    }
+    let mut next = 0u8;
+    let mut out = Vec::with_capacity(count);
+    for (mut inst, _) in insts.into_iter().zip(keep).filter(|(_, k)| **k) {
+        let (dst, srcs) = inst.operands_mut();
+        for r in srcs.into_iter().chain(dst) {
+            *r = *mapping[*r as usize].get_or_insert_with(|| {
+                while params & (1 << next) != 0 {
+                    next += 1;
+                }
+                next += 1;
+                next - 1
+            });
+        }
+        match &mut inst {
+            Instruction::Jump { target }
+            | Instruction::JumpIfFalse { target, .. }
+            | Instruction::JumpIfTrue { target, .. } => *target = new_index[*target],
+            _ => {}
+        }
+        out.push(inst);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+    use crate::program::Program;
+    use crate::vm::{execute, VmErrorKind};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    type RunResult = Result<(Vec<Value>, HashMap<String, Value>), VmErrorKind>;
+
+    fn run(insts: &[Instruction], inputs: &[(&str, Value)]) -> RunResult {
+        let program = Program::from_instructions(insts.to_vec());
+        let inputs = inputs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        execute(&program, &inputs, &HashMap::new(), &Limits::default()).map_err(|e| e.kind)
+    }
+
+    fn same_result(a: &RunResult, b: &RunResult) -> bool {
+        match (a, b) {
+            (Ok((oa, sa)), Ok((ob, sb))) => {
+                let bits = |vs: &[Value]| vs.iter().map(Value::bits_key).collect::<Vec<_>>();
+                let store_bits = |s: &HashMap<String, Value>| {
+                    let mut v: Vec<_> = s.iter().map(|(k, v)| (k.clone(), v.bits_key())).collect();
+                    v.sort();
+                    v
+                };
+                bits(oa) == bits(ob) && store_bits(sa) == store_bits(sb)
+            }
+            (Err(ea), Err(eb)) => format!("{:?}", ea) == format!("{:?}", eb),
+            _ => false,
+        }
+    }
+
+    fn assert_equivalent(insts: &[Instruction], inputs: &[(&str, Value)]) -> Vec<Instruction> {
+        let optimized = optimize(insts);
+        let (before, after) = (run(insts, inputs), run(&optimized, inputs));
+        assert!(
+            same_result(&before, &after),
+            "{:?} != {:?}\noptimized: {:?}",
+            before,
+            after,
+            optimized
+        );
+        optimized
+    }
+
+    #[test]
+    fn folds_constants_with_saturation() {
+        let program = [
+            Instruction::LoadI32 {
+                dst: 3,
+                value: i32::MAX,
+            },
+            Instruction::LoadI32 { dst: 7, value: 1 },
+            Instruction::Add {
+                dst: 9,
+                src_a: 3,
+                src_b: 7,
+            },
+            Instruction::StoreOutput { src: 9 },
+        ];
+        let optimized = assert_equivalent(&program, &[]);
+        assert!(matches!(
+            optimized.as_slice(),
+            [
+                Instruction::LoadI32 {
+                    dst: 0,
+                    value: i32::MAX
+                },
+                Instruction::StoreOutput { src: 0 }
+            ]
+        ));
+    }
+
+    #[test]
+    fn keeps_division_by_zero() {
+        let program = [
+            Instruction::LoadI32 { dst: 0, value: 1 },
+            Instruction::LoadI32 { dst: 1, value: 0 },
+            Instruction::Div {
+                dst: 2,
+                src_a: 0,
+                src_b: 1,
+            },
+        ];
+        let optimized = assert_equivalent(&program, &[]);
+        assert_eq!(optimized.len(), 3);
+    }
+
+    #[test]
+    fn removes_dead_infallible_instructions() {
+        let program = [
+            Instruction::LoadInput {
+                dst: 0,
+                name: Arc::from("dt"),
+            },
+            Instruction::LoadF32 { dst: 1, value: 2.0 },
+            Instruction::LoadF32 { dst: 4, value: 0.5 },
+            // 定数に畳み込まれ、読まれないので消える
+            Instruction::Mul {
+                dst: 5,
+                src_a: 1,
+                src_b: 1,
+            },
+            // 読まれないが、ゼロ除算・型不一致で失敗しうるので残る
+            Instruction::Div {
+                dst: 6,
+                src_a: 0,
+                src_b: 4,
+            },
+            // eq はどの型でも失敗しないので消える
+            Instruction::Eq {
+                dst: 2,
+                src_a: 0,
+                src_b: 1,
+            },
+            // dt がベクトルなら失敗するので残る
+            Instruction::Lt {
+                dst: 3,
+                src_a: 0,
+                src_b: 1,
+            },
+            Instruction::StoreOutput { src: 0 },
+        ];
+        let optimized = assert_equivalent(&program, &[("dt", Value::F32(0.25))]);
+        let ops: Vec<OpCode> = optimized.iter().map(Instruction::opcode).collect();
+        assert_eq!(
+            ops,
+            [
+                OpCode::LoadInput,
+                OpCode::LoadF32,
+                OpCode::LoadF32,
+                OpCode::Div,
+                OpCode::Lt,
+                OpCode::StoreOutput
+            ]
+        );
+        assert_equivalent(&program, &[("dt", Value::Vec2([1.0, 2.0]))]);
+    }
+
+    #[test]
+    fn folds_constant_branches() {
+        // if false { output 1 } ; output 2
+        let program = [
+            Instruction::LoadBool {
+                dst: 0,
+                value: false,
+            },
+            Instruction::JumpIfFalse { cond: 0, target: 4 },
+            Instruction::LoadI32 { dst: 1, value: 1 },
+            Instruction::StoreOutput { src: 1 },
+            Instruction::LoadI32 { dst: 1, value: 2 },
+            Instruction::StoreOutput { src: 1 },
+        ];
+        let optimized = assert_equivalent(&program, &[]);
+        assert!(matches!(
+            optimized.as_slice(),
+            [
+                Instruction::LoadI32 { dst: 0, value: 2 },
+                Instruction::StoreOutput { src: 0 }
+            ]
+        ));
+    }
+
+    #[test]
+    fn merges_keep_only_agreeing_constants() {
+        // 分岐の両側で r1 が異なる定数になる場合は畳み込まない
+        let program = [
+            Instruction::LoadInput {
+                dst: 0,
+                name: Arc::from("flag"),
+            },
+            Instruction::LoadI32 { dst: 1, value: 1 },
+            Instruction::JumpIfTrue { cond: 0, target: 4 },
+            Instruction::LoadI32 { dst: 1, value: 2 },
+            Instruction::LoadI32 { dst: 2, value: 10 },
+            Instruction::Mul {
+                dst: 3,
+                src_a: 1,
+                src_b: 2,
+            },
+            Instruction::WriteStore {
+                src: 3,
+                name: Arc::from("x"),
+            },
+        ];
+        for flag in [true, false] {
+            assert_equivalent(&program, &[("flag", Value::Bool(flag))]);
+        }
+        assert_equivalent(&program, &[]);
+    }
+
+    #[test]
+    fn preserves_uninitialized_register_errors() {
+        let program = [
+            Instruction::Add {
+                dst: 1,
+                src_a: 0,
+                src_b: 0,
+            },
+            Instruction::LoadI32 { dst: 2, value: 1 },
+            Instruction::StoreOutput { src: 2 },
+        ];
+        assert_equivalent(&program, &[]);
+    }
+
+    #[test]
+    fn select_result_type_follows_condition_value() {
+        // select は定数 false の条件で b（ベクトルになりうる入力）を選ぶ。and は型不一致で失敗し、
+        // Store への書き込みも起きない。最適化で select・and を消してはならない
+        let program = [
+            Instruction::LoadInput {
+                dst: 1,
+                name: Arc::from("b"),
+            },
+            Instruction::LoadBool {
+                dst: 2,
+                value: false,
+            },
+            Instruction::Select {
+                dst: 1,
+                cond: 2,
+                a: 2,
+                b: 1,
+            },
+            Instruction::And {
+                dst: 4,
+                src_a: 2,
+                src_b: 1,
+            },
+            Instruction::LoadInput {
+                dst: 0,
+                name: Arc::from("b"),
+            },
+            Instruction::WriteStore {
+                src: 0,
+                name: Arc::from("s"),
+            },
+            Instruction::StoreOutput { src: 2 },
+        ];
+        assert!(matches!(
+            run(&program, &[("b", Value::Vec2([1.0, 2.0]))]),
+            Err(VmErrorKind::TypeMismatch(ref op)) if op == "and"
+        ));
+        assert_equivalent(&program, &[("b", Value::Vec2([1.0, 2.0]))]);
+        assert_equivalent(&program, &[("b", Value::Bool(true))]);
+    }
+
+    #[test]
+    fn keeps_parameter_registers() {
+        // ライブラリ関数の引数（書き込む前に読む r0, r1）は番号を変えない
+        let function = [
+            Instruction::LoadI32 { dst: 9, value: 2 },
+            Instruction::Mul {
+                dst: 5,
+                src_a: 1,
+                src_b: 9,
+            },
+            Instruction::Sub {
+                dst: 6,
+                src_a: 5,
+                src_b: 0,
+            },
+            Instruction::Ret { src: 6 },
+        ];
+        let optimized = optimize(&function);
+        assert!(matches!(
+            optimized.as_slice(),
+            [
+                Instruction::LoadI32 { dst: 2, value: 2 },
+                Instruction::Mul {
+                    dst: 3,
+                    src_a: 1,
+                    src_b: 2
+                },
+                Instruction::Sub {
+                    dst: 4,
+                    src_a: 3,
+                    src_b: 0
+                },
+                Instruction::Ret { src: 4 },
+            ]
+        ));
+    }
+}
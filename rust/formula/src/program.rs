@@ -0,0 +1,220 @@
+//! Path: rust/formula/src/program.rs
+//! Summary: デコード・検証済みの Formula プログラム（一度だけコンパイルして繰り返し実行する）
+
+use super::decode::{decode_with_offsets, DecodeError, Instruction, Location};
+use super::encode::{encode_container, encode_instructions, EncodeError};
+#[cfg(feature = "jit")]
+use super::jit::JitCache;
+use super::library::Library;
+#[cfg(feature = "jit")]
+use super::limits::Limits;
+use super::optimize::optimize_with_origins;
+use super::source_map::SourceMap;
+use super::store::StoreSchema;
+#[cfg(feature = "jit")]
+use super::value::Value;
+#[cfg(feature = "jit")]
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// デコードとジャンプ先の検証を終えた命令列。入力名・Store キーはインターン済み。
+///
+/// 不変であり `Send + Sync`。NIF ではリソースとして保持し、複数プロセスから同時に実行できる。
+#[derive(Debug, Clone)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    writes_store: bool,
+    /// Call の呼び出し先。None なら Call は `VmErrorKind::UndefinedFunction`
+    library: Option<Arc<Library>>,
+    /// Store キーの型と既定値。None なら Store に無いキーの読み出しは `VmErrorKind::StoreNotFound`
+    store_schema: Option<Arc<StoreSchema>>,
+    /// 各命令の元のバイトコード上のオフセット（命令数 + 1 個。末尾はコード長）。エラー位置に使う
+    offsets: Vec<usize>,
+    /// オフセットから生成元を引く表。None ならエラー位置の origin は常に None
+    source_map: Option<Arc<SourceMap>>,
+    /// 実行回数とネイティブコード（jit.rs）。命令列が同じ複製（link 等）とは共有する
+    #[cfg(feature = "jit")]
+    jit: Arc<JitCache>,
+}
+
+impl Program {
+    /// バイトコードをデコード・検証してプログラムを作る
+    pub fn compile(bytecode: &[u8]) -> Result<Self, DecodeError> {
+        let (instructions, offsets) = decode_with_offsets(bytecode)?;
+        Ok(Self::with_offsets(instructions, offsets))
+    }
+
+    /// 検証済みの命令列から作る（ジャンプ先が前方の命令インデックスであること）。
+    /// オフセットは従来形式でエンコードした場合の位置になる
+    #[cfg(test)]
+    pub(super) fn from_instructions(instructions: Vec<Instruction>) -> Self {
+        let bytecode = encode_instructions(&instructions).expect("encodable instructions");
+        Self::compile(&bytecode).expect("valid instructions")
+    }
+
+    fn with_offsets(instructions: Vec<Instruction>, offsets: Vec<usize>) -> Self {
+        let writes_store = instructions.iter().any(Instruction::writes_store);
+        Self {
+            instructions,
+            writes_store,
+            library: None,
+            store_schema: None,
+            offsets,
+            source_map: None,
+            #[cfg(feature = "jit")]
+            jit: Arc::default(),
+        }
+    }
+
+    /// Call の呼び出し先としてライブラリを結び付けたプログラムを返す。
+    /// 関数名の解決は実行時に行う（ライブラリに無い名前は `VmErrorKind::UndefinedFunction`）。
+    pub fn link(&self, library: Arc<Library>) -> Program {
+        let calls = self
+            .instructions
+            .iter()
+            .any(|inst| matches!(inst, Instruction::Call { .. }));
+        Self {
+            writes_store: self.writes_store || (calls && library.writes_store()),
+            library: Some(library),
+            ..self.clone()
+        }
+    }
+
+    /// Store スキーマを結び付けたプログラムを返す（既に結び付いていれば置き換える）。ライブラリは引き継ぐ。
+    /// 呼び出し先の関数の Store アクセスにも同じスキーマが掛かる。
+    pub fn with_store_schema(&self, schema: Arc<StoreSchema>) -> Program {
+        Self {
+            store_schema: Some(schema),
+            ..self.clone()
+        }
+    }
+
+    /// オフセットと生成元の対応表を結び付けたプログラムを返す（既に結び付いていれば置き換える）。
+    /// 実行時エラーの `Location::origin` に使う。オフセットは最適化の前後とも元のバイトコード上の位置
+    pub fn with_source_map(&self, source_map: Arc<SourceMap>) -> Program {
+        Self {
+            source_map: Some(source_map),
+            ..self.clone()
+        }
+    }
+
+    /// 定数畳み込み・不要命令の除去・レジスタの詰め直しをしたプログラム（optimize.rs）。
+    /// ライブラリ・Store スキーマ・ソースマップは引き継ぎ、エラー位置は元のバイトコード上のオフセットを指す
+    pub fn optimize(&self) -> Program {
+        let (instructions, origins) = optimize_with_origins(&self.instructions);
+        let end = self.offsets[self.instructions.len()];
+        let offsets = origins
+            .iter()
+            .map(|pc| self.offsets[*pc])
+            .chain([end])
+            .collect();
+        let optimized = Self::with_offsets(instructions, offsets);
+        let optimized = match &self.library {
+            Some(library) => optimized.link(library.clone()),
+            None => optimized,
+        };
+        Self {
+            store_schema: self.store_schema.clone(),
+            source_map: self.source_map.clone(),
+            ..optimized
+        }
+    }
+
+    /// 従来形式（ヘッダなし）のバイトコードで書き出す
+    pub fn to_legacy(&self) -> Result<Vec<u8>, EncodeError> {
+        encode_instructions(&self.instructions)
+    }
+
+    /// WriteStore を含むか（バッチ実行で Store の複製が必要か）
+    pub fn writes_store(&self) -> bool {
+        self.writes_store
+    }
+
+    /// チェックサム付きのコンテナ形式で書き出す（従来形式で保存されたプログラムの移行用）
+    pub fn to_container(&self) -> Result<Vec<u8>, EncodeError> {
+        encode_container(&self.instructions, true)
+    }
+
+    pub(super) fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// 十分に実行されたプログラムをネイティブコードで実行する。None なら解釈実行する（jit.rs）
+    #[cfg(feature = "jit")]
+    pub(super) fn run_jit(
+        &self,
+        inputs: &HashMap<String, Value>,
+        limits: &Limits,
+    ) -> Option<Vec<Value>> {
+        self.jit.run(&self.instructions, inputs, limits)
+    }
+
+    #[cfg(all(test, feature = "jit"))]
+    pub(super) fn jit_compiled(&self) -> bool {
+        self.jit.is_compiled()
+    }
+
+    pub(super) fn library(&self) -> Option<&Library> {
+        self.library.as_deref()
+    }
+
+    pub(super) fn store_schema(&self) -> Option<&StoreSchema> {
+        self.store_schema.as_deref()
+    }
+
+    /// 命令インデックス pc（命令数なら終端）の位置。function は呼び出し先の関数名（トップレベルは None）
+    pub(super) fn location(&self, pc: usize, function: Option<&str>) -> Location {
+        let offset = self.offsets[pc.min(self.instructions.len())];
+        Location {
+            function: function.map(str::to_string),
+            pc,
+            offset,
+            origin: self
+                .source_map
+                .as_ref()
+                .and_then(|map| map.lookup(offset).cloned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::DecodeErrorKind;
+    use std::sync::Arc;
+
+    fn with_name(op: u8, reg: u8, name: &str) -> Vec<u8> {
+        let mut buf = vec![op, reg, name.len() as u8];
+        buf.extend_from_slice(name.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn names_are_interned() {
+        let mut bc = Vec::new();
+        bc.extend(with_name(12, 0, "score")); // OpCode::ReadStore
+        bc.extend(with_name(13, 0, "score")); // OpCode::WriteStore
+        let program = Program::compile(&bc).expect("compile");
+        match program.instructions() {
+            [Instruction::ReadStore { name: a, .. }, Instruction::WriteStore { name: b, .. }] => {
+                assert!(Arc::ptr_eq(a, b))
+            }
+            other => panic!("unexpected instructions {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compile_reports_decode_errors() {
+        assert!(matches!(
+            Program::compile(&[99]),
+            Err(DecodeError {
+                kind: DecodeErrorKind::InvalidOpCode(99),
+                location: Some(Location {
+                    pc: 0,
+                    offset: 0,
+                    ..
+                })
+            })
+        ));
+    }
+}
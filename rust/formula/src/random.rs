@@ -0,0 +1,229 @@
+//! Path: rust/formula/src/random.rs
+//! Summary: Formula VM の決定的な疑似乱数（Random / RandomRange）
+//!
+//! 生成器は mulberry32（状態 32 bit）。整数演算だけで次の値を作るため、同じシードからは
+//! どのプラットフォームでも同じ列が出る。f32 への変換も上位 24 bit に 2^-24 を掛けるだけで丸めは入らない。
+//!
+//! 状態は Store の予約キー `STATE_KEY` に I32（u32 のビット列そのまま）で置く。
+//! Random / RandomRange は実行のたびに Store から状態を読み、進めた状態を書き戻す。
+//! したがって実行は `(bytecode, inputs, store_values)` だけで決まり、返った Store を次の実行に渡せば列の続きになる。
+//!
+//! - キーが無ければ `VmErrorKind::StoreNotFound`（暗黙の既定シードは持たない）。I32 以外は `TypeMismatch`
+//! - シードは任意の i32。`write_store` で入力から設定してもよい（バッチでエンティティごとに列を変える場合など）
+
+use super::value::Value;
+use super::vm::VmErrorKind;
+
+/// 乱数状態を置く Store キー
+pub const STATE_KEY: &str = "$rng";
+
+/// 状態を 1 つ進め、32 bit の乱数を返す（mulberry32）
+pub(super) fn next_u32(state: &mut u32) -> u32 {
+    *state = state.wrapping_add(0x6D2B_79F5);
+    let mut z = *state;
+    z = (z ^ (z >> 15)).wrapping_mul(z | 1);
+    z ^= z.wrapping_add((z ^ (z >> 7)).wrapping_mul(z | 61));
+    z ^ (z >> 14)
+}
+
+/// [0, 1) の f32。上位 24 bit を使うので 2^-24 刻みで一様
+pub(super) fn unit(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / 16_777_216.0)
+}
+
+/// RandomRange の値。i32 同士は [lo, hi]（両端を含む）の整数、それ以外は [lo, hi) の f32。
+/// lo > hi はドメインエラー。lo == hi なら lo
+pub(super) fn range(x: u32, lo: Value, hi: Value) -> Result<Value, VmErrorKind> {
+    let domain_error = || VmErrorKind::DomainError("random_range".into());
+    if let (&Value::I32(lo), &Value::I32(hi)) = (&lo, &hi) {
+        if lo > hi {
+            return Err(domain_error());
+        }
+        // 幅は最大 2^32。乗算とシフトで [0, span) に写す（剰余より偏りが小さく、分岐も無い）
+        let span = (hi as i64 - lo as i64 + 1) as u64;
+        let offset = (x as u64 * span) >> 32;
+        return Ok(Value::I32((lo as i64 + offset as i64) as i32));
+    }
+    let type_mismatch = || VmErrorKind::TypeMismatch("random_range".into());
+    let flo = lo.as_f32().ok_or_else(type_mismatch)?;
+    let fhi = hi.as_f32().ok_or_else(type_mismatch)?;
+    if flo > fhi {
+        return Err(domain_error());
+    }
+    if flo.is_nan() || fhi.is_nan() {
+        return Ok(Value::F32(f32::NAN));
+    }
+    if flo == fhi {
+        return Ok(Value::F32(flo));
+    }
+    let result = flo + (fhi - flo) * unit(x);
+    if result.is_nan() {
+        // 無限大の端点（inf - inf 等）
+        return Err(domain_error());
+    }
+    // 丸めで hi に届いた場合は hi の直前に寄せ、半開区間を保つ
+    Ok(Value::F32(if result >= fhi {
+        fhi.next_down()
+    } else {
+        result
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::limits::Limits;
+    use crate::program::Program;
+    use crate::vm::{execute, execute_batch, VmError};
+    use std::collections::HashMap;
+
+    fn seeded(seed: i32) -> HashMap<String, Value> {
+        HashMap::from([(STATE_KEY.to_string(), Value::I32(seed))])
+    }
+
+    #[test]
+    fn sequence_is_fixed_for_a_seed() {
+        // 実装を変えるとリプレイが壊れるため、既知の列で固定する
+        let mut state = 0u32;
+        let first: Vec<u32> = (0..4).map(|_| next_u32(&mut state)).collect();
+        assert_eq!(first, [1_144_304_738, 1_416_247, 958_946_056, 627_933_444]);
+        assert_eq!(state, 0x6D2B_79F5u32.wrapping_mul(4));
+    }
+
+    #[test]
+    fn unit_is_half_open() {
+        assert_eq!(unit(0), 0.0);
+        assert!(unit(u32::MAX) < 1.0);
+    }
+
+    #[test]
+    fn integer_range_covers_both_ends() {
+        assert!(matches!(
+            range(0, Value::I32(1), Value::I32(6)),
+            Ok(Value::I32(1))
+        ));
+        assert!(matches!(
+            range(u32::MAX, Value::I32(1), Value::I32(6)),
+            Ok(Value::I32(6))
+        ));
+        assert!(matches!(
+            range(u32::MAX, Value::I32(i32::MIN), Value::I32(i32::MAX)),
+            Ok(Value::I32(i32::MAX))
+        ));
+        assert!(matches!(
+            range(7, Value::I32(3), Value::I32(3)),
+            Ok(Value::I32(3))
+        ));
+    }
+
+    #[test]
+    fn float_range_stays_below_hi() {
+        let Ok(Value::F32(v)) = range(u32::MAX, Value::F32(1.0), Value::F32(1.0 + f32::EPSILON))
+        else {
+            panic!("expected f32");
+        };
+        assert!(v < 1.0 + f32::EPSILON);
+        assert!(matches!(
+            range(0, Value::I32(2), Value::F32(4.0)),
+            Ok(Value::F32(v)) if v == 2.0
+        ));
+    }
+
+    #[test]
+    fn range_errors() {
+        assert!(matches!(
+            range(0, Value::I32(2), Value::I32(1)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            range(0, Value::F32(f32::NEG_INFINITY), Value::F32(f32::INFINITY)),
+            Err(VmErrorKind::DomainError(_))
+        ));
+        assert!(matches!(
+            range(0, Value::Vec2([0.0, 0.0]), Value::F32(1.0)),
+            Err(VmErrorKind::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn run_is_pure_given_the_store() {
+        let program = Program::compile(
+            &assemble("random r0\nload_i32 r1 1\nload_i32 r2 6\nrandom_range r3 r1 r2\nstore_output r0\nstore_output r3")
+                .expect("assemble"),
+        )
+        .expect("compile");
+        let inputs = HashMap::new();
+        let (first, store) =
+            execute(&program, &inputs, &seeded(42), &Limits::default()).expect("run");
+        let (again, _) = execute(&program, &inputs, &seeded(42), &Limits::default()).expect("run");
+        assert_eq!(format!("{:?}", first), format!("{:?}", again));
+
+        // 返った Store は 2 回分進んだ状態で、渡せば列の続きになる
+        let mut state = 42u32;
+        let a = next_u32(&mut state);
+        let b = next_u32(&mut state);
+        assert!(matches!(store[STATE_KEY], Value::I32(s) if s as u32 == state));
+        assert!(matches!(first[0], Value::F32(v) if v == unit(a)));
+        assert!(matches!(
+            (first[1].clone(), range(b, Value::I32(1), Value::I32(6))),
+            (Value::I32(x), Ok(Value::I32(y))) if x == y
+        ));
+        let (next, _) = execute(&program, &inputs, &store, &Limits::default()).expect("run");
+        assert!(matches!(next[0], Value::F32(v) if v == unit(next_u32(&mut state))));
+    }
+
+    #[test]
+    fn state_must_be_seeded_i32() {
+        let program = Program::compile(&assemble("random r0").expect("assemble")).expect("compile");
+        let inputs = HashMap::new();
+        assert!(matches!(
+            execute(&program, &inputs, &HashMap::new(), &Limits::default()),
+            Err(VmError { kind: VmErrorKind::StoreNotFound(key), .. }) if key == STATE_KEY
+        ));
+        let store = HashMap::from([(STATE_KEY.to_string(), Value::F32(1.0))]);
+        assert!(matches!(
+            execute(&program, &inputs, &store, &Limits::default()),
+            Err(VmError {
+                kind: VmErrorKind::TypeMismatch(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn unused_random_still_advances_the_state() {
+        let program = Program::compile(&assemble("random r0\nrandom r1").expect("assemble"))
+            .expect("compile")
+            .optimize();
+        let (_, store) =
+            execute(&program, &HashMap::new(), &seeded(0), &Limits::default()).expect("run");
+        assert!(
+            matches!(store[STATE_KEY], Value::I32(s) if s as u32 == 0x6D2B_79F5u32.wrapping_mul(2))
+        );
+    }
+
+    #[test]
+    fn batch_entities_start_from_the_same_state_unless_reseeded() {
+        let program = Program::compile(
+            &assemble(
+                "load_input r0 \"seed\"\nwrite_store r0 \"$rng\"\nrandom r1\nstore_output r1",
+            )
+            .expect("assemble"),
+        )
+        .expect("compile");
+        let entity = |seed| HashMap::from([("seed".to_string(), Value::I32(seed))]);
+        let results = execute_batch(
+            &program,
+            &[entity(1), entity(1), entity(2)],
+            &seeded(0),
+            &Limits::default(),
+        );
+        let values: Vec<String> = results
+            .into_iter()
+            .map(|r| format!("{:?}", r.expect("run")))
+            .collect();
+        assert_eq!(values[0], values[1]);
+        assert_ne!(values[0], values[2]);
+    }
+}
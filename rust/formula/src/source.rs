@@ -0,0 +1,1059 @@
+//! Path: rust/formula/src/source.rs
+//! Summary: Formula 式言語のコンパイラ（`hp - damage * (1 - armor)` → バイトコード）
+//!
+//! 1 行 1 文（`;` でも区切れる。括弧の中では改行できる）。`#` 以降は行末までコメント。
+//!
+//! ```text
+//! let reduced = damage * (1 - armor)
+//! store.hp = max(store.hp - reduced, 0)
+//! output store.hp
+//! output store.hp <= 0
+//! ```
+//!
+//! - 文: `output 式`（StoreOutput）、`store.キー = 式`（WriteStore）、`let 名前 = 式`（名前付きの値）、
+//!   `emit イベント名` / `emit イベント名(式)`（Emit。式はペイロード）
+//! - 識別子は let で定義した名前、それ以外は入力（LoadInput。同じ入力は 1 回だけ読む）
+//! - `store.キー` は Store の読み出し（ReadStore。読むたびに現在の値を読む）
+//! - リテラル: 整数（i32）、小数（f32。`1.5`、`2e3`）、`true` / `false`
+//! - 演算子（優先順位の低い順）: `or` `||` / `and` `&&` / `not` `!` / `== != < > <= >=`（連鎖不可）/
+//!   `+ -` / `* / %` / 単項 `-` / `.x .y .z`（ベクトル成分）
+//! - 関数: abs floor ceil sqrt sin cos length normalize（1 引数）、min max atan2 pow xor dot（2 引数）、
+//!   clamp lerp select（3 引数。select(cond, a, b)）、vec2 vec3（成分からベクトルを作る）、
+//!   random()（[0, 1)）、random_range(lo, hi)（呼ぶたびに Store の乱数状態を進める）
+//!
+//! 演算の意味は対応する OpCode と同じ。生成した命令列は optimize.rs で最適化し、コンテナ形式で出力する。
+
+use super::decode::{Instruction, Name, REGISTER_COUNT};
+use super::encode::{encode_container, EncodeError};
+use super::opcode::OpCode;
+use super::optimize::optimize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum SourceError {
+    /// 構文・意味のエラー。line / column は 1 始まり（column は文字単位）
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    Encode(EncodeError),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+fn error(pos: Pos, message: impl Into<String>) -> SourceError {
+    SourceError::Parse {
+        line: pos.line,
+        column: pos.column,
+        message: message.into(),
+    }
+}
+
+/// 関数名 → (OpCode, 引数の数)
+const FUNCTIONS: &[(&str, OpCode, usize)] = &[
+    ("abs", OpCode::Abs, 1),
+    ("floor", OpCode::Floor, 1),
+    ("ceil", OpCode::Ceil, 1),
+    ("sqrt", OpCode::Sqrt, 1),
+    ("sin", OpCode::Sin, 1),
+    ("cos", OpCode::Cos, 1),
+    ("length", OpCode::Length, 1),
+    ("normalize", OpCode::Normalize, 1),
+    ("min", OpCode::Min, 2),
+    ("max", OpCode::Max, 2),
+    ("atan2", OpCode::Atan2, 2),
+    ("pow", OpCode::Pow, 2),
+    ("xor", OpCode::Xor, 2),
+    ("dot", OpCode::Dot, 2),
+    ("clamp", OpCode::Clamp, 3),
+    ("lerp", OpCode::Lerp, 3),
+    ("select", OpCode::Select, 3),
+    ("vec2", OpCode::MakeVec, 2),
+    ("vec3", OpCode::MakeVec, 3),
+    ("random", OpCode::Random, 0),
+    ("random_range", OpCode::RandomRange, 2),
+];
+
+const KEYWORDS: &[&str] = &[
+    "let", "output", "store", "emit", "true", "false", "and", "or", "not",
+];
+
+// ---- 字句解析 ----
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Int(i64),
+    Float(f32),
+    Sym(&'static str),
+    /// 文の区切り（改行・`;`）
+    End,
+    Eof,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    pos: Pos,
+}
+
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "==", "!=", "&&", "||", "(", ")", ",", ".", "=", "+", "-", "*", "/", "%", "<", ">",
+    "!",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, SourceError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line, mut column) = (0, 1, 1);
+    let mut depth = 0usize;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let pos = Pos { line, column };
+        if c == '\n' || c == ';' {
+            if depth == 0 {
+                tokens.push(Token { tok: Tok::End, pos });
+            }
+            i += 1;
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            column += 1;
+            continue;
+        }
+        if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        let start = i;
+        let tok = if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            Tok::Ident(chars[start..i].iter().collect())
+        } else if c.is_ascii_digit() {
+            let mut is_float = false;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit() {
+                is_float = true;
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    is_float = true;
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            if is_float {
+                Tok::Float(
+                    text.parse()
+                        .map_err(|_| error(pos, format!("invalid number {}", text)))?,
+                )
+            } else {
+                Tok::Int(
+                    text.parse()
+                        .map_err(|_| error(pos, format!("integer {} is too large", text)))?,
+                )
+            }
+        } else {
+            let sym = SYMBOLS
+                .iter()
+                .find(|s| {
+                    s.chars()
+                        .enumerate()
+                        .all(|(k, sc)| chars.get(i + k) == Some(&sc))
+                })
+                .ok_or_else(|| error(pos, format!("unexpected character {:?}", c)))?;
+            i += sym.chars().count();
+            match *sym {
+                "(" => depth += 1,
+                ")" => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            Tok::Sym(sym)
+        };
+        column += i - start;
+        tokens.push(Token { tok, pos });
+    }
+    tokens.push(Token {
+        tok: Tok::Eof,
+        pos: Pos { line, column },
+    });
+    Ok(tokens)
+}
+
+// ---- 構文解析 ----
+
+#[derive(Debug)]
+enum Expr {
+    Int(i64, Pos),
+    Float(f32),
+    Bool(bool),
+    Var(String),
+    Store(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(OpCode, Box<Expr>, Box<Expr>),
+    Call(OpCode, Vec<Expr>),
+    Field(Box<Expr>, u8),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Let(String, Expr),
+    Output(Expr),
+    WriteStore(String, Expr),
+    Emit(String, Option<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].tok
+    }
+
+    fn here(&self) -> Pos {
+        self.tokens[self.pos].pos
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> SourceError {
+        let found = match self.peek() {
+            Tok::Ident(s) => format!("`{}`", s),
+            Tok::Int(n) => n.to_string(),
+            Tok::Float(f) => f.to_string(),
+            Tok::Sym(s) => format!("`{}`", s),
+            Tok::End => "end of statement".into(),
+            Tok::Eof => "end of input".into(),
+        };
+        error(
+            self.here(),
+            format!("expected {}, found {}", expected, found),
+        )
+    }
+
+    fn eat_sym(&mut self, sym: &'static str) -> bool {
+        if *self.peek() == Tok::Sym(sym) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_sym(&mut self, sym: &'static str) -> Result<(), SourceError> {
+        if self.eat_sym(sym) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", sym)))
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Tok::Ident(s) if s == keyword)
+    }
+
+    /// キーワードでない識別子
+    fn ident(&mut self, what: &str) -> Result<String, SourceError> {
+        match self.peek() {
+            Tok::Ident(s) if !KEYWORDS.contains(&s.as_str()) => {
+                let s = s.clone();
+                self.next();
+                Ok(s)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    fn program(&mut self) -> Result<Vec<(Pos, Stmt)>, SourceError> {
+        let mut stmts = Vec::new();
+        loop {
+            while *self.peek() == Tok::End {
+                self.next();
+            }
+            if *self.peek() == Tok::Eof {
+                return Ok(stmts);
+            }
+            let pos = self.here();
+            stmts.push((pos, self.statement()?));
+            match self.peek() {
+                Tok::End | Tok::Eof => {}
+                _ => return Err(self.unexpected("end of statement")),
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, SourceError> {
+        if self.is_keyword("let") {
+            self.next();
+            let name = self.ident("name")?;
+            self.expect_sym("=")?;
+            return Ok(Stmt::Let(name, self.expr()?));
+        }
+        if self.is_keyword("output") {
+            self.next();
+            return Ok(Stmt::Output(self.expr()?));
+        }
+        if self.is_keyword("store") {
+            self.next();
+            self.expect_sym(".")?;
+            let key = self.store_key()?;
+            self.expect_sym("=")?;
+            return Ok(Stmt::WriteStore(key, self.expr()?));
+        }
+        if self.is_keyword("emit") {
+            self.next();
+            let name = self.store_key()?;
+            if !self.eat_sym("(") {
+                return Ok(Stmt::Emit(name, None));
+            }
+            let payload = self.expr()?;
+            self.expect_sym(")")?;
+            return Ok(Stmt::Emit(name, Some(payload)));
+        }
+        Err(self.unexpected("`let`, `output`, `emit` or `store.key = ...`"))
+    }
+
+    /// `store.` に続くキー（キーワードも使える）
+    fn store_key(&mut self) -> Result<String, SourceError> {
+        match self.peek() {
+            Tok::Ident(s) => {
+                let s = s.clone();
+                self.next();
+                Ok(s)
+            }
+            _ => Err(self.unexpected("store key")),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, SourceError> {
+        self.or()
+    }
+
+    fn or(&mut self) -> Result<Expr, SourceError> {
+        let mut lhs = self.and()?;
+        while self.is_keyword("or") || *self.peek() == Tok::Sym("||") {
+            self.next();
+            lhs = Expr::Binary(OpCode::Or, Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, SourceError> {
+        let mut lhs = self.not()?;
+        while self.is_keyword("and") || *self.peek() == Tok::Sym("&&") {
+            self.next();
+            lhs = Expr::Binary(OpCode::And, Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<Expr, SourceError> {
+        if self.is_keyword("not") || *self.peek() == Tok::Sym("!") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison_op(&self) -> Option<OpCode> {
+        match self.peek() {
+            Tok::Sym("==") => Some(OpCode::Eq),
+            Tok::Sym("!=") => Some(OpCode::Ne),
+            Tok::Sym("<") => Some(OpCode::Lt),
+            Tok::Sym(">") => Some(OpCode::Gt),
+            Tok::Sym("<=") => Some(OpCode::Le),
+            Tok::Sym(">=") => Some(OpCode::Ge),
+            _ => None,
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, SourceError> {
+        let lhs = self.additive()?;
+        let Some(op) = self.comparison_op() else {
+            return Ok(lhs);
+        };
+        self.next();
+        let rhs = self.additive()?;
+        if self.comparison_op().is_some() {
+            return Err(error(self.here(), "comparison operators cannot be chained"));
+        }
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn additive(&mut self) -> Result<Expr, SourceError> {
+        let mut lhs = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Tok::Sym("+") => OpCode::Add,
+                Tok::Sym("-") => OpCode::Sub,
+                _ => return Ok(lhs),
+            };
+            self.next();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, SourceError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Tok::Sym("*") => OpCode::Mul,
+                Tok::Sym("/") => OpCode::Div,
+                Tok::Sym("%") => OpCode::Mod,
+                _ => return Ok(lhs),
+            };
+            self.next();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, SourceError> {
+        if self.eat_sym("-") {
+            return Ok(match self.unary()? {
+                // リテラルは符号付きの定数にする（-2147483648 を書けるように）
+                Expr::Int(n, pos) => Expr::Int(-n, pos),
+                Expr::Float(f) => Expr::Float(-f),
+                other => Expr::Neg(Box::new(other)),
+            });
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr, SourceError> {
+        let mut expr = self.primary()?;
+        while self.eat_sym(".") {
+            let index = match self.peek() {
+                Tok::Ident(s) if s == "x" => 0,
+                Tok::Ident(s) if s == "y" => 1,
+                Tok::Ident(s) if s == "z" => 2,
+                _ => return Err(self.unexpected("`x`, `y` or `z`")),
+            };
+            self.next();
+            expr = Expr::Field(Box::new(expr), index);
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, SourceError> {
+        let token = self.next();
+        match token.tok {
+            Tok::Int(n) => Ok(Expr::Int(n, token.pos)),
+            Tok::Float(f) => Ok(Expr::Float(f)),
+            Tok::Sym("(") => {
+                let expr = self.expr()?;
+                self.expect_sym(")")?;
+                Ok(expr)
+            }
+            Tok::Ident(s) if s == "true" || s == "false" => Ok(Expr::Bool(s == "true")),
+            Tok::Ident(s) if s == "store" => {
+                self.expect_sym(".")?;
+                Ok(Expr::Store(self.store_key()?))
+            }
+            Tok::Ident(s) if !KEYWORDS.contains(&s.as_str()) => {
+                if !self.eat_sym("(") {
+                    return Ok(Expr::Var(s));
+                }
+                let &(_, op, arity) = FUNCTIONS
+                    .iter()
+                    .find(|(name, _, _)| *name == s)
+                    .ok_or_else(|| error(token.pos, format!("unknown function {}", s)))?;
+                let mut args = Vec::new();
+                if !self.eat_sym(")") {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.eat_sym(")") {
+                            break;
+                        }
+                        self.expect_sym(",")?;
+                    }
+                }
+                if args.len() != arity {
+                    return Err(error(
+                        token.pos,
+                        format!("{} takes {} arguments, got {}", s, arity, args.len()),
+                    ));
+                }
+                Ok(Expr::Call(op, args))
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("expression"))
+            }
+        }
+    }
+}
+
+// ---- コード生成 ----
+
+/// レジスタの割り当て: r0 から順に、入力と let の値を固定（pinned）で置き、その上を一時レジスタに使う。
+/// 文の評価に使う入力は文の先頭でまとめて読み込むため、固定レジスタと一時レジスタは交差しない。
+struct Codegen {
+    instructions: Vec<Instruction>,
+    names: HashMap<String, Name>,
+    bindings: HashMap<String, u8>,
+    pinned: u8,
+    top: u8,
+    pos: Pos,
+}
+
+impl Codegen {
+    fn name(&mut self, s: &str) -> Name {
+        self.names
+            .entry(s.to_string())
+            .or_insert_with(|| Arc::from(s))
+            .clone()
+    }
+
+    fn alloc(&mut self) -> Result<u8, SourceError> {
+        if self.top as usize >= REGISTER_COUNT {
+            return Err(error(
+                self.pos,
+                format!("statement needs more than {} registers", REGISTER_COUNT),
+            ));
+        }
+        self.top += 1;
+        Ok(self.top - 1)
+    }
+
+    fn statement(&mut self, pos: Pos, stmt: &Stmt) -> Result<(), SourceError> {
+        self.pos = pos;
+        let expr = match stmt {
+            Stmt::Let(_, e) | Stmt::Output(e) | Stmt::WriteStore(_, e) => e,
+            Stmt::Emit(key, None) => {
+                let name = self.name(key);
+                self.instructions
+                    .push(Instruction::Emit { src: None, name });
+                return Ok(());
+            }
+            Stmt::Emit(_, Some(e)) => e,
+        };
+        let mut inputs = Vec::new();
+        collect_inputs(expr, &mut inputs);
+        for input in inputs {
+            if self.bindings.contains_key(&input) {
+                continue;
+            }
+            self.top = self.pinned;
+            let dst = self.alloc()?;
+            let name = self.name(&input);
+            self.instructions.push(Instruction::LoadInput { dst, name });
+            self.bindings.insert(input, dst);
+            self.pinned = dst + 1;
+        }
+
+        self.top = self.pinned;
+        let src = self.expr(expr)?;
+        match stmt {
+            Stmt::Let(name, _) => {
+                if self.bindings.contains_key(name) {
+                    return Err(error(pos, format!("{} is already defined", name)));
+                }
+                // 一時レジスタに計算した値はそのまま固定する。既存の名前の別名ならレジスタを共有する
+                if src >= self.pinned {
+                    self.pinned = src + 1;
+                }
+                self.bindings.insert(name.clone(), src);
+            }
+            Stmt::Output(_) => self.instructions.push(Instruction::StoreOutput { src }),
+            Stmt::WriteStore(key, _) => {
+                let name = self.name(key);
+                self.instructions
+                    .push(Instruction::WriteStore { src, name });
+            }
+            Stmt::Emit(key, _) => {
+                let name = self.name(key);
+                self.instructions.push(Instruction::Emit {
+                    src: Some(src),
+                    name,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// 式を評価し、値の入ったレジスタを返す。一時レジスタは結果の 1 つだけを残して解放する
+    fn expr(&mut self, expr: &Expr) -> Result<u8, SourceError> {
+        let mark = self.top;
+        let inst = match expr {
+            Expr::Var(name) => return Ok(self.bindings[name]),
+            Expr::Int(n, pos) => {
+                let value = i32::try_from(*n)
+                    .map_err(|_| error(*pos, format!("integer {} is out of i32 range", n)))?;
+                Instruction::LoadI32 {
+                    dst: self.alloc()?,
+                    value,
+                }
+            }
+            Expr::Float(value) => Instruction::LoadF32 {
+                dst: self.alloc()?,
+                value: *value,
+            },
+            Expr::Bool(value) => Instruction::LoadBool {
+                dst: self.alloc()?,
+                value: *value,
+            },
+            Expr::Store(key) => Instruction::ReadStore {
+                dst: self.alloc()?,
+                name: self.name(key),
+            },
+            Expr::Neg(operand) => {
+                let zero = self.alloc()?;
+                self.instructions.push(Instruction::LoadI32 {
+                    dst: zero,
+                    value: 0,
+                });
+                let src = self.expr(operand)?;
+                self.top = mark;
+                build(OpCode::Sub, self.alloc()?, &[zero, src])
+            }
+            Expr::Not(operand) => {
+                let src = self.expr(operand)?;
+                self.top = mark;
+                build(OpCode::Not, self.alloc()?, &[src])
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let a = self.expr(lhs)?;
+                let b = self.expr(rhs)?;
+                self.top = mark;
+                build(*op, self.alloc()?, &[a, b])
+            }
+            Expr::Call(op, args) => {
+                let srcs = args
+                    .iter()
+                    .map(|a| self.expr(a))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.top = mark;
+                build(*op, self.alloc()?, &srcs)
+            }
+            Expr::Field(operand, index) => {
+                let src = self.expr(operand)?;
+                self.top = mark;
+                Instruction::Extract {
+                    dst: self.alloc()?,
+                    src,
+                    index: *index,
+                }
+            }
+        };
+        self.instructions.push(inst);
+        Ok(mark)
+    }
+}
+
+/// 式が読む入力名（let で定義済みの名前を含む）を評価順に集める
+fn collect_inputs(expr: &Expr, out: &mut Vec<String>) {
+    match expr {
+        Expr::Var(name) => {
+            if !out.contains(name) {
+                out.push(name.clone());
+            }
+        }
+        Expr::Int(..) | Expr::Float(_) | Expr::Bool(_) | Expr::Store(_) => {}
+        Expr::Neg(e) | Expr::Not(e) | Expr::Field(e, _) => collect_inputs(e, out),
+        Expr::Binary(_, a, b) => {
+            collect_inputs(a, out);
+            collect_inputs(b, out);
+        }
+        Expr::Call(_, args) => args.iter().for_each(|a| collect_inputs(a, out)),
+    }
+}
+
+/// 演算命令を作る。s はオペランド順のソースレジスタ
+fn build(op: OpCode, dst: u8, s: &[u8]) -> Instruction {
+    match op {
+        OpCode::Add => Instruction::Add {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Sub => Instruction::Sub {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Mul => Instruction::Mul {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Div => Instruction::Div {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Mod => Instruction::Mod {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Lt => Instruction::Lt {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Gt => Instruction::Gt {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Le => Instruction::Le {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Ge => Instruction::Ge {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Eq => Instruction::Eq {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Ne => Instruction::Ne {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::And => Instruction::And {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Or => Instruction::Or {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Xor => Instruction::Xor {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Min => Instruction::Min {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Max => Instruction::Max {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Atan2 => Instruction::Atan2 {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Pow => Instruction::Pow {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Dot => Instruction::Dot {
+            dst,
+            src_a: s[0],
+            src_b: s[1],
+        },
+        OpCode::Not => Instruction::Not { dst, src: s[0] },
+        OpCode::Abs => Instruction::Abs { dst, src: s[0] },
+        OpCode::Floor => Instruction::Floor { dst, src: s[0] },
+        OpCode::Ceil => Instruction::Ceil { dst, src: s[0] },
+        OpCode::Sqrt => Instruction::Sqrt { dst, src: s[0] },
+        OpCode::Sin => Instruction::Sin { dst, src: s[0] },
+        OpCode::Cos => Instruction::Cos { dst, src: s[0] },
+        OpCode::Length => Instruction::Length { dst, src: s[0] },
+        OpCode::Normalize => Instruction::Normalize { dst, src: s[0] },
+        OpCode::Clamp => Instruction::Clamp {
+            dst,
+            src: s[0],
+            lo: s[1],
+            hi: s[2],
+        },
+        OpCode::Lerp => Instruction::Lerp {
+            dst,
+            a: s[0],
+            b: s[1],
+            t: s[2],
+        },
+        OpCode::Select => Instruction::Select {
+            dst,
+            cond: s[0],
+            a: s[1],
+            b: s[2],
+        },
+        OpCode::Random => Instruction::Random { dst },
+        OpCode::RandomRange => Instruction::RandomRange {
+            dst,
+            lo: s[0],
+            hi: s[1],
+        },
+        OpCode::MakeVec => {
+            let mut srcs = [0; 3];
+            srcs[..s.len()].copy_from_slice(s);
+            Instruction::MakeVec {
+                dst,
+                n: s.len() as u8,
+                srcs,
+            }
+        }
+        _ => unreachable!("{:?} is not an expression operator", op),
+    }
+}
+
+/// ソースを命令列にする（最適化前）
+fn compile_instructions(source: &str) -> Result<Vec<Instruction>, SourceError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let stmts = parser.program()?;
+    let mut codegen = Codegen {
+        instructions: Vec::new(),
+        names: HashMap::new(),
+        bindings: HashMap::new(),
+        pinned: 0,
+        top: 0,
+        pos: Pos { line: 1, column: 1 },
+    };
+    for (pos, stmt) in &stmts {
+        codegen.statement(*pos, stmt)?;
+    }
+    Ok(codegen.instructions)
+}
+
+/// 式言語のソースをコンパイルし、最適化したコンテナ形式のバイトコードを返す
+pub fn compile_source(source: &str) -> Result<Vec<u8>, SourceError> {
+    let instructions = optimize(&compile_instructions(source)?);
+    encode_container(&instructions, true).map_err(SourceError::Encode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+    use crate::value::Value;
+    use crate::vm::run;
+
+    fn eval(
+        source: &str,
+        inputs: &[(&str, Value)],
+        store: &[(&str, Value)],
+    ) -> (Vec<Value>, HashMap<String, Value>) {
+        let bytecode = compile_source(source).expect("compile");
+        let to_map = |kv: &[(&str, Value)]| -> HashMap<String, Value> {
+            kv.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+        };
+        run(
+            &bytecode,
+            &to_map(inputs),
+            &to_map(store),
+            &Limits::default(),
+        )
+        .expect("run")
+    }
+
+    fn f32_of(v: &Value) -> f32 {
+        v.as_f32().expect("scalar")
+    }
+
+    fn parse_error(source: &str) -> (usize, usize, String) {
+        match compile_source(source) {
+            Err(SourceError::Parse {
+                line,
+                column,
+                message,
+            }) => (line, column, message),
+            other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn damage_formula() {
+        let (outputs, _) = eval(
+            "output hp - damage * (1 - armor)",
+            &[
+                ("hp", Value::F32(100.0)),
+                ("damage", Value::F32(40.0)),
+                ("armor", Value::F32(0.25)),
+            ],
+            &[],
+        );
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(f32_of(&outputs[0]), 70.0);
+    }
+
+    #[test]
+    fn store_let_and_multiple_outputs() {
+        let source = "
+            # 被ダメージ
+            let reduced = damage * (1 - armor)
+            store.hp = max(store.hp - reduced, 0)
+            output store.hp; output store.hp <= 0
+        ";
+        let (outputs, store) = eval(
+            source,
+            &[("damage", Value::I32(30)), ("armor", Value::I32(0))],
+            &[("hp", Value::I32(20))],
+        );
+        assert!(matches!(
+            outputs.as_slice(),
+            [Value::I32(0), Value::Bool(true)]
+        ));
+        assert!(matches!(store["hp"], Value::I32(0)));
+    }
+
+    #[test]
+    fn precedence_and_logic() {
+        let (outputs, _) = eval(
+            "output 1 + 2 * 3 - 4 % 3\noutput not 1 > 2 and (true or false)\noutput -x * 2",
+            &[("x", Value::I32(5))],
+            &[],
+        );
+        assert!(matches!(
+            outputs.as_slice(),
+            [Value::I32(6), Value::Bool(true), Value::I32(-10)]
+        ));
+    }
+
+    #[test]
+    fn vectors_and_functions() {
+        let (outputs, _) = eval(
+            "let v = vec2(3, 4) * scale\noutput length(v)\noutput v.y\noutput clamp(v.x, 0, 5)",
+            &[("scale", Value::F32(2.0))],
+            &[],
+        );
+        let values: Vec<f32> = outputs.iter().map(f32_of).collect();
+        assert_eq!(values, [10.0, 8.0, 5.0]);
+    }
+
+    #[test]
+    fn inputs_are_loaded_once() {
+        let instructions = compile_instructions("output x * x + x").expect("compile");
+        let loads = instructions
+            .iter()
+            .filter(|i| matches!(i, Instruction::LoadInput { .. }))
+            .count();
+        assert_eq!(loads, 1);
+    }
+
+    #[test]
+    fn long_expressions_reuse_registers() {
+        let source = format!("output {}", vec!["x"; 200].join(" + "));
+        let (outputs, _) = eval(&source, &[("x", Value::I32(1))], &[]);
+        assert!(matches!(outputs.as_slice(), [Value::I32(200)]));
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        assert_eq!(
+            parse_error("output 1\noutput (2 +)"),
+            (2, 12, "expected expression, found `)`".into())
+        );
+        assert_eq!(
+            parse_error("output foo(1)"),
+            (1, 8, "unknown function foo".into())
+        );
+        assert_eq!(
+            parse_error("output min(1)"),
+            (1, 8, "min takes 2 arguments, got 1".into())
+        );
+        assert_eq!(
+            parse_error("output 3000000000"),
+            (1, 8, "integer 3000000000 is out of i32 range".into())
+        );
+        assert_eq!(
+            parse_error("output 1 < 2 < 3"),
+            (1, 14, "comparison operators cannot be chained".into())
+        );
+        assert_eq!(
+            parse_error("let a = 1\nlet a = 2"),
+            (2, 1, "a is already defined".into())
+        );
+        assert_eq!(parse_error("x = 1").0, 1);
+        assert_eq!(parse_error("output 1 $").1, 10);
+    }
+
+    #[test]
+    fn random_is_reproducible_from_the_store_seed() {
+        let source = "output random()\noutput random_range(1, 6)";
+        let seed = [("$rng", Value::I32(7))];
+        let (first, store) = eval(source, &[], &seed);
+        let (again, _) = eval(source, &[], &seed);
+        assert_eq!(format!("{:?}", first), format!("{:?}", again));
+        assert!(matches!(first[1], Value::I32(1..=6)));
+        assert!(!matches!(store["$rng"], Value::I32(7)));
+    }
+
+    #[test]
+    fn emit_statements() {
+        let bytecode = compile_source("store.hp = store.hp - damage\nemit hit(damage)\nemit dead")
+            .expect("compile");
+        let program = crate::program::Program::compile(&bytecode).expect("decode");
+        let (_, _, events) = crate::vm::execute_with_events(
+            &program,
+            &HashMap::from([("damage".to_string(), Value::I32(5))]),
+            &HashMap::from([("hp".to_string(), Value::I32(5))]),
+            &Limits::default(),
+        )
+        .expect("run");
+        let events: Vec<(&str, Option<i32>)> = events
+            .iter()
+            .map(|e| (&*e.name, e.payload.as_ref().and_then(Value::as_i32)))
+            .collect();
+        assert_eq!(events, [("hit", Some(5)), ("dead", None)]);
+        assert_eq!(
+            parse_error("emit hit(1").2,
+            "expected `)`, found end of input"
+        );
+    }
+
+    #[test]
+    fn min_i32_literal() {
+        let (outputs, _) = eval("output -2147483648", &[], &[]);
+        assert!(matches!(outputs.as_slice(), [Value::I32(i32::MIN)]));
+    }
+}
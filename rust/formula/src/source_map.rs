@@ -0,0 +1,55 @@
+//! Path: rust/formula/src/source_map.rs
+//! Summary: バイトオフセットから生成元（FormulaGraph のノード・ソースの位置）を引く対応表
+//!
+//! 生成側（FormulaGraph のコンパイル等）が、各区間の先頭命令のオフセットと生成元の組を渡す。
+//! あるオフセットの生成元は、そのオフセット以前で最も近いエントリ（次のエントリの手前まで同じ生成元が続く）。
+//! 実行には使わず、エラーの `Location::origin` を埋めるためだけに引く。
+
+/// 命令の生成元
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// FormulaGraph のノード ID
+    Node(String),
+    /// ソース上の位置（1 始まりの行・列）
+    Span { line: u32, column: u32 },
+}
+
+/// オフセット昇順の (オフセット, 生成元) の表
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    entries: Vec<(usize, Origin)>,
+}
+
+impl SourceMap {
+    /// エントリの順序は問わない（同じオフセットは後に渡したものが優先）
+    pub fn new(entries: impl IntoIterator<Item = (usize, Origin)>) -> Self {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by_key(|(offset, _)| *offset);
+        Self { entries }
+    }
+
+    /// offset を含む区間の生成元。最初のエントリより前なら None
+    pub fn lookup(&self, offset: usize) -> Option<&Origin> {
+        let end = self.entries.partition_point(|(start, _)| *start <= offset);
+        end.checked_sub(1).map(|i| &self.entries[i].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_finds_enclosing_entry() {
+        let map = SourceMap::new([
+            (8, Origin::Node("b".into())),
+            (2, Origin::Node("a".into())),
+            (8, Origin::Span { line: 3, column: 1 }),
+        ]);
+        assert_eq!(map.lookup(0), None);
+        assert_eq!(map.lookup(2), Some(&Origin::Node("a".into())));
+        assert_eq!(map.lookup(7), Some(&Origin::Node("a".into())));
+        assert_eq!(map.lookup(8), Some(&Origin::Span { line: 3, column: 1 }));
+        assert_eq!(map.lookup(100), Some(&Origin::Span { line: 3, column: 1 }));
+    }
+}
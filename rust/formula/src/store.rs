@@ -0,0 +1,452 @@
+//! Path: rust/formula/src/store.rs
+//! Summary: Formula VM の Store アクセス（全体を複製する従来モードと、書き込みだけを記録する変更セットモード）
+//!
+//! 従来の `vm::execute` は store_values を複製して書き換え、実行後の Store 全体を返す。
+//! 変更セットモード（`vm::execute_changes`）は元の Store を読み取り専用のビュー（`StoreBase`）として参照し、
+//! WriteStore と乱数状態の更新をオーバーレイに記録する。実行後は書き込んだキーだけを前後の値付きで返すため、
+//! 複製と NIF での再エンコードの量は Store の大きさではなく書き込んだキーの数に比例する。
+//!
+//! `StoreSchema` を結び付けたプログラム（`Program::with_store_schema`）は、どちらのモードでも `Schematized` を通して Store を読み書きする。
+//! 宣言したキーが Store に無ければ既定値を読み、宣言と型の異なる値の読み書きは `VmErrorKind::StoreTypeMismatch`。
+//! 既定値は読むだけでは Store に書き込まれない。宣言していないキーは従来どおり検査しない。
+
+use super::limits::{Budget, Limits};
+use super::value::Value;
+use super::verify::ValueType;
+use super::vm::VmErrorKind;
+use std::collections::HashMap;
+
+/// 読み取り専用の Store。NIF は Elixir の map をデコードせずにキーごとに引く実装を渡す
+pub trait StoreBase {
+    /// キーの値。Store の値として解釈できなければ `VmErrorKind::InvalidStoreValue`
+    fn get(&self, key: &str) -> Result<Option<Value>, VmErrorKind>;
+    /// キー数（max_store_entries の検査に使う）
+    fn entry_count(&self) -> usize;
+}
+
+impl StoreBase for HashMap<String, Value> {
+    fn get(&self, key: &str) -> Result<Option<Value>, VmErrorKind> {
+        Ok(HashMap::get(self, key).cloned())
+    }
+
+    fn entry_count(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Store キーの型と既定値の宣言
+#[derive(Debug, Clone, Default)]
+pub struct StoreSchema {
+    fields: HashMap<String, (ValueType, Value)>,
+}
+
+impl StoreSchema {
+    /// (キー, 型, 既定値) の並びから作る。既定値が型に合わないキーがあれば、そのキーを Err で返す
+    pub fn new(
+        fields: impl IntoIterator<Item = (String, ValueType, Value)>,
+    ) -> Result<Self, String> {
+        let mut schema = Self::default();
+        for (key, ty, default) in fields {
+            let default = widen(ty, default);
+            if ValueType::of(&default) != ty {
+                return Err(key);
+            }
+            schema.fields.insert(key, (ty, default));
+        }
+        Ok(schema)
+    }
+
+    /// 宣言されたキーなら値の型を検査する
+    fn check(&self, key: &str, value: &Value) -> Result<(), VmErrorKind> {
+        match self.fields.get(key) {
+            Some((ty, _)) if ValueType::of(value) != *ty => Err(VmErrorKind::StoreTypeMismatch(
+                key.to_string(),
+                *ty,
+                ValueType::of(value),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Store から読んだ値を宣言した型に合わせて検査する（`widen` で広げてから比べる）
+    fn conform(&self, key: &str, value: Value) -> Result<Value, VmErrorKind> {
+        let value = match self.fields.get(key) {
+            Some((ty, _)) => widen(*ty, value),
+            None => value,
+        };
+        self.check(key, &value)?;
+        Ok(value)
+    }
+}
+
+/// 値を失わずに宣言した 64 bit の型へ広げる（I32 → I64 / F64、F32 → F64）。
+/// Elixir の整数・浮動小数点は小さければ I32 / F32 としてデコードされるため、I64 / F64 のキーでもそのまま読めるようにする
+fn widen(ty: ValueType, value: Value) -> Value {
+    match (ty, &value) {
+        (ValueType::I64, Value::I32(v)) => Value::I64(i64::from(*v)),
+        (ValueType::F64, Value::I32(v)) => Value::F64(f64::from(*v)),
+        (ValueType::F64, Value::F32(v)) => Value::F64(f64::from(*v)),
+        _ => value,
+    }
+}
+
+/// 変更セットの 1 件。実行中に書き込んだキーごとに 1 件
+#[derive(Debug, Clone)]
+pub struct StoreChange {
+    pub key: String,
+    /// 実行前の値（新規キーは None）
+    pub before: Option<Value>,
+    /// 最後に書き込んだ値
+    pub after: Value,
+    /// 実行前と値が異なるか（ビット列で比較する。新規キーは true）
+    pub changed: bool,
+}
+
+/// VM から見た Store。読み出しと、件数上限付きの書き込み
+pub(super) trait StoreAccess {
+    fn read(&self, key: &str) -> Result<Option<Value>, VmErrorKind>;
+    fn write(&mut self, key: &str, value: Value, limits: &Limits) -> Result<(), VmErrorKind>;
+}
+
+fn store_entries_exceeded(limits: &Limits) -> VmErrorKind {
+    VmErrorKind::BudgetExceeded(Budget::StoreEntries, limits.max_store_entries)
+}
+
+/// 従来モード: 複製した Store を直接書き換える
+impl StoreAccess for HashMap<String, Value> {
+    fn read(&self, key: &str) -> Result<Option<Value>, VmErrorKind> {
+        Ok(self.get(key).cloned())
+    }
+
+    fn write(&mut self, key: &str, value: Value, limits: &Limits) -> Result<(), VmErrorKind> {
+        // 既存キーの上書きではキー文字列を確保しない
+        match self.get_mut(key) {
+            Some(slot) => *slot = value,
+            None => {
+                if self.len() >= limits.max_store_entries {
+                    return Err(store_entries_exceeded(limits));
+                }
+                self.insert(key.to_string(), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// スキーマ付きの Store アクセス。スキーマが無ければ内側の Store をそのまま使う
+pub(super) struct Schematized<'a, S: StoreAccess> {
+    pub(super) inner: &'a mut S,
+    pub(super) schema: Option<&'a StoreSchema>,
+}
+
+impl<S: StoreAccess> StoreAccess for Schematized<'_, S> {
+    fn read(&self, key: &str) -> Result<Option<Value>, VmErrorKind> {
+        let value = self.inner.read(key)?;
+        let Some(schema) = self.schema else {
+            return Ok(value);
+        };
+        match value {
+            Some(value) => schema.conform(key, value).map(Some),
+            None => Ok(schema.fields.get(key).map(|(_, default)| default.clone())),
+        }
+    }
+
+    fn write(&mut self, key: &str, value: Value, limits: &Limits) -> Result<(), VmErrorKind> {
+        if let Some(schema) = self.schema {
+            schema.check(key, &value)?;
+        }
+        self.inner.write(key, value, limits)
+    }
+}
+
+/// 変更セットモード: 元の Store は読むだけで、書き込みを最初の書き込み順に記録する
+pub(super) struct Overlay<'a, B: StoreBase + ?Sized> {
+    base: &'a B,
+    /// キー → changes のインデックス
+    index: HashMap<String, usize>,
+    changes: Vec<StoreChange>,
+    /// 元の Store に無かったキーの数
+    added: usize,
+}
+
+impl<'a, B: StoreBase + ?Sized> Overlay<'a, B> {
+    pub(super) fn new(base: &'a B) -> Self {
+        Self {
+            base,
+            index: HashMap::new(),
+            changes: Vec::new(),
+            added: 0,
+        }
+    }
+
+    pub(super) fn into_changes(self) -> Vec<StoreChange> {
+        self.changes
+            .into_iter()
+            .map(|mut change| {
+                change.changed = match &change.before {
+                    Some(before) => before.bits_key() != change.after.bits_key(),
+                    None => true,
+                };
+                change
+            })
+            .collect()
+    }
+}
+
+impl<B: StoreBase + ?Sized> StoreAccess for Overlay<'_, B> {
+    fn read(&self, key: &str) -> Result<Option<Value>, VmErrorKind> {
+        match self.index.get(key) {
+            Some(&i) => Ok(Some(self.changes[i].after.clone())),
+            None => self.base.get(key),
+        }
+    }
+
+    fn write(&mut self, key: &str, value: Value, limits: &Limits) -> Result<(), VmErrorKind> {
+        if let Some(&i) = self.index.get(key) {
+            self.changes[i].after = value;
+            return Ok(());
+        }
+        let before = self.base.get(key)?;
+        if before.is_none() {
+            if self.base.entry_count() + self.added >= limits.max_store_entries {
+                return Err(store_entries_exceeded(limits));
+            }
+            self.added += 1;
+        }
+        self.index.insert(key.to_string(), self.changes.len());
+        self.changes.push(StoreChange {
+            key: key.to_string(),
+            before,
+            after: value,
+            changed: true,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::program::Program;
+    use crate::vm::{execute, execute_changes, VmError};
+    use std::sync::Arc;
+
+    fn program(source: &str) -> Program {
+        Program::compile(&assemble(source).expect("assemble")).expect("compile")
+    }
+
+    #[test]
+    fn changes_list_only_written_keys_in_first_write_order() {
+        let store = HashMap::from([
+            ("hp".to_string(), Value::I32(10)),
+            ("mp".to_string(), Value::I32(5)),
+            ("untouched".to_string(), Value::F32(1.0)),
+        ]);
+        let program = program(
+            r#"
+                load_i32 r0 5
+                write_store r0 "mp"
+                read_store r1 "hp"
+                load_i32 r2 3
+                sub r3 r1 r2
+                write_store r3 "hp"
+                read_store r4 "hp"
+                store_output r4
+                write_store r2 "new"
+                write_store r3 "new"
+            "#,
+        );
+        let (outputs, changes, _) =
+            execute_changes(&program, &HashMap::new(), &store, &Limits::default()).expect("run");
+        assert!(matches!(outputs.as_slice(), [Value::I32(7)]));
+        let summary: Vec<(&str, Option<i32>, i32, bool)> = changes
+            .iter()
+            .map(|c| {
+                (
+                    c.key.as_str(),
+                    c.before.as_ref().and_then(Value::as_i32),
+                    c.after.as_i32().unwrap_or_default(),
+                    c.changed,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("mp", Some(5), 5, false),
+                ("hp", Some(10), 7, true),
+                ("new", None, 7, true)
+            ]
+        );
+
+        // 従来モードの Store に変更セットを当てると同じ結果になる
+        let (_, full) =
+            execute(&program, &HashMap::new(), &store, &Limits::default()).expect("run");
+        let mut applied = store.clone();
+        for change in changes {
+            applied.insert(change.key, change.after);
+        }
+        assert_eq!(
+            format!("{:?}", sorted(&full)),
+            format!("{:?}", sorted(&applied))
+        );
+    }
+
+    fn sorted(store: &HashMap<String, Value>) -> Vec<(&String, &Value)> {
+        let mut entries: Vec<_> = store.iter().collect();
+        entries.sort_by_key(|(k, _)| *k);
+        entries
+    }
+
+    #[test]
+    fn store_entry_budget_counts_the_base() {
+        let store = HashMap::from([("a".to_string(), Value::I32(0))]);
+        let limits = Limits {
+            max_store_entries: 2,
+            ..Limits::default()
+        };
+        let ok = program(
+            "load_i32 r0 1\nwrite_store r0 \"a\"\nwrite_store r0 \"b\"\nwrite_store r0 \"b\"",
+        );
+        assert!(execute_changes(&ok, &HashMap::new(), &store, &limits).is_ok());
+        let over = program("load_i32 r0 1\nwrite_store r0 \"b\"\nwrite_store r0 \"c\"");
+        assert!(matches!(
+            execute_changes(&over, &HashMap::new(), &store, &limits),
+            Err(VmError {
+                kind: VmErrorKind::BudgetExceeded(Budget::StoreEntries, 2),
+                ..
+            })
+        ));
+    }
+
+    /// 値の取得に失敗する読み取り専用ビュー（NIF の遅延デコードを模す）
+    struct Strict;
+
+    impl StoreBase for Strict {
+        fn get(&self, key: &str) -> Result<Option<Value>, VmErrorKind> {
+            match key {
+                "bad" => Err(VmErrorKind::InvalidStoreValue(key.to_string())),
+                "$rng" => Ok(Some(Value::I32(1))),
+                _ => Ok(None),
+            }
+        }
+
+        fn entry_count(&self) -> usize {
+            2
+        }
+    }
+
+    #[test]
+    fn base_is_read_lazily() {
+        let inputs = HashMap::new();
+        let (_, changes, _) =
+            execute_changes(&program("random r0"), &inputs, &Strict, &Limits::default())
+                .expect("run");
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, "$rng");
+        assert!(changes[0].changed);
+        assert!(matches!(
+            execute_changes(&program("read_store r0 \"bad\""), &inputs, &Strict, &Limits::default()),
+            Err(VmError { kind: VmErrorKind::InvalidStoreValue(key), .. }) if key == "bad"
+        ));
+    }
+
+    fn schema() -> Arc<StoreSchema> {
+        let fields = [
+            ("hp".to_string(), ValueType::I32, Value::I32(100)),
+            ("speed".to_string(), ValueType::F32, Value::F32(1.5)),
+        ];
+        Arc::new(StoreSchema::new(fields).expect("schema"))
+    }
+
+    #[test]
+    fn schema_defaults_fill_missing_keys_without_writing_them() {
+        let program = program(
+            r#"
+                read_store r0 "hp"
+                store_output r0
+                read_store r1 "speed"
+                store_output r1
+            "#,
+        )
+        .with_store_schema(schema());
+        let store = HashMap::from([("speed".to_string(), Value::F32(3.0))]);
+        let (outputs, after) =
+            execute(&program, &HashMap::new(), &store, &Limits::default()).expect("run");
+        assert_eq!(format!("{outputs:?}"), "[I32(100), F32(3.0)]");
+        assert!(!after.contains_key("hp"));
+
+        // 宣言していないキーは従来どおり StoreNotFound
+        let undeclared = program.with_store_schema(Arc::new(StoreSchema::default()));
+        assert!(matches!(
+            execute(&undeclared, &HashMap::new(), &store, &Limits::default()),
+            Err(VmError { kind: VmErrorKind::StoreNotFound(key), .. }) if key == "hp"
+        ));
+    }
+
+    #[test]
+    fn schema_rejects_values_of_the_wrong_type() {
+        let write = program("load_i32 r0 2\nwrite_store r0 \"speed\"").with_store_schema(schema());
+        for result in [
+            execute(&write, &HashMap::new(), &HashMap::new(), &Limits::default()).map(|_| ()),
+            execute_changes(&write, &HashMap::new(), &HashMap::new(), &Limits::default())
+                .map(|_| ()),
+        ] {
+            assert!(matches!(
+                result,
+                Err(VmError { kind: VmErrorKind::StoreTypeMismatch(key, ValueType::F32, ValueType::I32), .. }) if key == "speed"
+            ));
+        }
+        let read = program("read_store r0 \"hp\"").with_store_schema(schema());
+        let store = HashMap::from([("hp".to_string(), Value::Bool(true))]);
+        assert!(matches!(
+            execute(&read, &HashMap::new(), &store, &Limits::default()),
+            Err(VmError { kind: VmErrorKind::StoreTypeMismatch(key, ValueType::I32, ValueType::Bool), .. }) if key == "hp"
+        ));
+        assert_eq!(
+            StoreSchema::new([("hp".to_string(), ValueType::I32, Value::F32(1.0))]).err(),
+            Some("hp".to_string())
+        );
+    }
+
+    #[test]
+    fn schema_widens_narrow_values_to_declared_64_bit_types() {
+        let fields = [
+            ("ticks".to_string(), ValueType::I64, Value::I32(0)),
+            ("ratio".to_string(), ValueType::F64, Value::F32(0.5)),
+        ];
+        let schema = Arc::new(StoreSchema::new(fields).expect("schema"));
+        let program = program(
+            r#"
+                read_store r0 "ticks"
+                store_output r0
+                read_store r1 "ratio"
+                store_output r1
+            "#,
+        )
+        .with_store_schema(schema);
+        let store = HashMap::from([("ticks".to_string(), Value::I32(7))]);
+        let (outputs, _) =
+            execute(&program, &HashMap::new(), &store, &Limits::default()).expect("run");
+        assert_eq!(format!("{outputs:?}"), "[I64(7), F64(0.5)]");
+    }
+
+    #[test]
+    fn schema_survives_optimize_and_seeds_the_random_state() {
+        let fields = [("$rng".to_string(), ValueType::I32, Value::I32(7))];
+        let schema = Arc::new(StoreSchema::new(fields).expect("schema"));
+        let program = program("random r0\nstore_output r0")
+            .with_store_schema(schema)
+            .optimize();
+        let (_, changes, _) = execute_changes(
+            &program,
+            &HashMap::new(),
+            &HashMap::new(),
+            &Limits::default(),
+        )
+        .expect("run");
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].before.is_none());
+    }
+}
@@ -0,0 +1,362 @@
+//! Path: rust/formula/src/trace.rs
+//! Summary: Formula VM の実行トレースと命令ごとのプロファイル（`vm::execute_traced`）
+//!
+//! 本番で数式が想定外の値を返したときに、途中のレジスタの値を追えるようにする。
+//! VM は命令の実行前後に `Observer` を呼ぶ。通常の実行は何もしない `NoTrace` を渡すため、
+//! 単相化で呼び出しごと消え、トレースのコストはトレース実行にしか掛からない。
+//!
+//! - ステップは実行した命令ごとに 1 件。読み出したレジスタの実行前の値と、書き込んだレジスタの実行後の値を持つ
+//! - Call / ForEach / ListMap の結果は呼び出し先が Ret して呼び出し元に戻った時点で確定し、その命令のステップに入る
+//! - エラーで止まった命令のステップは result が None のまま最後に残る
+//! - ステップ数はフューエル（`Limits::max_instructions`）で抑えられる
+
+use super::decode::Instruction;
+use super::opcode::OpCode;
+use super::value::Value;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// トレース実行で何を記録するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceOptions {
+    /// 命令ごとのステップを記録する
+    pub steps: bool,
+    /// OpCode ごとの実行回数と所要時間を集計する
+    pub profile: bool,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        Self {
+            steps: true,
+            profile: false,
+        }
+    }
+}
+
+/// 実行した命令 1 つ分の記録
+#[derive(Debug, Clone)]
+pub struct TraceStep {
+    /// 呼び出しの深さ（トップレベルは 0）
+    pub depth: usize,
+    /// 実行中の関数名（トップレベルは None）
+    pub function: Option<String>,
+    /// フレーム内の命令インデックス
+    pub pc: usize,
+    pub opcode: OpCode,
+    /// 読み出したレジスタと実行前の値（オペランド順。未初期化は None）
+    pub operands: Vec<(u8, Option<Value>)>,
+    /// 書き込んだレジスタと実行後の値。書き込みの無い命令とエラーで止まった命令は None
+    pub result: Option<(u8, Value)>,
+}
+
+/// OpCode ごとの集計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpProfile {
+    pub count: u64,
+    /// 命令の実行前から次の命令の実行前まで（呼び出し先の命令は含まない）
+    pub time: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    /// 実行順のステップ（`TraceOptions::steps` が false なら空）
+    pub steps: Vec<TraceStep>,
+    /// OpCode の値の順（`TraceOptions::profile` が false なら None）
+    pub profile: Option<BTreeMap<u8, (OpCode, OpProfile)>>,
+}
+
+/// VM から命令ごとに呼ばれる観測点。`before` の後、次の命令（または実行の終了）の前に `after` が呼ばれる
+pub(super) trait Observer {
+    fn before(
+        &mut self,
+        depth: usize,
+        function: Option<&str>,
+        pc: usize,
+        inst: &Instruction,
+        registers: &[Option<Value>],
+    );
+    fn after(&mut self, depth: usize, registers: &[Option<Value>]);
+}
+
+/// 通常の実行。何も記録しない
+pub(super) struct NoTrace;
+
+impl Observer for NoTrace {
+    #[inline(always)]
+    fn before(
+        &mut self,
+        _: usize,
+        _: Option<&str>,
+        _: usize,
+        _: &Instruction,
+        _: &[Option<Value>],
+    ) {
+    }
+
+    #[inline(always)]
+    fn after(&mut self, _: usize, _: &[Option<Value>]) {}
+}
+
+/// 実行前の命令の情報（after で結果を埋める）
+struct Pending {
+    depth: usize,
+    opcode: OpCode,
+    dst: Option<u8>,
+    started: Option<Instant>,
+}
+
+pub(super) struct Tracer {
+    options: TraceOptions,
+    trace: Trace,
+    pending: Option<Pending>,
+    /// 呼び出し先から戻るのを待っている Call / ForEach / ListMap のステップ（ステップを記録しない場合は None）
+    calls: Vec<(Option<usize>, u8)>,
+}
+
+impl Tracer {
+    pub(super) fn new(options: TraceOptions) -> Self {
+        Self {
+            options,
+            trace: Trace {
+                steps: Vec::new(),
+                profile: options.profile.then(BTreeMap::new),
+            },
+            pending: None,
+            calls: Vec::new(),
+        }
+    }
+
+    /// 実行の終了。エラーで止まった命令は結果を持たないため、所要時間だけ数える
+    pub(super) fn finish(mut self) -> Trace {
+        if let Some(pending) = self.pending.take() {
+            self.account(&pending);
+        }
+        self.trace
+    }
+
+    fn account(&mut self, pending: &Pending) {
+        if let (Some(profile), Some(started)) = (&mut self.trace.profile, pending.started) {
+            let (_, entry) = profile
+                .entry(pending.opcode as u8)
+                .or_insert((pending.opcode, OpProfile::default()));
+            entry.count += 1;
+            entry.time += started.elapsed();
+        }
+    }
+}
+
+impl Observer for Tracer {
+    fn before(
+        &mut self,
+        depth: usize,
+        function: Option<&str>,
+        pc: usize,
+        inst: &Instruction,
+        registers: &[Option<Value>],
+    ) {
+        let (dst, srcs) = inst.operands();
+        if self.options.steps {
+            self.trace.steps.push(TraceStep {
+                depth,
+                function: function.map(str::to_string),
+                pc,
+                opcode: inst.opcode(),
+                operands: srcs
+                    .into_iter()
+                    .map(|r| (r, registers.get(r as usize).cloned().flatten()))
+                    .collect(),
+                result: None,
+            });
+        }
+        self.pending = Some(Pending {
+            depth,
+            opcode: inst.opcode(),
+            dst,
+            started: self.options.profile.then(Instant::now),
+        });
+    }
+
+    fn after(&mut self, depth: usize, registers: &[Option<Value>]) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        self.account(&pending);
+        let step = self.options.steps.then(|| self.trace.steps.len() - 1);
+        let (index, dst) = if depth > pending.depth {
+            // 呼び出し先に入った。結果は戻ったときに埋める
+            if let Some(dst) = pending.dst {
+                self.calls.push((step, dst));
+            }
+            return;
+        } else if depth < pending.depth {
+            // Ret で呼び出し元に戻った。呼び出した命令の結果を埋める
+            match self.calls.pop() {
+                Some(call) => call,
+                None => return,
+            }
+        } else {
+            match pending.dst {
+                Some(dst) => (step, dst),
+                None => return,
+            }
+        };
+        if let (Some(i), Some(Some(value))) = (index, registers.get(dst as usize)) {
+            self.trace.steps[i].result = Some((dst, value.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::library::Library;
+    use crate::limits::Limits;
+    use crate::program::Program;
+    use crate::vm::{execute, execute_traced, VmError, VmErrorKind};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn program(source: &str) -> Program {
+        let double = assemble("load_i32 r1 2\nmul r2 r0 r1\nret r2").expect("assemble");
+        let library = Library::compile([("double", double.as_slice())]).expect("library");
+        Program::compile(&assemble(source).expect("assemble"))
+            .expect("compile")
+            .link(Arc::new(library))
+    }
+
+    fn summary(trace: &Trace) -> Vec<String> {
+        trace
+            .steps
+            .iter()
+            .map(|s| {
+                let operands: Vec<String> = s
+                    .operands
+                    .iter()
+                    .map(|(r, v)| match v {
+                        Some(v) => format!("r{r}={v}"),
+                        None => format!("r{r}=?"),
+                    })
+                    .collect();
+                let result = match &s.result {
+                    Some((r, v)) => format!(" -> r{r}={v}"),
+                    None => String::new(),
+                };
+                format!(
+                    "{}{}@{} {}({}){}",
+                    "  ".repeat(s.depth),
+                    s.function.as_deref().unwrap_or("main"),
+                    s.pc,
+                    s.opcode.mnemonic(),
+                    operands.join(" "),
+                    result
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_operands_results_and_call_results() {
+        let program = program(
+            r#"
+                load_input r0 "hp"
+                call r1 "double" r0
+                store_output r1
+            "#,
+        );
+        let inputs = HashMap::from([("hp".to_string(), Value::I32(21))]);
+        let store = HashMap::new();
+        let (result, trace) = execute_traced(
+            &program,
+            &inputs,
+            &store,
+            &Limits::default(),
+            TraceOptions::default(),
+        );
+        let (outputs, _) = result.expect("run");
+        assert!(matches!(outputs.as_slice(), [Value::I32(42)]));
+        assert_eq!(
+            summary(&trace),
+            [
+                "main@0 load_input() -> r0=21",
+                "main@1 call(r0=21) -> r1=42",
+                "  double@0 load_i32() -> r1=2",
+                "  double@1 mul(r0=21 r1=2) -> r2=42",
+                "  double@2 ret(r2=42)",
+                "main@2 store_output(r1=42)",
+            ]
+        );
+        assert!(trace.profile.is_none());
+
+        // トレースしても結果は通常の実行と同じ
+        let (plain, _) = execute(&program, &inputs, &store, &Limits::default()).expect("run");
+        assert_eq!(format!("{plain:?}"), format!("{outputs:?}"));
+    }
+
+    #[test]
+    fn failing_step_is_last_and_has_no_result() {
+        let program = program("load_i32 r0 1\nload_i32 r1 0\ndiv r2 r0 r1\nstore_output r2");
+        let (result, trace) = execute_traced(
+            &program,
+            &HashMap::new(),
+            &HashMap::new(),
+            &Limits::default(),
+            TraceOptions::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(VmError {
+                kind: VmErrorKind::DivisionByZero,
+                ..
+            })
+        ));
+        assert_eq!(
+            summary(&trace).last().map(String::as_str),
+            Some("main@2 div(r0=1 r1=0)")
+        );
+    }
+
+    #[test]
+    fn profile_counts_per_opcode() {
+        let program = program(
+            r#"
+                load_input r0 "xs"
+                list_map r1 "double" r0
+                store_output r1
+            "#,
+        );
+        let xs = Value::List(Arc::from([Value::I32(1), Value::I32(2), Value::I32(3)]));
+        let inputs = HashMap::from([("xs".to_string(), xs)]);
+        let options = TraceOptions {
+            steps: false,
+            profile: true,
+        };
+        let (result, trace) = execute_traced(
+            &program,
+            &inputs,
+            &HashMap::new(),
+            &Limits::default(),
+            options,
+        );
+        assert!(result.is_ok());
+        assert!(trace.steps.is_empty());
+        let counts: Vec<(&str, u64)> = trace
+            .profile
+            .expect("profile")
+            .values()
+            .map(|(op, p)| (op.mnemonic(), p.count))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("load_input", 1),
+                ("load_i32", 3),
+                ("mul", 3),
+                ("store_output", 1),
+                ("ret", 3),
+                ("list_map", 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Path: rust/formula/src/value.rs
+//! Summary: Formula VM の値型（f32, i32, f64, i64, bool, vec2, vec3, list）
+//!
+//! 数値演算の型昇格（`promote`）: 全部 I32 なら I32、整数（I32 / I64）だけで I64 を含めば I64、
+//! それ以外で 64 bit の値（I64 / F64）を含めば F64、残りは F32。Bool は 0 / 1 の数として扱う。
+//! 既存の i32 / f32 だけのプログラムの結果は変わらない。
+
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    F32(f32),
+    I32(i32),
+    /// 64 bit 浮動小数点（入力・Store では `{:f64, x}`。通常の float は F32）
+    F64(f64),
+    /// 64 bit 整数（入力・Store の整数は i32 に収まらなければ I64）
+    I64(i64),
+    Bool(bool),
+    /// 2 次元ベクトル（成分は f32）
+    Vec2([f32; 2]),
+    /// 3 次元ベクトル（成分は f32）
+    Vec3([f32; 3]),
+    /// リスト。要素はスカラーかベクトル（入れ子のリストは持たない）。不変で、複製は参照カウントの増加だけ
+    List(Arc<[Value]>),
+}
+
+impl Value {
+    /// スカラーを f32 として読む。ベクトル・リストは None
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Value::F32(v) => Some(v),
+            Value::I32(v) => Some(v as f32),
+            Value::F64(v) => Some(v as f32),
+            Value::I64(v) => Some(v as f32),
+            Value::Bool(v) => Some(if v { 1.0 } else { 0.0 }),
+            Value::Vec2(_) | Value::Vec3(_) | Value::List(_) => None,
+        }
+    }
+
+    /// スカラーを i32 として読む（範囲外は飽和）。ベクトル・リストは None
+    pub fn as_i32(&self) -> Option<i32> {
+        match *self {
+            Value::F32(v) => Some(v as i32),
+            Value::I32(v) => Some(v),
+            Value::F64(v) => Some(v as i32),
+            Value::I64(v) => Some(v.clamp(i32::MIN as i64, i32::MAX as i64) as i32),
+            Value::Bool(v) => Some(if v { 1 } else { 0 }),
+            Value::Vec2(_) | Value::Vec3(_) | Value::List(_) => None,
+        }
+    }
+
+    /// スカラーを f64 として読む。ベクトル・リストは None
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::F32(v) => Some(v as f64),
+            Value::I32(v) => Some(v as f64),
+            Value::F64(v) => Some(v),
+            Value::I64(v) => Some(v as f64),
+            Value::Bool(v) => Some(if v { 1.0 } else { 0.0 }),
+            Value::Vec2(_) | Value::Vec3(_) | Value::List(_) => None,
+        }
+    }
+
+    /// スカラーを i64 として読む（浮動小数点は 0 方向に切り捨て、範囲外は飽和）。ベクトル・リストは None
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::F32(v) => Some(v as i64),
+            Value::I32(v) => Some(v as i64),
+            Value::F64(v) => Some(v as i64),
+            Value::I64(v) => Some(v),
+            Value::Bool(v) => Some(if v { 1 } else { 0 }),
+            Value::Vec2(_) | Value::Vec3(_) | Value::List(_) => None,
+        }
+    }
+
+    /// スカラーを真偽値として読む。ベクトル・リストは None
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(v) => Some(v),
+            Value::I32(v) => Some(v != 0),
+            Value::F32(v) => Some(v != 0.0),
+            Value::I64(v) => Some(v != 0),
+            Value::F64(v) => Some(v != 0.0),
+            Value::Vec2(_) | Value::Vec3(_) | Value::List(_) => None,
+        }
+    }
+
+    /// ベクトルの成分スライス。スカラーは None
+    pub fn components(&self) -> Option<&[f32]> {
+        match self {
+            Value::Vec2(v) => Some(v),
+            Value::Vec3(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// リストの要素。リスト以外は None
+    pub fn as_list(&self) -> Option<&Arc<[Value]>> {
+        match self {
+            Value::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// ビット列での同一性キー（-0.0 と 0.0、NaN のペイロードを区別する）。定数の重複排除・畳み込みで使う
+    pub(super) fn bits_key(&self) -> BitsKey {
+        let mut key = Vec::new();
+        self.push_bits(&mut key);
+        key
+    }
+
+    fn push_bits(&self, key: &mut Vec<u32>) {
+        match self {
+            Value::I32(v) => key.extend([0, *v as u32]),
+            Value::F32(v) => key.extend([1, v.to_bits()]),
+            Value::Bool(v) => key.extend([2, *v as u32]),
+            Value::I64(v) => key.extend([6, *v as u32, (*v >> 32) as u32]),
+            Value::F64(v) => {
+                let bits = v.to_bits();
+                key.extend([7, bits as u32, (bits >> 32) as u32])
+            }
+            Value::Vec2([x, y]) => key.extend([3, x.to_bits(), y.to_bits()]),
+            Value::Vec3([x, y, z]) => key.extend([4, x.to_bits(), y.to_bits(), z.to_bits()]),
+            Value::List(items) => {
+                key.extend([5, items.len() as u32]);
+                for item in items.iter() {
+                    item.push_bits(key);
+                }
+            }
+        }
+    }
+}
+
+/// 数値演算の結果の型（`promote`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum NumKind {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+/// 数値演算のオペランドの型昇格。スカラー（数値・Bool）でない値があれば None
+pub(super) fn promote(values: &[&Value]) -> Option<NumKind> {
+    let (mut ints, mut all_i32, mut wide) = (true, true, false);
+    for value in values {
+        match value {
+            Value::I32(_) => {}
+            Value::I64(_) => (all_i32, wide) = (false, true),
+            Value::F64(_) => (ints, all_i32, wide) = (false, false, true),
+            Value::F32(_) | Value::Bool(_) => (ints, all_i32) = (false, false),
+            Value::Vec2(_) | Value::Vec3(_) | Value::List(_) => return None,
+        }
+    }
+    Some(match (all_i32, ints, wide) {
+        (true, _, _) => NumKind::I32,
+        (false, true, _) => NumKind::I64,
+        (false, false, true) => NumKind::F64,
+        (false, false, false) => NumKind::F32,
+    })
+}
+
+/// `Value::bits_key` の戻り値（タグと成分のビット列を並べたもの）
+pub(super) type BitsKey = Vec<u32>;
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::F32(v) => write!(f, "{}", v),
+            Value::I32(v) => write!(f, "{}", v),
+            Value::F64(v) => write!(f, "{}", v),
+            Value::I64(v) => write!(f, "{}", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Vec2([x, y]) => write!(f, "({}, {})", x, y),
+            Value::Vec3([x, y, z]) => write!(f, "({}, {}, {})", x, y, z),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
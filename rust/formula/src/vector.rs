@@ -0,0 +1,184 @@
+//! Path: rust/formula/src/vector.rs
+//! Summary: Formula VM のベクトル演算（成分ごとの四則演算、dot, length, normalize）
+//!
+//! ベクトル同士は同じ次元でのみ演算できる。ベクトルとスカラーの組はスカラーを全成分に
+//! ブロードキャストする。次元不一致やスカラーに解釈できない値は `VmErrorKind::TypeMismatch`。
+
+use super::value::Value;
+use super::vm::VmErrorKind;
+
+fn type_mismatch(op: &str) -> VmErrorKind {
+    VmErrorKind::TypeMismatch(op.into())
+}
+
+/// 成分列から同じ次元のベクトルを作る。次元は 2 か 3 のみ
+pub(super) fn from_components(components: &[f32]) -> Option<Value> {
+    match *components {
+        [x, y] => Some(Value::Vec2([x, y])),
+        [x, y, z] => Some(Value::Vec3([x, y, z])),
+        _ => None,
+    }
+}
+
+fn map2(a: &[f32], b: &[f32], f: impl Fn(f32, f32) -> f32) -> Option<Value> {
+    let mut out = [0.0f32; 3];
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        out[i] = f(*x, *y);
+    }
+    from_components(&out[..a.len()])
+}
+
+/// 成分ごとの二項演算。どちらのオペランドもベクトルでなければ None（スカラー経路に任せる）
+pub(super) fn componentwise(
+    op: &str,
+    a: &Value,
+    b: &Value,
+    f: impl Fn(f32, f32) -> f32,
+) -> Option<Result<Value, VmErrorKind>> {
+    let result = match (a.components(), b.components()) {
+        (None, None) => return None,
+        (Some(va), Some(vb)) if va.len() == vb.len() => map2(va, vb, f),
+        (Some(_), Some(_)) => None,
+        (Some(va), None) => b.as_f32().and_then(|s| map2(va, &[s; 3][..va.len()], f)),
+        (None, Some(vb)) => a.as_f32().and_then(|s| map2(&[s; 3][..vb.len()], vb, f)),
+    };
+    Some(result.ok_or_else(|| type_mismatch(op)))
+}
+
+/// 成分ごとの除算。除数のいずれかの成分が 0 なら `VmErrorKind::DivisionByZero`
+pub(super) fn componentwise_div(a: &Value, b: &Value) -> Option<Result<Value, VmErrorKind>> {
+    let divisor_has_zero = match b.components() {
+        Some(vb) => vb.contains(&0.0),
+        None => b.as_f32() == Some(0.0),
+    };
+    let result = componentwise("div", a, b, |x, y| x / y)?;
+    if result.is_ok() && divisor_has_zero {
+        return Some(Err(VmErrorKind::DivisionByZero));
+    }
+    Some(result)
+}
+
+/// 成分を取り出す。index が次元以上なら TypeMismatch
+pub(super) fn extract(v: Value, index: u8) -> Result<Value, VmErrorKind> {
+    let components = v.components().ok_or_else(|| type_mismatch("extract"))?;
+    components
+        .get(index as usize)
+        .map(|c| Value::F32(*c))
+        .ok_or_else(|| type_mismatch("extract"))
+}
+
+pub(super) fn dot(a: Value, b: Value) -> Result<Value, VmErrorKind> {
+    match (a.components(), b.components()) {
+        (Some(va), Some(vb)) if va.len() == vb.len() => {
+            Ok(Value::F32(va.iter().zip(vb).map(|(x, y)| x * y).sum()))
+        }
+        _ => Err(type_mismatch("dot")),
+    }
+}
+
+pub(super) fn length(v: Value) -> Result<Value, VmErrorKind> {
+    let components = v.components().ok_or_else(|| type_mismatch("length"))?;
+    Ok(Value::F32(
+        components.iter().map(|c| c * c).sum::<f32>().sqrt(),
+    ))
+}
+
+/// 単位ベクトル化。長さ 0 のベクトルは NaN を避けてゼロベクトルのまま返す
+pub(super) fn normalize(v: Value) -> Result<Value, VmErrorKind> {
+    let components = v.components().ok_or_else(|| type_mismatch("normalize"))?;
+    let len = components.iter().map(|c| c * c).sum::<f32>().sqrt();
+    if len == 0.0 {
+        return Ok(v);
+    }
+    let mut scaled = [0.0f32; 3];
+    for (out, c) in scaled.iter_mut().zip(components) {
+        *out = c / len;
+    }
+    from_components(&scaled[..components.len()]).ok_or_else(|| type_mismatch("normalize"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec2_of(v: Value) -> [f32; 2] {
+        match v {
+            Value::Vec2(x) => x,
+            other => panic!("expected Vec2, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn componentwise_same_dims() {
+        let r = componentwise(
+            "add",
+            &Value::Vec2([1.0, 2.0]),
+            &Value::Vec2([3.0, 4.0]),
+            |x, y| x + y,
+        );
+        assert_eq!(vec2_of(r.unwrap().unwrap()), [4.0, 6.0]);
+    }
+
+    #[test]
+    fn componentwise_broadcasts_scalar() {
+        let r = componentwise("mul", &Value::I32(2), &Value::Vec2([1.5, -1.0]), |x, y| {
+            x * y
+        });
+        assert_eq!(vec2_of(r.unwrap().unwrap()), [3.0, -2.0]);
+    }
+
+    #[test]
+    fn componentwise_rejects_dimension_mismatch() {
+        let r = componentwise(
+            "add",
+            &Value::Vec2([1.0, 2.0]),
+            &Value::Vec3([1.0, 2.0, 3.0]),
+            |x, y| x + y,
+        );
+        assert!(matches!(r, Some(Err(VmErrorKind::TypeMismatch(_)))));
+    }
+
+    #[test]
+    fn componentwise_skips_scalars() {
+        assert!(componentwise("add", &Value::I32(1), &Value::F32(2.0), |x, y| x + y).is_none());
+    }
+
+    #[test]
+    fn div_by_zero_component_errors() {
+        let r = componentwise_div(&Value::Vec2([1.0, 1.0]), &Value::Vec2([1.0, 0.0]));
+        assert!(matches!(r, Some(Err(VmErrorKind::DivisionByZero))));
+        let r = componentwise_div(&Value::Vec2([1.0, 1.0]), &Value::F32(0.0));
+        assert!(matches!(r, Some(Err(VmErrorKind::DivisionByZero))));
+    }
+
+    #[test]
+    fn dot_length_normalize() {
+        let a = Value::Vec3([1.0, 2.0, 3.0]);
+        let b = Value::Vec3([4.0, 5.0, 6.0]);
+        assert!(matches!(dot(a.clone(), b), Ok(Value::F32(v)) if v == 32.0));
+        assert!(matches!(length(Value::Vec2([3.0, 4.0])), Ok(Value::F32(v)) if v == 5.0));
+        assert_eq!(
+            vec2_of(normalize(Value::Vec2([3.0, 4.0])).unwrap()),
+            [0.6, 0.8]
+        );
+        assert_eq!(
+            vec2_of(normalize(Value::Vec2([0.0, 0.0])).unwrap()),
+            [0.0, 0.0]
+        );
+        assert!(matches!(
+            dot(a, Value::F32(1.0)),
+            Err(VmErrorKind::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn extract_checks_index() {
+        let v = Value::Vec2([1.0, 2.0]);
+        assert!(matches!(extract(v.clone(), 1), Ok(Value::F32(v)) if v == 2.0));
+        assert!(matches!(extract(v, 2), Err(VmErrorKind::TypeMismatch(_))));
+        assert!(matches!(
+            extract(Value::F32(1.0), 0),
+            Err(VmErrorKind::TypeMismatch(_))
+        ));
+    }
+}
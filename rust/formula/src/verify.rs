@@ -0,0 +1,997 @@
+//! Path: rust/formula/src/verify.rs
+//! Summary: Formula プログラムの静的検証（確定代入解析と型推論）
+//!
+//! デコード済みの命令列を実行せずに走査し、未初期化レジスタの読み出しと型の不整合を報告する。
+//! 後方ジャンプが無いため、命令順に 1 回走査して合流点（ジャンプ先）で状態を結合すれば足りる。
+//!
+//! 型規則は vm.rs / math.rs / vector.rs の演算に合わせるが、次の点で VM より厳しい。
+//! - 数値文脈（四則演算・大小比較・数学関数・make_vec）の Bool は型エラー（VM は 0 / 1 に強制変換する）
+//! - eq / ne で種類の異なる値（Bool と数値、ベクトルとスカラー等）を比べるのは型エラー（VM は常に false）
+//!
+//! 型が分からない値（スキーマに無い入力・Store キー）は検査せず、結果の型も不明として扱う。
+
+use super::decode::{decode_with_offsets, DecodeError, Instruction, Name, REGISTER_COUNT};
+use super::opcode::OpCode;
+use super::random;
+use super::value::Value;
+use std::collections::{HashMap, HashSet};
+
+/// 値の型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    I32,
+    F32,
+    Bool,
+    Vec2,
+    Vec3,
+    /// リスト（要素の型は追跡しない）
+    List,
+    I64,
+    F64,
+}
+
+impl ValueType {
+    pub(super) const ALL: [ValueType; 8] = [
+        ValueType::I32,
+        ValueType::F32,
+        ValueType::Bool,
+        ValueType::Vec2,
+        ValueType::Vec3,
+        ValueType::List,
+        ValueType::I64,
+        ValueType::F64,
+    ];
+
+    /// NIF で受け渡す atom 名
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ValueType::I32 => "i32",
+            ValueType::F32 => "f32",
+            ValueType::Bool => "bool",
+            ValueType::Vec2 => "vec2",
+            ValueType::Vec3 => "vec3",
+            ValueType::List => "list",
+            ValueType::I64 => "i64",
+            ValueType::F64 => "f64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.as_str() == name)
+    }
+
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::I32(_) => ValueType::I32,
+            Value::F32(_) => ValueType::F32,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Vec2(_) => ValueType::Vec2,
+            Value::Vec3(_) => ValueType::Vec3,
+            Value::List(_) => ValueType::List,
+            Value::I64(_) => ValueType::I64,
+            Value::F64(_) => ValueType::F64,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(
+            self,
+            ValueType::I32 | ValueType::F32 | ValueType::I64 | ValueType::F64
+        )
+    }
+
+    fn is_integer(self) -> bool {
+        matches!(self, ValueType::I32 | ValueType::I64)
+    }
+
+    fn is_scalar(self) -> bool {
+        self.is_numeric() || self == ValueType::Bool
+    }
+
+    fn is_vector(self) -> bool {
+        matches!(self, ValueType::Vec2 | ValueType::Vec3)
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// レジスタが取りうる型の集合。全型を含む集合は「型不明」として扱う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeSet(u8);
+
+impl TypeSet {
+    pub(super) const EMPTY: TypeSet = TypeSet(0);
+    pub(super) const ANY: TypeSet = TypeSet(0b1111_1111);
+
+    pub(super) fn single(t: ValueType) -> Self {
+        TypeSet(t.bit())
+    }
+
+    pub(super) fn with(self, t: ValueType) -> Self {
+        TypeSet(self.0 | t.bit())
+    }
+
+    pub(super) fn union(self, other: TypeSet) -> Self {
+        TypeSet(self.0 | other.0)
+    }
+
+    pub fn is_unknown(self) -> bool {
+        self == Self::ANY
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = ValueType> {
+        ValueType::ALL
+            .into_iter()
+            .filter(move |t| self.0 & t.bit() != 0)
+    }
+}
+
+/// 入力・Store の型宣言。None のセクションは宣言なし（名前の検査をせず、型は不明）
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    pub inputs: Option<HashMap<String, ValueType>>,
+    pub store: Option<HashMap<String, ValueType>>,
+}
+
+/// 検出した問題の種類
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    /// どの経路でも初期化されていないレジスタを読んだ
+    UninitializedRegister(u8),
+    /// 一部の経路で初期化されていないレジスタを読んだ
+    MaybeUninitializedRegister(u8),
+    /// オペランドの型が演算に合わない。operands はオペランドごとの型集合
+    TypeMismatch { op: OpCode, operands: Vec<TypeSet> },
+    /// スキーマで宣言されていない入力を読んだ
+    UndeclaredInput(Name),
+    /// スキーマで宣言されておらず、先行する WriteStore も無い Store キーを読んだ
+    UndeclaredStoreKey(Name),
+    /// 宣言と異なる型の値を Store に書き込む
+    StoreTypeMismatch {
+        key: Name,
+        expected: ValueType,
+        found: TypeSet,
+    },
+}
+
+impl DiagnosticKind {
+    /// NIF で返す atom 名
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiagnosticKind::UninitializedRegister(_) => "uninitialized_register",
+            DiagnosticKind::MaybeUninitializedRegister(_) => "maybe_uninitialized_register",
+            DiagnosticKind::TypeMismatch { .. } => "type_mismatch",
+            DiagnosticKind::UndeclaredInput(_) => "undeclared_input",
+            DiagnosticKind::UndeclaredStoreKey(_) => "undeclared_store_key",
+            DiagnosticKind::StoreTypeMismatch { .. } => "store_type_mismatch",
+        }
+    }
+}
+
+/// 1 件の診断。pc は命令インデックス、offset はその命令のバイトオフセット（`verify_bytecode` のみ）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub pc: usize,
+    pub offset: Option<usize>,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Init {
+    No,
+    Maybe,
+    Yes,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Reg {
+    init: Init,
+    types: TypeSet,
+}
+
+const UNINIT: Reg = Reg {
+    init: Init::No,
+    types: TypeSet::EMPTY,
+};
+
+type State = [Reg; REGISTER_COUNT];
+
+/// 合流点での結合。初期化は両経路で済んでいれば Yes、片方だけなら Maybe
+fn join(into: &mut State, other: &State) {
+    for (a, b) in into.iter_mut().zip(other) {
+        a.init = if a.init == b.init {
+            a.init
+        } else {
+            Init::Maybe
+        };
+        a.types = a.types.union(b.types);
+    }
+}
+
+fn merge(slot: &mut Option<State>, state: &State) {
+    match slot {
+        Some(existing) => join(existing, state),
+        None => *slot = Some(*state),
+    }
+}
+
+/// バイトコードをデコードして検証する。オフセットはコード部分の先頭から数える（`decode_with_offsets` と同じ）
+pub fn verify_bytecode(bytecode: &[u8], schema: &Schema) -> Result<Vec<Diagnostic>, DecodeError> {
+    let (instructions, offsets) = decode_with_offsets(bytecode)?;
+    let mut diagnostics = verify(&instructions, schema);
+    for d in &mut diagnostics {
+        d.offset = Some(offsets[d.pc]);
+    }
+    Ok(diagnostics)
+}
+
+/// 命令列を検証し、診断を命令順に返す。空なら問題なし。到達しない命令は検査しない
+pub fn verify(instructions: &[Instruction], schema: &Schema) -> Vec<Diagnostic> {
+    let mut verifier = Verifier {
+        schema,
+        state: [UNINIT; REGISTER_COUNT],
+        pc: 0,
+        written_keys: HashSet::new(),
+        diagnostics: Vec::new(),
+    };
+    let mut incoming: Vec<Option<State>> = vec![None; instructions.len() + 1];
+    let mut fallthrough = Some([UNINIT; REGISTER_COUNT]);
+
+    for (pc, inst) in instructions.iter().enumerate() {
+        let mut state = fallthrough.take();
+        if let Some(jumped) = incoming[pc].take() {
+            merge(&mut state, &jumped);
+        }
+        let Some(state) = state else { continue };
+        verifier.pc = pc;
+        verifier.state = state;
+        verifier.step(inst);
+
+        match inst {
+            Instruction::Jump { target } => merge(&mut incoming[*target], &verifier.state),
+            Instruction::JumpIfFalse { target, .. } | Instruction::JumpIfTrue { target, .. } => {
+                merge(&mut incoming[*target], &verifier.state);
+                fallthrough = Some(verifier.state);
+            }
+            Instruction::Ret { .. } => {}
+            _ => fallthrough = Some(verifier.state),
+        }
+    }
+
+    verifier.diagnostics
+}
+
+struct Verifier<'a> {
+    schema: &'a Schema,
+    state: State,
+    pc: usize,
+    /// 既に WriteStore した（宣言が無くても読んでよい）キー
+    written_keys: HashSet<Name>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Verifier<'_> {
+    fn report(&mut self, kind: DiagnosticKind) {
+        self.diagnostics.push(Diagnostic {
+            pc: self.pc,
+            offset: None,
+            kind,
+        });
+    }
+
+    /// レジスタを読む。未初期化は報告し、後続の連鎖を避けるため型不明として続ける
+    fn read(&mut self, r: u8) -> TypeSet {
+        let reg = self.state[r as usize];
+        match reg.init {
+            Init::Yes => reg.types,
+            Init::Maybe => {
+                self.report(DiagnosticKind::MaybeUninitializedRegister(r));
+                reg.types
+            }
+            Init::No => {
+                self.report(DiagnosticKind::UninitializedRegister(r));
+                TypeSet::ANY
+            }
+        }
+    }
+
+    /// Random / RandomRange が読み書きする乱数状態のキーを、ReadStore と同じ規則で検査する
+    fn read_random_state(&mut self) {
+        let Some(store) = &self.schema.store else {
+            return;
+        };
+        if !store.contains_key(random::STATE_KEY) && !self.written_keys.contains(random::STATE_KEY)
+        {
+            self.report(DiagnosticKind::UndeclaredStoreKey(random::STATE_KEY.into()));
+        }
+    }
+
+    fn write(&mut self, r: u8, types: TypeSet) {
+        self.state[r as usize] = Reg {
+            init: Init::Yes,
+            types,
+        };
+    }
+
+    /// オペランドの型の全組み合わせに rule を適用し、結果の型集合を返す。
+    /// 型の分かっている組み合わせで rule が失敗すれば TypeMismatch を報告する
+    fn check(
+        &mut self,
+        op: OpCode,
+        srcs: &[u8],
+        rule: impl Fn(&[ValueType]) -> Option<ValueType>,
+    ) -> TypeSet {
+        let operands: Vec<TypeSet> = srcs.iter().map(|r| self.read(*r)).collect();
+        let unknown = operands.iter().any(|t| t.is_unknown());
+        let mut result = TypeSet::EMPTY;
+        let mut failed = false;
+        for combination in combinations(&operands) {
+            match rule(&combination) {
+                Some(t) => result = result.with(t),
+                None => failed = true,
+            }
+        }
+        if failed && (!unknown || result == TypeSet::EMPTY) {
+            self.report(DiagnosticKind::TypeMismatch { op, operands });
+        }
+        if result == TypeSet::EMPTY {
+            TypeSet::ANY
+        } else {
+            result
+        }
+    }
+
+    fn step(&mut self, inst: &Instruction) {
+        let op = inst.opcode();
+        match inst {
+            Instruction::LoadInput { dst, name } => {
+                let types = match &self.schema.inputs {
+                    Some(inputs) => match inputs.get(&**name) {
+                        Some(t) => TypeSet::single(*t),
+                        None => {
+                            self.report(DiagnosticKind::UndeclaredInput(name.clone()));
+                            TypeSet::ANY
+                        }
+                    },
+                    None => TypeSet::ANY,
+                };
+                self.write(*dst, types);
+            }
+            Instruction::LoadI32 { dst, .. } => self.write(*dst, TypeSet::single(ValueType::I32)),
+            Instruction::LoadF32 { dst, .. } => self.write(*dst, TypeSet::single(ValueType::F32)),
+            Instruction::LoadI64 { dst, .. } => self.write(*dst, TypeSet::single(ValueType::I64)),
+            Instruction::LoadF64 { dst, .. } => self.write(*dst, TypeSet::single(ValueType::F64)),
+            Instruction::LoadBool { dst, .. } => self.write(*dst, TypeSet::single(ValueType::Bool)),
+            Instruction::LoadVec { dst, value } => {
+                self.write(*dst, TypeSet::single(ValueType::of(value)))
+            }
+            Instruction::Add { dst, src_a, src_b }
+            | Instruction::Sub { dst, src_a, src_b }
+            | Instruction::Mul { dst, src_a, src_b }
+            | Instruction::Div { dst, src_a, src_b } => {
+                let t = self.check(op, &[*src_a, *src_b], |t| arithmetic(t[0], t[1]));
+                self.write(*dst, t);
+            }
+            Instruction::Lt { dst, src_a, src_b }
+            | Instruction::Gt { dst, src_a, src_b }
+            | Instruction::Le { dst, src_a, src_b }
+            | Instruction::Ge { dst, src_a, src_b } => {
+                let t = self.check(op, &[*src_a, *src_b], |t| {
+                    all_numeric(t).then_some(ValueType::Bool)
+                });
+                self.write(*dst, t);
+            }
+            Instruction::Eq { dst, src_a, src_b } | Instruction::Ne { dst, src_a, src_b } => {
+                let t = self.check(op, &[*src_a, *src_b], |t| {
+                    let comparable = (t[0].is_numeric() && t[1].is_numeric()) || t[0] == t[1];
+                    comparable.then_some(ValueType::Bool)
+                });
+                self.write(*dst, t);
+            }
+            Instruction::StoreOutput { src } => {
+                self.read(*src);
+            }
+            Instruction::Emit { src, .. } => {
+                if let Some(src) = src {
+                    self.read(*src);
+                }
+            }
+            Instruction::ReadStore { dst, name } => {
+                let types = match &self.schema.store {
+                    Some(store) => match store.get(&**name) {
+                        Some(t) => TypeSet::single(*t),
+                        None => {
+                            if !self.written_keys.contains(name) {
+                                self.report(DiagnosticKind::UndeclaredStoreKey(name.clone()));
+                            }
+                            TypeSet::ANY
+                        }
+                    },
+                    None => TypeSet::ANY,
+                };
+                self.write(*dst, types);
+            }
+            Instruction::WriteStore { src, name } => {
+                let found = self.read(*src);
+                let declared = self.schema.store.as_ref().and_then(|s| s.get(&**name));
+                if let Some(expected) = declared {
+                    if !found.is_unknown() && found != TypeSet::single(*expected) {
+                        self.report(DiagnosticKind::StoreTypeMismatch {
+                            key: name.clone(),
+                            expected: *expected,
+                            found,
+                        });
+                    }
+                }
+                self.written_keys.insert(name.clone());
+            }
+            Instruction::Jump { .. } => {}
+            Instruction::Call { dst, args, .. } => {
+                // 関数の中身は検査しない。戻り値の型は不明
+                for arg in args {
+                    self.read(*arg);
+                }
+                self.write(*dst, TypeSet::ANY);
+            }
+            Instruction::Ret { src } => {
+                self.read(*src);
+            }
+            Instruction::Random { dst } => {
+                self.read_random_state();
+                self.write(*dst, TypeSet::single(ValueType::F32));
+            }
+            Instruction::RandomRange { dst, lo, hi } => {
+                let t = self.check(op, &[*lo, *hi], |t| {
+                    if t.iter().all(|t| *t == ValueType::I32) {
+                        Some(ValueType::I32)
+                    } else {
+                        all_numeric(t).then_some(ValueType::F32)
+                    }
+                });
+                self.read_random_state();
+                self.write(*dst, t);
+            }
+            Instruction::JumpIfFalse { cond, .. } | Instruction::JumpIfTrue { cond, .. } => {
+                self.check(op, &[*cond], |t| {
+                    t[0].is_scalar().then_some(ValueType::Bool)
+                });
+            }
+            Instruction::Abs { dst, src }
+            | Instruction::Floor { dst, src }
+            | Instruction::Ceil { dst, src } => {
+                let t = self.check(op, &[*src], |t| t[0].is_numeric().then_some(t[0]));
+                self.write(*dst, t);
+            }
+            Instruction::Sqrt { dst, src }
+            | Instruction::Sin { dst, src }
+            | Instruction::Cos { dst, src }
+            | Instruction::Length { dst, src } => {
+                let t = if op == OpCode::Length {
+                    self.check(op, &[*src], |t| t[0].is_vector().then_some(ValueType::F32))
+                } else {
+                    self.check(op, &[*src], floating)
+                };
+                self.write(*dst, t);
+            }
+            Instruction::Min { dst, src_a, src_b }
+            | Instruction::Max { dst, src_a, src_b }
+            | Instruction::Pow { dst, src_a, src_b }
+            | Instruction::Mod { dst, src_a, src_b } => {
+                let t = self.check(op, &[*src_a, *src_b], integer_preserving);
+                self.write(*dst, t);
+            }
+            Instruction::Clamp { dst, src, lo, hi } => {
+                let t = self.check(op, &[*src, *lo, *hi], integer_preserving);
+                self.write(*dst, t);
+            }
+            Instruction::Atan2 { dst, src_a, src_b } => {
+                let t = self.check(op, &[*src_a, *src_b], floating);
+                self.write(*dst, t);
+            }
+            Instruction::Lerp { dst, a, b, t } => {
+                let result = self.check(op, &[*a, *b, *t], floating);
+                self.write(*dst, result);
+            }
+            Instruction::Not { dst, src } => {
+                let t = self.check(op, &[*src], |t| t[0].is_scalar().then_some(ValueType::Bool));
+                self.write(*dst, t);
+            }
+            Instruction::And { dst, src_a, src_b }
+            | Instruction::Or { dst, src_a, src_b }
+            | Instruction::Xor { dst, src_a, src_b } => {
+                let t = self.check(op, &[*src_a, *src_b], |t| {
+                    t.iter().all(|t| t.is_scalar()).then_some(ValueType::Bool)
+                });
+                self.write(*dst, t);
+            }
+            Instruction::Select { dst, cond, a, b } => {
+                self.check(op, &[*cond], |t| {
+                    t[0].is_scalar().then_some(ValueType::Bool)
+                });
+                let t = self.read(*a).union(self.read(*b));
+                self.write(*dst, t);
+            }
+            Instruction::MakeVec { dst, n, srcs } => {
+                let vec_type = if *n == 2 {
+                    ValueType::Vec2
+                } else {
+                    ValueType::Vec3
+                };
+                let t = self.check(op, &srcs[..*n as usize], |t| {
+                    all_numeric(t).then_some(vec_type)
+                });
+                self.write(*dst, t);
+            }
+            Instruction::Extract { dst, src, index } => {
+                let t = self.check(op, &[*src], |t| {
+                    let dims = match t[0] {
+                        ValueType::Vec2 => 2,
+                        ValueType::Vec3 => 3,
+                        _ => 0,
+                    };
+                    (*index < dims).then_some(ValueType::F32)
+                });
+                self.write(*dst, t);
+            }
+            Instruction::Dot { dst, src_a, src_b } => {
+                let t = self.check(op, &[*src_a, *src_b], |t| {
+                    (t[0].is_vector() && t[0] == t[1]).then_some(ValueType::F32)
+                });
+                self.write(*dst, t);
+            }
+            Instruction::Normalize { dst, src } => {
+                let t = self.check(op, &[*src], |t| t[0].is_vector().then_some(t[0]));
+                self.write(*dst, t);
+            }
+            Instruction::ListLen { dst, src } => {
+                let t = self.check(op, &[*src], |t| is_list(t[0]).then_some(ValueType::I32));
+                self.write(*dst, t);
+            }
+            Instruction::ListIndex { dst, list, index } => {
+                self.check(op, &[*list, *index], |t| {
+                    (is_list(t[0]) && t[1] == ValueType::I32).then_some(ValueType::I32)
+                });
+                // 要素の型は追跡しないため不明
+                self.write(*dst, TypeSet::ANY);
+            }
+            Instruction::ListSum { dst, src }
+            | Instruction::ListMin { dst, src }
+            | Instruction::ListMax { dst, src } => {
+                self.check(op, &[*src], |t| is_list(t[0]).then_some(ValueType::I32));
+                self.write(*dst, TypeSet::ANY);
+            }
+            Instruction::ForEach {
+                dst, list, init, ..
+            } => {
+                // 関数の中身は検査しない（Call と同じ）。結果の型は不明
+                self.check(op, &[*list], |t| is_list(t[0]).then_some(ValueType::I32));
+                self.read(*init);
+                self.write(*dst, TypeSet::ANY);
+            }
+            Instruction::ListMap { dst, list, .. } => {
+                let t = self.check(op, &[*list], |t| is_list(t[0]).then_some(ValueType::List));
+                self.write(*dst, t);
+            }
+            Instruction::CastI32 { dst, src }
+            | Instruction::CastF32 { dst, src }
+            | Instruction::CastI64 { dst, src }
+            | Instruction::CastF64 { dst, src } => {
+                let target = match op {
+                    OpCode::CastI32 => ValueType::I32,
+                    OpCode::CastF32 => ValueType::F32,
+                    OpCode::CastI64 => ValueType::I64,
+                    _ => ValueType::F64,
+                };
+                let t = self.check(op, &[*src], |t| t[0].is_numeric().then_some(target));
+                self.write(*dst, t);
+            }
+        }
+    }
+}
+
+fn is_list(t: ValueType) -> bool {
+    t == ValueType::List
+}
+
+fn all_numeric(types: &[ValueType]) -> bool {
+    types.iter().all(|t| t.is_numeric())
+}
+
+/// 数値の昇格（`value::promote` と同じ）。すべて I32 なら I32、整数だけなら I64、
+/// 64 ビットの値を含めば F64、それ以外は F32
+fn promote(types: &[ValueType]) -> ValueType {
+    if types.iter().all(|t| *t == ValueType::I32) {
+        ValueType::I32
+    } else if types.iter().all(|t| t.is_integer()) {
+        ValueType::I64
+    } else if types
+        .iter()
+        .any(|t| matches!(t, ValueType::I64 | ValueType::F64))
+    {
+        ValueType::F64
+    } else {
+        ValueType::F32
+    }
+}
+
+/// add / sub / mul / div: ベクトルは同次元同士かスカラー（数値）とのブロードキャスト。
+/// スカラー同士は `promote` の型
+fn arithmetic(a: ValueType, b: ValueType) -> Option<ValueType> {
+    match (a, b) {
+        _ if a.is_numeric() && b.is_numeric() => Some(promote(&[a, b])),
+        _ if a.is_vector() && (a == b || b.is_numeric()) => Some(a),
+        _ if b.is_vector() && a.is_numeric() => Some(b),
+        _ => None,
+    }
+}
+
+/// min / max / pow / mod / clamp: 数値なら `promote` の型
+fn integer_preserving(types: &[ValueType]) -> Option<ValueType> {
+    all_numeric(types).then(|| promote(types))
+}
+
+/// sqrt / sin / cos / atan2 / lerp: 常に浮動小数点。64 bit の値を含めば F64、それ以外は F32
+fn floating(types: &[ValueType]) -> Option<ValueType> {
+    all_numeric(types).then(|| match promote(types) {
+        ValueType::I64 | ValueType::F64 => ValueType::F64,
+        _ => ValueType::F32,
+    })
+}
+
+/// 型集合の直積（オペランドは高々 3 つ、各 8 型まで）
+pub(super) fn combinations(operands: &[TypeSet]) -> Vec<Vec<ValueType>> {
+    operands.iter().fold(vec![Vec::new()], |acc, set| {
+        acc.iter()
+            .flat_map(|prefix| {
+                set.iter().map(move |t| {
+                    let mut next = prefix.clone();
+                    next.push(t);
+                    next
+                })
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn name(s: &str) -> Name {
+        Arc::from(s)
+    }
+
+    fn kinds(instructions: &[Instruction], schema: &Schema) -> Vec<(usize, DiagnosticKind)> {
+        verify(instructions, schema)
+            .into_iter()
+            .map(|d| (d.pc, d.kind))
+            .collect()
+    }
+
+    #[test]
+    fn well_typed_program_has_no_diagnostics() {
+        let schema = Schema {
+            inputs: Some(HashMap::from([("dt".to_string(), ValueType::F32)])),
+            store: Some(HashMap::from([("score".to_string(), ValueType::F32)])),
+        };
+        let program = [
+            Instruction::LoadInput {
+                dst: 0,
+                name: name("dt"),
+            },
+            Instruction::ReadStore {
+                dst: 1,
+                name: name("score"),
+            },
+            Instruction::Add {
+                dst: 1,
+                src_a: 1,
+                src_b: 0,
+            },
+            Instruction::WriteStore {
+                src: 1,
+                name: name("score"),
+            },
+            Instruction::StoreOutput { src: 1 },
+        ];
+        assert!(verify(&program, &schema).is_empty());
+    }
+
+    #[test]
+    fn reports_uninitialized_and_maybe_uninitialized() {
+        let program = [
+            Instruction::LoadBool {
+                dst: 0,
+                value: true,
+            },
+            Instruction::JumpIfFalse { cond: 0, target: 3 },
+            Instruction::LoadI32 { dst: 1, value: 1 },
+            Instruction::StoreOutput { src: 1 },
+            Instruction::StoreOutput { src: 2 },
+        ];
+        assert_eq!(
+            kinds(&program, &Schema::default()),
+            [
+                (3, DiagnosticKind::MaybeUninitializedRegister(1)),
+                (4, DiagnosticKind::UninitializedRegister(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn both_branches_assign_definitely() {
+        // if r0 { r1 = 1 } else { r1 = 2.0 }; output r1
+        let program = [
+            Instruction::LoadBool {
+                dst: 0,
+                value: true,
+            },
+            Instruction::JumpIfFalse { cond: 0, target: 4 },
+            Instruction::LoadI32 { dst: 1, value: 1 },
+            Instruction::Jump { target: 5 },
+            Instruction::LoadF32 { dst: 1, value: 2.0 },
+            Instruction::Mul {
+                dst: 2,
+                src_a: 1,
+                src_b: 1,
+            },
+            Instruction::StoreOutput { src: 2 },
+        ];
+        assert!(verify(&program, &Schema::default()).is_empty());
+    }
+
+    #[test]
+    fn bool_compared_with_f32_is_type_mismatch() {
+        let program = [
+            Instruction::LoadBool {
+                dst: 0,
+                value: true,
+            },
+            Instruction::LoadF32 { dst: 1, value: 1.0 },
+            Instruction::Lt {
+                dst: 2,
+                src_a: 0,
+                src_b: 1,
+            },
+        ];
+        assert_eq!(
+            kinds(&program, &Schema::default()),
+            [(
+                2,
+                DiagnosticKind::TypeMismatch {
+                    op: OpCode::Lt,
+                    operands: vec![
+                        TypeSet::single(ValueType::Bool),
+                        TypeSet::single(ValueType::F32)
+                    ],
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn unknown_types_are_not_reported() {
+        let program = [
+            Instruction::LoadInput {
+                dst: 0,
+                name: name("anything"),
+            },
+            Instruction::Dot {
+                dst: 1,
+                src_a: 0,
+                src_b: 0,
+            },
+        ];
+        assert!(verify(&program, &Schema::default()).is_empty());
+    }
+
+    #[test]
+    fn schema_checks_names_and_store_types() {
+        let schema = Schema {
+            inputs: Some(HashMap::new()),
+            store: Some(HashMap::from([("hp".to_string(), ValueType::I32)])),
+        };
+        let program = [
+            Instruction::LoadInput {
+                dst: 0,
+                name: name("dt"),
+            },
+            Instruction::LoadF32 { dst: 1, value: 0.5 },
+            Instruction::WriteStore {
+                src: 1,
+                name: name("hp"),
+            },
+            Instruction::ReadStore {
+                dst: 2,
+                name: name("mp"),
+            },
+            Instruction::WriteStore {
+                src: 1,
+                name: name("tmp"),
+            },
+            Instruction::ReadStore {
+                dst: 3,
+                name: name("tmp"),
+            },
+        ];
+        assert_eq!(
+            kinds(&program, &schema),
+            [
+                (0, DiagnosticKind::UndeclaredInput(name("dt"))),
+                (
+                    2,
+                    DiagnosticKind::StoreTypeMismatch {
+                        key: name("hp"),
+                        expected: ValueType::I32,
+                        found: TypeSet::single(ValueType::F32),
+                    }
+                ),
+                (3, DiagnosticKind::UndeclaredStoreKey(name("mp"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn wide_types_promote_like_vm() {
+        let schema = Schema {
+            inputs: None,
+            store: Some(HashMap::from([("ticks".to_string(), ValueType::I64)])),
+        };
+        let program = [
+            Instruction::ReadStore {
+                dst: 0,
+                name: name("ticks"),
+            },
+            Instruction::LoadI32 { dst: 1, value: 1 },
+            Instruction::Add {
+                dst: 2,
+                src_a: 0,
+                src_b: 1,
+            },
+            Instruction::WriteStore {
+                src: 2,
+                name: name("ticks"),
+            },
+            Instruction::LoadF32 { dst: 3, value: 0.5 },
+            Instruction::Mul {
+                dst: 4,
+                src_a: 0,
+                src_b: 3,
+            },
+            Instruction::WriteStore {
+                src: 4,
+                name: name("ticks"),
+            },
+            Instruction::CastI64 { dst: 5, src: 4 },
+            Instruction::WriteStore {
+                src: 5,
+                name: name("ticks"),
+            },
+        ];
+        assert_eq!(
+            kinds(&program, &schema),
+            [(
+                6,
+                DiagnosticKind::StoreTypeMismatch {
+                    key: name("ticks"),
+                    expected: ValueType::I64,
+                    found: TypeSet::single(ValueType::F64),
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn random_state_key_follows_the_store_schema() {
+        let program = [
+            Instruction::Random { dst: 0 },
+            Instruction::LoadI32 { dst: 1, value: 1 },
+            Instruction::LoadF32 { dst: 2, value: 6.0 },
+            Instruction::RandomRange {
+                dst: 3,
+                lo: 1,
+                hi: 1,
+            },
+            Instruction::RandomRange {
+                dst: 4,
+                lo: 1,
+                hi: 2,
+            },
+            Instruction::Sqrt { dst: 5, src: 4 },
+            Instruction::Dot {
+                dst: 6,
+                src_a: 0,
+                src_b: 3,
+            },
+        ];
+        let undeclared = Schema {
+            inputs: None,
+            store: Some(HashMap::new()),
+        };
+        assert_eq!(
+            kinds(&program, &undeclared)[0],
+            (0, DiagnosticKind::UndeclaredStoreKey(name("$rng")))
+        );
+        // random は f32、random_range は i32 同士なら i32
+        let declared = Schema {
+            inputs: None,
+            store: Some(HashMap::from([("$rng".to_string(), ValueType::I32)])),
+        };
+        assert_eq!(
+            kinds(&program, &declared),
+            [(
+                6,
+                DiagnosticKind::TypeMismatch {
+                    op: OpCode::Dot,
+                    operands: vec![
+                        TypeSet::single(ValueType::F32),
+                        TypeSet::single(ValueType::I32)
+                    ],
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn vector_rules_follow_vm() {
+        let program = [
+            Instruction::LoadVec {
+                dst: 0,
+                value: Value::Vec2([1.0, 2.0]),
+            },
+            Instruction::LoadI32 { dst: 1, value: 2 },
+            Instruction::Mul {
+                dst: 2,
+                src_a: 0,
+                src_b: 1,
+            },
+            Instruction::Extract {
+                dst: 3,
+                src: 2,
+                index: 1,
+            },
+            Instruction::Extract {
+                dst: 4,
+                src: 2,
+                index: 2,
+            },
+        ];
+        assert_eq!(
+            kinds(&program, &Schema::default()),
+            [(
+                4,
+                DiagnosticKind::TypeMismatch {
+                    op: OpCode::Extract,
+                    operands: vec![TypeSet::single(ValueType::Vec2)],
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn verify_bytecode_reports_offsets() {
+        // load_i32 r0 1 (6 bytes); store_output r1
+        let bytecode = [1, 0, 1, 0, 0, 0, 11, 1];
+        let diagnostics = verify_bytecode(&bytecode, &Schema::default()).expect("decode");
+        assert_eq!(
+            diagnostics,
+            [Diagnostic {
+                pc: 1,
+                offset: Some(6),
+                kind: DiagnosticKind::UninitializedRegister(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn unreachable_code_is_skipped() {
+        let program = [
+            Instruction::Jump { target: 2 },
+            Instruction::StoreOutput { src: 9 },
+        ];
+        assert!(verify(&program, &Schema::default()).is_empty());
+    }
+}
@@ -0,0 +1,1766 @@
+//! Path: rust/formula/src/vm.rs
+//! Summary: Formula VM（レジスタマシン）の実行
+
+use super::decode::{DecodeError, DecodeErrorKind, Instruction, Location, Name, REGISTER_COUNT};
+use super::library::Library;
+use super::limits::{Budget, Limits};
+use super::list;
+use super::math;
+use super::program::Program;
+use super::random;
+use super::store::{Overlay, Schematized, StoreAccess, StoreBase, StoreChange};
+use super::trace::{NoTrace, Observer, Trace, TraceOptions, Tracer};
+use super::value::{promote, NumKind, Value};
+use super::vector;
+use super::verify::ValueType;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// 実行・デコードの失敗と、それが起きた命令の位置
+#[derive(Debug)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    /// 失敗した命令。呼び出し先の関数の中で失敗した場合はその関数の命令を指す。
+    /// コンテナのヘッダ・表の誤りなど、命令に属さないデコードエラーは None
+    pub location: Option<Location>,
+}
+
+#[derive(Debug)]
+pub enum VmErrorKind {
+    Decode(DecodeErrorKind),
+    InputNotFound(String),
+    StoreNotFound(String),
+    TypeMismatch(String),
+    RegisterOutOfRange(u8),
+    DivisionByZero,
+    /// 数学関数の定義域外（負の sqrt、clamp の lo > hi 等）。detail は演算名
+    DomainError(String),
+    /// 実行命令数が上限を超えた（ループ安全ポリシー違反）
+    StepLimitExceeded(usize),
+    /// `Limits` の上限を超えた。usize は超過した上限値
+    BudgetExceeded(Budget, usize),
+    /// Call の関数がライブラリに無い（ライブラリが結び付いていない場合を含む）
+    UndefinedFunction(String),
+    /// 関数が Ret せずに終端に達した。detail は関数名
+    MissingReturn(String),
+    /// 読み取り専用の Store ビューの値を Store の値として解釈できない。detail はキー
+    InvalidStoreValue(String),
+    /// ListIndex の添字がリストの範囲外（負を含む）。detail は添字
+    IndexOutOfRange(i32),
+    /// Store スキーマで宣言した型と異なる値を読み書きした。(キー, 宣言の型, 値の型)
+    StoreTypeMismatch(String, ValueType, ValueType),
+}
+
+/// Emit で発行したイベント。発行順に実行結果へ並ぶ
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub name: Name,
+    pub payload: Option<Value>,
+}
+
+impl From<DecodeError> for VmError {
+    fn from(e: DecodeError) -> Self {
+        VmError {
+            kind: VmErrorKind::Decode(e.kind),
+            location: e.location,
+        }
+    }
+}
+
+/// バイトコードを実行し、出力値のリストと更新後の Store を返す。
+/// store_values は Elixir が管理する初期値。永続化は Elixir の責務。
+///
+/// 毎回デコードする。同じプログラムを繰り返し実行する場合は `Program::compile` と `execute` を使う。
+/// limits を超えた場合は `VmErrorKind::BudgetExceeded` を返す。エラーには失敗した命令の位置が付く。
+pub fn run(
+    bytecode: &[u8],
+    inputs: &HashMap<String, Value>,
+    store_values: &HashMap<String, Value>,
+    limits: &Limits,
+) -> Result<(Vec<Value>, HashMap<String, Value>), VmError> {
+    let program = Program::compile(bytecode)?;
+    execute(&program, inputs, store_values, limits)
+}
+
+/// コンパイル済みプログラムを実行する。戻り値は `run` と同じ（Emit のイベントは捨てる）。
+pub fn execute(
+    program: &Program,
+    inputs: &HashMap<String, Value>,
+    store_values: &HashMap<String, Value>,
+    limits: &Limits,
+) -> Result<(Vec<Value>, HashMap<String, Value>), VmError> {
+    // ネイティブコードは Store・イベントに触れない。失敗したら解釈実行し直す
+    #[cfg(feature = "jit")]
+    if let Some(outputs) = program.run_jit(inputs, limits) {
+        return Ok((outputs, store_values.clone()));
+    }
+    execute_with_events(program, inputs, store_values, limits)
+        .map(|(outputs, store, _)| (outputs, store))
+}
+
+/// `execute` に加えて、Emit で発行したイベントを発行順に返す
+#[allow(clippy::type_complexity)]
+pub fn execute_with_events(
+    program: &Program,
+    inputs: &HashMap<String, Value>,
+    store_values: &HashMap<String, Value>,
+    limits: &Limits,
+) -> Result<(Vec<Value>, HashMap<String, Value>, Vec<Event>), VmError> {
+    let mut outputs = Vec::new();
+    let mut events = Vec::new();
+    let mut store = store_values.clone();
+    execute_into(
+        program,
+        inputs,
+        &mut store,
+        &mut outputs,
+        &mut events,
+        limits,
+        &mut NoTrace,
+    )?;
+    Ok((outputs, store, events))
+}
+
+/// 変更セットモード。store は複製せず読み取り専用で参照し、実行中に書き込んだキーだけを返す。
+/// 戻り値は (出力, 書き込んだキーの変更（最初の書き込み順）, イベント)。
+///
+/// 出力・イベント・エラーは `execute_with_events` と同じ。変更セットを元の Store に当てた結果は
+/// `execute` が返す Store と一致する（`StoreChange::changed` が false のキーは値が変わっていない）。
+#[allow(clippy::type_complexity)]
+pub fn execute_changes<B: StoreBase + ?Sized>(
+    program: &Program,
+    inputs: &HashMap<String, Value>,
+    store: &B,
+    limits: &Limits,
+) -> Result<(Vec<Value>, Vec<StoreChange>, Vec<Event>), VmError> {
+    let mut outputs = Vec::new();
+    let mut events = Vec::new();
+    let mut overlay = Overlay::new(store);
+    execute_into(
+        program,
+        inputs,
+        &mut overlay,
+        &mut outputs,
+        &mut events,
+        limits,
+        &mut NoTrace,
+    )?;
+    Ok((outputs, overlay.into_changes(), events))
+}
+
+/// `execute` をトレース付きで実行する。実行結果（エラーを含む）と、そこまでのトレースを返す。
+///
+/// 実行の意味論・limits は `execute` と同じ。トレースの内容は trace.rs を参照。
+#[allow(clippy::type_complexity)]
+pub fn execute_traced(
+    program: &Program,
+    inputs: &HashMap<String, Value>,
+    store_values: &HashMap<String, Value>,
+    limits: &Limits,
+    options: TraceOptions,
+) -> (Result<(Vec<Value>, HashMap<String, Value>), VmError>, Trace) {
+    let mut outputs = Vec::new();
+    let mut events = Vec::new();
+    let mut store = store_values.clone();
+    let mut tracer = Tracer::new(options);
+    let result = execute_into(
+        program,
+        inputs,
+        &mut store,
+        &mut outputs,
+        &mut events,
+        limits,
+        &mut tracer,
+    );
+    (result.map(|()| (outputs, store)), tracer.finish())
+}
+
+/// 同じプログラムを複数エンティティの入力で実行し、エンティティごとの出力（またはエラー）を返す。
+///
+/// - store_values は全エンティティ共通の初期値。各エンティティは同じ初期 Store から実行され、
+///   WriteStore はそのエンティティの実行中にだけ見える（結果には含まれず、他エンティティにも伝播しない）。
+/// - あるエンティティのエラーは他エンティティの実行を止めない。
+/// - Emit のイベントは結果に含めない（limits.max_events の検査はする）。
+/// - 各エンティティの実行は `execute` と同じ意味論。limits はエンティティごとに適用する。
+pub fn execute_batch(
+    program: &Program,
+    entities: &[HashMap<String, Value>],
+    store_values: &HashMap<String, Value>,
+    limits: &Limits,
+) -> Vec<Result<Vec<Value>, VmError>> {
+    let mut store = store_values.clone();
+    let mut events = Vec::new();
+    entities
+        .iter()
+        .enumerate()
+        .map(|(i, inputs)| {
+            // 書き込みのないプログラムは Store を使い回し、エンティティごとの複製を省く
+            if i > 0 && program.writes_store() {
+                store.clone_from(store_values);
+            }
+            #[cfg(feature = "jit")]
+            if let Some(outputs) = program.run_jit(inputs, limits) {
+                return Ok(outputs);
+            }
+            events.clear();
+            let mut outputs = Vec::new();
+            execute_into(
+                program,
+                inputs,
+                &mut store,
+                &mut outputs,
+                &mut events,
+                limits,
+                &mut NoTrace,
+            )
+            .map(|()| outputs)
+        })
+        .collect()
+}
+
+/// 実行中の関数 1 つ分の状態。トップレベルのプログラムも 1 つのフレーム
+struct Frame<'a> {
+    program: &'a Program,
+    registers: [Option<Value>; REGISTER_COUNT],
+    pc: usize,
+    /// 実行中（エラー時は失敗した）命令のインデックス。終端に達したら命令数
+    at: usize,
+    steps: usize,
+    /// 呼び出された関数の名前と戻り値の扱い（トップレベルは None）
+    call: Option<(&'a str, Return)>,
+}
+
+/// Ret した値の行き先
+enum Return {
+    /// Call: 呼び出し元のレジスタに書く
+    Register(u8),
+    /// ForEach: 次の要素があれば累積値として次の呼び出しに渡し、無ければ dst に書く
+    Fold {
+        dst: u8,
+        list: Arc<[Value]>,
+        next: usize,
+    },
+    /// ListMap: 結果に積み、次の要素があれば呼び出し、無ければリストとして dst に書く
+    Map {
+        dst: u8,
+        list: Arc<[Value]>,
+        next: usize,
+        results: Vec<Value>,
+    },
+}
+
+impl<'a> Frame<'a> {
+    fn new(program: &'a Program, call: Option<(&'a str, Return)>) -> Self {
+        Self {
+            program,
+            registers: [const { None }; REGISTER_COUNT],
+            pc: 0,
+            at: 0,
+            steps: 0,
+            call,
+        }
+    }
+
+    /// 先頭のレジスタから順に引数を置く
+    fn with_args<const N: usize>(mut self, args: [Value; N]) -> Self {
+        for (param, arg) in self.registers.iter_mut().zip(args) {
+            *param = Some(arg);
+        }
+        self
+    }
+
+    fn location(&self) -> Location {
+        let function = self.call.as_ref().map(|(name, _)| *name);
+        self.program.location(self.at, function)
+    }
+}
+
+/// VM 本体。outputs・store・events を呼び出し側のバッファに書き込む。
+///
+/// ループ安全ポリシー: デコード時に後方ジャンプを拒否するため、1 回の呼び出しの中で各命令は高々 1 回しか実行されない。
+/// 念のためフレームごとに実行命令数を命令数で打ち切り、超過時は `VmErrorKind::StepLimitExceeded` を返す。
+/// Call の入れ子は limits.max_call_depth までなので、再帰を含めても実行は必ず停止する。
+/// ForEach / ListMap は要素ごとに関数を呼ぶが、反復は同じ深さのフレームの差し替えで、回数はリスト長で決まる。
+///
+/// フューエル: 命令を 1 つ実行するたびに 1 消費し（呼び出し先の命令も数える）、limits.max_instructions を超えたら
+/// `VmErrorKind::BudgetExceeded(Budget::Instructions, _)`。出力数・Store 件数・呼び出しの深さ・イベント数も同様に検査する。
+///
+/// Store はプログラムに結び付いた `StoreSchema` を通して読み書きする（store.rs）。
+///
+/// エラーには失敗した時点のフレームの命令位置を付ける（Ret なしの終端は命令数 = 終端のオフセット）。
+fn execute_into(
+    program: &Program,
+    inputs: &HashMap<String, Value>,
+    store: &mut impl StoreAccess,
+    outputs: &mut Vec<Value>,
+    events: &mut Vec<Event>,
+    limits: &Limits,
+    observer: &mut impl Observer,
+) -> Result<(), VmError> {
+    let store = &mut Schematized {
+        inner: store,
+        schema: program.store_schema(),
+    };
+    let mut frame = Frame::new(program, None);
+    let io = Io {
+        inputs,
+        outputs,
+        events,
+    };
+    run_frames(program.library(), io, store, limits, observer, &mut frame).map_err(|kind| VmError {
+        kind,
+        location: Some(frame.location()),
+    })
+}
+
+/// 入力と、出力・イベントの書き込み先
+struct Io<'a> {
+    inputs: &'a HashMap<String, Value>,
+    outputs: &'a mut Vec<Value>,
+    events: &'a mut Vec<Event>,
+}
+
+/// `execute_into` の命令ループ。失敗時の frame は失敗した命令を実行していたフレーム
+fn run_frames<'a>(
+    library: Option<&'a Library>,
+    io: Io<'_>,
+    store: &mut impl StoreAccess,
+    limits: &Limits,
+    observer: &mut impl Observer,
+    frame: &mut Frame<'a>,
+) -> Result<(), VmErrorKind> {
+    let Io {
+        inputs,
+        outputs,
+        events,
+    } = io;
+    // 呼び出し元のフレーム。Rust のスタックを使わないため、深さの上限を大きくしてもあふれない
+    let mut callers: Vec<Frame> = Vec::new();
+    let mut fuel = 0usize;
+
+    loop {
+        observer.after(callers.len(), &frame.registers);
+        let instructions = frame.program.instructions();
+        frame.at = frame.pc;
+        let Some(inst) = instructions.get(frame.pc) else {
+            return match frame.call {
+                None => Ok(()),
+                Some((name, _)) => Err(VmErrorKind::MissingReturn(name.to_string())),
+            };
+        };
+        frame.steps += 1;
+        if frame.steps > instructions.len() {
+            return Err(VmErrorKind::StepLimitExceeded(instructions.len()));
+        }
+        fuel += 1;
+        if fuel > limits.max_instructions {
+            return Err(VmErrorKind::BudgetExceeded(
+                Budget::Instructions,
+                limits.max_instructions,
+            ));
+        }
+        let function = frame.call.as_ref().map(|(name, _)| *name);
+        observer.before(callers.len(), function, frame.pc, inst, &frame.registers);
+        frame.pc += 1;
+        let registers = &mut frame.registers;
+        match inst {
+            Instruction::LoadInput { dst, name } => {
+                let value = inputs
+                    .get(&**name)
+                    .ok_or_else(|| VmErrorKind::InputNotFound(name.to_string()))?;
+                list::check_len(value, limits)?;
+                registers[*dst as usize] = Some(value.clone());
+            }
+            Instruction::StoreOutput { src } => {
+                let value = get_register(registers, *src)?;
+                if outputs.len() >= limits.max_outputs {
+                    return Err(VmErrorKind::BudgetExceeded(
+                        Budget::Outputs,
+                        limits.max_outputs,
+                    ));
+                }
+                outputs.push(value);
+            }
+            Instruction::ReadStore { dst, name } => {
+                let value = store
+                    .read(name)?
+                    .ok_or_else(|| VmErrorKind::StoreNotFound(name.to_string()))?;
+                list::check_len(&value, limits)?;
+                registers[*dst as usize] = Some(value);
+            }
+            Instruction::WriteStore { src, name } => {
+                let value = get_register(registers, *src)?;
+                store.write(name, value, limits)?;
+            }
+            Instruction::Emit { src, name } => {
+                let payload = src.map(|r| get_register(registers, r)).transpose()?;
+                if events.len() >= limits.max_events {
+                    return Err(VmErrorKind::BudgetExceeded(
+                        Budget::Events,
+                        limits.max_events,
+                    ));
+                }
+                events.push(Event {
+                    name: name.clone(),
+                    payload,
+                });
+            }
+            Instruction::Jump { target } => {
+                frame.pc = *target;
+            }
+            Instruction::JumpIfFalse { cond, target } => {
+                if !get_condition(registers, *cond)? {
+                    frame.pc = *target;
+                }
+            }
+            Instruction::JumpIfTrue { cond, target } => {
+                if get_condition(registers, *cond)? {
+                    frame.pc = *target;
+                }
+            }
+            Instruction::Call { dst, name, args } => {
+                let function = lookup(library, name, callers.len(), limits)?;
+                let mut callee = Frame::new(function, Some((name, Return::Register(*dst))));
+                for (param, arg) in callee.registers.iter_mut().zip(args) {
+                    *param = Some(get_register(registers, *arg)?);
+                }
+                callers.push(std::mem::replace(frame, callee));
+            }
+            Instruction::ForEach {
+                dst,
+                list,
+                init,
+                name,
+            } => {
+                let function = lookup(library, name, callers.len(), limits)?;
+                let items = get_list(registers, *list, "for_each")?;
+                let acc = get_register(registers, *init)?;
+                let Some(first) = items.first().cloned() else {
+                    registers[*dst as usize] = Some(acc);
+                    continue;
+                };
+                let ret = Return::Fold {
+                    dst: *dst,
+                    list: items,
+                    next: 1,
+                };
+                let callee = Frame::new(function, Some((name, ret)));
+                callers.push(std::mem::replace(frame, callee.with_args([acc, first])));
+            }
+            Instruction::ListMap { dst, list, name } => {
+                let function = lookup(library, name, callers.len(), limits)?;
+                let items = get_list(registers, *list, "list_map")?;
+                let Some(first) = items.first().cloned() else {
+                    registers[*dst as usize] = Some(Value::List(items));
+                    continue;
+                };
+                let ret = Return::Map {
+                    dst: *dst,
+                    results: Vec::with_capacity(items.len()),
+                    list: items,
+                    next: 1,
+                };
+                let callee = Frame::new(function, Some((name, ret)));
+                callers.push(std::mem::replace(frame, callee.with_args([first])));
+            }
+            Instruction::Random { dst } => {
+                let x = next_random(store, limits)?;
+                registers[*dst as usize] = Some(Value::F32(random::unit(x)));
+            }
+            Instruction::RandomRange { dst, lo, hi } => {
+                let lo = get_register(registers, *lo)?;
+                let hi = get_register(registers, *hi)?;
+                let x = next_random(store, limits)?;
+                registers[*dst as usize] = Some(random::range(x, lo, hi)?);
+            }
+            Instruction::Ret { src } => {
+                let value = get_register(registers, *src)?;
+                let Some((name, ret)) = frame.call.take() else {
+                    return Ok(());
+                };
+                let function = frame.program;
+                let (dst, value) = match ret {
+                    Return::Register(dst) => (dst, value),
+                    Return::Fold { dst, list, next } => match list.get(next).cloned() {
+                        Some(item) => {
+                            let ret = Return::Fold {
+                                dst,
+                                list,
+                                next: next + 1,
+                            };
+                            *frame =
+                                Frame::new(function, Some((name, ret))).with_args([value, item]);
+                            continue;
+                        }
+                        None => (dst, value),
+                    },
+                    Return::Map {
+                        dst,
+                        list,
+                        next,
+                        mut results,
+                    } => {
+                        results.push(value);
+                        match list.get(next).cloned() {
+                            Some(item) => {
+                                let ret = Return::Map {
+                                    dst,
+                                    list,
+                                    next: next + 1,
+                                    results,
+                                };
+                                *frame = Frame::new(function, Some((name, ret))).with_args([item]);
+                                continue;
+                            }
+                            None => (dst, Value::List(results.into())),
+                        }
+                    }
+                };
+                *frame = callers.pop().expect("a callee frame always has a caller");
+                // ListMap の結果の長さの超過は、呼び出し元の ListMap 命令の位置で報告する
+                list::check_len(&value, limits)?;
+                frame.registers[dst as usize] = Some(value);
+            }
+            _ => {
+                if let Some((dst, value)) = eval_pure(inst, registers)? {
+                    registers[dst as usize] = Some(value);
+                }
+            }
+        }
+    }
+}
+
+/// 副作用のない命令（定数ロード・演算・ベクトル・リスト）を評価し、(dst, 結果) を返す。
+/// 入力・出力・Store・イベント・ジャンプ・呼び出し・乱数は Ok(None)（`execute_into` が扱う）。
+///
+/// 最適化の定数畳み込み（optimize.rs）も同じ関数で評価するため、飽和演算・誤差付き比較・
+/// ゼロ除算などの意味論は実行時と一致する。
+pub(super) fn eval_pure(
+    inst: &Instruction,
+    registers: &[Option<Value>],
+) -> Result<Option<(u8, Value)>, VmErrorKind> {
+    let result = match inst {
+        Instruction::LoadI32 { dst, value } => (*dst, Value::I32(*value)),
+        Instruction::LoadF32 { dst, value } => (*dst, Value::F32(*value)),
+        Instruction::LoadBool { dst, value } => (*dst, Value::Bool(*value)),
+        Instruction::LoadI64 { dst, value } => (*dst, Value::I64(*value)),
+        Instruction::LoadF64 { dst, value } => (*dst, Value::F64(*value)),
+        Instruction::Add { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = binary_add(a, b).ok_or_else(|| VmErrorKind::TypeMismatch("add".into()))?;
+            (*dst, result)
+        }
+        Instruction::Sub { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = binary_sub(a, b).ok_or_else(|| VmErrorKind::TypeMismatch("sub".into()))?;
+            (*dst, result)
+        }
+        Instruction::Mul { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = binary_mul(a, b).ok_or_else(|| VmErrorKind::TypeMismatch("mul".into()))?;
+            (*dst, result)
+        }
+        Instruction::Div { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = binary_div(a, b)?;
+            (*dst, result)
+        }
+        Instruction::Lt { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = compare_lt(a, b).ok_or_else(|| VmErrorKind::TypeMismatch("lt".into()))?;
+            (*dst, result)
+        }
+        Instruction::Gt { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = compare_gt(a, b).ok_or_else(|| VmErrorKind::TypeMismatch("gt".into()))?;
+            (*dst, result)
+        }
+        Instruction::Eq { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = compare_eq(a, b);
+            (*dst, result)
+        }
+        Instruction::Abs { dst, src } => (*dst, math::abs(get_register(registers, *src)?)?),
+        Instruction::Floor { dst, src } => (*dst, math::floor(get_register(registers, *src)?)?),
+        Instruction::Ceil { dst, src } => (*dst, math::ceil(get_register(registers, *src)?)?),
+        Instruction::Sqrt { dst, src } => (*dst, math::sqrt(get_register(registers, *src)?)?),
+        Instruction::Sin { dst, src } => (*dst, math::sin(get_register(registers, *src)?)?),
+        Instruction::Cos { dst, src } => (*dst, math::cos(get_register(registers, *src)?)?),
+        Instruction::CastI32 { dst, src } => {
+            let v = get_register(registers, *src)?;
+            (*dst, math::cast(NumKind::I32, v)?)
+        }
+        Instruction::CastF32 { dst, src } => {
+            let v = get_register(registers, *src)?;
+            (*dst, math::cast(NumKind::F32, v)?)
+        }
+        Instruction::CastI64 { dst, src } => {
+            let v = get_register(registers, *src)?;
+            (*dst, math::cast(NumKind::I64, v)?)
+        }
+        Instruction::CastF64 { dst, src } => {
+            let v = get_register(registers, *src)?;
+            (*dst, math::cast(NumKind::F64, v)?)
+        }
+        Instruction::Min { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            (*dst, math::min(a, b)?)
+        }
+        Instruction::Max { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            (*dst, math::max(a, b)?)
+        }
+        Instruction::Atan2 { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            (*dst, math::atan2(a, b)?)
+        }
+        Instruction::Pow { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            (*dst, math::pow(a, b)?)
+        }
+        Instruction::Mod { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            (*dst, math::modulo(a, b)?)
+        }
+        Instruction::Clamp { dst, src, lo, hi } => {
+            let x = get_register(registers, *src)?;
+            let lo = get_register(registers, *lo)?;
+            let hi = get_register(registers, *hi)?;
+            (*dst, math::clamp(x, lo, hi)?)
+        }
+        Instruction::Lerp { dst, a, b, t } => {
+            let a = get_register(registers, *a)?;
+            let b = get_register(registers, *b)?;
+            let t = get_register(registers, *t)?;
+            (*dst, math::lerp(a, b, t)?)
+        }
+        Instruction::Not { dst, src } => {
+            let a = get_bool(registers, *src, "not")?;
+            (*dst, Value::Bool(!a))
+        }
+        Instruction::And { dst, src_a, src_b } => {
+            let a = get_bool(registers, *src_a, "and")?;
+            let b = get_bool(registers, *src_b, "and")?;
+            (*dst, Value::Bool(a && b))
+        }
+        Instruction::Or { dst, src_a, src_b } => {
+            let a = get_bool(registers, *src_a, "or")?;
+            let b = get_bool(registers, *src_b, "or")?;
+            (*dst, Value::Bool(a || b))
+        }
+        Instruction::Xor { dst, src_a, src_b } => {
+            let a = get_bool(registers, *src_a, "xor")?;
+            let b = get_bool(registers, *src_b, "xor")?;
+            (*dst, Value::Bool(a ^ b))
+        }
+        Instruction::Le { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = compare_le(a, b).ok_or_else(|| VmErrorKind::TypeMismatch("le".into()))?;
+            (*dst, result)
+        }
+        Instruction::Ge { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            let result = compare_ge(a, b).ok_or_else(|| VmErrorKind::TypeMismatch("ge".into()))?;
+            (*dst, result)
+        }
+        Instruction::Ne { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            (*dst, compare_ne(a, b))
+        }
+        Instruction::Select { dst, cond, a, b } => {
+            // 分岐なし選択: 両オペランドは初期化済みであることを要求する
+            let c = get_bool(registers, *cond, "select")?;
+            let a = get_register(registers, *a)?;
+            let b = get_register(registers, *b)?;
+            (*dst, if c { a } else { b })
+        }
+        Instruction::LoadVec { dst, value } => (*dst, value.clone()),
+        Instruction::MakeVec { dst, n, srcs } => {
+            let mut components = [0.0f32; 3];
+            for (c, src) in components.iter_mut().zip(&srcs[..*n as usize]) {
+                *c = get_register(registers, *src)?
+                    .as_f32()
+                    .ok_or_else(|| VmErrorKind::TypeMismatch("make_vec".into()))?;
+            }
+            let value = vector::from_components(&components[..*n as usize])
+                .ok_or_else(|| VmErrorKind::TypeMismatch("make_vec".into()))?;
+            (*dst, value)
+        }
+        Instruction::Extract { dst, src, index } => {
+            let v = get_register(registers, *src)?;
+            (*dst, vector::extract(v, *index)?)
+        }
+        Instruction::Dot { dst, src_a, src_b } => {
+            let a = get_register(registers, *src_a)?;
+            let b = get_register(registers, *src_b)?;
+            (*dst, vector::dot(a, b)?)
+        }
+        Instruction::Length { dst, src } => (*dst, vector::length(get_register(registers, *src)?)?),
+        Instruction::Normalize { dst, src } => {
+            let v = get_register(registers, *src)?;
+            (*dst, vector::normalize(v)?)
+        }
+        Instruction::ListLen { dst, src } => (*dst, list::len(get_register(registers, *src)?)?),
+        Instruction::ListIndex { dst, list, index } => {
+            let l = get_register(registers, *list)?;
+            let i = get_register(registers, *index)?;
+            (*dst, list::index(l, i)?)
+        }
+        Instruction::ListSum { dst, src } => (*dst, list::sum(get_register(registers, *src)?)?),
+        Instruction::ListMin { dst, src } => (*dst, list::min(get_register(registers, *src)?)?),
+        Instruction::ListMax { dst, src } => (*dst, list::max(get_register(registers, *src)?)?),
+        Instruction::LoadInput { .. }
+        | Instruction::StoreOutput { .. }
+        | Instruction::ReadStore { .. }
+        | Instruction::WriteStore { .. }
+        | Instruction::Emit { .. }
+        | Instruction::Jump { .. }
+        | Instruction::JumpIfFalse { .. }
+        | Instruction::JumpIfTrue { .. }
+        | Instruction::Call { .. }
+        | Instruction::ForEach { .. }
+        | Instruction::ListMap { .. }
+        | Instruction::Ret { .. }
+        | Instruction::Random { .. }
+        | Instruction::RandomRange { .. } => return Ok(None),
+    };
+    Ok(Some(result))
+}
+
+/// 関数をライブラリから引き、呼び出しの深さを検査する（Call・ForEach・ListMap 共通）
+fn lookup<'a>(
+    library: Option<&'a Library>,
+    name: &str,
+    depth: usize,
+    limits: &Limits,
+) -> Result<&'a Program, VmErrorKind> {
+    let function = library
+        .and_then(|l| l.get(name))
+        .ok_or_else(|| VmErrorKind::UndefinedFunction(name.to_string()))?;
+    if depth >= limits.max_call_depth {
+        return Err(VmErrorKind::BudgetExceeded(
+            Budget::CallDepth,
+            limits.max_call_depth,
+        ));
+    }
+    Ok(function)
+}
+
+fn get_list(registers: &[Option<Value>], r: u8, op: &str) -> Result<Arc<[Value]>, VmErrorKind> {
+    match get_register(registers, r)? {
+        Value::List(items) => Ok(items),
+        _ => Err(VmErrorKind::TypeMismatch(op.into())),
+    }
+}
+
+/// Store の乱数状態（`random::STATE_KEY`）を 1 つ進め、32 bit の乱数を返す
+fn next_random(store: &mut impl StoreAccess, limits: &Limits) -> Result<u32, VmErrorKind> {
+    let bits = match store.read(random::STATE_KEY)? {
+        Some(Value::I32(bits)) => bits,
+        Some(_) => return Err(VmErrorKind::TypeMismatch("random".into())),
+        None => return Err(VmErrorKind::StoreNotFound(random::STATE_KEY.to_string())),
+    };
+    let mut state = bits as u32;
+    let x = random::next_u32(&mut state);
+    // 既存キーの上書きなので件数上限には掛からない（スキーマの既定値を読んだ場合は新規キーになる）
+    store.write(random::STATE_KEY, Value::I32(state as i32), limits)?;
+    Ok(x)
+}
+
+fn get_register(registers: &[Option<Value>], r: u8) -> Result<Value, VmErrorKind> {
+    if r >= REGISTER_COUNT as u8 {
+        return Err(VmErrorKind::RegisterOutOfRange(r));
+    }
+    registers[r as usize]
+        .clone()
+        .ok_or_else(|| VmErrorKind::TypeMismatch(format!("register r{} uninitialized", r)))
+}
+
+/// レジスタを `Value::as_bool` の強制変換で真偽値として読む
+fn get_bool(registers: &[Option<Value>], r: u8, op: &str) -> Result<bool, VmErrorKind> {
+    get_register(registers, r)?
+        .as_bool()
+        .ok_or_else(|| VmErrorKind::TypeMismatch(op.into()))
+}
+
+fn get_condition(registers: &[Option<Value>], r: u8) -> Result<bool, VmErrorKind> {
+    get_bool(registers, r, "jump condition")
+}
+
+pub(super) fn binary_add(a: Value, b: Value) -> Option<Value> {
+    // ベクトルを含む場合は成分ごと（スカラーはブロードキャスト）
+    if let Some(result) = vector::componentwise("add", &a, &b, |x, y| x + y) {
+        return result.ok();
+    }
+    arithmetic(
+        &a,
+        &b,
+        i32::saturating_add,
+        i64::saturating_add,
+        |x, y| x + y,
+        |x, y| x + y,
+    )
+}
+
+fn binary_sub(a: Value, b: Value) -> Option<Value> {
+    if let Some(result) = vector::componentwise("sub", &a, &b, |x, y| x - y) {
+        return result.ok();
+    }
+    arithmetic(
+        &a,
+        &b,
+        i32::saturating_sub,
+        i64::saturating_sub,
+        |x, y| x - y,
+        |x, y| x - y,
+    )
+}
+
+fn binary_mul(a: Value, b: Value) -> Option<Value> {
+    if let Some(result) = vector::componentwise("mul", &a, &b, |x, y| x * y) {
+        return result.ok();
+    }
+    arithmetic(
+        &a,
+        &b,
+        i32::saturating_mul,
+        i64::saturating_mul,
+        |x, y| x * y,
+        |x, y| x * y,
+    )
+}
+
+/// スカラーの四則演算。結果の型は `value::promote`（I32 / I64 は飽和演算）
+fn arithmetic(
+    a: &Value,
+    b: &Value,
+    int32: fn(i32, i32) -> i32,
+    int64: fn(i64, i64) -> i64,
+    float32: fn(f32, f32) -> f32,
+    float64: fn(f64, f64) -> f64,
+) -> Option<Value> {
+    Some(match promote(&[a, b])? {
+        NumKind::I32 => Value::I32(int32(a.as_i32()?, b.as_i32()?)),
+        NumKind::I64 => Value::I64(int64(a.as_i64()?, b.as_i64()?)),
+        NumKind::F32 => Value::F32(float32(a.as_f32()?, b.as_f32()?)),
+        NumKind::F64 => Value::F64(float64(a.as_f64()?, b.as_f64()?)),
+    })
+}
+
+fn binary_div(a: Value, b: Value) -> Result<Value, VmErrorKind> {
+    // 型は加減乗と揃える。as_i32() は F32 も truncate して Some を返すため、型を先に判定する。
+    if let Some(result) = vector::componentwise_div(&a, &b) {
+        return result;
+    }
+    let kind = promote(&[&a, &b]).ok_or_else(|| VmErrorKind::TypeMismatch("div".into()))?;
+    let mismatch = || VmErrorKind::TypeMismatch("div".into());
+    match kind {
+        NumKind::I32 | NumKind::I64 => {
+            let (va, vb) = (
+                a.as_i64().ok_or_else(mismatch)?,
+                b.as_i64().ok_or_else(mismatch)?,
+            );
+            if vb == 0 {
+                return Err(VmErrorKind::DivisionByZero);
+            }
+            // checked_div: MIN / -1 のオーバーフローを封じる（saturating 方針）
+            Ok(if kind == NumKind::I32 {
+                Value::I32((va as i32).checked_div(vb as i32).unwrap_or(i32::MAX))
+            } else {
+                Value::I64(va.checked_div(vb).unwrap_or(i64::MAX))
+            })
+        }
+        NumKind::F32 | NumKind::F64 => {
+            let (fa, fb) = (
+                a.as_f64().ok_or_else(mismatch)?,
+                b.as_f64().ok_or_else(mismatch)?,
+            );
+            if fb == 0.0 {
+                return Err(VmErrorKind::DivisionByZero);
+            }
+            Ok(if kind == NumKind::F32 {
+                Value::F32(fa as f32 / fb as f32)
+            } else {
+                Value::F64(fa / fb)
+            })
+        }
+    }
+}
+
+/// 数値の大小比較。整数同士は i64、それ以外は昇格した浮動小数点の精度で比べる（NaN を含めば偽）
+fn compare_with(a: Value, b: Value, accept: fn(Ordering) -> bool) -> Option<Value> {
+    let ordering = match promote(&[&a, &b])? {
+        NumKind::I32 | NumKind::I64 => a.as_i64()?.partial_cmp(&b.as_i64()?),
+        NumKind::F32 => a.as_f32()?.partial_cmp(&b.as_f32()?),
+        NumKind::F64 => a.as_f64()?.partial_cmp(&b.as_f64()?),
+    };
+    Some(Value::Bool(ordering.is_some_and(accept)))
+}
+
+fn compare_lt(a: Value, b: Value) -> Option<Value> {
+    compare_with(a, b, Ordering::is_lt)
+}
+
+fn compare_gt(a: Value, b: Value) -> Option<Value> {
+    compare_with(a, b, Ordering::is_gt)
+}
+
+fn compare_le(a: Value, b: Value) -> Option<Value> {
+    compare_with(a, b, Ordering::is_le)
+}
+
+fn compare_ge(a: Value, b: Value) -> Option<Value> {
+    compare_with(a, b, Ordering::is_ge)
+}
+
+/// compare_eq の否定（同じ誤差許容）
+fn compare_ne(a: Value, b: Value) -> Value {
+    match compare_eq(a, b) {
+        Value::Bool(eq) => Value::Bool(!eq),
+        other => other,
+    }
+}
+
+fn components_eq(a: &[f32], b: &[f32]) -> bool {
+    a.iter().zip(b).all(|(x, y)| (x - y).abs() < f32::EPSILON)
+}
+
+fn compare_eq(a: Value, b: Value) -> Value {
+    // F32 比較は絶対誤差 f32::EPSILON（F64 は f64::EPSILON）を使用。ゲーム用途で値が小さい場合は許容。
+    // 大きい値での比較には相対誤差の検討が必要。整数同士は厳密に比較する。
+    let result = match (&a, &b) {
+        (Value::Bool(x), Value::Bool(y)) => x == y,
+        (Value::I32(x), Value::I32(y)) => x == y,
+        (Value::F32(x), Value::F32(y)) => (*x - *y).abs() < f32::EPSILON,
+        (Value::Vec2(x), Value::Vec2(y)) => components_eq(x, y),
+        (Value::Vec3(x), Value::Vec3(y)) => components_eq(x, y),
+        _ => match promote(&[&a, &b]) {
+            Some(NumKind::I32 | NumKind::I64) => a.as_i64() == b.as_i64(),
+            Some(NumKind::F32) => match (a.as_f32(), b.as_f32()) {
+                (Some(fa), Some(fb)) => (fa - fb).abs() < f32::EPSILON,
+                _ => false,
+            },
+            Some(NumKind::F64) => match (a.as_f64(), b.as_f64()) {
+                (Some(fa), Some(fb)) => (fa - fb).abs() < f64::EPSILON,
+                _ => false,
+            },
+            None => false,
+        },
+    };
+    Value::Bool(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source_map::{Origin, SourceMap};
+    use std::collections::HashMap;
+
+    fn load_i32(dst: u8, value: i32) -> Vec<u8> {
+        let mut buf = vec![1u8, dst]; // OpCode::LoadI32
+        buf.extend_from_slice(&value.to_le_bytes());
+        buf
+    }
+
+    fn load_f32(dst: u8, value: f32) -> Vec<u8> {
+        let mut buf = vec![2u8, dst]; // OpCode::LoadF32
+        buf.extend_from_slice(&value.to_le_bytes());
+        buf
+    }
+
+    fn div(dst: u8, src_a: u8, src_b: u8) -> Vec<u8> {
+        vec![7u8, dst, src_a, src_b] // OpCode::Div
+    }
+
+    fn store_output(src: u8) -> Vec<u8> {
+        vec![11u8, src] // OpCode::StoreOutput
+    }
+
+    fn load_bool(dst: u8, value: bool) -> Vec<u8> {
+        vec![3u8, dst, value as u8] // OpCode::LoadBool
+    }
+
+    fn jump(target: u32) -> Vec<u8> {
+        let mut buf = vec![14u8]; // OpCode::Jump
+        buf.extend_from_slice(&target.to_le_bytes());
+        buf
+    }
+
+    fn jump_if_false(cond: u8, target: u32) -> Vec<u8> {
+        let mut buf = vec![15u8, cond]; // OpCode::JumpIfFalse
+        buf.extend_from_slice(&target.to_le_bytes());
+        buf
+    }
+
+    fn jump_if_true(cond: u8, target: u32) -> Vec<u8> {
+        let mut buf = vec![16u8, cond]; // OpCode::JumpIfTrue
+        buf.extend_from_slice(&target.to_le_bytes());
+        buf
+    }
+
+    /// if cond { out 1 } else { out 2 } を組み立てる
+    fn branch_program(cond: bool, jump_bytes: fn(u8, u32) -> Vec<u8>) -> Vec<u8> {
+        // 0: load_bool(3) / 3: jump_if_*(6) / 9: load_i32(6) / 15: jump(5) / 20: load_i32(6) / 26: store_output(2)
+        let mut bc = Vec::new();
+        bc.extend(load_bool(0, cond));
+        bc.extend(jump_bytes(0, 20));
+        bc.extend(load_i32(1, 1));
+        bc.extend(jump(26));
+        bc.extend(load_i32(1, 2));
+        bc.extend(store_output(1));
+        bc
+    }
+
+    fn run_empty(bytecode: &[u8]) -> Result<Vec<Value>, VmErrorKind> {
+        let (outputs, _) = run(
+            bytecode,
+            &HashMap::new(),
+            &HashMap::new(),
+            &Limits::default(),
+        )
+        .map_err(|e| e.kind)?;
+        Ok(outputs)
+    }
+
+    #[test]
+    fn div_f32_returns_float() {
+        // 5.0 / 2.0 → F32(2.5)（整数除算に化けないこと）
+        let mut bc = Vec::new();
+        bc.extend(load_f32(0, 5.0));
+        bc.extend(load_f32(1, 2.0));
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+
+        let outputs = run_empty(&bc).expect("run");
+        assert_eq!(outputs.len(), 1);
+        match &outputs[0] {
+            Value::F32(v) => assert!((v - 2.5).abs() < f32::EPSILON, "got {}", v),
+            other => panic!("expected F32(2.5), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn div_i32_keeps_integer() {
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, 7));
+        bc.extend(load_i32(1, 2));
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+
+        let outputs = run_empty(&bc).expect("run");
+        assert!(matches!(outputs.as_slice(), [Value::I32(3)]));
+    }
+
+    #[test]
+    fn div_mixed_promotes_to_f32() {
+        // I32 / F32 → F32
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, 5));
+        bc.extend(load_f32(1, 2.0));
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+
+        let outputs = run_empty(&bc).expect("run");
+        match &outputs[0] {
+            Value::F32(v) => assert!((v - 2.5).abs() < f32::EPSILON, "got {}", v),
+            other => panic!("expected F32(2.5), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn div_i32_min_by_neg_one_does_not_panic() {
+        // i32::MIN / -1 → saturating で i32::MAX（パニックしない）
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, i32::MIN));
+        bc.extend(load_i32(1, -1));
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+
+        let outputs = run_empty(&bc).expect("run must not panic");
+        assert!(matches!(outputs.as_slice(), [Value::I32(i32::MAX)]));
+    }
+
+    #[test]
+    fn div_by_zero_i32_errors() {
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, 1));
+        bc.extend(load_i32(1, 0));
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+
+        assert!(matches!(run_empty(&bc), Err(VmErrorKind::DivisionByZero)));
+    }
+
+    #[test]
+    fn div_by_zero_f32_errors() {
+        let mut bc = Vec::new();
+        bc.extend(load_f32(0, 1.0));
+        bc.extend(load_f32(1, 0.0));
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+
+        assert!(matches!(run_empty(&bc), Err(VmErrorKind::DivisionByZero)));
+    }
+
+    #[test]
+    fn jump_if_false_selects_branch() {
+        let taken = run_empty(&branch_program(false, jump_if_false)).expect("run");
+        assert!(matches!(taken.as_slice(), [Value::I32(2)]));
+        let fallthrough = run_empty(&branch_program(true, jump_if_false)).expect("run");
+        assert!(matches!(fallthrough.as_slice(), [Value::I32(1)]));
+    }
+
+    #[test]
+    fn jump_if_true_selects_branch() {
+        let taken = run_empty(&branch_program(true, jump_if_true)).expect("run");
+        assert!(matches!(taken.as_slice(), [Value::I32(2)]));
+        let fallthrough = run_empty(&branch_program(false, jump_if_true)).expect("run");
+        assert!(matches!(fallthrough.as_slice(), [Value::I32(1)]));
+    }
+
+    #[test]
+    fn jump_to_end_of_bytecode_terminates() {
+        // 8: jump(13) → 13 は終端（バイトコード長）。後続命令は存在しない
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, 7));
+        bc.extend(store_output(0));
+        bc.extend(jump(13));
+        let outputs = run_empty(&bc).expect("run");
+        assert!(matches!(outputs.as_slice(), [Value::I32(7)]));
+    }
+
+    #[test]
+    fn backward_jump_is_rejected_at_decode() {
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, 1));
+        bc.extend(jump(0));
+        assert!(matches!(
+            run_empty(&bc),
+            Err(VmErrorKind::Decode(DecodeErrorKind::BackwardJump(0)))
+        ));
+    }
+
+    #[test]
+    fn self_jump_is_rejected_at_decode() {
+        assert!(matches!(
+            run_empty(&jump(0)),
+            Err(VmErrorKind::Decode(DecodeErrorKind::BackwardJump(0)))
+        ));
+    }
+
+    #[test]
+    fn jump_out_of_bounds_is_rejected_at_decode() {
+        assert!(matches!(
+            run_empty(&jump(100)),
+            Err(VmErrorKind::Decode(DecodeErrorKind::JumpOutOfBounds(100)))
+        ));
+    }
+
+    #[test]
+    fn jump_into_operand_is_rejected_at_decode() {
+        // 5: load_i32 のオペランド途中（7）を指す
+        let mut bc = Vec::new();
+        bc.extend(jump(7));
+        bc.extend(load_i32(0, 1));
+        assert!(matches!(
+            run_empty(&bc),
+            Err(VmErrorKind::Decode(DecodeErrorKind::JumpNotOnBoundary(7)))
+        ));
+    }
+
+    #[test]
+    fn jump_condition_uninitialized_errors() {
+        let mut bc = Vec::new();
+        bc.extend(jump_if_true(5, 6));
+        assert!(matches!(run_empty(&bc), Err(VmErrorKind::TypeMismatch(_))));
+    }
+
+    #[test]
+    fn math_opcodes_run_through_vm() {
+        // clamp(sqrt(16.0), 0, 3) → F32(3.0)
+        let mut bc = Vec::new();
+        bc.extend(load_f32(0, 16.0));
+        bc.extend(vec![20u8, 1, 0]); // OpCode::Sqrt
+        bc.extend(load_i32(2, 0));
+        bc.extend(load_i32(3, 3));
+        bc.extend(vec![28u8, 4, 1, 2, 3]); // OpCode::Clamp
+        bc.extend(store_output(4));
+
+        let outputs = run_empty(&bc).expect("run");
+        match &outputs[0] {
+            Value::F32(v) => assert!((v - 3.0).abs() < f32::EPSILON, "got {}", v),
+            other => panic!("expected F32(3.0), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mod_by_zero_errors_through_vm() {
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, 5));
+        bc.extend(load_i32(1, 0));
+        bc.extend(vec![27u8, 2, 0, 1]); // OpCode::Mod
+        assert!(matches!(run_empty(&bc), Err(VmErrorKind::DivisionByZero)));
+    }
+
+    fn binary(op: u8, dst: u8, src_a: u8, src_b: u8) -> Vec<u8> {
+        vec![op, dst, src_a, src_b]
+    }
+
+    fn run_single_output(bc: &[u8]) -> Value {
+        let outputs = run_empty(bc).expect("run");
+        assert_eq!(outputs.len(), 1);
+        outputs[0].clone()
+    }
+
+    #[test]
+    fn logical_ops_coerce_like_as_bool() {
+        // I32(2) は真、F32(0.0) は偽
+        for (op, expected) in [(31u8, false), (32, true), (33, true)] {
+            let mut bc = Vec::new();
+            bc.extend(load_i32(0, 2));
+            bc.extend(load_f32(1, 0.0));
+            bc.extend(binary(op, 2, 0, 1));
+            bc.extend(store_output(2));
+            assert!(
+                matches!(run_single_output(&bc), Value::Bool(v) if v == expected),
+                "op {}",
+                op
+            );
+        }
+
+        let mut bc = Vec::new();
+        bc.extend(load_i32(0, 0));
+        bc.extend(vec![30u8, 1, 0]); // OpCode::Not
+        bc.extend(store_output(1));
+        assert!(matches!(run_single_output(&bc), Value::Bool(true)));
+    }
+
+    #[test]
+    fn le_ge_ne_compare() {
+        // (op, a, b, expected)
+        let cases = [
+            (34u8, 1.0, 1.0, true),
+            (34, 2.0, 1.0, false),
+            (35, 1.0, 1.0, true),
+            (35, 1.0, 2.0, false),
+            (36, 1.0, 1.0, false),
+            (36, 1.0, 2.0, true),
+        ];
+        for (op, a, b, expected) in cases {
+            let mut bc = Vec::new();
+            bc.extend(load_f32(0, a));
+            bc.extend(load_f32(1, b));
+            bc.extend(binary(op, 2, 0, 1));
+            bc.extend(store_output(2));
+            assert!(
+                matches!(run_single_output(&bc), Value::Bool(v) if v == expected),
+                "op {} {} {}",
+                op,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn ne_uses_eq_epsilon() {
+        let mut bc = Vec::new();
+        bc.extend(load_f32(0, 1.0));
+        bc.extend(load_f32(1, 1.0 + f32::EPSILON / 2.0));
+        bc.extend(binary(36, 2, 0, 1));
+        bc.extend(store_output(2));
+        assert!(matches!(run_single_output(&bc), Value::Bool(false)));
+    }
+
+    #[test]
+    fn select_picks_without_branching() {
+        for (cond, expected) in [(true, 10), (false, 20)] {
+            let mut bc = Vec::new();
+            bc.extend(load_bool(0, cond));
+            bc.extend(load_i32(1, 10));
+            bc.extend(load_i32(2, 20));
+            bc.extend(vec![37u8, 3, 0, 1, 2]); // OpCode::Select
+            bc.extend(store_output(3));
+            assert!(matches!(run_single_output(&bc), Value::I32(v) if v == expected));
+        }
+    }
+
+    fn load_vec2(dst: u8, x: f32, y: f32) -> Vec<u8> {
+        let mut buf = vec![38u8, dst, 2]; // OpCode::LoadVec
+        buf.extend_from_slice(&x.to_le_bytes());
+        buf.extend_from_slice(&y.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn vector_movement_in_one_program() {
+        // pos + vel * dt → Vec2、x 成分も取り出す
+        let mut bc = Vec::new();
+        bc.extend(load_vec2(0, 1.0, 2.0));
+        bc.extend(load_f32(1, 2.0));
+        bc.extend(load_f32(2, -1.0));
+        bc.extend(vec![39u8, 3, 2, 1, 2]); // OpCode::MakeVec n=2
+        bc.extend(load_f32(4, 0.5));
+        bc.extend(binary(6, 5, 3, 4)); // Mul: vel * dt
+        bc.extend(binary(4, 6, 0, 5)); // Add: pos + ...
+        bc.extend(store_output(6));
+        bc.extend(vec![40u8, 7, 6, 0]); // OpCode::Extract index 0
+        bc.extend(store_output(7));
+
+        let outputs = run_empty(&bc).expect("run");
+        match outputs.as_slice() {
+            [Value::Vec2([x, y]), Value::F32(ex)] => {
+                assert!((x - 2.0).abs() < f32::EPSILON);
+                assert!((y - 1.5).abs() < f32::EPSILON);
+                assert!((ex - 2.0).abs() < f32::EPSILON);
+            }
+            other => panic!("unexpected outputs {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_vec_rejects_invalid_size() {
+        let bc = vec![38u8, 0, 4];
+        assert!(matches!(
+            run_empty(&bc),
+            Err(VmErrorKind::Decode(DecodeErrorKind::InvalidVectorSize(4)))
+        ));
+    }
+
+    #[test]
+    fn vector_compare_lt_is_type_mismatch() {
+        let mut bc = Vec::new();
+        bc.extend(load_vec2(0, 1.0, 2.0));
+        bc.extend(load_vec2(1, 1.0, 2.0));
+        bc.extend(binary(10, 2, 0, 1)); // Eq
+        bc.extend(store_output(2));
+        assert!(matches!(run_single_output(&bc), Value::Bool(true)));
+
+        bc.extend(binary(8, 3, 0, 1)); // Lt
+        assert!(matches!(run_empty(&bc), Err(VmErrorKind::TypeMismatch(_))));
+    }
+
+    #[test]
+    fn compiled_program_runs_repeatedly() {
+        // dt * 2 を入力だけ変えて繰り返し実行する
+        let mut bc = vec![0u8, 0, 2, b'd', b't']; // OpCode::LoadInput
+        bc.extend(load_i32(1, 2));
+        bc.extend(binary(6, 2, 0, 1)); // Mul
+        bc.extend(store_output(2));
+        let program = Program::compile(&bc).expect("compile");
+
+        for i in 0..3 {
+            let inputs = HashMap::from([("dt".to_string(), Value::I32(i))]);
+            let (outputs, _) =
+                execute(&program, &inputs, &HashMap::new(), &Limits::default()).expect("execute");
+            assert!(matches!(outputs.as_slice(), [Value::I32(v)] if *v == i * 2));
+        }
+    }
+
+    #[test]
+    fn batch_reports_errors_per_entity() {
+        // hp / divisor
+        let mut bc = vec![0u8, 0, 2, b'h', b'p']; // OpCode::LoadInput
+        bc.extend(vec![0u8, 1, 3, b'd', b'i', b'v']);
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+        let program = Program::compile(&bc).expect("compile");
+
+        let entity = |hp: i32, div: i32| {
+            HashMap::from([
+                ("hp".to_string(), Value::I32(hp)),
+                ("div".to_string(), Value::I32(div)),
+            ])
+        };
+        let entities = vec![
+            entity(10, 2),
+            entity(10, 0),
+            HashMap::from([("hp".to_string(), Value::I32(1))]),
+            entity(9, 3),
+        ];
+        let results = execute_batch(&program, &entities, &HashMap::new(), &Limits::default());
+
+        assert_eq!(results.len(), 4);
+        assert!(matches!(results[0].as_deref(), Ok([Value::I32(5)])));
+        assert!(matches!(
+            results[1],
+            Err(VmError {
+                kind: VmErrorKind::DivisionByZero,
+                ..
+            })
+        ));
+        assert!(
+            matches!(&results[2], Err(VmError { kind: VmErrorKind::InputNotFound(name), .. }) if name == "div")
+        );
+        assert!(matches!(results[3].as_deref(), Ok([Value::I32(3)])));
+    }
+
+    #[test]
+    fn batch_store_writes_do_not_leak_between_entities() {
+        // score = score + x; output score
+        let mut bc = vec![12u8, 0, 5, b's', b'c', b'o', b'r', b'e']; // OpCode::ReadStore
+        bc.extend(vec![0u8, 1, 1, b'x']);
+        bc.extend(binary(4, 2, 0, 1)); // Add
+        bc.extend(vec![13u8, 2, 5, b's', b'c', b'o', b'r', b'e']); // OpCode::WriteStore
+        bc.extend(store_output(2));
+        let program = Program::compile(&bc).expect("compile");
+
+        let store = HashMap::from([("score".to_string(), Value::I32(100))]);
+        let entities: Vec<_> = [1, 2, 3]
+            .iter()
+            .map(|x| HashMap::from([("x".to_string(), Value::I32(*x))]))
+            .collect();
+        let results = execute_batch(&program, &entities, &store, &Limits::default());
+
+        let got: Vec<i32> = results
+            .iter()
+            .map(|r| match r.as_deref() {
+                Ok([Value::I32(v)]) => *v,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(got, vec![101, 102, 103]);
+    }
+
+    #[test]
+    fn instruction_budget_is_enforced() {
+        // 3 命令のプログラムに 2 命令分のフューエル
+        let mut bc = load_i32(0, 1);
+        bc.extend(load_i32(1, 2));
+        bc.extend(store_output(0));
+        let limits = Limits {
+            max_instructions: 2,
+            ..Limits::default()
+        };
+        let result = run(&bc, &HashMap::new(), &HashMap::new(), &limits);
+        assert!(matches!(
+            result,
+            Err(VmError {
+                kind: VmErrorKind::BudgetExceeded(Budget::Instructions, 2),
+                ..
+            })
+        ));
+
+        let limits = Limits {
+            max_instructions: 3,
+            ..Limits::default()
+        };
+        assert!(run(&bc, &HashMap::new(), &HashMap::new(), &limits).is_ok());
+    }
+
+    #[test]
+    fn output_and_store_budgets_are_enforced() {
+        let mut bc = load_i32(0, 1);
+        bc.extend(store_output(0));
+        bc.extend(store_output(0));
+        let limits = Limits {
+            max_outputs: 1,
+            ..Limits::default()
+        };
+        let result = run(&bc, &HashMap::new(), &HashMap::new(), &limits);
+        assert!(matches!(
+            result,
+            Err(VmError {
+                kind: VmErrorKind::BudgetExceeded(Budget::Outputs, 1),
+                ..
+            })
+        ));
+
+        // 既存キーの上書きは上限に数えない。新規キーの追加で超過する
+        let mut bc = load_i32(0, 1);
+        bc.extend(vec![13u8, 0, 1, b'a']); // OpCode::WriteStore
+        bc.extend(vec![13u8, 0, 1, b'b']);
+        let store = HashMap::from([("a".to_string(), Value::I32(0))]);
+        let limits = Limits {
+            max_store_entries: 1,
+            ..Limits::default()
+        };
+        let result = run(&bc, &HashMap::new(), &store, &limits);
+        assert!(matches!(
+            result,
+            Err(VmError {
+                kind: VmErrorKind::BudgetExceeded(Budget::StoreEntries, 1),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn emit_collects_events_in_order() {
+        let mut bc = load_i32(0, 3);
+        bc.extend(vec![49u8, 0, 4]); // OpCode::Emit（payload r0）
+        bc.extend(b"died");
+        bc.extend(vec![49u8, 0xFF, 8]); // ペイロードなし
+        bc.extend(b"level_up");
+        bc.extend(store_output(0));
+        let program = Program::compile(&bc).expect("compile");
+        let (outputs, _, events) = execute_with_events(
+            &program,
+            &HashMap::new(),
+            &HashMap::new(),
+            &Limits::default(),
+        )
+        .expect("run");
+        assert!(matches!(outputs.as_slice(), [Value::I32(3)]));
+        assert_eq!(events.len(), 2);
+        assert_eq!(&*events[0].name, "died");
+        assert!(matches!(events[0].payload, Some(Value::I32(3))));
+        assert_eq!(&*events[1].name, "level_up");
+        assert!(events[1].payload.is_none());
+
+        // 従来の execute は同じ結果でイベントだけを捨てる
+        assert!(execute(
+            &program,
+            &HashMap::new(),
+            &HashMap::new(),
+            &Limits::default()
+        )
+        .is_ok());
+
+        let limits = Limits {
+            max_events: 1,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            execute_with_events(&program, &HashMap::new(), &HashMap::new(), &limits),
+            Err(VmError {
+                kind: VmErrorKind::BudgetExceeded(Budget::Events, 1),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn emit_payload_must_be_initialized() {
+        let bc = vec![49u8, 5, 1, b'e'];
+        assert!(matches!(
+            run(&bc, &HashMap::new(), &HashMap::new(), &Limits::default()),
+            Err(VmError {
+                kind: VmErrorKind::TypeMismatch(_),
+                ..
+            })
+        ));
+        assert!(matches!(
+            Program::compile(&[49u8, 64, 1, b'e']),
+            Err(DecodeError {
+                kind: DecodeErrorKind::RegisterOutOfRange(64),
+                ..
+            })
+        ));
+    }
+
+    fn load_i64(dst: u8, value: i64) -> Vec<u8> {
+        let mut buf = vec![57u8, dst]; // OpCode::LoadI64
+        buf.extend_from_slice(&value.to_le_bytes());
+        buf
+    }
+
+    fn load_f64(dst: u8, value: f64) -> Vec<u8> {
+        let mut buf = vec![58u8, dst]; // OpCode::LoadF64
+        buf.extend_from_slice(&value.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn i64_arithmetic_is_exact_and_saturating() {
+        // 2^40 + 1 は f32 では表せない
+        let mut bc = Vec::new();
+        bc.extend(load_i64(0, 1 << 40));
+        bc.extend(load_i32(1, 1));
+        bc.extend(binary(4, 2, 0, 1)); // OpCode::Add
+        bc.extend(store_output(2));
+        assert!(matches!(run_single_output(&bc), Value::I64(v) if v == (1 << 40) + 1));
+
+        let mut bc = Vec::new();
+        bc.extend(load_i64(0, i64::MAX));
+        bc.extend(binary(6, 1, 0, 0)); // OpCode::Mul
+        bc.extend(store_output(1));
+        assert!(matches!(run_single_output(&bc), Value::I64(i64::MAX)));
+
+        let mut bc = Vec::new();
+        bc.extend(load_i64(0, i64::MIN));
+        bc.extend(load_i32(1, -1));
+        bc.extend(div(2, 0, 1));
+        bc.extend(store_output(2));
+        assert!(matches!(run_single_output(&bc), Value::I64(i64::MAX)));
+    }
+
+    #[test]
+    fn f64_keeps_double_precision() {
+        // 0.1 + 0.2 は f64 で計算する（F32 のオペランドも F64 に昇格）
+        let mut bc = Vec::new();
+        bc.extend(load_f64(0, 0.1));
+        bc.extend(load_f64(1, 0.2));
+        bc.extend(binary(4, 2, 0, 1)); // OpCode::Add
+        bc.extend(load_f32(3, 0.5));
+        bc.extend(binary(4, 4, 2, 3)); // OpCode::Add
+        bc.extend(store_output(2));
+        bc.extend(store_output(4));
+        let outputs = run_empty(&bc).expect("run");
+        assert!(matches!(outputs[0], Value::F64(v) if v == 0.1 + 0.2));
+        assert!(matches!(outputs[1], Value::F64(v) if v == 0.1 + 0.2 + 0.5));
+    }
+
+    #[test]
+    fn mixed_width_comparisons() {
+        // I64 と I32 は整数のまま、I64 と F32 は f64 で比べる
+        for (op, expected) in [(8u8, false), (9, true), (10, false)] {
+            let mut bc = Vec::new();
+            bc.extend(load_i64(0, (1 << 40) + 1));
+            bc.extend(load_f32(1, (1u64 << 40) as f32));
+            bc.extend(binary(op, 2, 0, 1));
+            bc.extend(store_output(2));
+            assert!(
+                matches!(run_single_output(&bc), Value::Bool(v) if v == expected),
+                "op {}",
+                op
+            );
+        }
+    }
+
+    #[test]
+    fn cast_opcodes_convert_between_widths() {
+        let mut bc = Vec::new();
+        bc.extend(load_f64(0, 3.9));
+        bc.extend(vec![59u8, 1, 0]); // OpCode::CastI32
+        bc.extend(vec![61u8, 2, 1]); // OpCode::CastI64
+        bc.extend(vec![60u8, 3, 0]); // OpCode::CastF32
+        bc.extend(vec![62u8, 4, 1]); // OpCode::CastF64
+        for r in 1..=4 {
+            bc.extend(store_output(r));
+        }
+        let outputs = run_empty(&bc).expect("run");
+        assert!(matches!(
+            outputs.as_slice(),
+            [Value::I32(3), Value::I64(3), Value::F32(x), Value::F64(y)] if *x == 3.9f32 && *y == 3.0
+        ));
+
+        let mut bc = Vec::new();
+        bc.extend(load_i64(0, i64::MAX));
+        bc.extend(vec![59u8, 1, 0]); // OpCode::CastI32
+        assert!(matches!(run_empty(&bc), Err(VmErrorKind::DomainError(op)) if op == "cast_i32"));
+    }
+
+    fn run_err(program: &Program) -> VmError {
+        execute(
+            program,
+            &HashMap::new(),
+            &HashMap::new(),
+            &Limits::default(),
+        )
+        .expect_err("program fails")
+    }
+
+    fn assembled(source: &str) -> Program {
+        Program::compile(&crate::asm::assemble(source).expect("assemble")).expect("compile")
+    }
+
+    #[test]
+    fn errors_carry_instruction_index_and_offset() {
+        // 0: load_i32(6) / 6: load_i32(6) / 12: div
+        let program = assembled("load_i32 r0 1\nload_i32 r1 0\ndiv r2 r0 r1");
+        let err = run_err(&program);
+        assert!(matches!(err.kind, VmErrorKind::DivisionByZero));
+        assert_eq!(
+            err.location,
+            Some(Location {
+                function: None,
+                pc: 2,
+                offset: 12,
+                origin: None
+            })
+        );
+
+        // デコードエラーは失敗した命令の位置。ジャンプ先の誤りはジャンプ命令の位置
+        let mut bc = load_i32(0, 1);
+        bc.extend(jump(0));
+        let err = run(&bc, &HashMap::new(), &HashMap::new(), &Limits::default()).unwrap_err();
+        assert!(matches!(
+            err.kind,
+            VmErrorKind::Decode(DecodeErrorKind::BackwardJump(0))
+        ));
+        assert!(matches!(
+            err.location,
+            Some(Location {
+                pc: 1,
+                offset: 6,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn errors_inside_functions_point_at_the_callee() {
+        let function =
+            crate::asm::assemble("load_i32 r1 0\ndiv r2 r0 r1\nret r2").expect("assemble");
+        let library = Library::compile([("half", function.as_slice())]).expect("library");
+        let program = assembled("load_i32 r0 4\ncall r1 \"half\" r0\nstore_output r1")
+            .link(Arc::new(library));
+        let err = run_err(&program);
+        assert!(matches!(err.kind, VmErrorKind::DivisionByZero));
+        assert_eq!(
+            err.location,
+            Some(Location {
+                function: Some("half".into()),
+                pc: 1,
+                offset: 6,
+                origin: None
+            })
+        );
+
+        // Ret なしの終端は命令数（終端のオフセット）
+        let function = crate::asm::assemble("load_i32 r0 1").expect("assemble");
+        let library = Library::compile([("none", function.as_slice())]).expect("library");
+        let program = assembled("call r0 \"none\"").link(Arc::new(library));
+        let err = run_err(&program);
+        assert!(matches!(err.kind, VmErrorKind::MissingReturn(_)));
+        assert!(matches!(
+            err.location,
+            Some(Location {
+                pc: 1,
+                offset: 6,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn source_map_names_the_origin_and_survives_optimization() {
+        // 0: load_i32(6) a / 6: load_i32(6) b / 12: load_input(4) c / 16: div c
+        let program = assembled("load_i32 r0 1\nload_i32 r1 0\nload_input r2 \"x\"\ndiv r3 r2 r1")
+            .with_source_map(Arc::new(SourceMap::new([
+                (0, Origin::Node("a".into())),
+                (12, Origin::Node("c".into())),
+                (6, Origin::Span { line: 2, column: 1 }),
+            ])));
+        let inputs = HashMap::from([("x".to_string(), Value::I32(1))]);
+        for program in [program.clone(), program.optimize()] {
+            let err = execute(&program, &inputs, &HashMap::new(), &Limits::default()).unwrap_err();
+            assert!(matches!(err.kind, VmErrorKind::DivisionByZero));
+            let location = err.location.expect("location");
+            assert_eq!(location.offset, 16);
+            assert_eq!(location.origin, Some(Origin::Node("c".into())));
+        }
+        // 定数ロードを除いた後も、オフセットは元のバイトコード上の位置を指す
+        assert_eq!(
+            run_err(&program.optimize()).location.map(|l| l.offset),
+            Some(12)
+        );
+    }
+}
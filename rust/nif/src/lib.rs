@@ -1,9 +1,8 @@
-//! Rustler NIF クレート — **Formula VM のみ**（`run_formula_bytecode/3`）。
+//! Rustler NIF クレート — **Formula VM のみ**（NIF の一覧は `Core.NifBridge` を参照）。
 //!
 //! ゲーム ECS・物理・protobuf インジェクション等はフェーズ 4 で削除した。
 //! 復旧が必要な場合は Git 履歴の `native/nif/src/physics`（移行前パス）等を参照する。
 
-mod formula;
 mod nif;
 
 pub use nif::load;
@@ -1,16 +1,38 @@
-//! Path: native/nif/src/nif/formula_nif.rs
-//! Summary: run_formula_bytecode NIF — バイトコードを実行し結果を返す
+//! Path: rust/nif/src/nif/formula_nif.rs
+//! Summary: Formula NIF — バイトコードの実行（run_formula_bytecode）と
+//! コンパイル済みプログラムの生成・実行（compile_formula / run_compiled）、バッチ実行（run_formula_batch）、
+//! テキストアセンブリとの相互変換（disassemble_formula / assemble_formula）、コンテナ形式への変換（pack_formula）、
+//! 実行前の静的検証（verify_formula）、最適化（optimize_formula）、式言語のコンパイル（compile_formula_source）、
+//! 関数ライブラリの登録とプログラムへの結び付け（compile_formula_library / link_formula）、
+//! イベント付きの実行（run_formula_events）、Store の変更セットを返す実行（run_formula_changes）、
+//! トレース付きの実行（run_formula_traced）、Store スキーマの結び付け（bind_store_schema）、
+//! エラー位置の生成元を引くソースマップの結び付け（bind_source_map）、
+//! 計算グラフの読み込みと差分評価（load_formula_graph / evaluate_formula_graph / formula_graph_recomputed）
 //!
 //! ドメインエラー（input_not_found, division_by_zero 等）は NIF としては成功とし、
-//! Ok({:error, reason_atom, detail}) を返す。他 NIF の NifResult::Err は NIF 層の異常用。
+//! Ok({:error, reason_atom, detail, location}) を返す。location は失敗した命令の位置（`location_to_term`）。
+//! 入力の整数範囲・Store スキーマの誤りなど、命令に関係しないエラーは {:error, reason_atom, detail}。
+//! 他 NIF の NifResult::Err は NIF 層の異常用。
+//!
+//! 実行系 NIF は最後の引数に実行上限（limits）を取る（run_formula_bytecode は limits を省いた /3 も残す）。長いプログラム・大きなバッチ向けに
+//! DirtyCpu スケジューラで動く `*_dirty` 版を用意している（引数・戻り値は同じ）。
 
-use crate::formula::{run, Value, VmError};
+use formula::{
+    assemble, compile_source, disassemble, execute, execute_batch, execute_changes, execute_traced,
+    execute_with_events, is_container, run, verify_bytecode, AsmError, DiagnosticKind, EncodeError,
+    Event, Graph, GraphError, Library, Limits, Location, NodeKind, Op, Origin, Program, Schema,
+    SourceError, SourceMap, StoreBase, StoreChange, StoreSchema, TraceOptions, TraceStep, TypeSet,
+    Value, ValueType, VmError, VmErrorKind,
+};
 use rustler::types::map::MapIterator;
-use rustler::{Encoder, Env, NifResult, Term};
+use rustler::types::tuple::get_tuple;
+use rustler::{Encoder, Env, NifResult, ResourceArc, Term};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 enum InputDecodeError {
-    IntegerOutOfRange(i64),
+    /// i64 に収まらない整数（detail は 10 進表記）
+    IntegerOutOfRange(String),
     ExpectedMap,
     InvalidKey,
     InvalidValue,
@@ -19,10 +41,31 @@ enum InputDecodeError {
 /// バイトコードと入力マップ・Store 初期値を受け取り、出力と更新後の Store を返す。
 ///
 /// - bytecode: バイナリ形式のバイトコード
-/// - inputs: %{"name" => value} 形式のマップ。value は integer | float | boolean
+/// - inputs: %{"name" => value} 形式のマップ。value は integer | float | boolean | vector | list
+///   （vector は {x, y} / {x, y, z} のタプル、または %{x: _, y: _} / %{x: _, y: _, z: _} のマップ。
+///   list は integer | float | boolean | vector を要素に持つリストで、入れ子にはできない）。
+///   integer は i32 に収まらなければ i64、float は f32 に丸める（倍精度は {:f64, x}）
 /// - store_values: Store の初期値。%{"key" => value}。READ_STORE で参照するキーは事前に含めること。
+/// - limits: %{max_instructions: n, max_store_entries: n, max_outputs: n, max_call_depth: n, max_events: n,
+///   max_list_len: n}。省略したキーは既定値
 ///
-/// 戻り値: {:ok, {outputs, updated_store}} | {:error, reason_atom, detail}
+/// 戻り値: {:ok, {outputs, updated_store}} | {:error, reason_atom, detail, location}
+/// ベクトル値は float のタプル {x, y} / {x, y, z}、リスト値はリストで返す。
+/// 上限超過は {:error, :budget_exceeded, {kind, limit}, location}
+/// （kind は :instructions | :store_entries | :outputs | :call_depth | :events | :list_len）。
+/// Emit のイベントは捨てる（受け取る場合は run_formula_events）。
+#[rustler::nif(name = "run_formula_bytecode")]
+pub fn run_formula_bytecode_with_limits<'a>(
+    env: Env<'a>,
+    bytecode: rustler::Binary<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_bytecode_impl(env, bytecode, inputs, store_values, decode_limits(limits)?)
+}
+
+/// 実行上限を既定値にした run_formula_bytecode/4（上限を導入する前からの互換用）
 #[rustler::nif]
 pub fn run_formula_bytecode<'a>(
     env: Env<'a>,
@@ -30,29 +73,1334 @@ pub fn run_formula_bytecode<'a>(
     inputs: Term<'a>,
     store_values: Term<'a>,
 ) -> NifResult<Term<'a>> {
+    run_bytecode_impl(env, bytecode, inputs, store_values, Limits::default())
+}
+
+/// run_formula_bytecode/4 の DirtyCpu 版
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn run_formula_bytecode_dirty<'a>(
+    env: Env<'a>,
+    bytecode: rustler::Binary<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_bytecode_impl(env, bytecode, inputs, store_values, decode_limits(limits)?)
+}
+
+fn run_bytecode_impl<'a>(
+    env: Env<'a>,
+    bytecode: rustler::Binary<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Limits,
+) -> NifResult<Term<'a>> {
+    let (input_map, store_map) = match decode_run_args(env, inputs, store_values)? {
+        Ok(args) => args,
+        Err(err_term) => return Ok(err_term),
+    };
+    encode_run_result(
+        env,
+        run(bytecode.as_slice(), &input_map, &store_map, &limits),
+    )
+}
+
+/// デコード・検証済みプログラムを保持する NIF リソース。不変なので複数プロセスから共有してよい。
+pub struct FormulaProgram {
+    program: Program,
+}
+
+impl rustler::Resource for FormulaProgram {}
+
+/// バイトコードを一度だけデコード・検証し、プログラムハンドルを返す。
+///
+/// 戻り値: {:ok, program_ref} | {:error, reason_atom, detail, location}（デコードエラーは run_formula_bytecode と同じ）
+#[rustler::nif]
+pub fn compile_formula<'a>(env: Env<'a>, bytecode: rustler::Binary<'a>) -> NifResult<Term<'a>> {
+    match Program::compile(bytecode.as_slice()) {
+        Ok(program) => {
+            let resource = ResourceArc::new(FormulaProgram { program });
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, resource).encode(env))
+        }
+        Err(e) => error_to_term(env, e.into()),
+    }
+}
+
+/// Call で呼び出す関数ライブラリを保持する NIF リソース。不変なので複数のプログラム・プロセスから共有してよい。
+pub struct FormulaLibrary {
+    library: Arc<Library>,
+}
+
+impl rustler::Resource for FormulaLibrary {}
+
+/// 関数名 => バイトコードのマップから関数ライブラリを作る。関数は引数を r0.. で受け取り、Ret で値を返す。
+///
+/// 戻り値: {:ok, library_ref} | {:error, :invalid_function, {name, {reason_atom, detail, location}}}（関数のデコードエラー）
+#[rustler::nif]
+pub fn compile_formula_library<'a>(env: Env<'a>, functions: Term<'a>) -> NifResult<Term<'a>> {
+    let iter = MapIterator::new(functions)
+        .ok_or_else(|| rustler::Error::Term(Box::new("functions: expected map")))?;
+    let mut sources: Vec<(String, rustler::Binary)> = Vec::new();
+    for (key, value) in iter {
+        let name: String = key
+            .decode()
+            .map_err(|_| rustler::Error::Term(Box::new("function name: expected string")))?;
+        let bytecode: rustler::Binary = value
+            .decode()
+            .map_err(|_| rustler::Error::Term(Box::new("function bytecode: expected binary")))?;
+        sources.push((name, bytecode));
+    }
+    let library = Library::compile(sources.iter().map(|(n, b)| (n.as_str(), b.as_slice())));
+    match library {
+        Ok(library) => {
+            let resource = ResourceArc::new(FormulaLibrary {
+                library: Arc::new(library),
+            });
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, resource).encode(env))
+        }
+        Err((name, e)) => {
+            let e = VmError::from(e);
+            let location = location_to_term(env, e.location.as_ref())?;
+            let (reason, detail) = error_reason(env, e.kind)?;
+            let err_atom = rustler::Atom::from_str(env, "error")?;
+            let invalid = rustler::Atom::from_str(env, "invalid_function")?;
+            Ok((err_atom, invalid, (name, (reason, detail, location))).encode(env))
+        }
+    }
+}
+
+/// バイトコードをデコード・検証し、関数ライブラリを結び付けたプログラムハンドルを返す（compile_formula/1 と同じく
+/// run_compiled / run_formula_batch で実行する）。関数名は実行時に解決し、無ければ {:error, :undefined_function, name, location}。
+///
+/// 戻り値: {:ok, program_ref} | {:error, reason_atom, detail, location}
+#[rustler::nif]
+pub fn link_formula<'a>(
+    env: Env<'a>,
+    bytecode: rustler::Binary<'a>,
+    library: ResourceArc<FormulaLibrary>,
+) -> NifResult<Term<'a>> {
+    match Program::compile(bytecode.as_slice()) {
+        Ok(program) => {
+            let program = program.link(library.library.clone());
+            let resource = ResourceArc::new(FormulaProgram { program });
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, resource).encode(env))
+        }
+        Err(e) => error_to_term(env, e.into()),
+    }
+}
+
+/// プログラムに Store スキーマ（キーごとの型と既定値）を結び付けたハンドルを返す。
+/// ライブラリを結び付けたハンドルを渡せばライブラリも引き継ぐ。既にスキーマがあれば置き換える。
+///
+/// - program: バイトコード（binary）または compile_formula/1・link_formula/2 のハンドル
+/// - schema: %{"key" => {type, default}}。type は :i32 | :f32 | :i64 | :f64 | :bool | :vec2 | :vec3 | :list、
+///   default は store_values と同じ形式の値
+///
+/// 実行時、宣言したキーが Store に無ければ既定値を読む（既定値は書き込まれるまで Store に現れない）。
+/// 宣言と異なる型の値の読み書きは {:error, :store_type_mismatch, {key, expected, actual}, location}。
+///
+/// 戻り値: {:ok, program_ref} | {:error, :invalid_store_schema, key} | {:error, reason_atom, detail, location}
+/// 既定値が型に合わないキーは :invalid_store_schema。
+#[rustler::nif]
+pub fn bind_store_schema<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    schema: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let program = match decode_program(env, program)? {
+        Ok(program) => program,
+        Err(error) => return Ok(error),
+    };
+    let schema = match decode_store_schema(schema)? {
+        Ok(schema) => schema,
+        Err(key) => {
+            let err_atom = rustler::Atom::from_str(env, "error")?;
+            let reason = rustler::Atom::from_str(env, "invalid_store_schema")?;
+            return Ok((err_atom, reason, key).encode(env));
+        }
+    };
+    let program = program.get().with_store_schema(Arc::new(schema));
+    let resource = ResourceArc::new(FormulaProgram { program });
+    let ok_atom = rustler::Atom::from_str(env, "ok")?;
+    Ok((ok_atom, resource).encode(env))
+}
+
+/// %{"key" => {type, default}} をデコードする。既定値が解釈できない・型に合わないキーは Ok(Err(key))
+fn decode_store_schema(term: Term) -> NifResult<Result<StoreSchema, String>> {
+    let iter = MapIterator::new(term)
+        .ok_or_else(|| rustler::Error::Term(Box::new("store schema: expected map")))?;
+    let mut fields = Vec::new();
+    for (key_term, field) in iter {
+        let key = term_to_string(key_term)?;
+        let (ty, default) = match get_tuple(field).as_deref() {
+            Ok([ty, default]) => (*ty, *default),
+            _ => {
+                return Err(rustler::Error::Term(Box::new(
+                    "store schema field: expected {type, default}",
+                )))
+            }
+        };
+        let ty = ty
+            .atom_to_string()
+            .ok()
+            .and_then(|t| ValueType::from_name(&t))
+            .ok_or_else(|| {
+                rustler::Error::Term(Box::new(
+                    "store schema type: expected :i32, :f32, :i64, :f64, :bool, :vec2, :vec3 or :list",
+                ))
+            })?;
+        match term_to_value(default) {
+            Ok(default) => fields.push((key, ty, default)),
+            Err(_) => return Ok(Err(key)),
+        }
+    }
+    Ok(StoreSchema::new(fields))
+}
+
+/// プログラムにソースマップ（バイトオフセット => 生成元）を結び付けたハンドルを返す。
+/// ライブラリ・Store スキーマは引き継ぐ。既にソースマップがあれば置き換える。
+///
+/// - program: バイトコード（binary）または compile_formula/1 等のハンドル
+/// - entries: [{offset, origin}]。offset はコード部分の先頭からのバイトオフセット（エラーの location.offset と同じ）、
+///   origin は FormulaGraph のノード ID（atom）か {line, column}
+///
+/// 実行・デコードのエラーの location.origin に、offset 以前で最も近いエントリの origin が入る。
+///
+/// 戻り値: {:ok, program_ref} | {:error, reason_atom, detail, location}
+#[rustler::nif]
+pub fn bind_source_map<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    entries: Vec<(usize, Term<'a>)>,
+) -> NifResult<Term<'a>> {
+    let program = match decode_program(env, program)? {
+        Ok(program) => program,
+        Err(error) => return Ok(error),
+    };
+    let entries = entries
+        .into_iter()
+        .map(|(offset, origin)| Ok((offset, decode_origin(origin)?)))
+        .collect::<NifResult<Vec<_>>>()?;
+    let program = program
+        .get()
+        .with_source_map(Arc::new(SourceMap::new(entries)));
+    let resource = ResourceArc::new(FormulaProgram { program });
+    let ok_atom = rustler::Atom::from_str(env, "ok")?;
+    Ok((ok_atom, resource).encode(env))
+}
+
+/// ノード ID（atom）か {line, column}
+fn decode_origin(term: Term) -> NifResult<Origin> {
+    if let Ok(id) = term.atom_to_string() {
+        return Ok(Origin::Node(id));
+    }
+    term.decode::<(u32, u32)>()
+        .map(|(line, column)| Origin::Span { line, column })
+        .map_err(|_| {
+            rustler::Error::Term(Box::new(
+                "source map origin: expected node id atom or {line, column}",
+            ))
+        })
+}
+
+/// compile_formula/1 で得たプログラムを実行する。inputs・store_values・limits・戻り値は run_formula_bytecode と同じ。
+#[rustler::nif]
+pub fn run_compiled<'a>(
+    env: Env<'a>,
+    program: ResourceArc<FormulaProgram>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_compiled_impl(env, program, inputs, store_values, limits)
+}
+
+/// run_compiled/4 の DirtyCpu 版
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn run_compiled_dirty<'a>(
+    env: Env<'a>,
+    program: ResourceArc<FormulaProgram>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_compiled_impl(env, program, inputs, store_values, limits)
+}
+
+fn run_compiled_impl<'a>(
+    env: Env<'a>,
+    program: ResourceArc<FormulaProgram>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let limits = decode_limits(limits)?;
+    let (input_map, store_map) = match decode_run_args(env, inputs, store_values)? {
+        Ok(args) => args,
+        Err(err_term) => return Ok(err_term),
+    };
+    encode_run_result(
+        env,
+        execute(&program.program, &input_map, &store_map, &limits),
+    )
+}
+
+/// バイトコードを人が読めるテキストアセンブリにする（ログ・デバッグ用）。
+///
+/// 戻り値: {:ok, text} | {:error, reason_atom, detail, location}（デコードエラーは run_formula_bytecode と同じ）
+#[rustler::nif]
+pub fn disassemble_formula<'a>(env: Env<'a>, bytecode: rustler::Binary<'a>) -> NifResult<Term<'a>> {
+    match disassemble(bytecode.as_slice()) {
+        Ok(text) => {
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, text).encode(env))
+        }
+        Err(e) => error_to_term(env, e.into()),
+    }
+}
+
+/// バイトコード（従来形式・コンテナのどちらでも）をチェックサム付きのコンテナ形式にする。
+/// キャッシュ済みの従来形式プログラムをバージョン付きの形式へ移行する用途。
+///
+/// 戻り値: {:ok, container_bytecode} | {:error, reason_atom, detail, location}（デコードエラー）|
+/// {:error, reason_atom, detail}（エンコードエラー）
+#[rustler::nif]
+pub fn pack_formula<'a>(env: Env<'a>, bytecode: rustler::Binary<'a>) -> NifResult<Term<'a>> {
+    let program = match Program::compile(bytecode.as_slice()) {
+        Ok(program) => program,
+        Err(e) => return error_to_term(env, e.into()),
+    };
+    match program.to_container() {
+        Ok(packed) => {
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, bytes_to_binary(env, &packed)?).encode(env))
+        }
+        Err(e) => encode_error_to_term(env, e),
+    }
+}
+
+/// 定数畳み込み・不要命令の除去・レジスタの詰め直しをしたバイトコードを返す。
+/// 出力・Store への書き込み・実行時エラーは元のバイトコードと同じ（実行命令数だけが減る）。
+/// 入力がコンテナ形式ならコンテナ形式、従来形式なら従来形式で返す。
+///
+/// 戻り値: {:ok, optimized_bytecode} | {:error, reason_atom, detail, location}（デコードエラー）|
+/// {:error, reason_atom, detail}（エンコードエラー）
+#[rustler::nif]
+pub fn optimize_formula<'a>(env: Env<'a>, bytecode: rustler::Binary<'a>) -> NifResult<Term<'a>> {
+    let program = match Program::compile(bytecode.as_slice()) {
+        Ok(program) => program.optimize(),
+        Err(e) => return error_to_term(env, e.into()),
+    };
+    let encoded = if is_container(bytecode.as_slice()) {
+        program.to_container()
+    } else {
+        program.to_legacy()
+    };
+    match encoded {
+        Ok(optimized) => {
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, bytes_to_binary(env, &optimized)?).encode(env))
+        }
+        Err(e) => encode_error_to_term(env, e),
+    }
+}
+
+/// テキストアセンブリをバイトコードにする。disassemble_formula/1 の出力を入力するとバイト単位で元に戻る。
+///
+/// 戻り値: {:ok, bytecode} | {:error, :syntax_error, {line, message}} | {:error, reason_atom, detail}（エンコードエラー）|
+/// {:error, reason_atom, detail, location}（デコードエラー）
+#[rustler::nif]
+pub fn assemble_formula<'a>(env: Env<'a>, source: &str) -> NifResult<Term<'a>> {
+    match assemble(source) {
+        Ok(bytecode) => {
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, bytes_to_binary(env, &bytecode)?).encode(env))
+        }
+        Err(AsmError::Syntax { line, message }) => {
+            let err_atom = rustler::Atom::from_str(env, "error")?;
+            let reason = rustler::Atom::from_str(env, "syntax_error")?;
+            Ok((err_atom, reason, (line, message)).encode(env))
+        }
+        Err(AsmError::Encode(e)) => encode_error_to_term(env, e),
+        Err(AsmError::Decode(e)) => error_to_term(env, e.into()),
+    }
+}
+
+/// 式言語のソース（例: `output hp - damage * (1 - armor)`）をコンテナ形式のバイトコードにする。
+/// 文法は formula/source.rs を参照。生成したバイトコードは最適化済み。
+///
+/// 戻り値: {:ok, bytecode} | {:error, :parse_error, {line, column, message}} | {:error, reason_atom, detail}
+#[rustler::nif]
+pub fn compile_formula_source<'a>(env: Env<'a>, source: &str) -> NifResult<Term<'a>> {
+    match compile_source(source) {
+        Ok(bytecode) => {
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, bytes_to_binary(env, &bytecode)?).encode(env))
+        }
+        Err(SourceError::Parse {
+            line,
+            column,
+            message,
+        }) => {
+            let err_atom = rustler::Atom::from_str(env, "error")?;
+            let reason = rustler::Atom::from_str(env, "parse_error")?;
+            Ok((err_atom, reason, (line, column, message)).encode(env))
+        }
+        Err(SourceError::Encode(e)) => encode_error_to_term(env, e),
+    }
+}
+
+/// バイトコードを実行せずに検証する（未初期化レジスタの読み出し・型の不整合・未宣言の名前）。
+///
+/// - schema: %{inputs: %{"name" => type}, store: %{"key" => type}}。type は :i32 | :f32 | :i64 | :f64 | :bool | :vec2 | :vec3。
+///   省略したセクションは宣言なしとして扱い、その名前の型は検査しない。
+///
+/// 戻り値: :ok | {:error, :verification_failed, [diagnostic]} | {:error, reason_atom, detail, location}（デコードエラー）
+/// diagnostic は %{pc: 命令インデックス, offset: バイトオフセット, kind: atom, detail: term}。
+#[rustler::nif]
+pub fn verify_formula<'a>(
+    env: Env<'a>,
+    bytecode: rustler::Binary<'a>,
+    schema: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let schema = decode_schema(schema)?;
+    let diagnostics = match verify_bytecode(bytecode.as_slice(), &schema) {
+        Ok(diagnostics) => diagnostics,
+        Err(e) => return error_to_term(env, e.into()),
+    };
+    if diagnostics.is_empty() {
+        return Ok(rustler::Atom::from_str(env, "ok")?.encode(env));
+    }
+    let keys = ["pc", "offset", "kind", "detail"]
+        .iter()
+        .map(|k| rustler::Atom::from_str(env, k).map(|a| a.encode(env)))
+        .collect::<NifResult<Vec<_>>>()?;
+    let mut terms = Vec::with_capacity(diagnostics.len());
+    for d in &diagnostics {
+        let values = [
+            d.pc.encode(env),
+            d.offset.encode(env),
+            rustler::Atom::from_str(env, d.kind.as_str())?.encode(env),
+            diagnostic_detail(env, &d.kind)?,
+        ];
+        terms.push(
+            Term::map_from_term_arrays(env, &keys, &values)
+                .map_err(|_| rustler::Error::Term(Box::new("failed to build diagnostic map")))?,
+        );
+    }
+    let err_atom = rustler::Atom::from_str(env, "error")?;
+    let reason = rustler::Atom::from_str(env, "verification_failed")?;
+    Ok((err_atom, reason, terms).encode(env))
+}
+
+fn decode_schema(term: Term) -> NifResult<Schema> {
+    let iter = MapIterator::new(term)
+        .ok_or_else(|| rustler::Error::Term(Box::new("schema: expected map")))?;
+    let mut schema = Schema::default();
+    for (key_term, section) in iter {
+        let key = key_term
+            .atom_to_string()
+            .map_err(|_| rustler::Error::Term(Box::new("schema key: expected atom")))?;
+        let types = Some(decode_type_map(section)?);
+        match key.as_str() {
+            "inputs" => schema.inputs = types,
+            "store" => schema.store = types,
+            _ => {
+                return Err(rustler::Error::Term(Box::new(format!(
+                    "schema: unknown key {}",
+                    key
+                ))))
+            }
+        }
+    }
+    Ok(schema)
+}
+
+fn decode_type_map(term: Term) -> NifResult<HashMap<String, ValueType>> {
+    let iter = MapIterator::new(term)
+        .ok_or_else(|| rustler::Error::Term(Box::new("schema section: expected map")))?;
+    let mut types = HashMap::new();
+    for (name_term, type_term) in iter {
+        let name = term_to_string(name_term)?;
+        let ty = type_term
+            .atom_to_string()
+            .ok()
+            .and_then(|t| ValueType::from_name(&t))
+            .ok_or_else(|| {
+                rustler::Error::Term(Box::new(
+                    "schema type: expected :i32, :f32, :i64, :f64, :bool, :vec2 or :vec3",
+                ))
+            })?;
+        types.insert(name, ty);
+    }
+    Ok(types)
+}
+
+fn type_set_to_term<'a>(env: Env<'a>, types: TypeSet) -> NifResult<Term<'a>> {
+    let atoms = types
+        .iter()
+        .map(|t| rustler::Atom::from_str(env, t.as_str()))
+        .collect::<NifResult<Vec<_>>>()?;
+    Ok(atoms.encode(env))
+}
+
+/// 診断の detail。レジスタ番号、名前、または {op, [operand_types]} / {key, expected, found}
+fn diagnostic_detail<'a>(env: Env<'a>, kind: &DiagnosticKind) -> NifResult<Term<'a>> {
+    Ok(match kind {
+        DiagnosticKind::UninitializedRegister(r)
+        | DiagnosticKind::MaybeUninitializedRegister(r) => r.encode(env),
+        DiagnosticKind::TypeMismatch { op, operands } => {
+            let op = rustler::Atom::from_str(env, op.mnemonic())?;
+            let operands = operands
+                .iter()
+                .map(|t| type_set_to_term(env, *t))
+                .collect::<NifResult<Vec<_>>>()?;
+            (op, operands).encode(env)
+        }
+        DiagnosticKind::UndeclaredInput(name) | DiagnosticKind::UndeclaredStoreKey(name) => {
+            name.encode(env)
+        }
+        DiagnosticKind::StoreTypeMismatch {
+            key,
+            expected,
+            found,
+        } => {
+            let expected = rustler::Atom::from_str(env, expected.as_str())?;
+            (&**key, expected, type_set_to_term(env, *found)?).encode(env)
+        }
+    })
+}
+
+fn bytes_to_binary<'a>(env: Env<'a>, bytes: &[u8]) -> NifResult<rustler::Binary<'a>> {
+    let mut binary = rustler::OwnedBinary::new(bytes.len())
+        .ok_or_else(|| rustler::Error::Term(Box::new("failed to allocate binary")))?;
+    binary.as_mut_slice().copy_from_slice(bytes);
+    Ok(binary.release(env))
+}
+
+fn encode_error_to_term<'a>(env: Env<'a>, e: EncodeError) -> NifResult<Term<'a>> {
+    let err_atom = rustler::Atom::from_str(env, "error")?;
+    let nil_term: Term = None::<i32>.encode(env);
+    let (reason, detail) = match e {
+        EncodeError::NameTooLong(name) => ("name_too_long", name.encode(env)),
+        EncodeError::JumpOutOfBounds(target) => ("jump_out_of_bounds", target.encode(env)),
+        EncodeError::InvalidVector => ("invalid_vector_size", nil_term),
+        EncodeError::TooLarge => ("bytecode_too_large", nil_term),
+    };
+    Ok((err_atom, rustler::Atom::from_str(env, reason)?, detail).encode(env))
+}
+
+type ValueMap = HashMap<String, Value>;
+
+/// 同じプログラムを複数エンティティの入力で 1 回の NIF 呼び出しで実行する。
+///
+/// - program: バイトコード（binary）または compile_formula/1 のハンドル
+/// - entities: 入力マップのリスト `[%{"hp" => 10}, ...]`、または入力名ごとのリストを持つマップ
+///   `%{"hp" => [10, 20], "dt" => 0.05}`（列形式。リストでない値は全エンティティ共通の値として扱う。
+///   リストの長さはすべて一致していること。全エンティティ共通のリスト値は列形式では書けないため行形式を使う）
+/// - store_values: 全エンティティ共通の Store 初期値。WriteStore はエンティティ内でのみ有効で結果に含まれない
+/// - limits: run_formula_bytecode と同じ。エンティティごとに適用する
+///
+/// 戻り値: {:ok, [{:ok, outputs} | {:error, reason_atom, detail, location}, ...]} | {:error, reason_atom, detail, location}
+/// エンティティごとのドメインエラー（input_not_found, integer_out_of_range, budget_exceeded 等）は
+/// その要素だけに現れる。バイトコードのデコードエラーのみ全体を {:error, ...} にする。
+#[rustler::nif]
+pub fn run_formula_batch<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    entities: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_batch_impl(env, program, entities, store_values, limits)
+}
+
+/// run_formula_batch/4 の DirtyCpu 版
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn run_formula_batch_dirty<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    entities: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_batch_impl(env, program, entities, store_values, limits)
+}
+
+fn run_batch_impl<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    entities: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let limits = decode_limits(limits)?;
+    let program = match decode_program(env, program)? {
+        Ok(program) => program,
+        Err(err_term) => return Ok(err_term),
+    };
+    let decoded = decode_batch_entities(env, entities)?;
+    let store_map = decode_store_map(store_values)?;
+
+    // デコードに失敗したエンティティはエラー term を残し、成功したものだけをまとめて実行する
+    let mut inputs = Vec::with_capacity(decoded.len());
+    let slots: Vec<Option<Term<'a>>> = decoded
+        .into_iter()
+        .map(|entity| match entity {
+            Ok(map) => {
+                inputs.push(map);
+                None
+            }
+            Err(err_term) => Some(err_term),
+        })
+        .collect();
+    let mut results = execute_batch(program.get(), &inputs, &store_map, &limits).into_iter();
+
+    let ok_atom = rustler::Atom::from_str(env, "ok")?;
+    let mut terms = Vec::with_capacity(slots.len());
+    for slot in slots {
+        let term = match slot {
+            Some(err_term) => err_term,
+            None => match results.next() {
+                Some(Ok(outputs)) => {
+                    let outputs: Vec<Term<'a>> =
+                        outputs.iter().map(|v| value_to_term(env, v)).collect();
+                    (ok_atom, outputs).encode(env)
+                }
+                Some(Err(e)) => error_to_term(env, e)?,
+                None => unreachable!("execute_batch returns one result per entity"),
+            },
+        };
+        terms.push(term);
+    }
+    Ok((ok_atom, terms).encode(env))
+}
+
+enum ProgramRef {
+    Compiled(ResourceArc<FormulaProgram>),
+    Owned(Program),
+}
+
+impl ProgramRef {
+    fn get(&self) -> &Program {
+        match self {
+            ProgramRef::Compiled(resource) => &resource.program,
+            ProgramRef::Owned(program) => program,
+        }
+    }
+}
+
+/// バイトコード（binary）または compile_formula/1 のハンドルを受け取る。
+/// デコードエラーはドメインエラーとして Ok(Err({:error, reason, detail, location}))、それ以外の型は NIF 層のエラー。
+fn decode_program<'a>(env: Env<'a>, term: Term<'a>) -> NifResult<Result<ProgramRef, Term<'a>>> {
+    if let Ok(resource) = term.decode::<ResourceArc<FormulaProgram>>() {
+        return Ok(Ok(ProgramRef::Compiled(resource)));
+    }
+    let bytecode: rustler::Binary = term.decode().map_err(|_| {
+        rustler::Error::Term(Box::new("program: expected binary or compiled formula"))
+    })?;
+    match Program::compile(bytecode.as_slice()) {
+        Ok(program) => Ok(Ok(ProgramRef::Owned(program))),
+        Err(e) => error_to_term(env, e.into()).map(Err),
+    }
+}
+
+/// プログラムを実行し、出力・更新後の Store に加えて Emit で発行したイベントを返す。
+///
+/// - program: バイトコード（binary）または compile_formula/1 のハンドル
+/// - inputs・store_values・limits: run_formula_bytecode と同じ
+///
+/// 戻り値: {:ok, {outputs, updated_store, events}} | {:error, reason_atom, detail, location}
+/// events は発行順の `{name, payload}` のリスト。ペイロードなしの Emit は payload が nil。
+#[rustler::nif]
+pub fn run_formula_events<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_events_impl(env, program, inputs, store_values, limits)
+}
+
+/// run_formula_events/4 の DirtyCpu 版
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn run_formula_events_dirty<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_events_impl(env, program, inputs, store_values, limits)
+}
+
+fn run_events_impl<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let limits = decode_limits(limits)?;
+    let program = match decode_program(env, program)? {
+        Ok(program) => program,
+        Err(err_term) => return Ok(err_term),
+    };
+    let (input_map, store_map) = match decode_run_args(env, inputs, store_values)? {
+        Ok(args) => args,
+        Err(err_term) => return Ok(err_term),
+    };
+    match execute_with_events(program.get(), &input_map, &store_map, &limits) {
+        Ok((outputs, updated_store, events)) => {
+            let terms: Vec<Term<'a>> = outputs.iter().map(|v| value_to_term(env, v)).collect();
+            let store_terms = map_value_map_to_elixir(env, &updated_store);
+            let event_terms: Vec<Term<'a>> = events.iter().map(|e| event_to_term(env, e)).collect();
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, (terms, store_terms, event_terms)).encode(env))
+        }
+        Err(e) => error_to_term(env, e),
+    }
+}
+
+/// {name, payload}。ペイロードなしは nil
+fn event_to_term<'a>(env: Env<'a>, event: &Event) -> Term<'a> {
+    let payload = match &event.payload {
+        Some(v) => value_to_term(env, v),
+        None => None::<i32>.encode(env),
+    };
+    (&*event.name, payload).encode(env)
+}
+
+/// プログラムを実行し、Store 全体の代わりに書き込んだキーの変更セットを返す。
+///
+/// - program: バイトコード（binary）または compile_formula/1 のハンドル
+/// - inputs・limits: run_formula_bytecode と同じ
+/// - store_values: Store の map。デコード・複製せず、READ_STORE / WRITE_STORE / 乱数が参照したキーだけを読む。
+///   読んだ値が Store の値として不正なら {:error, :invalid_store_value, key, location}
+///
+/// 戻り値: {:ok, {outputs, changes, events}} | {:error, reason_atom, detail, location}
+/// changes は最初の書き込み順の `{key, before, after, :written | :unchanged}` のリスト。
+/// before は実行前の値（新規キーは nil）、after は最後に書き込んだ値。値が変わらなかったキーは :unchanged。
+#[rustler::nif]
+pub fn run_formula_changes<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_changes_impl(env, program, inputs, store_values, limits)
+}
+
+/// run_formula_changes/4 の DirtyCpu 版
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn run_formula_changes_dirty<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_changes_impl(env, program, inputs, store_values, limits)
+}
+
+fn run_changes_impl<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let limits = decode_limits(limits)?;
+    let program = match decode_program(env, program)? {
+        Ok(program) => program,
+        Err(err_term) => return Ok(err_term),
+    };
     let input_map = match decode_input_map(inputs) {
         Ok(m) => m,
-        Err(InputDecodeError::IntegerOutOfRange(v)) => {
-            let err_term = input_error_to_term(env, &InputDecodeError::IntegerOutOfRange(v))?;
-            return Ok(err_term);
+        Err(e) => return input_decode_failure(env, e),
+    };
+    let store = TermStore::new(env, store_values)?;
+    match execute_changes(program.get(), &input_map, &store, &limits) {
+        Ok((outputs, changes, events)) => {
+            let terms: Vec<Term<'a>> = outputs.iter().map(|v| value_to_term(env, v)).collect();
+            let change_terms = changes
+                .iter()
+                .map(|c| change_to_term(env, c))
+                .collect::<NifResult<Vec<Term<'a>>>>()?;
+            let event_terms: Vec<Term<'a>> = events.iter().map(|e| event_to_term(env, e)).collect();
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, (terms, change_terms, event_terms)).encode(env))
+        }
+        Err(e) => error_to_term(env, e),
+    }
+}
+
+/// Elixir の map を読み取り専用の Store として引く。キーは string を先に、無ければ同名の atom を引く。
+/// 値は参照されたときにだけデコードする
+struct TermStore<'a> {
+    env: Env<'a>,
+    map: Term<'a>,
+    len: usize,
+}
+
+impl<'a> TermStore<'a> {
+    fn new(env: Env<'a>, map: Term<'a>) -> NifResult<Self> {
+        let len = map
+            .map_size()
+            .map_err(|_| rustler::Error::Term(Box::new("store_values: expected map")))?;
+        Ok(Self { env, map, len })
+    }
+}
+
+impl StoreBase for TermStore<'_> {
+    fn get(&self, key: &str) -> Result<Option<Value>, VmErrorKind> {
+        let term = self.map.map_get(key.encode(self.env)).ok().or_else(|| {
+            rustler::Atom::try_from_bytes(self.env, key.as_bytes())
+                .ok()
+                .flatten()
+                .and_then(|atom| self.map.map_get(atom).ok())
+        });
+        term.map(|t| term_to_value(t).map_err(|_| VmErrorKind::InvalidStoreValue(key.to_string())))
+            .transpose()
+    }
+
+    fn entry_count(&self) -> usize {
+        self.len
+    }
+}
+
+/// {key, before | nil, after, :written | :unchanged}
+fn change_to_term<'a>(env: Env<'a>, change: &StoreChange) -> NifResult<Term<'a>> {
+    let before = match &change.before {
+        Some(v) => value_to_term(env, v),
+        None => None::<i32>.encode(env),
+    };
+    let flag = rustler::Atom::from_str(
+        env,
+        if change.changed {
+            "written"
+        } else {
+            "unchanged"
+        },
+    )?;
+    Ok((
+        change.key.as_str(),
+        before,
+        value_to_term(env, &change.after),
+        flag,
+    )
+        .encode(env))
+}
+
+/// プログラムをトレース付きで実行し、命令ごとのレジスタの値と（任意で）OpCode ごとの集計を返す。
+///
+/// - program: バイトコード（binary）または compile_formula/1 のハンドル
+/// - inputs・store_values・limits: run_formula_bytecode と同じ
+/// - options: %{steps: boolean, profile: boolean}。省略したキーは steps: true, profile: false
+///
+/// 戻り値: {:ok, %{result: result, steps: steps, profile: profile}} | {:error, reason_atom, detail, location}
+/// - result は run_formula_bytecode の戻り値と同じ（{:ok, {outputs, updated_store}} | {:error, reason_atom, detail, location}）。
+///   実行時エラーでもそこまでのトレースを返すため、実行時エラーは result の中に入る。
+///   デコードエラー・入力の不正は他の実行系 NIF と同じく全体を {:error, ...} にする
+/// - steps は実行順の %{depth, function, pc, op, operands, result}。operands は読み出したレジスタの
+///   `{reg, value | nil}`（実行前の値）、result は書き込んだレジスタの `{reg, value}` か nil
+/// - profile は profile: true のとき OpCode の値の順の `{op, count, time_ns}`、それ以外は nil
+#[rustler::nif]
+pub fn run_formula_traced<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    options: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_traced_impl(env, program, inputs, store_values, options, limits)
+}
+
+/// run_formula_traced/5 の DirtyCpu 版
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn run_formula_traced_dirty<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    options: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    run_traced_impl(env, program, inputs, store_values, options, limits)
+}
+
+fn run_traced_impl<'a>(
+    env: Env<'a>,
+    program: Term<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    options: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let options = decode_trace_options(options)?;
+    let limits = decode_limits(limits)?;
+    let program = match decode_program(env, program)? {
+        Ok(program) => program,
+        Err(err_term) => return Ok(err_term),
+    };
+    let (input_map, store_map) = match decode_run_args(env, inputs, store_values)? {
+        Ok(args) => args,
+        Err(err_term) => return Ok(err_term),
+    };
+    let (result, trace) = execute_traced(program.get(), &input_map, &store_map, &limits, options);
+    let keys = ["result", "steps", "profile"]
+        .iter()
+        .map(|k| rustler::Atom::from_str(env, k).map(|a| a.encode(env)))
+        .collect::<NifResult<Vec<_>>>()?;
+    let steps = trace
+        .steps
+        .iter()
+        .map(|step| trace_step_to_term(env, step))
+        .collect::<NifResult<Vec<_>>>()?;
+    let profile = match &trace.profile {
+        Some(profile) => profile
+            .values()
+            .map(|(op, p)| {
+                let op = rustler::Atom::from_str(env, op.mnemonic())?;
+                Ok((op, p.count, p.time.as_nanos() as u64).encode(env))
+            })
+            .collect::<NifResult<Vec<_>>>()?
+            .encode(env),
+        None => None::<i32>.encode(env),
+    };
+    let values = [encode_run_result(env, result)?, steps.encode(env), profile];
+    let map = Term::map_from_term_arrays(env, &keys, &values)
+        .map_err(|_| rustler::Error::Term(Box::new("failed to build trace map")))?;
+    let ok_atom = rustler::Atom::from_str(env, "ok")?;
+    Ok((ok_atom, map).encode(env))
+}
+
+/// %{depth, function, pc, op, operands, result}
+fn trace_step_to_term<'a>(env: Env<'a>, step: &TraceStep) -> NifResult<Term<'a>> {
+    let nil = None::<i32>.encode(env);
+    let keys = ["depth", "function", "pc", "op", "operands", "result"]
+        .iter()
+        .map(|k| rustler::Atom::from_str(env, k).map(|a| a.encode(env)))
+        .collect::<NifResult<Vec<_>>>()?;
+    let operands: Vec<Term<'a>> = step
+        .operands
+        .iter()
+        .map(|(r, v)| {
+            let value = v.as_ref().map_or(nil, |v| value_to_term(env, v));
+            (*r, value).encode(env)
+        })
+        .collect();
+    let values = [
+        step.depth.encode(env),
+        step.function.as_deref().encode(env),
+        step.pc.encode(env),
+        rustler::Atom::from_str(env, step.opcode.mnemonic())?.encode(env),
+        operands.encode(env),
+        step.result
+            .as_ref()
+            .map_or(nil, |(r, v)| (*r, value_to_term(env, v)).encode(env)),
+    ];
+    Term::map_from_term_arrays(env, &keys, &values)
+        .map_err(|_| rustler::Error::Term(Box::new("failed to build trace step map")))
+}
+
+/// %{steps: boolean, profile: boolean} を `TraceOptions` にする。未知のキーや boolean 以外は NIF 層のエラー
+fn decode_trace_options(term: Term) -> NifResult<TraceOptions> {
+    let iter = MapIterator::new(term)
+        .ok_or_else(|| rustler::Error::Term(Box::new("trace options: expected map")))?;
+    let mut options = TraceOptions::default();
+    for (key_term, value_term) in iter {
+        let key = key_term
+            .atom_to_string()
+            .map_err(|_| rustler::Error::Term(Box::new("trace options key: expected atom")))?;
+        let value: bool = value_term
+            .decode()
+            .map_err(|_| rustler::Error::Term(Box::new("trace options value: expected boolean")))?;
+        match key.as_str() {
+            "steps" => options.steps = value,
+            "profile" => options.profile = value,
+            _ => {
+                return Err(rustler::Error::Term(Box::new(format!(
+                    "trace options: unknown key {}",
+                    key
+                ))))
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// 読み込んだ計算グラフと、前回の評価で得た各ノードの値を保持する NIF リソース。
+/// 評価のたびに値を更新するため、同時に評価すると直列化される（プロセスごとにグラフを読み込むのがよい）。
+pub struct FormulaGraph {
+    graph: Mutex<Graph>,
+}
+
+impl rustler::Resource for FormulaGraph {}
+
+/// 計算グラフを読み込み、差分評価するハンドルを返す。
+///
+/// - nodes: [{id, op, param}]。id は atom、op は Core.FormulaGraph のノード種別 atom。
+///   param は :input の入力名・:read_store / :write_store のキー・:int / :float / :bool の値、それ以外は nil
+/// - edges: [{from, to, port}]。port は接続先のポート atom（:a, :b, :value 等）。:after は評価順序だけを指定する
+/// - outputs: 出力ノード ID のリスト。評価結果の出力はこの順に並ぶ
+///
+/// 戻り値: {:ok, graph_ref} | {:error, :invalid_graph, detail} | {:error, :cycle_detected, nil}
+/// detail は :empty_nodes | {:duplicate_node, id} | {:unknown_node, id} | {:unknown_op, id, op} |
+/// {:invalid_param, id} | {:unknown_port, id, port} | {:duplicate_input, id, port} | {:missing_input, id, port} |
+/// {:not_a_producer, id} | {:not_an_output, id}
+#[rustler::nif]
+pub fn load_formula_graph<'a>(
+    env: Env<'a>,
+    nodes: Vec<(Term<'a>, rustler::Atom, Term<'a>)>,
+    edges: Vec<(Term<'a>, Term<'a>, rustler::Atom)>,
+    outputs: Vec<Term<'a>>,
+) -> NifResult<Term<'a>> {
+    let err_atom = rustler::Atom::from_str(env, "error")?;
+    let invalid = rustler::Atom::from_str(env, "invalid_graph")?;
+    let mut specs = Vec::with_capacity(nodes.len());
+    for (id, op, param) in nodes {
+        let id = term_to_string(id)?;
+        let op = op.to_term(env).atom_to_string()?;
+        match decode_node_kind(&op, param)? {
+            Ok(kind) => specs.push((id, kind)),
+            Err(detail) => {
+                let detail = match detail {
+                    "unknown_op" => (
+                        rustler::Atom::from_str(env, detail)?,
+                        node_id_term(env, &id)?,
+                        rustler::Atom::from_str(env, &op)?,
+                    )
+                        .encode(env),
+                    _ => (
+                        rustler::Atom::from_str(env, detail)?,
+                        node_id_term(env, &id)?,
+                    )
+                        .encode(env),
+                };
+                return Ok((err_atom, invalid, detail).encode(env));
+            }
+        }
+    }
+    let edges = edges
+        .into_iter()
+        .map(|(from, to, port)| {
+            Ok((
+                term_to_string(from)?,
+                term_to_string(to)?,
+                port.to_term(env).atom_to_string()?,
+            ))
+        })
+        .collect::<NifResult<Vec<_>>>()?;
+    let outputs = outputs
+        .into_iter()
+        .map(term_to_string)
+        .collect::<NifResult<Vec<_>>>()?;
+
+    match Graph::new(specs, &edges, &outputs) {
+        Ok(graph) => {
+            let resource = ResourceArc::new(FormulaGraph {
+                graph: Mutex::new(graph),
+            });
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, resource).encode(env))
+        }
+        Err(GraphError::CycleDetected) => {
+            let reason = rustler::Atom::from_str(env, "cycle_detected")?;
+            Ok((err_atom, reason, None::<i32>).encode(env))
+        }
+        Err(e) => Ok((err_atom, invalid, graph_error_detail(env, e)?).encode(env)),
+    }
+}
+
+/// ノード種別 atom 名と param を `NodeKind` にする。未知の種別・param の誤りは Ok(Err(detail の atom 名))
+fn decode_node_kind(op: &str, param: Term) -> NifResult<Result<NodeKind, &'static str>> {
+    let value = || term_to_value(param).ok();
+    let kind = match op {
+        "input" => NodeKind::Input(term_to_string(param)?),
+        "read_store" => NodeKind::ReadStore(term_to_string(param)?),
+        "write_store" => NodeKind::WriteStore(term_to_string(param)?),
+        "output" => NodeKind::Output,
+        "int" => match value() {
+            Some(v @ Value::I32(_)) => NodeKind::Const(v),
+            _ => return Ok(Err("invalid_param")),
+        },
+        "float" => match value() {
+            Some(v @ (Value::I32(_) | Value::I64(_) | Value::F32(_) | Value::F64(_))) => {
+                NodeKind::Const(Value::F32(v.as_f32().expect("scalar")))
+            }
+            _ => return Ok(Err("invalid_param")),
+        },
+        "bool" => match value() {
+            Some(v @ Value::Bool(_)) => NodeKind::Const(v),
+            _ => return Ok(Err("invalid_param")),
+        },
+        _ => match Op::from_name(op) {
+            Some(op) => NodeKind::Op(op),
+            None => return Ok(Err("unknown_op")),
+        },
+    };
+    Ok(Ok(kind))
+}
+
+fn graph_error_detail<'a>(env: Env<'a>, e: GraphError) -> NifResult<Term<'a>> {
+    let tag = |name: &str| rustler::Atom::from_str(env, name);
+    let detail = match e {
+        GraphError::EmptyGraph => tag("empty_nodes")?.encode(env),
+        GraphError::DuplicateNode(id) => {
+            (tag("duplicate_node")?, node_id_term(env, &id)?).encode(env)
+        }
+        GraphError::UnknownNode(id) => (tag("unknown_node")?, node_id_term(env, &id)?).encode(env),
+        GraphError::UnknownPort(id, p) => {
+            (tag("unknown_port")?, node_id_term(env, &id)?, tag(&p)?).encode(env)
+        }
+        GraphError::DuplicateInput(id, p) => {
+            (tag("duplicate_input")?, node_id_term(env, &id)?, tag(&p)?).encode(env)
+        }
+        GraphError::MissingInput(id, p) => {
+            (tag("missing_input")?, node_id_term(env, &id)?, tag(&p)?).encode(env)
+        }
+        GraphError::NotAProducer(id) => {
+            (tag("not_a_producer")?, node_id_term(env, &id)?).encode(env)
+        }
+        GraphError::NotAnOutput(id) => (tag("not_an_output")?, node_id_term(env, &id)?).encode(env),
+        GraphError::CycleDetected => tag("cycle_detected")?.encode(env),
+    };
+    Ok(detail)
+}
+
+/// ノード ID は atom で返す（Core.FormulaGraph のノード ID と同じ）
+fn node_id_term<'a>(env: Env<'a>, id: &str) -> NifResult<Term<'a>> {
+    Ok(rustler::Atom::from_str(env, id)?.encode(env))
+}
+
+/// 読み込んだグラフを評価する。前回から値の変わった入力・Store 読み出しの下流のノードだけを計算し直す。
+/// inputs・store_values・limits は run_formula_bytecode と同じ（max_instructions は計算し直した演算ノードの数）。
+///
+/// 戻り値: {:ok, {outputs, updated_store}} | {:error, reason_atom, detail, %{node: id}} |
+/// {:error, :integer_out_of_range, v}。reason・detail は run_formula_bytecode と同じで、node は失敗したノード ID。
+/// 失敗した後の評価はすべてのノードを計算し直す。
+#[rustler::nif]
+pub fn evaluate_formula_graph<'a>(
+    env: Env<'a>,
+    graph: ResourceArc<FormulaGraph>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    evaluate_graph_impl(env, graph, inputs, store_values, limits)
+}
+
+/// evaluate_formula_graph/4 の DirtyCpu 版
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn evaluate_formula_graph_dirty<'a>(
+    env: Env<'a>,
+    graph: ResourceArc<FormulaGraph>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    evaluate_graph_impl(env, graph, inputs, store_values, limits)
+}
+
+fn evaluate_graph_impl<'a>(
+    env: Env<'a>,
+    graph: ResourceArc<FormulaGraph>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+    limits: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let limits = decode_limits(limits)?;
+    let (input_map, mut store_map) = match decode_run_args(env, inputs, store_values)? {
+        Ok(args) => args,
+        Err(err_term) => return Ok(err_term),
+    };
+    let mut graph = lock_graph(&graph)?;
+    match graph.evaluate(&input_map, &mut store_map, &limits) {
+        Ok(outputs) => {
+            let terms: Vec<Term<'a>> = outputs.iter().map(|v| value_to_term(env, v)).collect();
+            let store_terms = map_value_map_to_elixir(env, &store_map);
+            let ok_atom = rustler::Atom::from_str(env, "ok")?;
+            Ok((ok_atom, (terms, store_terms)).encode(env))
         }
-        Err(InputDecodeError::ExpectedMap) => {
-            return Err(rustler::Error::Term(Box::new("inputs: expected map")));
+        Err(e) => {
+            let err_atom = rustler::Atom::from_str(env, "error")?;
+            let keys = [rustler::Atom::from_str(env, "node")?.encode(env)];
+            let values = [node_id_term(env, &e.node)?];
+            let location = Term::map_from_term_arrays(env, &keys, &values)?;
+            let (reason, detail) = error_reason(env, e.kind)?;
+            Ok((err_atom, reason, detail, location).encode(env))
         }
-        Err(InputDecodeError::InvalidKey) => {
-            return Err(rustler::Error::Term(Box::new(
-                "input key: expected string or atom",
-            )));
+    }
+}
+
+/// 直前の evaluate_formula_graph で値を計算し直したノード ID のリスト（評価順）。
+/// 値の変わった入力・Store 読み出し・定数と、計算し直した演算ノード。出力・Store 書き込みは含まない
+#[rustler::nif]
+pub fn formula_graph_recomputed<'a>(
+    env: Env<'a>,
+    graph: ResourceArc<FormulaGraph>,
+) -> NifResult<Term<'a>> {
+    let graph = lock_graph(&graph)?;
+    let ids = graph
+        .recomputed()
+        .map(|id| node_id_term(env, id))
+        .collect::<NifResult<Vec<_>>>()?;
+    Ok(ids.encode(env))
+}
+
+fn lock_graph(graph: &FormulaGraph) -> NifResult<std::sync::MutexGuard<'_, Graph>> {
+    graph
+        .graph
+        .lock()
+        .map_err(|_| rustler::Error::Term(Box::new("formula graph: poisoned by a previous panic")))
+}
+
+/// バッチ入力をエンティティごとの入力マップにする。
+/// 各要素の Err はそのエンティティのドメインエラー term（integer_out_of_range）。
+fn decode_batch_entities<'a>(
+    env: Env<'a>,
+    entities: Term<'a>,
+) -> NifResult<Vec<Result<ValueMap, Term<'a>>>> {
+    if let Ok(rows) = entities.decode::<Vec<Term<'a>>>() {
+        return rows
+            .into_iter()
+            .map(|row| match decode_input_map(row) {
+                Ok(m) => Ok(Ok(m)),
+                Err(e) => input_decode_failure(env, e).map(Err),
+            })
+            .collect();
+    }
+
+    // 列形式: %{"name" => [v0, v1, ...] | shared_value}
+    let iter = MapIterator::new(entities).ok_or_else(|| {
+        rustler::Error::Term(Box::new("entities: expected list of maps or map of lists"))
+    })?;
+    let mut columns: Vec<(String, Result<Vec<Term<'a>>, Term<'a>>)> = Vec::new();
+    let mut len: Option<usize> = None;
+    for (key_term, value_term) in iter {
+        let key = term_to_string(key_term)
+            .map_err(|_| rustler::Error::Term(Box::new("input key: expected string or atom")))?;
+        match value_term.decode::<Vec<Term<'a>>>() {
+            Ok(column) => {
+                if len.is_some_and(|n| n != column.len()) {
+                    return Err(rustler::Error::Term(Box::new(
+                        "entities: column lengths differ",
+                    )));
+                }
+                len = Some(column.len());
+                columns.push((key, Ok(column)));
+            }
+            Err(_) => columns.push((key, Err(value_term))),
         }
-        Err(InputDecodeError::InvalidValue) => {
-            return Err(rustler::Error::Term(Box::new(
-                "input value: expected integer (i32 range), float, or boolean",
-            )));
+    }
+    let len = len.ok_or_else(|| {
+        rustler::Error::Term(Box::new("entities: map of lists needs at least one list"))
+    })?;
+
+    let mut result = Vec::with_capacity(len);
+    for i in 0..len {
+        let mut map = HashMap::with_capacity(columns.len());
+        let mut entity_error = None;
+        for (key, column) in &columns {
+            let term = match column {
+                Ok(values) => values[i],
+                Err(shared) => *shared,
+            };
+            match term_to_value(term) {
+                Ok(value) => {
+                    map.insert(key.clone(), value);
+                }
+                Err(e) => {
+                    entity_error = Some(input_decode_failure(env, e)?);
+                    break;
+                }
+            }
         }
+        result.push(match entity_error {
+            Some(err_term) => Err(err_term),
+            None => Ok(map),
+        });
+    }
+    Ok(result)
+}
+
+/// run 系 NIF 共通: 入力マップと Store 初期値をデコードする。
+///
+/// 入力整数の i64 範囲外はドメインエラーとして Ok(Err({:error, :integer_out_of_range, v})) を返す。
+/// それ以外の形式不正は NIF 層のエラー（Err）。
+fn decode_run_args<'a>(
+    env: Env<'a>,
+    inputs: Term<'a>,
+    store_values: Term<'a>,
+) -> NifResult<Result<(ValueMap, ValueMap), Term<'a>>> {
+    let input_map = match decode_input_map(inputs) {
+        Ok(m) => m,
+        Err(e) => return input_decode_failure(env, e).map(Err),
     };
-    let store_map = decode_value_map(store_values).map_err(|e| match e {
+    let store_map = decode_store_map(store_values)?;
+    Ok(Ok((input_map, store_map)))
+}
+
+/// 入力のデコード失敗を振り分ける。i64 範囲外はドメインエラーの term、それ以外は NIF 層のエラー。
+fn input_decode_failure<'a>(env: Env<'a>, e: InputDecodeError) -> NifResult<Term<'a>> {
+    match e {
+        e @ InputDecodeError::IntegerOutOfRange(_) => input_error_to_term(env, &e),
+        InputDecodeError::ExpectedMap => {
+            Err(rustler::Error::Term(Box::new("inputs: expected map")))
+        }
+        InputDecodeError::InvalidKey => Err(rustler::Error::Term(Box::new(
+            "input key: expected string or atom",
+        ))),
+        InputDecodeError::InvalidValue => Err(rustler::Error::Term(Box::new(
+            "input value: expected integer (i64 range), float, boolean, vector, or flat list of those",
+        ))),
+    }
+}
+
+/// %{max_instructions: n, max_store_entries: n, max_outputs: n, max_call_depth: n, max_events: n, max_list_len: n}
+/// を `Limits` にする。
+/// 省略したキーは `Limits::default()` の値。未知のキーや負数・非整数は NIF 層のエラー。
+fn decode_limits(term: Term) -> NifResult<Limits> {
+    let iter = MapIterator::new(term)
+        .ok_or_else(|| rustler::Error::Term(Box::new("limits: expected map")))?;
+    let mut limits = Limits::default();
+    for (key_term, value_term) in iter {
+        let key = key_term
+            .atom_to_string()
+            .map_err(|_| rustler::Error::Term(Box::new("limits key: expected atom")))?;
+        let value: u64 = value_term.decode().map_err(|_| {
+            rustler::Error::Term(Box::new("limits value: expected non-negative integer"))
+        })?;
+        let value = usize::try_from(value).unwrap_or(usize::MAX);
+        match key.as_str() {
+            "max_instructions" => limits.max_instructions = value,
+            "max_store_entries" => limits.max_store_entries = value,
+            "max_outputs" => limits.max_outputs = value,
+            "max_call_depth" => limits.max_call_depth = value,
+            "max_events" => limits.max_events = value,
+            "max_list_len" => limits.max_list_len = value,
+            _ => {
+                return Err(rustler::Error::Term(Box::new(format!(
+                    "limits: unknown key {}",
+                    key
+                ))))
+            }
+        }
+    }
+    Ok(limits)
+}
+
+fn decode_store_map(store_values: Term) -> NifResult<ValueMap> {
+    decode_value_map(store_values).map_err(|e| match e {
         InputDecodeError::IntegerOutOfRange(v) => rustler::Error::Term(Box::new(format!(
-            "store value: integer {} out of i32 range",
+            "store value: integer {} out of i64 range",
             v
         ))),
         InputDecodeError::ExpectedMap => {
@@ -62,10 +1410,17 @@ pub fn run_formula_bytecode<'a>(
             rustler::Error::Term(Box::new("store key: expected string or atom"))
         }
         InputDecodeError::InvalidValue => rustler::Error::Term(Box::new(
-            "store value: expected integer (i32 range), float, or boolean",
+            "store value: expected integer (i64 range), float, boolean, vector, or flat list of those",
         )),
-    })?;
-    match run(bytecode.as_slice(), &input_map, &store_map) {
+    })
+}
+
+/// run 系 NIF 共通: 実行結果を {:ok, {outputs, updated_store}} | {:error, reason, detail, location} にする
+fn encode_run_result<'a>(
+    env: Env<'a>,
+    result: Result<(Vec<Value>, ValueMap), VmError>,
+) -> NifResult<Term<'a>> {
+    match result {
         Ok((outputs, updated_store)) => {
             let terms: Vec<Term<'a>> = outputs.iter().map(|v| value_to_term(env, v)).collect();
             let store_terms = map_value_map_to_elixir(env, &updated_store);
@@ -102,19 +1457,27 @@ fn map_value_map_to_elixir<'a>(env: Env<'a>, map: &HashMap<String, Value>) -> ru
     pairs.encode(env)
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 enum StoreEncodable {
     I32(i32),
+    I64(i64),
     F64(f64),
     Bool(bool),
+    Vec2((f64, f64)),
+    Vec3((f64, f64, f64)),
+    List(Vec<StoreEncodable>),
 }
 
 impl rustler::Encoder for StoreEncodable {
     fn encode<'a>(&self, env: rustler::Env<'a>) -> rustler::Term<'a> {
         match self {
             StoreEncodable::I32(x) => x.encode(env),
+            StoreEncodable::I64(x) => x.encode(env),
             StoreEncodable::F64(x) => x.encode(env),
             StoreEncodable::Bool(x) => x.encode(env),
+            StoreEncodable::Vec2(x) => x.encode(env),
+            StoreEncodable::Vec3(x) => x.encode(env),
+            StoreEncodable::List(x) => x.encode(env),
         }
     }
 }
@@ -123,7 +1486,14 @@ fn value_to_store_encodable(v: &Value) -> StoreEncodable {
     match v {
         Value::I32(x) => StoreEncodable::I32(*x),
         Value::F32(x) => StoreEncodable::F64(*x as f64),
+        Value::I64(x) => StoreEncodable::I64(*x),
+        Value::F64(x) => StoreEncodable::F64(*x),
         Value::Bool(x) => StoreEncodable::Bool(*x),
+        Value::Vec2([x, y]) => StoreEncodable::Vec2((*x as f64, *y as f64)),
+        Value::Vec3([x, y, z]) => StoreEncodable::Vec3((*x as f64, *y as f64, *z as f64)),
+        Value::List(items) => {
+            StoreEncodable::List(items.iter().map(value_to_store_encodable).collect())
+        }
     }
 }
 
@@ -143,29 +1513,110 @@ fn term_to_string(term: Term) -> NifResult<String> {
     }
 }
 
+/// スカラー・ベクトル、またはそれらを要素に持つリスト（入れ子のリストは InvalidValue）
 fn term_to_value(term: Term) -> Result<Value, InputDecodeError> {
+    if term.is_list() {
+        let elems: Vec<Term> = term.decode().map_err(|_| InputDecodeError::InvalidValue)?;
+        let items = elems
+            .into_iter()
+            .map(term_to_element)
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Value::List(items.into()));
+    }
+    term_to_element(term)
+}
+
+/// リスト以外の値
+fn term_to_element(term: Term) -> Result<Value, InputDecodeError> {
     if let Ok(i) = term.decode::<i32>() {
         return Ok(Value::I32(i));
     }
     if let Ok(i) = term.decode::<i64>() {
-        let v = i32::try_from(i).map_err(|_| InputDecodeError::IntegerOutOfRange(i))?;
-        return Ok(Value::I32(v));
+        return Ok(Value::I64(i));
+    }
+    if term.get_type() == rustler::TermType::Integer {
+        return Err(InputDecodeError::IntegerOutOfRange(format!("{term:?}")));
     }
     if let Ok(f) = term.decode::<f64>() {
         return Ok(Value::F32(f as f32));
     }
+    if let Some(v) = term_to_tagged(term) {
+        return Ok(v);
+    }
     if let Ok(b) = term.decode::<bool>() {
         return Ok(Value::Bool(b));
     }
+    if let Some(v) = term_to_vector(term) {
+        return Ok(v);
+    }
     Err(InputDecodeError::InvalidValue)
 }
 
+/// 型を明示した数値 {:f64, x} / {:i64, n}。float は既定では f32 に丸めるため、倍精度は {:f64, x} で渡す
+fn term_to_tagged(term: Term) -> Option<Value> {
+    let elems = get_tuple(term).ok()?;
+    let [tag, value] = elems.as_slice() else {
+        return None;
+    };
+    match tag.atom_to_string().ok()?.as_str() {
+        "f64" => match value.decode::<f64>() {
+            Ok(f) => Some(Value::F64(f)),
+            Err(_) => value.decode::<i64>().ok().map(|i| Value::F64(i as f64)),
+        },
+        "i64" => value.decode::<i64>().ok().map(Value::I64),
+        _ => None,
+    }
+}
+
+/// ベクトル成分: integer | float を f32 として読む
+fn term_to_component(term: Term) -> Option<f32> {
+    if let Ok(f) = term.decode::<f64>() {
+        return Some(f as f32);
+    }
+    term.decode::<i64>().ok().map(|i| i as f32)
+}
+
+/// {x, y} / {x, y, z} のタプル、または x, y(, z) キー（atom / string）を持つマップをベクトルとして読む
+fn term_to_vector(term: Term) -> Option<Value> {
+    let mut components = [0.0f32; 3];
+    let n = if let Ok(elems) = get_tuple(term) {
+        if !(2..=3).contains(&elems.len()) {
+            return None;
+        }
+        for (c, elem) in components.iter_mut().zip(&elems) {
+            *c = term_to_component(*elem)?;
+        }
+        elems.len()
+    } else if term.is_map() {
+        let env = term.get_env();
+        let mut n = 0;
+        for (c, key) in components.iter_mut().zip(["x", "y", "z"]) {
+            let atom_key = rustler::Atom::from_str(env, key).ok()?;
+            let Some(component) = term.map_get(atom_key).or_else(|_| term.map_get(key)).ok() else {
+                break;
+            };
+            *c = term_to_component(component)?;
+            n += 1;
+        }
+        if n < 2 || term.map_size().ok()? != n {
+            return None;
+        }
+        n
+    } else {
+        return None;
+    };
+    match n {
+        2 => Some(Value::Vec2([components[0], components[1]])),
+        _ => Some(Value::Vec3(components)),
+    }
+}
+
 fn input_error_to_term<'a>(env: Env<'a>, e: &InputDecodeError) -> NifResult<Term<'a>> {
     let err_atom = rustler::Atom::from_str(env, "error")?;
     let (reason, detail): (rustler::Atom, Term) = match e {
         InputDecodeError::IntegerOutOfRange(v) => (
             rustler::Atom::from_str(env, "integer_out_of_range")?,
-            (*v).encode(env),
+            v.encode(env),
         ),
         _ => unreachable!("input_error_to_term only handles IntegerOutOfRange"),
     };
@@ -176,51 +1627,174 @@ fn value_to_term<'a>(env: Env<'a>, v: &Value) -> Term<'a> {
     match v {
         Value::F32(x) => (*x as f64).encode(env),
         Value::I32(x) => x.encode(env),
+        Value::F64(x) => x.encode(env),
+        Value::I64(x) => x.encode(env),
         Value::Bool(x) => x.encode(env),
+        Value::Vec2([x, y]) => (*x as f64, *y as f64).encode(env),
+        Value::Vec3([x, y, z]) => (*x as f64, *y as f64, *z as f64).encode(env),
+        Value::List(items) => items
+            .iter()
+            .map(|v| value_to_term(env, v))
+            .collect::<Vec<_>>()
+            .encode(env),
     }
 }
 
-/// エラーは常に 3 要素タプル {:error, reason_atom, detail} に統一。
-/// detail が不要な場合は nil を渡す。
+/// 実行・デコードのエラーは 4 要素タプル {:error, reason_atom, detail, location} に統一。
+/// detail が不要な場合は nil を渡す。location は `location_to_term`（命令に属さないエラーは nil）。
+/// 引数の誤り・スキーマの誤りなど、プログラムの命令に関係しないエラーは従来どおり 3 要素タプル。
 fn error_to_term<'a>(env: Env<'a>, e: VmError) -> NifResult<Term<'a>> {
     let err_atom = rustler::Atom::from_str(env, "error")?;
+    let location = location_to_term(env, e.location.as_ref())?;
+    let (reason, detail) = error_reason(env, e.kind)?;
+    Ok((err_atom, reason, detail, location).encode(env))
+}
+
+/// %{pc: 命令インデックス, offset: バイトオフセット, function: 関数名 | nil, origin: 生成元 | nil}。
+/// 生成元はノード ID（atom）か {line, column}
+fn location_to_term<'a>(env: Env<'a>, location: Option<&Location>) -> NifResult<Term<'a>> {
+    let Some(location) = location else {
+        return Ok(None::<i32>.encode(env));
+    };
+    let origin = match &location.origin {
+        Some(Origin::Node(id)) => rustler::Atom::from_str(env, id)?.encode(env),
+        Some(Origin::Span { line, column }) => (*line, *column).encode(env),
+        None => None::<i32>.encode(env),
+    };
+    let keys = ["pc", "offset", "function", "origin"]
+        .iter()
+        .map(|k| rustler::Atom::from_str(env, k).map(|a| a.encode(env)))
+        .collect::<NifResult<Vec<_>>>()?;
+    let values = [
+        location.pc.encode(env),
+        location.offset.encode(env),
+        location.function.encode(env),
+        origin,
+    ];
+    Term::map_from_term_arrays(env, &keys, &values)
+}
+
+/// error_to_term の {reason_atom, detail} 部分
+fn error_reason<'a>(env: Env<'a>, e: VmErrorKind) -> NifResult<(rustler::Atom, Term<'a>)> {
     let nil_term: Term = None::<i32>.encode(env);
 
     let (reason, detail): (rustler::Atom, Term) = match e {
-        VmError::Decode(de) => match de {
-            crate::formula::DecodeError::UnexpectedEof => {
+        VmErrorKind::Decode(de) => match de {
+            formula::DecodeErrorKind::UnexpectedEof => {
                 (rustler::Atom::from_str(env, "unexpected_eof")?, nil_term)
             }
-            crate::formula::DecodeError::InvalidOpCode(b) => (
+            formula::DecodeErrorKind::InvalidOpCode(b) => (
                 rustler::Atom::from_str(env, "invalid_opcode")?,
                 b.encode(env),
             ),
-            crate::formula::DecodeError::RegisterOutOfRange(r) => (
+            formula::DecodeErrorKind::RegisterOutOfRange(r) => (
                 rustler::Atom::from_str(env, "register_out_of_range")?,
                 r.encode(env),
             ),
-            crate::formula::DecodeError::InvalidUtf8 => {
+            formula::DecodeErrorKind::InvalidUtf8 => {
                 (rustler::Atom::from_str(env, "invalid_utf8")?, nil_term)
             }
+            formula::DecodeErrorKind::JumpOutOfBounds(t) => (
+                rustler::Atom::from_str(env, "jump_out_of_bounds")?,
+                t.encode(env),
+            ),
+            formula::DecodeErrorKind::JumpNotOnBoundary(t) => (
+                rustler::Atom::from_str(env, "jump_not_on_boundary")?,
+                t.encode(env),
+            ),
+            formula::DecodeErrorKind::BackwardJump(t) => (
+                rustler::Atom::from_str(env, "backward_jump")?,
+                t.encode(env),
+            ),
+            formula::DecodeErrorKind::InvalidVectorSize(n) => (
+                rustler::Atom::from_str(env, "invalid_vector_size")?,
+                n.encode(env),
+            ),
+            formula::DecodeErrorKind::UnsupportedVersion(v) => (
+                rustler::Atom::from_str(env, "unsupported_version")?,
+                v.encode(env),
+            ),
+            formula::DecodeErrorKind::UnsupportedFlags(f) => (
+                rustler::Atom::from_str(env, "unsupported_flags")?,
+                f.encode(env),
+            ),
+            formula::DecodeErrorKind::ChecksumMismatch => {
+                (rustler::Atom::from_str(env, "checksum_mismatch")?, nil_term)
+            }
+            formula::DecodeErrorKind::StringOutOfRange(i) => (
+                rustler::Atom::from_str(env, "string_out_of_range")?,
+                i.encode(env),
+            ),
+            formula::DecodeErrorKind::ConstantOutOfRange(i) => (
+                rustler::Atom::from_str(env, "constant_out_of_range")?,
+                i.encode(env),
+            ),
+            formula::DecodeErrorKind::InvalidConstant(tag) => (
+                rustler::Atom::from_str(env, "invalid_constant")?,
+                tag.encode(env),
+            ),
+            formula::DecodeErrorKind::TooManyArguments(n) => (
+                rustler::Atom::from_str(env, "too_many_arguments")?,
+                n.encode(env),
+            ),
         },
-        VmError::InputNotFound(name) => (
+        VmErrorKind::InputNotFound(name) => (
             rustler::Atom::from_str(env, "input_not_found")?,
             name.encode(env),
         ),
-        VmError::StoreNotFound(name) => (
+        VmErrorKind::StoreNotFound(name) => (
             rustler::Atom::from_str(env, "store_not_found")?,
             name.encode(env),
         ),
-        VmError::TypeMismatch(msg) => (
+        VmErrorKind::TypeMismatch(msg) => (
             rustler::Atom::from_str(env, "type_mismatch")?,
             msg.encode(env),
         ),
-        VmError::RegisterOutOfRange(r) => (
+        VmErrorKind::RegisterOutOfRange(r) => (
             rustler::Atom::from_str(env, "register_out_of_range")?,
             r.encode(env),
         ),
-        VmError::DivisionByZero => (rustler::Atom::from_str(env, "division_by_zero")?, nil_term),
+        VmErrorKind::DivisionByZero => {
+            (rustler::Atom::from_str(env, "division_by_zero")?, nil_term)
+        }
+        VmErrorKind::DomainError(op) => (
+            rustler::Atom::from_str(env, "domain_error")?,
+            op.encode(env),
+        ),
+        VmErrorKind::StepLimitExceeded(limit) => (
+            rustler::Atom::from_str(env, "step_limit_exceeded")?,
+            limit.encode(env),
+        ),
+        VmErrorKind::BudgetExceeded(budget, limit) => (
+            rustler::Atom::from_str(env, "budget_exceeded")?,
+            (rustler::Atom::from_str(env, budget.as_str())?, limit).encode(env),
+        ),
+        VmErrorKind::UndefinedFunction(name) => (
+            rustler::Atom::from_str(env, "undefined_function")?,
+            name.encode(env),
+        ),
+        VmErrorKind::MissingReturn(name) => (
+            rustler::Atom::from_str(env, "missing_return")?,
+            name.encode(env),
+        ),
+        VmErrorKind::InvalidStoreValue(key) => (
+            rustler::Atom::from_str(env, "invalid_store_value")?,
+            key.encode(env),
+        ),
+        VmErrorKind::IndexOutOfRange(i) => (
+            rustler::Atom::from_str(env, "index_out_of_range")?,
+            i.encode(env),
+        ),
+        VmErrorKind::StoreTypeMismatch(key, expected, actual) => (
+            rustler::Atom::from_str(env, "store_type_mismatch")?,
+            (
+                key,
+                rustler::Atom::from_str(env, expected.as_str())?,
+                rustler::Atom::from_str(env, actual.as_str())?,
+            )
+                .encode(env),
+        ),
     };
 
-    Ok((err_atom, reason, detail).encode(env))
+    Ok((reason, detail))
 }
@@ -1,4 +1,5 @@
-//! NIF ロード。リソース型（GameWorld 等）は登録しない。
+//! NIF ロード。リソース型は Formula のコンパイル済みプログラム（`FormulaProgram`）・関数ライブラリ（`FormulaLibrary`）・
+//! 差分評価する計算グラフ（`FormulaGraph`）を登録する。
 
 #[cfg(debug_assertions)]
 fn init_panic_hook() {
@@ -9,9 +10,31 @@ fn init_panic_hook() {
 }
 
 #[allow(non_local_definitions)]
-pub fn load(_env: rustler::Env, _: rustler::Term) -> bool {
+pub fn load(env: rustler::Env, _: rustler::Term) -> bool {
     #[cfg(debug_assertions)]
     init_panic_hook();
     let _ = env_logger::Builder::from_default_env().try_init();
-    true
+    env.register::<super::formula_nif::FormulaProgram>().is_ok()
+        && env.register::<super::formula_nif::FormulaLibrary>().is_ok()
+        && env.register::<super::formula_nif::FormulaGraph>().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    /// 未登録のリソース型は `ResourceArc::new` で panic するため、formula_nif.rs の全リソース型を登録していること
+    #[test]
+    fn registers_every_resource_type() {
+        let nifs = include_str!("formula_nif.rs");
+        let load = include_str!("load.rs");
+        let resources: Vec<&str> = nifs
+            .lines()
+            .filter_map(|line| line.strip_prefix("impl rustler::Resource for "))
+            .filter_map(|rest| rest.split_whitespace().next())
+            .collect();
+        assert!(resources.contains(&"FormulaGraph"), "{resources:?}");
+        for name in resources {
+            let register = format!("env.register::<super::formula_nif::{name}>()");
+            assert!(load.contains(&register), "{name} is not registered in load");
+        }
+    }
 }